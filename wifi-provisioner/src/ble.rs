@@ -5,22 +5,25 @@
 use std::sync::Arc;
 use std::time::Duration;
 
-use bluer::adv::Advertisement;
+use bluer::adv::{Advertisement, AdvertisementHandle};
 use bluer::gatt::local::{
-    characteristic_control, Application, Characteristic, CharacteristicControl,
-    CharacteristicControlEvent, CharacteristicNotify, CharacteristicNotifyMethod,
-    CharacteristicRead, CharacteristicWrite, CharacteristicWriteMethod, Service,
+    characteristic_control, Application, ApplicationHandle, Characteristic,
+    CharacteristicControl, CharacteristicControlEvent, CharacteristicNotify,
+    CharacteristicNotifyMethod, CharacteristicRead, CharacteristicWrite,
+    CharacteristicWriteMethod, Service,
 };
 use bluer::gatt::CharacteristicWriter;
-use bluer::{Adapter, Session};
+use bluer::{Adapter, Device, DeviceEvent, DeviceProperty, Session};
 use futures_util::StreamExt;
-use tokio::sync::{mpsc, RwLock};
-use tracing::{debug, error, info, warn};
-
-use crate::improv::{
-    build_device_info_response, build_provision_response, build_response, build_scan_response,
-    capabilities, characteristic, ImprovError, ImprovState, RpcCommand, RpcRequest, SERVICE_UUID,
-};
+use tokio::sync::{mpsc, Mutex, RwLock};
+use tracing::{debug, info, warn};
+
+use crate::connectivity::ConnectivityFailure;
+use crate::improv::{capabilities, characteristic, ImprovState, SERVICE_UUID};
+use crate::secret;
+use crate::session::{ImprovSession, SessionOutput};
+use crate::ssid::Ssid;
+use crate::state_machine::StateMachine;
 use crate::wifi::WifiManager;
 
 /// BLE manager configuration.
@@ -34,7 +37,14 @@ pub struct BleConfig {
     /// Hardware type for device info.
     pub hardware_type: String,
     /// URL to redirect to after successful provisioning.
+    ///
+    /// `{hostname}` is replaced with the lowercase device name, so the URL
+    /// follows hostname changes made during provisioning.
     pub redirect_url: String,
+    /// Require local confirmation before accepting WiFi credentials.
+    pub require_authorization: bool,
+    /// How long a local confirmation keeps the device authorized.
+    pub authorization_timeout: Duration,
 }
 
 impl Default for BleConfig {
@@ -45,39 +55,14 @@ impl Default for BleConfig {
             firmware_version: env!("CARGO_PKG_VERSION").to_string(),
             hardware_type: "RaspberryPi".to_string(),
             redirect_url: "http://dirtsim.local:8081".to_string(),
-        }
-    }
-}
-
-/// Shared state for BLE operations.
-pub struct BleState {
-    /// Current Improv state.
-    pub improv_state: ImprovState,
-    /// Current error state.
-    pub error_state: ImprovError,
-    /// Latest RPC result to be read by client.
-    pub rpc_result: Vec<u8>,
-    /// Whether advertising is active.
-    pub advertising: bool,
-    /// Active RPC result writer for sending notifications to subscribed clients.
-    pub rpc_result_notifier: Option<CharacteristicWriter>,
-}
-
-impl Default for BleState {
-    fn default() -> Self {
-        Self {
-            // Start authorized (no auth required for now).
-            improv_state: ImprovState::Authorized,
-            error_state: ImprovError::None,
-            rpc_result: Vec::new(),
-            advertising: false,
-            rpc_result_notifier: None,
+            require_authorization: false,
+            authorization_timeout: Duration::from_secs(60),
         }
     }
 }
 
 /// Events from BLE to main application.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum BleEvent {
     /// Client requested identify (blink LED, etc.).
     Identify,
@@ -85,16 +70,66 @@ pub enum BleEvent {
     ClientConnected,
     /// Client disconnected.
     ClientDisconnected,
+    /// Started joining this network.
+    ProvisioningStarted(Ssid),
     /// Provisioning succeeded with this URL.
     ProvisioningComplete(String),
+    /// Provisioning failed at this connectivity step.
+    ProvisioningFailed(ConnectivityFailure),
+    /// Client set a new hostname; the device name has been updated.
+    HostnameChanged(String),
+}
+
+/// Result type for BLE operations.
+pub type BleResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Control over the BLE side of the daemon.
+///
+/// This abstraction lets the WebSocket server and timers start and stop
+/// advertising and authorize clients without depending on BlueZ, so they can
+/// be tested with a mock.
+pub trait BleControl: Send + Sync {
+    /// Start advertising. Does nothing if already advertising.
+    fn start_advertising(&self) -> impl std::future::Future<Output = BleResult<()>> + Send;
+
+    /// Stop advertising. Does nothing if not advertising.
+    fn stop_advertising(&self) -> impl std::future::Future<Output = BleResult<()>> + Send;
+
+    /// Stop and start advertising again, picking up config changes.
+    fn restart_advertising(&self) -> impl std::future::Future<Output = BleResult<()>> + Send;
+
+    /// Whether an advertisement is currently registered with BlueZ.
+    fn is_advertising(&self) -> impl std::future::Future<Output = bool> + Send;
+
+    /// Confirm locally that the connected client may send credentials.
+    ///
+    /// Returns the Improv state after the confirmation.
+    fn authorize(&self) -> impl std::future::Future<Output = ImprovState> + Send;
+
+    /// Lock serializing advertising changes with the state inputs that
+    /// record them.
+    ///
+    /// It is held across the BlueZ call, so take it before the state lock,
+    /// never while holding it, and re-check the state once BlueZ is done.
+    fn advertising_lock(&self) -> &Mutex<()>;
 }
 
 /// BLE manager for Improv WiFi.
 pub struct BleManager<W: WifiManager> {
-    config: BleConfig,
-    state: Arc<RwLock<BleState>>,
-    wifi: Arc<W>,
+    config: Arc<RwLock<BleConfig>>,
+    session: Arc<ImprovSession<W>>,
+    /// Session outputs, taken by `init` to drive notifications.
+    outputs: Mutex<Option<mpsc::UnboundedReceiver<SessionOutput>>>,
+    notifiers: Arc<Mutex<Notifiers>>,
     event_tx: mpsc::Sender<BleEvent>,
+    /// Adapter in use, set once `init` has run.
+    adapter: Mutex<Option<Adapter>>,
+    /// Registered GATT application; dropping it unregisters the service.
+    app_handle: Mutex<Option<ApplicationHandle>>,
+    /// Active advertisement; dropping it stops advertising.
+    adv_handle: Mutex<Option<AdvertisementHandle>>,
+    /// See `BleControl::advertising_lock`.
+    advertising: Mutex<()>,
 }
 
 impl<W: WifiManager + 'static> BleManager<W> {
@@ -102,29 +137,45 @@ impl<W: WifiManager + 'static> BleManager<W> {
     pub fn new(
         config: BleConfig,
         wifi: Arc<W>,
+        state: Arc<RwLock<StateMachine>>,
         event_tx: mpsc::Sender<BleEvent>,
     ) -> Self {
+        let require_authorization = config.require_authorization;
+        let config = Arc::new(RwLock::new(config));
+        let (session, outputs) =
+            ImprovSession::new(Arc::clone(&config), wifi, state, require_authorization);
         Self {
             config,
-            state: Arc::new(RwLock::new(BleState::default())),
-            wifi,
+            session: Arc::new(session),
+            outputs: Mutex::new(Some(outputs)),
+            notifiers: Arc::new(Mutex::new(Notifiers::default())),
             event_tx,
+            adapter: Mutex::new(None),
+            app_handle: Mutex::new(None),
+            adv_handle: Mutex::new(None),
+            advertising: Mutex::new(()),
         }
     }
 
-    /// Get the shared state.
-    pub fn state(&self) -> Arc<RwLock<BleState>> {
-        Arc::clone(&self.state)
+    /// Get the shared configuration.
+    pub fn config(&self) -> Arc<RwLock<BleConfig>> {
+        Arc::clone(&self.config)
     }
 
-    /// Run the BLE GATT server.
+    /// Get the Improv session behind the GATT service.
+    pub fn session(&self) -> Arc<ImprovSession<W>> {
+        Arc::clone(&self.session)
+    }
+
+    /// Initialize the BLE GATT server.
     ///
     /// This will:
     /// 1. Initialize the Bluetooth adapter
     /// 2. Register the Improv WiFi GATT service
-    /// 3. Start advertising
-    /// 4. Handle incoming connections and commands
-    pub async fn run(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    /// 3. Start listening for notification subscriptions
+    ///
+    /// Advertising is not started here; use `start_advertising`.
+    pub async fn init(&self) -> BleResult<()> {
         info!("Initializing BLE...");
 
         // Connect to BlueZ.
@@ -139,27 +190,84 @@ impl<W: WifiManager + 'static> BleManager<W> {
         );
 
         // Set adapter name for advertising.
-        adapter.set_alias(self.config.device_name.clone()).await?;
+        let device_name = self.config.read().await.device_name.clone();
+        adapter.set_alias(device_name).await?;
 
         // Build and register the GATT application.
-        let (app, rpc_result_control) = self.build_gatt_application().await;
-        let _app_handle = adapter.serve_gatt_application(app).await?;
+        let (app, controls) = self.build_gatt_application().await;
+        let app_handle = adapter.serve_gatt_application(app).await?;
 
         info!("GATT application registered");
 
-        // Start advertising.
-        self.start_advertising(&adapter).await?;
+        *self.app_handle.lock().await = Some(app_handle);
+        *self.adapter.lock().await = Some(adapter.clone());
+
+        // Deliver session outputs as notifications, including state changes
+        // made over other transports.
+        self.session.watch_machine();
+        if let Some(mut outputs) = self.outputs.lock().await.take() {
+            let notifiers = Arc::clone(&self.notifiers);
+            let event_tx = self.event_tx.clone();
+            tokio::spawn(async move {
+                while let Some(output) = outputs.recv().await {
+                    match output {
+                        SessionOutput::State(state) => {
+                            let mut n = notifiers.lock().await;
+                            notify_all(&mut n.current_state, &[state.into()]).await;
+                        }
+                        SessionOutput::Error(error) => {
+                            let mut n = notifiers.lock().await;
+                            notify_all(&mut n.error_state, &[error.into()]).await;
+                        }
+                        SessionOutput::RpcResult(response) => {
+                            let mut n = notifiers.lock().await;
+                            if let Some(ref mut writer) = n.rpc_result {
+                                debug!(
+                                    "Sending RPC result notification ({} bytes)",
+                                    response.len()
+                                );
+                                if let Err(e) = writer.send(&response).await {
+                                    warn!("Failed to send RPC result notification: {}", e);
+                                }
+                            }
+                        }
+                        SessionOutput::Event(event) => {
+                            let _ = event_tx.send(event).await;
+                        }
+                    }
+                }
+            });
+        }
 
-        // Spawn task to handle RPC result notification subscriptions.
-        let state_for_notify = Arc::clone(&self.state);
+        // Spawn tasks to handle notification subscriptions. Improv clients
+        // subscribe to RPC results once connected, so that subscription
+        // marks a client connecting.
+        let notifiers = Arc::clone(&self.notifiers);
+        let session = Arc::clone(&self.session);
+        let event_tx = self.event_tx.clone();
         tokio::spawn(async move {
-            let mut control = rpc_result_control;
+            let mut control = controls.rpc_result;
+            let mut watcher: Option<tokio::task::JoinHandle<()>> = None;
             while let Some(event) = control.next().await {
                 match event {
                     CharacteristicControlEvent::Notify(writer) => {
                         info!("Client subscribed to RPC result notifications");
-                        let mut s = state_for_notify.write().await;
-                        s.rpc_result_notifier = Some(writer);
+                        // Results must fit in a single notification.
+                        session.set_max_packet_len(writer.mtu());
+                        // Resubscribing on the same connection is not a new client.
+                        let connected = watcher.as_ref().is_some_and(|w| !w.is_finished());
+                        if !connected {
+                            let _ = event_tx.send(BleEvent::ClientConnected).await;
+                            match adapter.device(writer.device_address()) {
+                                Ok(device) => {
+                                    let event_tx = event_tx.clone();
+                                    watcher =
+                                        Some(tokio::spawn(watch_disconnect(device, event_tx)));
+                                }
+                                Err(e) => warn!("Cannot watch BLE client: {}", e),
+                            }
+                        }
+                        notifiers.lock().await.rpc_result = Some(writer);
                     }
                     CharacteristicControlEvent::Write(_) => {
                         // RPC Result is read-only, ignore writes.
@@ -168,58 +276,106 @@ impl<W: WifiManager + 'static> BleManager<W> {
             }
         });
 
-        // Keep the application running.
-        info!("BLE server running, waiting for connections...");
+        let notifiers = Arc::clone(&self.notifiers);
+        tokio::spawn(async move {
+            let mut control = controls.current_state;
+            while let Some(event) = control.next().await {
+                if let CharacteristicControlEvent::Notify(writer) = event {
+                    info!("Client subscribed to current state notifications");
+                    notifiers.lock().await.current_state.push(writer);
+                }
+            }
+        });
 
-        // Wait forever (the handles keep things alive).
-        loop {
-            tokio::time::sleep(Duration::from_secs(3600)).await;
-        }
+        let notifiers = Arc::clone(&self.notifiers);
+        tokio::spawn(async move {
+            let mut control = controls.error_state;
+            while let Some(event) = control.next().await {
+                if let CharacteristicControlEvent::Notify(writer) = event {
+                    info!("Client subscribed to error state notifications");
+                    notifiers.lock().await.error_state.push(writer);
+                }
+            }
+        });
 
-        // Cleanup (unreachable but good practice).
-        #[allow(unreachable_code)]
-        {
-            drop(_app_handle);
-            Ok(())
-        }
+        info!("BLE server running, waiting for connections...");
+        Ok(())
     }
 
     /// Start BLE advertising.
-    pub async fn start_advertising(
-        &self,
-        adapter: &Adapter,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    pub async fn start_advertising(&self) -> BleResult<()> {
+        let mut adv_handle = self.adv_handle.lock().await;
+        if adv_handle.is_some() {
+            debug!("BLE advertising already active");
+            return Ok(());
+        }
+
+        let adapter = self.adapter.lock().await;
+        let adapter = adapter.as_ref().ok_or("BLE not initialized")?;
+
+        let device_name = self.config.read().await.device_name.clone();
         let adv = Advertisement {
             service_uuids: vec![SERVICE_UUID].into_iter().collect(),
-            local_name: Some(self.config.device_name.clone()),
+            local_name: Some(device_name.clone()),
             discoverable: Some(true),
             ..Default::default()
         };
 
-        let _handle = adapter.advertise(adv).await?;
-        info!("BLE advertising started as '{}'", self.config.device_name);
+        *adv_handle = Some(adapter.advertise(adv).await?);
+        info!("BLE advertising started as '{}'", device_name);
+        Ok(())
+    }
+
+    /// Stop BLE advertising.
+    pub async fn stop_advertising(&self) -> BleResult<()> {
+        // Dropping the handle unregisters the advertisement with BlueZ.
+        if self.adv_handle.lock().await.take().is_some() {
+            info!("BLE advertising stopped");
+        }
+        Ok(())
+    }
+
+    /// Restart BLE advertising.
+    pub async fn restart_advertising(&self) -> BleResult<()> {
+        self.stop_advertising().await?;
+        self.start_advertising().await
+    }
+
+    /// Authorize the client after local confirmation.
+    ///
+    /// See `ImprovSession::authorize`.
+    pub async fn authorize(&self) -> ImprovState {
+        self.session.authorize().await
+    }
+
+    /// Push the current device name to the adapter alias and advertisement.
+    ///
+    /// Called after a hostname change so the new name shows up in the
+    /// Bluetooth picker without restarting the daemon.
+    pub async fn apply_device_name(&self) -> BleResult<()> {
+        let device_name = self.config.read().await.device_name.clone();
 
         {
-            let mut state = self.state.write().await;
-            state.advertising = true;
+            let adapter = self.adapter.lock().await;
+            let adapter = adapter.as_ref().ok_or("BLE not initialized")?;
+            adapter.set_alias(device_name.clone()).await?;
         }
 
-        // Keep the handle alive by leaking it.
-        // In a real app, we'd store this handle to stop advertising later.
-        std::mem::forget(_handle);
+        info!("BLE device name changed to '{}'", device_name);
 
+        let _advertising = self.advertising.lock().await;
+        if self.adv_handle.lock().await.is_some() {
+            self.restart_advertising().await?;
+        }
         Ok(())
     }
 
     /// Build the GATT application with Improv WiFi service.
     ///
-    /// Returns the application and a control handle for the RPC Result characteristic
-    /// (used to receive notification subscription events).
-    async fn build_gatt_application(&self) -> (Application, CharacteristicControl) {
-        let state = Arc::clone(&self.state);
-        let config = self.config.clone();
-        let wifi = Arc::clone(&self.wifi);
-        let event_tx = self.event_tx.clone();
+    /// Returns the application and control handles for the notifying
+    /// characteristics (used to receive notification subscription events).
+    async fn build_gatt_application(&self) -> (Application, NotifyControls) {
+        let session = Arc::clone(&self.session);
 
         // Capabilities characteristic - read only.
         let capabilities_read = {
@@ -236,66 +392,54 @@ impl<W: WifiManager + 'static> BleManager<W> {
         };
 
         // Current State characteristic - read + notify.
-        let state_for_read = Arc::clone(&state);
+        let session_for_read = Arc::clone(&session);
         let current_state_read = CharacteristicRead {
             read: true,
             fun: Box::new(move |_req| {
-                let state = Arc::clone(&state_for_read);
-                Box::pin(async move {
-                    let s = state.read().await;
-                    Ok(vec![s.improv_state.into()])
-                })
+                let session = Arc::clone(&session_for_read);
+                Box::pin(async move { Ok(vec![session.improv_state().await.into()]) })
             }),
             ..Default::default()
         };
 
         // Error State characteristic - read + notify.
-        let state_for_error = Arc::clone(&state);
+        let session_for_error = Arc::clone(&session);
         let error_state_read = CharacteristicRead {
             read: true,
             fun: Box::new(move |_req| {
-                let state = Arc::clone(&state_for_error);
-                Box::pin(async move {
-                    let s = state.read().await;
-                    Ok(vec![s.error_state.into()])
-                })
+                let session = Arc::clone(&session_for_error);
+                Box::pin(async move { Ok(vec![session.error_state().await.into()]) })
             }),
             ..Default::default()
         };
 
         // RPC Result characteristic - read + notify (IO-based for push notifications).
-        let state_for_result = Arc::clone(&state);
+        let session_for_result = Arc::clone(&session);
         let rpc_result_read = CharacteristicRead {
             read: true,
             fun: Box::new(move |_req| {
-                let state = Arc::clone(&state_for_result);
-                Box::pin(async move {
-                    let s = state.read().await;
-                    Ok(s.rpc_result.clone())
-                })
+                let rpc_result = session_for_result.rpc_result();
+                Box::pin(async move { Ok(rpc_result) })
             }),
             ..Default::default()
         };
 
-        // Create control handle for RPC Result notifications.
+        // Create control handles for notifications.
+        let (current_state_control, current_state_handle) = characteristic_control();
+        let (error_state_control, error_state_handle) = characteristic_control();
         let (rpc_result_control, rpc_result_handle) = characteristic_control();
 
         // RPC Command characteristic - write only.
-        let state_for_cmd = Arc::clone(&state);
-        let wifi_for_cmd = Arc::clone(&wifi);
-        let config_for_cmd = config.clone();
-        let event_tx_for_cmd = event_tx.clone();
-
         let rpc_command_write = CharacteristicWrite {
             write: true,
             method: CharacteristicWriteMethod::Fun(Box::new(move |new_value, _req| {
-                let state = Arc::clone(&state_for_cmd);
-                let wifi = Arc::clone(&wifi_for_cmd);
-                let config = config_for_cmd.clone();
-                let event_tx = event_tx_for_cmd.clone();
+                let session = Arc::clone(&session);
 
                 Box::pin(async move {
-                    handle_rpc_command(&new_value, state, wifi, &config, event_tx).await;
+                    let mut new_value = new_value;
+                    session.handle_rpc(&new_value).await;
+                    // RPC data may carry credentials.
+                    secret::zeroize(&mut new_value);
                     Ok(())
                 })
             })),
@@ -313,28 +457,26 @@ impl<W: WifiManager + 'static> BleManager<W> {
                         read: Some(capabilities_read),
                         ..Default::default()
                     },
-                    // Current State (read + notify).
+                    // Current State (read + notify via IO).
                     Characteristic {
                         uuid: characteristic::CURRENT_STATE,
                         read: Some(current_state_read),
+                        control_handle: current_state_handle,
                         notify: Some(CharacteristicNotify {
                             notify: true,
-                            method: CharacteristicNotifyMethod::Fun(Box::new(|_| {
-                                Box::pin(async {})
-                            })),
+                            method: CharacteristicNotifyMethod::Io,
                             ..Default::default()
                         }),
                         ..Default::default()
                     },
-                    // Error State (read + notify).
+                    // Error State (read + notify via IO).
                     Characteristic {
                         uuid: characteristic::ERROR_STATE,
                         read: Some(error_state_read),
+                        control_handle: error_state_handle,
                         notify: Some(CharacteristicNotify {
                             notify: true,
-                            method: CharacteristicNotifyMethod::Fun(Box::new(|_| {
-                                Box::pin(async {})
-                            })),
+                            method: CharacteristicNotifyMethod::Io,
                             ..Default::default()
                         }),
                         ..Default::default()
@@ -363,173 +505,169 @@ impl<W: WifiManager + 'static> BleManager<W> {
             ..Default::default()
         };
 
-        (app, rpc_result_control)
+        let controls = NotifyControls {
+            current_state: current_state_control,
+            error_state: error_state_control,
+            rpc_result: rpc_result_control,
+        };
+
+        (app, controls)
     }
 }
 
-impl BleConfig {
-    fn clone(&self) -> Self {
-        Self {
-            device_name: self.device_name.clone(),
-            firmware_name: self.firmware_name.clone(),
-            firmware_version: self.firmware_version.clone(),
-            hardware_type: self.hardware_type.clone(),
-            redirect_url: self.redirect_url.clone(),
-        }
-    }
+/// Writers for clients subscribed to notifications.
+#[derive(Default)]
+struct Notifiers {
+    /// Active RPC result writer.
+    rpc_result: Option<CharacteristicWriter>,
+    /// Writers for clients subscribed to Current State notifications.
+    current_state: Vec<CharacteristicWriter>,
+    /// Writers for clients subscribed to Error State notifications.
+    error_state: Vec<CharacteristicWriter>,
 }
 
-/// Send an RPC result notification to the subscribed client.
-async fn send_rpc_notification(state: &Arc<RwLock<BleState>>, response: &[u8]) {
-    let mut s = state.write().await;
-    if let Some(ref mut writer) = s.rpc_result_notifier {
-        debug!("Sending RPC result notification ({} bytes)", response.len());
-        if let Err(e) = writer.send(response).await {
-            warn!("Failed to send RPC result notification: {}", e);
-        }
+/// Control handles for the characteristics that push notifications.
+struct NotifyControls {
+    current_state: CharacteristicControl,
+    error_state: CharacteristicControl,
+    rpc_result: CharacteristicControl,
+}
+
+impl<W: WifiManager + 'static> BleControl for BleManager<W> {
+    async fn start_advertising(&self) -> BleResult<()> {
+        BleManager::start_advertising(self).await
+    }
+
+    async fn stop_advertising(&self) -> BleResult<()> {
+        BleManager::stop_advertising(self).await
+    }
+
+    async fn restart_advertising(&self) -> BleResult<()> {
+        BleManager::restart_advertising(self).await
+    }
+
+    async fn is_advertising(&self) -> bool {
+        self.adv_handle.lock().await.is_some()
+    }
+
+    async fn authorize(&self) -> ImprovState {
+        BleManager::authorize(self).await
+    }
+
+    fn advertising_lock(&self) -> &Mutex<()> {
+        &self.advertising
     }
 }
 
-/// Handle an incoming RPC command.
-async fn handle_rpc_command<W: WifiManager>(
-    data: &[u8],
-    state: Arc<RwLock<BleState>>,
-    wifi: Arc<W>,
-    config: &BleConfig,
-    event_tx: mpsc::Sender<BleEvent>,
-) {
-    debug!("Received RPC command: {:?}", data);
+impl BleConfig {
+    /// Redirect URL with `{hostname}` filled in from the device name.
+    pub fn redirect_url(&self) -> String {
+        self.redirect_url
+            .replace("{hostname}", &self.device_name.to_lowercase())
+    }
+}
 
-    // Parse the RPC packet.
-    let request = match RpcRequest::parse(data) {
-        Ok(req) => req,
+/// Send `ClientDisconnected` once `device` drops its connection.
+async fn watch_disconnect(device: Device, event_tx: mpsc::Sender<BleEvent>) {
+    let events = match device.events().await {
+        Ok(events) => events,
         Err(e) => {
-            error!("Failed to parse RPC command: {}", e);
-            let mut s = state.write().await;
-            s.error_state = ImprovError::InvalidRpc;
+            warn!("Cannot watch BLE client {}: {}", device.address(), e);
             return;
         }
     };
+    futures_util::pin_mut!(events);
 
-    info!("Processing RPC command: {:?}", request.command);
-
-    match request.command {
-        RpcCommand::Identify => {
-            // Send identify event to main app.
-            let _ = event_tx.send(BleEvent::Identify).await;
-
-            // Send empty response to acknowledge.
-            let response = build_response(RpcCommand::Identify, &[]);
-            {
-                let mut s = state.write().await;
-                s.rpc_result = response.clone();
-            }
-            send_rpc_notification(&state, &response).await;
+    while let Some(DeviceEvent::PropertyChanged(property)) = events.next().await {
+        if matches!(property, DeviceProperty::Connected(false)) {
+            info!("BLE client {} disconnected", device.address());
+            let _ = event_tx.send(BleEvent::ClientDisconnected).await;
+            return;
         }
+    }
+}
 
-        RpcCommand::GetDeviceInfo => {
-            let response = build_device_info_response(
-                &config.firmware_name,
-                &config.firmware_version,
-                &config.hardware_type,
-                &config.device_name,
-            );
-
-            {
-                let mut s = state.write().await;
-                s.rpc_result = response.clone();
-                s.error_state = ImprovError::None;
-            }
-            send_rpc_notification(&state, &response).await;
+/// Send a notification to every subscribed writer, dropping closed ones.
+async fn notify_all(writers: &mut Vec<CharacteristicWriter>, value: &[u8]) {
+    let mut open = Vec::with_capacity(writers.len());
+    for writer in writers.drain(..) {
+        match writer.send(value).await {
+            Ok(()) => open.push(writer),
+            Err(e) => debug!("Dropping notification subscriber: {}", e),
         }
+    }
+    *writers = open;
+}
 
-        RpcCommand::ScanWifiNetworks => {
-            // Update state to show we're busy.
-            {
-                let mut s = state.write().await;
-                s.error_state = ImprovError::None;
-            }
-
-            // Perform the scan.
-            match wifi.scan().await {
-                Ok(networks) => {
-                    let network_tuples: Vec<(String, i32, bool)> = networks
-                        .iter()
-                        .map(|n| (n.ssid.clone(), n.signal, n.security != "open"))
-                        .collect();
-
-                    let response = build_scan_response(&network_tuples);
+/// Mock BLE control for testing.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockBleControl {
+    pub advertising: std::sync::atomic::AtomicBool,
+    pub authorized: std::sync::atomic::AtomicBool,
+    pub start_error: Option<String>,
+    /// If set, starting waits for a notification, as if BlueZ were slow.
+    pub start_gate: Option<Arc<tokio::sync::Notify>>,
+    pub adv_lock: Mutex<()>,
+}
 
-                    {
-                        let mut s = state.write().await;
-                        s.rpc_result = response.clone();
-                    }
-                    send_rpc_notification(&state, &response).await;
-                }
-                Err(e) => {
-                    error!("WiFi scan failed: {}", e);
-                    let mut s = state.write().await;
-                    s.error_state = ImprovError::Unknown;
-                }
-            }
+#[cfg(test)]
+impl BleControl for MockBleControl {
+    async fn start_advertising(&self) -> BleResult<()> {
+        if let Some(msg) = &self.start_error {
+            return Err(msg.clone().into());
         }
+        if let Some(gate) = &self.start_gate {
+            gate.notified().await;
+        }
+        self.advertising
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        Ok(())
+    }
 
-        RpcCommand::SendWifiSettings => {
-            // Parse credentials.
-            let creds = match request.parse_wifi_credentials() {
-                Ok(c) => c,
-                Err(e) => {
-                    error!("Failed to parse WiFi credentials: {}", e);
-                    let mut s = state.write().await;
-                    s.error_state = ImprovError::InvalidRpc;
-                    return;
-                }
-            };
-
-            info!("Attempting to connect to WiFi: {}", creds.ssid);
-
-            // Update state to provisioning.
-            {
-                let mut s = state.write().await;
-                s.improv_state = ImprovState::Provisioning;
-                s.error_state = ImprovError::None;
-            }
+    async fn stop_advertising(&self) -> BleResult<()> {
+        self.advertising
+            .store(false, std::sync::atomic::Ordering::SeqCst);
+        Ok(())
+    }
 
-            // Attempt to connect.
-            match wifi.connect(&creds.ssid, &creds.password).await {
-                Ok(()) => {
-                    info!("Successfully connected to WiFi: {}", creds.ssid);
+    async fn restart_advertising(&self) -> BleResult<()> {
+        self.stop_advertising().await?;
+        self.start_advertising().await
+    }
 
-                    let response = build_provision_response(&config.redirect_url);
+    async fn is_advertising(&self) -> bool {
+        self.advertising.load(std::sync::atomic::Ordering::SeqCst)
+    }
 
-                    {
-                        let mut s = state.write().await;
-                        s.improv_state = ImprovState::Provisioned;
-                        s.rpc_result = response.clone();
-                    }
+    async fn authorize(&self) -> ImprovState {
+        self.authorized
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        ImprovState::Authorized
+    }
 
-                    // Send the notification BEFORE emitting the event.
-                    send_rpc_notification(&state, &response).await;
+    fn advertising_lock(&self) -> &Mutex<()> {
+        &self.adv_lock
+    }
+}
 
-                    let _ = event_tx
-                        .send(BleEvent::ProvisioningComplete(config.redirect_url.clone()))
-                        .await;
-                }
-                Err(e) => {
-                    error!("Failed to connect to WiFi: {}", e);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                    let mut s = state.write().await;
-                    s.improv_state = ImprovState::Authorized;
-                    s.error_state = ImprovError::UnableToConnect;
-                }
-            }
-        }
+    #[test]
+    fn redirect_url_substitutes_hostname() {
+        let config = BleConfig {
+            device_name: "DirtSim-Kitchen".into(),
+            redirect_url: "http://{hostname}.local:8081".into(),
+            ..Default::default()
+        };
+        assert_eq!(config.redirect_url(), "http://dirtsim-kitchen.local:8081");
+    }
 
-        RpcCommand::Hostname => {
-            // Hostname setting not implemented yet.
-            warn!("Hostname command not implemented");
-            let mut s = state.write().await;
-            s.error_state = ImprovError::UnknownCommand;
-        }
+    #[test]
+    fn redirect_url_without_placeholder_is_unchanged() {
+        let config = BleConfig::default();
+        assert_eq!(config.redirect_url(), "http://dirtsim.local:8081");
     }
 }
@@ -0,0 +1,109 @@
+//! Physical button input for local authorization.
+//!
+//! Reads key events from a Linux input device (e.g. a `gpio-keys` button
+//! exposed as `/dev/input/event0`) and reports each key press.
+
+use std::io::Read;
+use std::path::PathBuf;
+
+use tokio::sync::mpsc;
+use tracing::{debug, error, info};
+
+/// `EV_KEY` event type from `linux/input-event-codes.h`.
+const EV_KEY: u16 = 0x01;
+
+/// Key value for a press (0 is release, 2 is autorepeat).
+const KEY_PRESSED: i32 = 1;
+
+/// Size of `struct input_event` on this platform.
+///
+/// The timestamp is two `long`s, so the struct is 16 bytes on 32-bit targets
+/// and 24 bytes on 64-bit targets.
+const EVENT_SIZE: usize = 2 * std::mem::size_of::<usize>() + 8;
+
+/// Parse one `struct input_event` and return the key code if it is a key press.
+pub fn parse_key_press(event: &[u8]) -> Option<u16> {
+    if event.len() != EVENT_SIZE {
+        return None;
+    }
+
+    let fields = &event[EVENT_SIZE - 8..];
+    let event_type = u16::from_ne_bytes([fields[0], fields[1]]);
+    let code = u16::from_ne_bytes([fields[2], fields[3]]);
+    let value = i32::from_ne_bytes([fields[4], fields[5], fields[6], fields[7]]);
+
+    (event_type == EV_KEY && value == KEY_PRESSED).then_some(code)
+}
+
+/// Watch an input device and send a message for every key press.
+///
+/// Input devices only support blocking reads, so this runs on its own thread.
+/// The channel closes if the device cannot be read.
+pub fn spawn_button_listener(path: PathBuf) -> mpsc::Receiver<u16> {
+    let (tx, rx) = mpsc::channel(4);
+
+    std::thread::spawn(move || {
+        let mut device = match std::fs::File::open(&path) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("Failed to open button device {}: {}", path.display(), e);
+                return;
+            }
+        };
+
+        info!("Listening for button presses on {}", path.display());
+
+        let mut buf = [0u8; EVENT_SIZE];
+        loop {
+            if let Err(e) = device.read_exact(&mut buf) {
+                error!("Failed to read button device {}: {}", path.display(), e);
+                return;
+            }
+
+            if let Some(code) = parse_key_press(&buf) {
+                debug!("Button pressed (key code {})", code);
+                if tx.blocking_send(code).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_event(event_type: u16, code: u16, value: i32) -> Vec<u8> {
+        let mut event = vec![0u8; EVENT_SIZE - 8];
+        event.extend_from_slice(&event_type.to_ne_bytes());
+        event.extend_from_slice(&code.to_ne_bytes());
+        event.extend_from_slice(&value.to_ne_bytes());
+        event
+    }
+
+    #[test]
+    fn parses_key_press() {
+        let event = make_event(EV_KEY, 28, KEY_PRESSED);
+        assert_eq!(parse_key_press(&event), Some(28));
+    }
+
+    #[test]
+    fn ignores_release_and_repeat() {
+        assert_eq!(parse_key_press(&make_event(EV_KEY, 28, 0)), None);
+        assert_eq!(parse_key_press(&make_event(EV_KEY, 28, 2)), None);
+    }
+
+    #[test]
+    fn ignores_other_event_types() {
+        // EV_SYN.
+        assert_eq!(parse_key_press(&make_event(0x00, 0, 1)), None);
+    }
+
+    #[test]
+    fn rejects_wrong_size() {
+        assert_eq!(parse_key_press(&[0u8; 4]), None);
+    }
+}
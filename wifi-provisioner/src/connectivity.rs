@@ -0,0 +1,462 @@
+//! Post-connect connectivity verification.
+//!
+//! A backend reporting a successful connect only means the attempt didn't
+//! fail outright. Before calling a device provisioned, we check each step a
+//! working connection needs, in order: association with the requested
+//! network, a usable IP address, a default gateway and DNS resolution.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::ipconfig::IpPrefix;
+use crate::ssid::Ssid;
+use crate::wifi::{ErrorCode, WifiManager};
+
+/// How long to wait for a new connection to come fully online.
+pub const VERIFY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Delay between verification attempts.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Host resolved to check DNS.
+const DNS_CHECK_HOST: &str = "allan.pizza";
+
+/// How long a single DNS lookup may take.
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One step of connectivity verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectivityStep {
+    /// Associated with the requested network.
+    Association,
+    /// Has a non-loopback, non-link-local IPv4 or IPv6 address.
+    Address,
+    /// Has a default route.
+    Gateway,
+    /// Can resolve a public host name.
+    Dns,
+}
+
+impl fmt::Display for ConnectivityStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ConnectivityStep::Association => "association",
+            ConnectivityStep::Address => "address",
+            ConnectivityStep::Gateway => "gateway",
+            ConnectivityStep::Dns => "dns",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The step that failed and why.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+pub struct ConnectivityFailure {
+    pub step: ConnectivityStep,
+    pub detail: String,
+    /// Client-visible class of the failure.
+    #[serde(default)]
+    pub code: ErrorCode,
+    /// SSID of the previous connection, if it was restored afterwards.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restored: Option<String>,
+}
+
+impl ConnectivityFailure {
+    /// A failure at `step`. Missing an address means DHCP did not finish.
+    pub fn new(step: ConnectivityStep, detail: impl Into<String>) -> Self {
+        let code = match step {
+            ConnectivityStep::Address => ErrorCode::DhcpTimeout,
+            _ => ErrorCode::Unknown,
+        };
+        Self {
+            step,
+            detail: detail.into(),
+            code,
+            restored: None,
+        }
+    }
+
+    /// Set the client-visible class, e.g. from the backend's error.
+    pub fn with_code(mut self, code: ErrorCode) -> Self {
+        self.code = code;
+        self
+    }
+
+    /// Note that the previous connection to `ssid` was restored.
+    pub fn with_restored(mut self, ssid: impl Into<String>) -> Self {
+        self.restored = Some(ssid.into());
+        self
+    }
+}
+
+impl fmt::Display for ConnectivityFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} check failed: {}", self.step, self.detail)?;
+        if let Some(ssid) = &self.restored {
+            write!(f, ", restored {}", ssid)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConnectivityFailure {}
+
+/// Result of connectivity verification.
+pub type ConnectivityResult = Result<(), ConnectivityFailure>;
+
+/// Wait until the device is online on `ssid`, or report the failing step.
+///
+/// Retries until `timeout` has passed, since addresses and routes take a
+/// moment to appear after association. Runs at least once.
+pub async fn verify<W: WifiManager>(
+    wifi: &W,
+    ssid: &Ssid,
+    timeout: Duration,
+) -> ConnectivityResult {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let failure = match check_once(wifi, ssid).await {
+            Ok(()) => {
+                info!("Connectivity verified on {}", ssid);
+                return Ok(());
+            }
+            Err(failure) => failure,
+        };
+
+        if Instant::now() + RETRY_INTERVAL > deadline {
+            warn!("Connectivity verification failed: {}", failure);
+            return Err(failure);
+        }
+        debug!("Not online yet ({}), retrying", failure);
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+async fn check_once<W: WifiManager>(wifi: &W, ssid: &Ssid) -> ConnectivityResult {
+    let status = wifi
+        .status()
+        .await
+        .map_err(|e| {
+            ConnectivityFailure::new(ConnectivityStep::Association, e.to_string())
+                .with_code(e.code())
+        })?;
+
+    match status.ssid {
+        Some(current) if status.connected && current == *ssid => {}
+        Some(current) if status.connected => {
+            return Err(ConnectivityFailure::new(
+                ConnectivityStep::Association,
+                format!("connected to {} instead of {}", current, ssid),
+            ));
+        }
+        _ => {
+            return Err(ConnectivityFailure::new(
+                ConnectivityStep::Association,
+                format!("not associated with {}", ssid),
+            ));
+        }
+    }
+
+    wifi.check_network().await
+}
+
+/// Check address, gateway and DNS on this machine.
+///
+/// The address and gateway must be on `interface` if given, so a wired
+/// link can't stand in for a WiFi network that never got a lease.
+pub async fn check_system(interface: Option<&str>) -> ConnectivityResult {
+    let addresses = interface_addresses(interface).map_err(|e| {
+        ConnectivityFailure::new(ConnectivityStep::Address, format!("getifaddrs: {}", e))
+    })?;
+    if !addresses.iter().any(|address| is_usable_address(&address.addr)) {
+        return Err(ConnectivityFailure::new(
+            ConnectivityStep::Address,
+            "no IPv4 or global IPv6 address",
+        ));
+    }
+
+    let ipv4_routes = std::fs::read_to_string("/proc/net/route").unwrap_or_default();
+    let ipv6_routes = std::fs::read_to_string("/proc/net/ipv6_route").unwrap_or_default();
+    if !has_ipv4_default_route(&ipv4_routes, interface)
+        && !has_ipv6_default_route(&ipv6_routes, interface)
+    {
+        return Err(ConnectivityFailure::new(
+            ConnectivityStep::Gateway,
+            "no default route",
+        ));
+    }
+
+    resolve(DNS_CHECK_HOST).await
+}
+
+async fn resolve(host: &str) -> ConnectivityResult {
+    let lookup = tokio::net::lookup_host((host, 443));
+    match tokio::time::timeout(DNS_TIMEOUT, lookup).await {
+        Ok(Ok(mut addrs)) => match addrs.next() {
+            Some(_) => Ok(()),
+            None => Err(ConnectivityFailure::new(
+                ConnectivityStep::Dns,
+                format!("no addresses for {}", host),
+            )),
+        },
+        Ok(Err(e)) => Err(ConnectivityFailure::new(
+            ConnectivityStep::Dns,
+            format!("failed to resolve {}: {}", host, e),
+        )),
+        Err(_) => Err(ConnectivityFailure::new(
+            ConnectivityStep::Dns,
+            format!("timed out resolving {}", host),
+        )),
+    }
+}
+
+/// Usable addresses on `interface` (or every interface), for reporting in
+/// status.
+pub fn local_addresses(interface: Option<&str>) -> Vec<IpPrefix> {
+    match interface_addresses(interface) {
+        Ok(addresses) => addresses
+            .into_iter()
+            .filter(|address| is_usable_address(&address.addr))
+            .collect(),
+        Err(e) => {
+            warn!("getifaddrs failed: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Addresses assigned to `interface`, or to every non-loopback interface,
+/// with prefix lengths.
+fn interface_addresses(interface: Option<&str>) -> std::io::Result<Vec<IpPrefix>> {
+    let mut addresses = Vec::new();
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+
+    // SAFETY: getifaddrs fills `ifaddrs` with a linked list that stays valid
+    // until freeifaddrs. `ifa_name` is a NUL-terminated string; `ifa_addr`
+    // and `ifa_netmask` are checked for null and only cast to the sockaddr
+    // type matching the address family.
+    unsafe {
+        if libc::getifaddrs(&mut ifaddrs) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut entry = ifaddrs;
+        while !entry.is_null() {
+            let ifa = &*entry;
+            entry = ifa.ifa_next;
+
+            if ifa.ifa_addr.is_null() || ifa.ifa_flags & libc::IFF_LOOPBACK as u32 != 0 {
+                continue;
+            }
+            if let Some(interface) = interface {
+                let name = std::ffi::CStr::from_ptr(ifa.ifa_name);
+                if name.to_bytes() != interface.as_bytes() {
+                    continue;
+                }
+            }
+
+            let netmask = (!ifa.ifa_netmask.is_null()).then_some(ifa.ifa_netmask);
+            match i32::from((*ifa.ifa_addr).sa_family) {
+                libc::AF_INET => {
+                    let addr = &*(ifa.ifa_addr as *const libc::sockaddr_in);
+                    let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                    let prefix = netmask.map_or(32, |mask| {
+                        let mask = &*(mask as *const libc::sockaddr_in);
+                        mask.sin_addr.s_addr.count_ones() as u8
+                    });
+                    addresses.push(IpPrefix {
+                        addr: IpAddr::V4(ip),
+                        prefix,
+                    });
+                }
+                libc::AF_INET6 => {
+                    let addr = &*(ifa.ifa_addr as *const libc::sockaddr_in6);
+                    let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                    let prefix = netmask.map_or(128, |mask| {
+                        let mask = &*(mask as *const libc::sockaddr_in6);
+                        mask.sin6_addr.s6_addr.iter().map(|b| b.count_ones() as u8).sum()
+                    });
+                    addresses.push(IpPrefix {
+                        addr: IpAddr::V6(ip),
+                        prefix,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        libc::freeifaddrs(ifaddrs);
+    }
+
+    Ok(addresses)
+}
+
+/// Whether an address can reach beyond the local link.
+fn is_usable_address(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => !v4.is_loopback() && !v4.is_link_local() && !v4.is_unspecified(),
+        // fe80::/10 is link-local.
+        IpAddr::V6(v6) => {
+            !v6.is_loopback() && !v6.is_unspecified() && (v6.segments()[0] & 0xffc0) != 0xfe80
+        }
+    }
+}
+
+/// Whether /proc/net/route has an up default route through a gateway, on
+/// `interface` if given.
+fn has_ipv4_default_route(table: &str, interface: Option<&str>) -> bool {
+    const RTF_UP: u32 = 0x1;
+    const RTF_GATEWAY: u32 = 0x2;
+
+    table.lines().skip(1).any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 {
+            return false;
+        }
+        let flags = u32::from_str_radix(fields[3], 16).unwrap_or(0);
+        interface.is_none_or(|interface| fields[0] == interface)
+            && fields[1] == "00000000"
+            && flags & (RTF_UP | RTF_GATEWAY) == RTF_UP | RTF_GATEWAY
+    })
+}
+
+/// Whether /proc/net/ipv6_route has a ::/0 route through a gateway, on
+/// `interface` if given.
+///
+/// Fields: destination, prefix length, source, source prefix length, next
+/// hop, metric, refcount, use, flags, device.
+fn has_ipv6_default_route(table: &str, interface: Option<&str>) -> bool {
+    let zero = "0".repeat(32);
+
+    table.lines().any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields.len() >= 10
+            && fields[0] == zero
+            && fields[1] == "00"
+            && fields[4] != zero
+            && fields[9] != "lo"
+            && interface.is_none_or(|interface| fields[9] == interface)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wifi::{ConnectRequest, MockWifiManager, WifiStatus};
+
+    #[test]
+    fn usable_addresses() {
+        assert!(is_usable_address(&"192.168.1.20".parse().unwrap()));
+        assert!(is_usable_address(&"2001:db8::1".parse().unwrap()));
+        assert!(!is_usable_address(&"127.0.0.1".parse().unwrap()));
+        assert!(!is_usable_address(&"169.254.10.1".parse().unwrap()));
+        assert!(!is_usable_address(&"fe80::1".parse().unwrap()));
+        assert!(!is_usable_address(&"::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn detects_ipv4_default_route() {
+        let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+            wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\n\
+            wlan0\t0001A8C0\t00000000\t0001\t0\t0\t600\t00FFFFFF\n";
+        assert!(has_ipv4_default_route(table, None));
+
+        let local_only = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+            wlan0\t0001A8C0\t00000000\t0001\t0\t0\t600\t00FFFFFF\n";
+        assert!(!has_ipv4_default_route(local_only, None));
+        assert!(!has_ipv4_default_route("", None));
+    }
+
+    #[test]
+    fn detects_ipv6_default_route() {
+        let zero = "0".repeat(32);
+        let gateway = "fe800000000000000000000000000001";
+        let table = format!(
+            "{z} 00 {z} 00 {g} 00000400 00000001 00000000 00000003 wlan0\n",
+            z = zero,
+            g = gateway
+        );
+        assert!(has_ipv6_default_route(&table, None));
+
+        let unreachable = format!(
+            "{z} 00 {z} 00 {z} ffffffff 00000001 00000000 00200200 lo\n",
+            z = zero
+        );
+        assert!(!has_ipv6_default_route(&unreachable, None));
+    }
+
+    #[test]
+    fn default_route_must_be_on_the_wifi_interface() {
+        let ipv4 = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+            eth0\t00000000\t0100000A\t0003\t0\t0\t100\t00000000\n\
+            wlan0\t0001A8C0\t00000000\t0001\t0\t0\t600\t00FFFFFF\n";
+        assert!(has_ipv4_default_route(ipv4, Some("eth0")));
+        assert!(!has_ipv4_default_route(ipv4, Some("wlan0")));
+
+        let zero = "0".repeat(32);
+        let ipv6 = format!(
+            "{z} 00 {z} 00 fe800000000000000000000000000001 00000400 00000001 00000000 00000003 eth0\n",
+            z = zero
+        );
+        assert!(has_ipv6_default_route(&ipv6, Some("eth0")));
+        assert!(!has_ipv6_default_route(&ipv6, Some("wlan0")));
+    }
+
+    #[test]
+    fn failure_display_names_the_step() {
+        let failure = ConnectivityFailure::new(ConnectivityStep::Dns, "timed out");
+        assert_eq!(failure.to_string(), "dns check failed: timed out");
+        assert_eq!(
+            serde_json::to_string(&failure).unwrap(),
+            r#"{"step":"dns","detail":"timed out","code":1}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_requires_association_with_requested_ssid() {
+        let wifi = MockWifiManager {
+            status: WifiStatus {
+                connected: true,
+                ssid: Some("other".into()),
+            },
+            ..Default::default()
+        };
+
+        let failure = verify(&wifi, &"home".into(), Duration::ZERO).await.unwrap_err();
+        assert_eq!(failure.step, ConnectivityStep::Association);
+        assert!(failure.detail.contains("other"));
+    }
+
+    #[tokio::test]
+    async fn verify_reports_network_check_failure() {
+        let wifi = MockWifiManager {
+            network_check: Err(ConnectivityFailure::new(
+                ConnectivityStep::Gateway,
+                "no route",
+            )),
+            ..Default::default()
+        };
+        wifi.connect(&ConnectRequest::psk("home", "hunter22")).await.unwrap();
+
+        let failure = verify(&wifi, &"home".into(), Duration::ZERO).await.unwrap_err();
+        assert_eq!(failure.step, ConnectivityStep::Gateway);
+    }
+
+    #[tokio::test]
+    async fn verify_succeeds_when_online() {
+        let wifi = MockWifiManager::default();
+        wifi.connect(&ConnectRequest::psk("home", "hunter22")).await.unwrap();
+
+        assert_eq!(verify(&wifi, &"home".into(), Duration::ZERO).await, Ok(()));
+    }
+}
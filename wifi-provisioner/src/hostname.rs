@@ -0,0 +1,214 @@
+//! Hostname management via systemd-hostnamed (hostnamectl).
+//!
+//! The daemon runs with `ProtectSystem=strict`, so it cannot write
+//! `/etc/hostname` itself. hostnamectl asks systemd-hostnamed over D-Bus to
+//! make the change on our behalf.
+//!
+//! `hostname-setup` copies `/boot/hostname.txt` over `/etc/hostname` at every
+//! boot, so on images that have the file a new name is written there too;
+//! otherwise it would be lost on the next reboot.
+
+use std::path::Path;
+use std::process::Stdio;
+use tokio::process::Command;
+use tracing::{debug, error, info};
+
+/// Maximum hostname length (a single DNS label).
+pub const MAX_HOSTNAME_LEN: usize = 63;
+
+/// Hostname file `hostname-setup` applies at boot.
+pub const BOOT_HOSTNAME_FILE: &str = "/boot/hostname.txt";
+
+/// Errors from hostname operations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostnameError {
+    /// Hostname fails validation.
+    Invalid(String),
+    /// hostnamectl command failed.
+    CommandFailed(String),
+    /// The boot hostname file could not be updated.
+    Persist(String),
+}
+
+impl std::fmt::Display for HostnameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HostnameError::Invalid(name) => write!(f, "Invalid hostname: {:?}", name),
+            HostnameError::CommandFailed(msg) => write!(f, "hostnamectl failed: {}", msg),
+            HostnameError::Persist(msg) => write!(f, "Failed to persist hostname: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for HostnameError {}
+
+/// Validate a hostname.
+///
+/// Uses the same rules as `hostname-setup`: alphanumeric and hyphens only,
+/// not starting with a hyphen. Also limited to a single DNS label.
+pub fn validate_hostname(name: &str) -> Result<(), HostnameError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_HOSTNAME_LEN
+        && !name.starts_with('-')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(HostnameError::Invalid(name.to_string()))
+    }
+}
+
+/// Get the current hostname.
+pub fn get_hostname() -> Result<String, HostnameError> {
+    match std::fs::read_to_string("/proc/sys/kernel/hostname") {
+        Ok(name) => Ok(name.trim().to_string()),
+        Err(e) => Err(HostnameError::CommandFailed(format!(
+            "Failed to read hostname: {}",
+            e
+        ))),
+    }
+}
+
+/// Write `name` to the boot hostname file at `path`, if it exists.
+pub fn update_boot_hostname(path: &Path, name: &str) -> Result<(), HostnameError> {
+    if !path.exists() {
+        return Ok(());
+    }
+    debug!("Writing hostname to {}", path.display());
+    std::fs::write(path, format!("{}\n", name))
+        .map_err(|e| HostnameError::Persist(format!("{}: {}", path.display(), e)))
+}
+
+/// Validate and set the system hostname, and update the boot hostname file.
+pub async fn set_hostname(name: &str) -> Result<(), HostnameError> {
+    validate_hostname(name)?;
+
+    debug!("Running: hostnamectl set-hostname {}", name);
+
+    let output = Command::new("hostnamectl")
+        .args(["set-hostname", name])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(|e| {
+            HostnameError::CommandFailed(format!("Failed to execute hostnamectl: {}", e))
+        })?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        error!("hostnamectl failed: {}", stderr);
+        return Err(HostnameError::CommandFailed(stderr.into_owned()));
+    }
+
+    update_boot_hostname(Path::new(BOOT_HOSTNAME_FILE), name)?;
+
+    info!("Hostname set to: {}", name);
+    Ok(())
+}
+
+/// Access to the system hostname.
+///
+/// Lets Improv sessions read and change the hostname without touching the
+/// host's, so they can be tested with a mock.
+pub trait HostnameControl: Send + Sync {
+    /// Get the current hostname.
+    fn get(&self) -> Result<String, HostnameError>;
+
+    /// Validate and set the hostname.
+    fn set(
+        &self,
+        name: &str,
+    ) -> impl std::future::Future<Output = Result<(), HostnameError>> + Send;
+}
+
+/// The real hostname, via `get_hostname` and `set_hostname`.
+pub struct SystemHostname;
+
+impl HostnameControl for SystemHostname {
+    fn get(&self) -> Result<String, HostnameError> {
+        get_hostname()
+    }
+
+    async fn set(&self, name: &str) -> Result<(), HostnameError> {
+        set_hostname(name).await
+    }
+}
+
+/// Mock hostname for testing.
+#[cfg(test)]
+pub struct MockHostname {
+    pub name: std::sync::Mutex<String>,
+}
+
+#[cfg(test)]
+impl Default for MockHostname {
+    fn default() -> Self {
+        Self {
+            name: std::sync::Mutex::new("dirtsim".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+impl HostnameControl for MockHostname {
+    fn get(&self) -> Result<String, HostnameError> {
+        Ok(self.name.lock().unwrap().clone())
+    }
+
+    async fn set(&self, name: &str) -> Result<(), HostnameError> {
+        validate_hostname(name)?;
+        *self.name.lock().unwrap() = name.to_string();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_valid_hostnames() {
+        assert!(validate_hostname("dirtsim").is_ok());
+        assert!(validate_hostname("DirtSim-A1B2").is_ok());
+        assert!(validate_hostname("inky-soup-2").is_ok());
+        assert!(validate_hostname("a").is_ok());
+        assert!(validate_hostname("1pi").is_ok());
+    }
+
+    #[test]
+    fn rejects_leading_hyphen() {
+        assert!(validate_hostname("-dirtsim").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_characters() {
+        assert!(validate_hostname("dirt sim").is_err());
+        assert!(validate_hostname("dirt_sim").is_err());
+        assert!(validate_hostname("dirtsim.local").is_err());
+        assert!(validate_hostname("dírtsim").is_err());
+    }
+
+    #[test]
+    fn rejects_empty_and_too_long() {
+        assert!(validate_hostname("").is_err());
+        assert!(validate_hostname(&"a".repeat(MAX_HOSTNAME_LEN)).is_ok());
+        assert!(validate_hostname(&"a".repeat(MAX_HOSTNAME_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn updates_boot_hostname_only_if_present() {
+        let dir = std::env::temp_dir().join(format!("hostname-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hostname.txt");
+
+        update_boot_hostname(&path, "inky-soup").unwrap();
+        assert!(!path.exists());
+
+        std::fs::write(&path, "dirtsim\n").unwrap();
+        update_boot_hostname(&path, "inky-soup").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "inky-soup\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
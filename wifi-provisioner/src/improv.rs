@@ -3,6 +3,13 @@
 //! See https://www.improv-wifi.com/ble/ for the protocol specification.
 
 use bluer::Uuid;
+use tracing::warn;
+
+use crate::ipconfig::{self, IpConfig, IpMethod, IpSettings};
+use crate::protocol::{EapMethod, EnterpriseCredentials, Phase2Auth};
+use crate::regdomain::CountryCode;
+use crate::secret::{self, Secret};
+use crate::ssid::Ssid;
 
 /// Improv WiFi service UUID.
 pub const SERVICE_UUID: Uuid = Uuid::from_u128(0x00467768_6228_2272_4663_277478268000);
@@ -93,6 +100,16 @@ pub enum RpcCommand {
     ScanWifiNetworks = 0x04,
     /// Get or set hostname.
     Hostname = 0x05,
+    /// Send WPA2/WPA3-Enterprise (802.1X) credentials. Vendor extension.
+    SendEnterpriseSettings = 0x80,
+    /// Upload a chunk of the CA certificate for the next enterprise
+    /// network. Vendor extension.
+    CaCertificate = 0x81,
+    /// Set static IP, DNS and proxy settings for the next network. Vendor
+    /// extension.
+    IpSettings = 0x82,
+    /// Get or set the WiFi regulatory country. Vendor extension.
+    Country = 0x83,
 }
 
 impl TryFrom<u8> for RpcCommand {
@@ -105,6 +122,10 @@ impl TryFrom<u8> for RpcCommand {
             0x03 => Ok(RpcCommand::GetDeviceInfo),
             0x04 => Ok(RpcCommand::ScanWifiNetworks),
             0x05 => Ok(RpcCommand::Hostname),
+            0x80 => Ok(RpcCommand::SendEnterpriseSettings),
+            0x81 => Ok(RpcCommand::CaCertificate),
+            0x82 => Ok(RpcCommand::IpSettings),
+            0x83 => Ok(RpcCommand::Country),
             _ => Err(RpcError::UnknownCommand(value)),
         }
     }
@@ -121,6 +142,12 @@ pub enum RpcError {
     UnknownCommand(u8),
     /// Data length doesn't match packet size.
     LengthMismatch { expected: usize, actual: usize },
+    /// String too long for its length byte.
+    StringTooLong(usize),
+    /// Packet data too long for the length byte.
+    DataTooLong(usize),
+    /// A field holds a value the command does not accept.
+    InvalidValue(String),
 }
 
 impl std::fmt::Display for RpcError {
@@ -134,6 +161,13 @@ impl std::fmt::Display for RpcError {
             RpcError::LengthMismatch { expected, actual } => {
                 write!(f, "Length mismatch: expected {}, got {}", expected, actual)
             }
+            RpcError::StringTooLong(len) => {
+                write!(f, "String too long: {} bytes (max {})", len, u8::MAX)
+            }
+            RpcError::DataTooLong(len) => {
+                write!(f, "Data too long: {} bytes (max {})", len, u8::MAX)
+            }
+            RpcError::InvalidValue(msg) => write!(f, "Invalid value: {}", msg),
         }
     }
 }
@@ -141,17 +175,36 @@ impl std::fmt::Display for RpcError {
 impl std::error::Error for RpcError {}
 
 /// Parsed RPC request.
-#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// The data may hold credentials, so it is wiped on drop and left out of
+/// `Debug`.
+#[derive(Clone, PartialEq, Eq)]
 pub struct RpcRequest {
     pub command: RpcCommand,
     pub data: Vec<u8>,
 }
 
+impl Drop for RpcRequest {
+    fn drop(&mut self) {
+        secret::zeroize(&mut self.data);
+    }
+}
+
+impl std::fmt::Debug for RpcRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RpcRequest")
+            .field("command", &self.command)
+            .field("data_len", &self.data.len())
+            .finish()
+    }
+}
+
 /// WiFi credentials parsed from SendWifiSettings command.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct WifiCredentials {
-    pub ssid: String,
-    pub password: String,
+    /// Raw SSID bytes, which need not be UTF-8.
+    pub ssid: Ssid,
+    pub password: Secret,
 }
 
 impl RpcRequest {
@@ -168,7 +221,6 @@ impl RpcRequest {
             return Err(RpcError::TooShort);
         }
 
-        let command_byte = data[0];
         let data_len = data[1] as usize;
 
         // Check total length: command + length + data + checksum.
@@ -192,11 +244,33 @@ impl RpcRequest {
             });
         }
 
+        Self::parse_body(&data[..checksum_idx])
+    }
+
+    /// Parse an RPC packet that has no checksum of its own.
+    ///
+    /// Improv Serial carries the command, length and data inside a frame
+    /// whose checksum has already been verified.
+    pub fn parse_body(data: &[u8]) -> Result<Self, RpcError> {
+        if data.len() < 2 {
+            return Err(RpcError::TooShort);
+        }
+
+        let command_byte = data[0];
+        let data_len = data[1] as usize;
+
+        if data.len() != 2 + data_len {
+            return Err(RpcError::LengthMismatch {
+                expected: 2 + data_len,
+                actual: data.len(),
+            });
+        }
+
         // Parse command.
         let command = RpcCommand::try_from(command_byte)?;
 
         // Extract data.
-        let payload = data[2..2 + data_len].to_vec();
+        let payload = data[2..].to_vec();
 
         Ok(RpcRequest {
             command,
@@ -225,7 +299,7 @@ impl RpcRequest {
             return Err(RpcError::TooShort);
         }
 
-        let ssid = String::from_utf8_lossy(&self.data[1..1 + ssid_len]).to_string();
+        let ssid = Ssid::from(&self.data[1..1 + ssid_len]);
 
         let password_len = self.data[1 + ssid_len] as usize;
         let password_start = 2 + ssid_len;
@@ -235,11 +309,177 @@ impl RpcRequest {
         }
 
         let password =
-            String::from_utf8_lossy(&self.data[password_start..password_start + password_len])
-                .to_string();
+            Secret::from_utf8_lossy(&self.data[password_start..password_start + password_len]);
 
         Ok(WifiCredentials { ssid, password })
     }
+
+    /// Parse 802.1X credentials from a SendEnterpriseSettings command.
+    ///
+    /// Data is six length-prefixed strings, like SendWifiSettings:
+    /// SSID, EAP method ("peap" or "ttls"), phase 2 method (empty for
+    /// MSCHAPv2), identity, anonymous identity (empty for none), password.
+    /// The CA certificate is sent separately with CaCertificate.
+    pub fn parse_enterprise_credentials(
+        &self,
+    ) -> Result<(Ssid, EnterpriseCredentials), RpcError> {
+        if self.command != RpcCommand::SendEnterpriseSettings {
+            return Err(RpcError::UnknownCommand(self.command as u8));
+        }
+
+        let fields = split_strings(&self.data)?;
+        let [ssid, eap, phase2, identity, anonymous_identity, password] = fields[..] else {
+            return Err(RpcError::TooShort);
+        };
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).to_string();
+
+        let eap = EapMethod::parse(&text(eap))
+            .ok_or_else(|| RpcError::InvalidValue(format!("EAP method {:?}", text(eap))))?;
+        let phase2 = match text(phase2).as_str() {
+            "" => Phase2Auth::default(),
+            name => Phase2Auth::parse(name)
+                .ok_or_else(|| RpcError::InvalidValue(format!("phase 2 method {:?}", name)))?,
+        };
+        let anonymous_identity = Some(text(anonymous_identity)).filter(|id| !id.is_empty());
+
+        Ok((
+            Ssid::from(ssid),
+            EnterpriseCredentials {
+                eap,
+                phase2,
+                identity: text(identity),
+                anonymous_identity,
+                password: Secret::from_utf8_lossy(password),
+                ca_cert: None,
+            },
+        ))
+    }
+
+    /// Parse IP settings from an IpSettings command.
+    ///
+    /// Data is nine length-prefixed strings: IPv4 method, IPv4 addresses,
+    /// IPv4 gateway, IPv6 method, IPv6 addresses, IPv6 gateway, DNS servers,
+    /// search domains, proxy PAC URL. Empty strings keep the defaults (an
+    /// empty method is "auto"); lists are comma-separated and addresses are
+    /// written `address/prefix`. The settings are validated.
+    pub fn parse_ip_config(&self) -> Result<IpConfig, RpcError> {
+        if self.command != RpcCommand::IpSettings {
+            return Err(RpcError::UnknownCommand(self.command as u8));
+        }
+
+        let fields = split_strings(&self.data)?;
+        let [
+            ipv4_method,
+            ipv4_addresses,
+            ipv4_gateway,
+            ipv6_method,
+            ipv6_addresses,
+            ipv6_gateway,
+            dns,
+            dns_search,
+            pac_url,
+        ] = fields[..]
+        else {
+            return Err(RpcError::TooShort);
+        };
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).trim().to_string();
+        let invalid = |e: ipconfig::IpConfigError| RpcError::InvalidValue(e.to_string());
+
+        let family = |method: &[u8], addresses: &[u8], gateway: &[u8]| {
+            let method = match text(method).as_str() {
+                "" => IpMethod::Auto,
+                name => IpMethod::parse(name)
+                    .ok_or_else(|| RpcError::InvalidValue(format!("IP method {:?}", name)))?,
+            };
+            let gateway = match text(gateway).as_str() {
+                "" => None,
+                address => Some(address.parse().map_err(|_| {
+                    RpcError::InvalidValue(format!("gateway {:?}", address))
+                })?),
+            };
+            Ok::<_, RpcError>(IpSettings {
+                method,
+                addresses: ipconfig::parse_list(&text(addresses), "address").map_err(invalid)?,
+                gateway,
+            })
+        };
+
+        let config = IpConfig {
+            ipv4: family(ipv4_method, ipv4_addresses, ipv4_gateway)?,
+            ipv6: family(ipv6_method, ipv6_addresses, ipv6_gateway)?,
+            dns: ipconfig::parse_list(&text(dns), "DNS server").map_err(invalid)?,
+            dns_search: ipconfig::parse_list(&text(dns_search), "search domain")
+                .map_err(invalid)?,
+            proxy_pac_url: Some(text(pac_url)).filter(|url| !url.is_empty()),
+        };
+        config.validate().map_err(invalid)?;
+        Ok(config)
+    }
+
+    /// Parse the country code from a Country command.
+    ///
+    /// Returns `None` when the data is empty (a "get" request). Otherwise
+    /// the data is one length-prefixed string, like Hostname.
+    pub fn parse_country(&self) -> Result<Option<CountryCode>, RpcError> {
+        if self.command != RpcCommand::Country {
+            return Err(RpcError::UnknownCommand(self.command as u8));
+        }
+
+        let fields = split_strings(&self.data)?;
+        match fields[..] {
+            [] => Ok(None),
+            [code] => {
+                let code = String::from_utf8_lossy(code);
+                code.parse()
+                    .map(Some)
+                    .map_err(|_| RpcError::InvalidValue(format!("country code {:?}", code)))
+            }
+            _ => Err(RpcError::InvalidValue(format!(
+                "{} country codes",
+                fields.len()
+            ))),
+        }
+    }
+
+    /// Parse the hostname from a Hostname command.
+    ///
+    /// Returns `None` when the data is empty (a "get" request).
+    ///
+    /// Data format:
+    /// - Byte 0: Hostname length
+    /// - Bytes 1..1+len: Hostname
+    pub fn parse_hostname(&self) -> Result<Option<String>, RpcError> {
+        if self.command != RpcCommand::Hostname {
+            return Err(RpcError::UnknownCommand(self.command as u8));
+        }
+
+        if self.data.is_empty() {
+            return Ok(None);
+        }
+
+        let name_len = self.data[0] as usize;
+        if self.data.len() < 1 + name_len {
+            return Err(RpcError::TooShort);
+        }
+
+        let name = String::from_utf8_lossy(&self.data[1..1 + name_len]).to_string();
+        Ok(Some(name))
+    }
+}
+
+/// Split data into length-prefixed strings.
+fn split_strings(mut data: &[u8]) -> Result<Vec<&[u8]>, RpcError> {
+    let mut strings = Vec::new();
+    while let Some((&len, rest)) = data.split_first() {
+        let len = len as usize;
+        if rest.len() < len {
+            return Err(RpcError::TooShort);
+        }
+        let (string, rest) = rest.split_at(len);
+        strings.push(string);
+        data = rest;
+    }
+    Ok(strings)
 }
 
 /// Calculate checksum for a byte slice.
@@ -256,27 +496,51 @@ pub fn calculate_checksum(data: &[u8]) -> u8 {
 /// - Byte 1: Total data length
 /// - Bytes 2+: String list (each string prefixed with length byte)
 /// - Final byte: Checksum
-pub fn build_response(command: RpcCommand, strings: &[&str]) -> Vec<u8> {
-    let mut packet = Vec::new();
+///
+/// Fails if a string or the total data does not fit its length byte.
+pub fn build_response(command: RpcCommand, strings: &[&str]) -> Result<Vec<u8>, RpcError> {
+    build_raw_response(command as u8, strings)
+}
 
-    // Command byte.
-    packet.push(command as u8);
+/// Build an RPC response packet for a raw command ID.
+///
+/// Used for transport-specific commands that are not in `RpcCommand`.
+pub fn build_raw_response(command: u8, strings: &[&str]) -> Result<Vec<u8>, RpcError> {
+    let fields: Vec<&[u8]> = strings.iter().map(|s| s.as_bytes()).collect();
+    build_bytes_response(command, &fields)
+}
+
+/// Build an RPC response packet from byte strings.
+///
+/// Used where a field is not necessarily UTF-8, such as an SSID.
+pub fn build_bytes_response(command: u8, strings: &[&[u8]]) -> Result<Vec<u8>, RpcError> {
+    if let Some(s) = strings.iter().find(|s| s.len() > u8::MAX as usize) {
+        return Err(RpcError::StringTooLong(s.len()));
+    }
 
     // Calculate total data length (sum of length bytes + string bytes).
     let data_len: usize = strings.iter().map(|s| 1 + s.len()).sum();
+    if data_len > u8::MAX as usize {
+        return Err(RpcError::DataTooLong(data_len));
+    }
+
+    let mut packet = Vec::with_capacity(2 + data_len + 1);
+
+    // Command byte.
+    packet.push(command);
     packet.push(data_len as u8);
 
     // Add each string with its length prefix.
     for s in strings {
         packet.push(s.len() as u8);
-        packet.extend_from_slice(s.as_bytes());
+        packet.extend_from_slice(s);
     }
 
     // Add checksum.
     let checksum = calculate_checksum(&packet);
     packet.push(checksum);
 
-    packet
+    Ok(packet)
 }
 
 /// Build a device info response.
@@ -287,29 +551,84 @@ pub fn build_device_info_response(
     firmware_version: &str,
     hardware_type: &str,
     device_name: &str,
-) -> Vec<u8> {
+) -> Result<Vec<u8>, RpcError> {
     build_response(
         RpcCommand::GetDeviceInfo,
         &[firmware_name, firmware_version, hardware_type, device_name],
     )
 }
 
-/// Build a WiFi scan result response.
+/// RSSI sent for a network whose signal strength wasn't measured.
+///
+/// Improv needs a number, so these networks go at the bottom of the scale.
+pub const UNMEASURED_RSSI: i32 = -100;
+
+/// Build the scan result for a single network.
 ///
-/// Each network is: "SSID,RSSI,AUTH" (AUTH is 1 if secured, 0 if open).
-pub fn build_scan_response(networks: &[(String, i32, bool)]) -> Vec<u8> {
-    let network_strings: Vec<String> = networks
-        .iter()
-        .map(|(ssid, rssi, secured)| format!("{},{},{}", ssid, rssi, if *secured { 1 } else { 0 }))
-        .collect();
-
-    let str_refs: Vec<&str> = network_strings.iter().map(|s| s.as_str()).collect();
-    build_response(RpcCommand::ScanWifiNetworks, &str_refs)
+/// Strings are: SSID, RSSI, and "YES"/"NO" for whether auth is required.
+/// The SSID is sent as its raw bytes.
+pub fn build_scan_result(ssid: &Ssid, rssi: i32, secured: bool) -> Result<Vec<u8>, RpcError> {
+    let rssi = rssi.to_string();
+    let auth: &[u8] = if secured { b"YES" } else { b"NO" };
+    build_bytes_response(
+        RpcCommand::ScanWifiNetworks as u8,
+        &[ssid.as_bytes(), rssi.as_bytes(), auth],
+    )
+}
+
+/// Build WiFi scan results.
+///
+/// Per the Improv spec, each network is sent as its own RPC result, followed
+/// by an empty result marking the end of the list. Networks whose packet
+/// would exceed `max_packet_len` (the notification MTU) are skipped.
+pub fn build_scan_responses(
+    networks: &[(Ssid, i32, bool)],
+    max_packet_len: usize,
+) -> Vec<Vec<u8>> {
+    let mut packets = Vec::with_capacity(networks.len() + 1);
+
+    for (ssid, rssi, secured) in networks {
+        match build_scan_result(ssid, *rssi, *secured) {
+            Ok(packet) if packet.len() <= max_packet_len => packets.push(packet),
+            Ok(packet) => warn!(
+                "Skipping scan result for {:?}: {} bytes exceeds MTU of {}",
+                ssid,
+                packet.len(),
+                max_packet_len
+            ),
+            Err(e) => warn!("Skipping scan result for {:?}: {}", ssid, e),
+        }
+    }
+
+    // Terminator: an empty result. Always fits (3 bytes).
+    packets.push(vec![
+        RpcCommand::ScanWifiNetworks as u8,
+        0,
+        calculate_checksum(&[RpcCommand::ScanWifiNetworks as u8, 0]),
+    ]);
+
+    packets
+}
+
+/// Build a hostname response with the current hostname.
+pub fn build_hostname_response(hostname: &str) -> Result<Vec<u8>, RpcError> {
+    build_response(RpcCommand::Hostname, &[hostname])
+}
+
+/// Build a Country response; an unset country is an empty string.
+pub fn build_country_response(country: Option<CountryCode>) -> Result<Vec<u8>, RpcError> {
+    let code = country.map(|c| c.to_string()).unwrap_or_default();
+    build_response(RpcCommand::Country, &[&code])
 }
 
 /// Build a successful provisioning response with redirect URL.
-pub fn build_provision_response(redirect_url: &str) -> Vec<u8> {
-    build_response(RpcCommand::SendWifiSettings, &[redirect_url])
+///
+/// `command` is the command that carried the credentials.
+pub fn build_provision_response(
+    command: RpcCommand,
+    redirect_url: &str,
+) -> Result<Vec<u8>, RpcError> {
+    build_response(command, &[redirect_url])
 }
 
 #[cfg(test)]
@@ -371,7 +690,198 @@ mod tests {
 
         let creds = request.parse_wifi_credentials().unwrap();
         assert_eq!(creds.ssid, "test");
-        assert_eq!(creds.password, "pass");
+        assert_eq!(creds.password.expose(), "pass");
+    }
+
+    #[test]
+    fn test_parse_wifi_credentials_keeps_non_utf8_ssid() {
+        let ssid = [b'c', b'a', b'f', 0xe9];
+        let mut data = vec![0x01, (1 + ssid.len() + 2) as u8, ssid.len() as u8];
+        data.extend_from_slice(&ssid);
+        data.extend_from_slice(&[1, b'p']);
+        data.push(calculate_checksum(&data));
+
+        let creds = RpcRequest::parse(&data).unwrap().parse_wifi_credentials().unwrap();
+        assert_eq!(creds.ssid.as_bytes(), &ssid);
+        assert_eq!(creds.ssid.hex_if_not_utf8().as_deref(), Some("636166e9"));
+    }
+
+    fn enterprise_request(fields: &[&str]) -> RpcRequest {
+        let mut data = Vec::new();
+        for field in fields {
+            data.push(field.len() as u8);
+            data.extend_from_slice(field.as_bytes());
+        }
+        RpcRequest {
+            command: RpcCommand::SendEnterpriseSettings,
+            data,
+        }
+    }
+
+    #[test]
+    fn test_parse_enterprise_credentials() {
+        let request = enterprise_request(&["eduroam", "TTLS", "pap", "alice", "anon", "hunter22"]);
+        let (ssid, creds) = request.parse_enterprise_credentials().unwrap();
+
+        assert_eq!(ssid, "eduroam");
+        assert_eq!(creds.eap, EapMethod::Ttls);
+        assert_eq!(creds.phase2, Phase2Auth::Pap);
+        assert_eq!(creds.identity, "alice");
+        assert_eq!(creds.anonymous_identity.as_deref(), Some("anon"));
+        assert_eq!(creds.password.expose(), "hunter22");
+        assert_eq!(creds.ca_cert, None);
+
+        let request = enterprise_request(&["eduroam", "peap", "", "alice", "", "hunter22"]);
+        let (_, creds) = request.parse_enterprise_credentials().unwrap();
+        assert_eq!(creds.phase2, Phase2Auth::Mschapv2);
+        assert_eq!(creds.anonymous_identity, None);
+    }
+
+    #[test]
+    fn test_parse_enterprise_credentials_rejects_bad_fields() {
+        let request = enterprise_request(&["eduroam", "tls", "", "alice", "", "hunter22"]);
+        assert!(matches!(
+            request.parse_enterprise_credentials(),
+            Err(RpcError::InvalidValue(_))
+        ));
+
+        let request = enterprise_request(&["eduroam", "peap", "", "alice"]);
+        assert_eq!(request.parse_enterprise_credentials(), Err(RpcError::TooShort));
+
+        let mut request = enterprise_request(&["eduroam"]);
+        request.data[0] = 20;
+        assert_eq!(request.parse_enterprise_credentials(), Err(RpcError::TooShort));
+    }
+
+    fn ip_request(fields: &[&str]) -> RpcRequest {
+        let mut request = enterprise_request(fields);
+        request.command = RpcCommand::IpSettings;
+        request
+    }
+
+    #[test]
+    fn test_parse_ip_config() {
+        let request = ip_request(&[
+            "manual",
+            "192.168.1.50/24",
+            "192.168.1.1",
+            "",
+            "",
+            "",
+            "192.168.1.1, 2001:db8::53",
+            "lan,example.com",
+            "http://wpad/wpad.dat",
+        ]);
+        let ip = request.parse_ip_config().unwrap();
+
+        assert_eq!(ip.ipv4.method, IpMethod::Manual);
+        assert_eq!(ip.ipv4.addresses[0].to_string(), "192.168.1.50/24");
+        assert_eq!(ip.ipv4.gateway, Some("192.168.1.1".parse().unwrap()));
+        assert_eq!(ip.ipv6, IpSettings::default());
+        assert_eq!(ip.dns.len(), 2);
+        assert_eq!(ip.dns_search, ["lan", "example.com"]);
+        assert_eq!(ip.proxy_pac_url.as_deref(), Some("http://wpad/wpad.dat"));
+
+        let request = ip_request(&["", "", "", "", "", "", "", "", ""]);
+        assert!(request.parse_ip_config().unwrap().is_default());
+    }
+
+    #[test]
+    fn test_parse_ip_config_rejects_bad_fields() {
+        let fields = ["manual", "", "", "", "", "", "", "", ""];
+        assert!(matches!(
+            ip_request(&fields).parse_ip_config(),
+            Err(RpcError::InvalidValue(_))
+        ));
+
+        let fields = ["static", "10.0.0.2/8", "", "", "", "", "", "", ""];
+        assert!(matches!(
+            ip_request(&fields).parse_ip_config(),
+            Err(RpcError::InvalidValue(_))
+        ));
+
+        let fields = ["", "", "", "", "", "", "1.1.1.1,nope", "", ""];
+        assert!(matches!(
+            ip_request(&fields).parse_ip_config(),
+            Err(RpcError::InvalidValue(_))
+        ));
+
+        assert_eq!(ip_request(&["manual"]).parse_ip_config(), Err(RpcError::TooShort));
+    }
+
+    #[test]
+    fn test_parse_country() {
+        let request = |data: &[u8]| RpcRequest {
+            command: RpcCommand::Country,
+            data: data.to_vec(),
+        };
+
+        assert_eq!(request(&[]).parse_country(), Ok(None));
+        assert_eq!(request(&[2, b'g', b'b']).parse_country(), Ok(Some("GB".parse().unwrap())));
+        assert!(matches!(
+            request(&[3, b'g', b'b', b'r']).parse_country(),
+            Err(RpcError::InvalidValue(_))
+        ));
+        assert_eq!(request(&[2, b'g']).parse_country(), Err(RpcError::TooShort));
+    }
+
+    #[test]
+    fn test_parse_body_without_checksum() {
+        let request = RpcRequest::parse_body(&[0x03, 0x00]).unwrap();
+        assert_eq!(request.command, RpcCommand::GetDeviceInfo);
+
+        let request = RpcRequest::parse_body(&[0x05, 0x02, 0x01, b'a']).unwrap();
+        assert_eq!(request.data, vec![0x01, b'a']);
+
+        assert!(matches!(
+            RpcRequest::parse_body(&[0x03, 0x02, 0x00]),
+            Err(RpcError::LengthMismatch { .. })
+        ));
+        assert_eq!(RpcRequest::parse_body(&[0x03]), Err(RpcError::TooShort));
+    }
+
+    #[test]
+    fn test_parse_hostname_get() {
+        let checksum = calculate_checksum(&[0x05, 0x00]);
+        let packet = vec![0x05, 0x00, checksum];
+
+        let request = RpcRequest::parse(&packet).unwrap();
+        assert_eq!(request.command, RpcCommand::Hostname);
+        assert_eq!(request.parse_hostname().unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_hostname_set() {
+        let name = b"dirtsim-kitchen";
+
+        let mut data = vec![0x05, (1 + name.len()) as u8, name.len() as u8];
+        data.extend_from_slice(name);
+        data.push(calculate_checksum(&data));
+
+        let request = RpcRequest::parse(&data).unwrap();
+        assert_eq!(
+            request.parse_hostname().unwrap(),
+            Some("dirtsim-kitchen".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_hostname_truncated() {
+        let request = RpcRequest {
+            command: RpcCommand::Hostname,
+            data: vec![10, b'a', b'b'],
+        };
+        assert_eq!(request.parse_hostname(), Err(RpcError::TooShort));
+    }
+
+    #[test]
+    fn test_build_hostname_response() {
+        let response = build_hostname_response("dirtsim").unwrap();
+
+        assert_eq!(response[0], RpcCommand::Hostname as u8);
+        assert_eq!(response[1], 8); // Length byte + 7 characters.
+        assert_eq!(response[2], 7);
+        assert_eq!(&response[3..10], b"dirtsim");
     }
 
     #[test]
@@ -417,7 +927,8 @@ mod tests {
 
     #[test]
     fn test_build_device_info_response() {
-        let response = build_device_info_response("wifi-provisioner", "0.1.0", "Pi", "DirtSim");
+        let response =
+            build_device_info_response("wifi-provisioner", "0.1.0", "Pi", "DirtSim").unwrap();
 
         // Verify structure: cmd + len + strings + checksum.
         assert_eq!(response[0], RpcCommand::GetDeviceInfo as u8);
@@ -430,7 +941,7 @@ mod tests {
 
     #[test]
     fn test_build_response_roundtrip() {
-        let original = build_response(RpcCommand::Identify, &[]);
+        let original = build_response(RpcCommand::Identify, &[]).unwrap();
 
         // Should be parseable (though Identify doesn't normally have a response).
         assert_eq!(original[0], 0x02);
@@ -440,6 +951,95 @@ mod tests {
         assert_eq!(original[original.len() - 1], checksum);
     }
 
+    #[test]
+    fn test_build_response_rejects_long_string() {
+        let long = "x".repeat(256);
+        assert_eq!(
+            build_response(RpcCommand::SendWifiSettings, &[&long]),
+            Err(RpcError::StringTooLong(256))
+        );
+
+        // 255 bytes fits the string length byte but not the data length byte.
+        let max = "x".repeat(255);
+        assert_eq!(
+            build_response(RpcCommand::SendWifiSettings, &[&max]),
+            Err(RpcError::DataTooLong(256))
+        );
+
+        let fits = "x".repeat(254);
+        assert!(build_response(RpcCommand::SendWifiSettings, &[&fits]).is_ok());
+    }
+
+    #[test]
+    fn test_build_response_rejects_long_data() {
+        let s = "x".repeat(100);
+        assert_eq!(
+            build_response(RpcCommand::GetDeviceInfo, &[&s, &s, &s]),
+            Err(RpcError::DataTooLong(303))
+        );
+    }
+
+    #[test]
+    fn test_build_scan_result() {
+        let packet = build_scan_result(&"MyWiFi".into(), -45, true).unwrap();
+
+        let mut expected = vec![0x04, 15, 6];
+        expected.extend_from_slice(b"MyWiFi");
+        expected.push(3);
+        expected.extend_from_slice(b"-45");
+        expected.push(3);
+        expected.extend_from_slice(b"YES");
+        expected.push(calculate_checksum(&expected));
+
+        assert_eq!(packet, expected);
+
+        let open = build_scan_result(&"Cafe".into(), -70, false).unwrap();
+        assert_eq!(&open[open.len() - 3..open.len() - 1], b"NO");
+    }
+
+    #[test]
+    fn test_build_scan_result_sends_raw_ssid_bytes() {
+        let ssid = Ssid::new(vec![b'a', 0xff, b'b']);
+        let packet = build_scan_result(&ssid, -45, true).unwrap();
+
+        assert_eq!(&packet[2..6], &[3, b'a', 0xff, b'b']);
+    }
+
+    #[test]
+    fn test_build_scan_responses_one_per_network_with_terminator() {
+        let networks: Vec<(Ssid, i32, bool)> = (0..20)
+            .map(|i| (format!("Network-{:02}", i).into(), -40 - i, i % 2 == 0))
+            .collect();
+
+        let packets = build_scan_responses(&networks, 512);
+
+        assert_eq!(packets.len(), 21);
+        for packet in &packets {
+            assert_eq!(packet[0], RpcCommand::ScanWifiNetworks as u8);
+            assert_eq!(packet.len(), 2 + packet[1] as usize + 1);
+            let checksum = calculate_checksum(&packet[..packet.len() - 1]);
+            assert_eq!(packet[packet.len() - 1], checksum);
+        }
+
+        // Terminator has no data.
+        assert_eq!(packets[20][1], 0);
+    }
+
+    #[test]
+    fn test_build_scan_responses_skips_packets_over_mtu() {
+        let networks = vec![
+            ("short".into(), -50, true),
+            ("a-much-longer-network-name".into(), -60, true),
+        ];
+
+        // "short" packet: 2 + 6 + 4 + 4 + 1 = 17 bytes.
+        let packets = build_scan_responses(&networks, 20);
+
+        assert_eq!(packets.len(), 2);
+        assert_eq!(&packets[0][3..8], b"short");
+        assert_eq!(packets[1][1], 0);
+    }
+
     #[test]
     fn test_service_uuid() {
         // Verify UUID format matches spec.
@@ -0,0 +1,402 @@
+//! Static IP, DNS and proxy settings for new connections.
+//!
+//! Provisioning creates DHCP profiles unless told otherwise. An `IpConfig`
+//! overrides the address method per family and adds DNS servers, search
+//! domains and a proxy, and is applied to the NetworkManager profile the
+//! provisioner creates.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Errors from parsing or validating IP settings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IpConfigError {
+    /// A value could not be parsed.
+    Parse(String),
+    /// The settings are inconsistent or not allowed.
+    Invalid(String),
+}
+
+impl fmt::Display for IpConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpConfigError::Parse(msg) => write!(f, "Cannot parse {}", msg),
+            IpConfigError::Invalid(msg) => write!(f, "Invalid IP settings: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for IpConfigError {}
+
+/// How an address family is configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum IpMethod {
+    /// DHCP for IPv4, SLAAC or DHCPv6 for IPv6.
+    #[default]
+    Auto,
+    /// Only the given addresses.
+    Manual,
+    /// The family is not used.
+    Disabled,
+}
+
+impl IpMethod {
+    /// Name used by NetworkManager and the protocols.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IpMethod::Auto => "auto",
+            IpMethod::Manual => "manual",
+            IpMethod::Disabled => "disabled",
+        }
+    }
+
+    /// Parse a method name, case-insensitively.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "auto" => Some(IpMethod::Auto),
+            "manual" => Some(IpMethod::Manual),
+            "disabled" => Some(IpMethod::Disabled),
+            _ => None,
+        }
+    }
+}
+
+/// An address with its prefix length, written `address/prefix`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpPrefix {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl IpPrefix {
+    /// Longest prefix for the address family.
+    fn max_prefix(addr: &IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+}
+
+impl FromStr for IpPrefix {
+    type Err = IpConfigError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let error =
+            || IpConfigError::Parse(format!("address {:?} (expected address/prefix)", text));
+        let (addr, prefix) = text.trim().split_once('/').ok_or_else(error)?;
+        let addr: IpAddr = addr.parse().map_err(|_| error())?;
+        let prefix: u8 = prefix.parse().map_err(|_| error())?;
+        if prefix > Self::max_prefix(&addr) {
+            return Err(error());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for IpPrefix {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpPrefix {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+impl schemars::JsonSchema for IpPrefix {
+    fn schema_name() -> String {
+        "IpPrefix".into()
+    }
+
+    fn json_schema(_: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        serde_json::from_value(serde_json::json!({
+            "description": "An address with its prefix length, e.g. \"192.168.1.50/24\".",
+            "type": "string",
+        }))
+        .unwrap()
+    }
+}
+
+/// Settings for one address family.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+pub struct IpSettings {
+    #[serde(default)]
+    pub method: IpMethod,
+    /// Static addresses; required for `manual`, added to the automatic ones
+    /// for `auto`.
+    #[serde(default)]
+    pub addresses: Vec<IpPrefix>,
+    #[serde(default)]
+    pub gateway: Option<IpAddr>,
+}
+
+/// IP settings for a new connection. The default is DHCP on both families.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+pub struct IpConfig {
+    #[serde(default)]
+    pub ipv4: IpSettings,
+    #[serde(default)]
+    pub ipv6: IpSettings,
+    /// DNS servers of either family, most preferred first.
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+    /// DNS search domains.
+    #[serde(default)]
+    pub dns_search: Vec<String>,
+    /// URL of a proxy auto-config (PAC) script.
+    #[serde(default)]
+    pub proxy_pac_url: Option<String>,
+}
+
+impl IpConfig {
+    /// Whether these are the plain DHCP defaults.
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// DNS servers of the given family.
+    pub fn dns_for(&self, ipv6: bool) -> impl Iterator<Item = &IpAddr> {
+        self.dns.iter().filter(move |addr| addr.is_ipv6() == ipv6)
+    }
+
+    /// Check the settings before handing them to a backend.
+    pub fn validate(&self) -> Result<(), IpConfigError> {
+        validate_family("ipv4", &self.ipv4, false)?;
+        validate_family("ipv6", &self.ipv6, true)?;
+        if self.ipv4.method == IpMethod::Disabled && self.ipv6.method == IpMethod::Disabled {
+            return Err(invalid("ipv4 and ipv6 are both disabled".to_string()));
+        }
+
+        for server in &self.dns {
+            let settings = if server.is_ipv6() {
+                &self.ipv6
+            } else {
+                &self.ipv4
+            };
+            if server.is_unspecified() || server.is_multicast() {
+                return Err(invalid(format!(
+                    "DNS server {} is not a unicast address",
+                    server
+                )));
+            }
+            if settings.method == IpMethod::Disabled {
+                return Err(invalid(format!(
+                    "DNS server {} is in a disabled family",
+                    server
+                )));
+            }
+        }
+
+        if let Some(domain) = self.dns_search.iter().find(|d| !is_valid_domain(d)) {
+            return Err(invalid(format!("search domain {:?}", domain)));
+        }
+
+        if let Some(url) = &self.proxy_pac_url {
+            let valid = (url.starts_with("http://") || url.starts_with("https://"))
+                && !url.contains(|c: char| c.is_whitespace() || c.is_control());
+            if !valid {
+                return Err(invalid(format!(
+                    "proxy PAC URL {:?} (expected http or https)",
+                    url
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid(msg: String) -> IpConfigError {
+    IpConfigError::Invalid(msg)
+}
+
+fn validate_family(name: &str, settings: &IpSettings, ipv6: bool) -> Result<(), IpConfigError> {
+    for address in &settings.addresses {
+        let addr = address.addr;
+        if addr.is_ipv6() != ipv6 {
+            return Err(invalid(format!(
+                "{} address {} has the wrong family",
+                name, address
+            )));
+        }
+        if addr.is_unspecified() || addr.is_loopback() || addr.is_multicast() {
+            return Err(invalid(format!(
+                "{} address {} is not usable",
+                name, address
+            )));
+        }
+    }
+
+    if let Some(gateway) = settings.gateway {
+        if gateway.is_ipv6() != ipv6 {
+            return Err(invalid(format!(
+                "{} gateway {} has the wrong family",
+                name, gateway
+            )));
+        }
+        // NetworkManager only accepts a gateway alongside static addresses.
+        if settings.addresses.is_empty() {
+            return Err(invalid(format!("{} gateway needs an address", name)));
+        }
+    }
+
+    match settings.method {
+        IpMethod::Manual if settings.addresses.is_empty() => {
+            Err(invalid(format!("{} method manual needs an address", name)))
+        }
+        IpMethod::Disabled if !settings.addresses.is_empty() => {
+            Err(invalid(format!("{} is disabled but has addresses", name)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether `domain` is a DNS name: dot-separated labels of letters, digits
+/// and hyphens, not starting or ending with a hyphen.
+fn is_valid_domain(domain: &str) -> bool {
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Parse a comma-separated list, ignoring blanks.
+pub fn parse_list<T: FromStr>(text: &str, what: &str) -> Result<Vec<T>, IpConfigError> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            item.parse()
+                .map_err(|_| IpConfigError::Parse(format!("{} {:?}", what, item)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn static_v4() -> IpConfig {
+        IpConfig {
+            ipv4: IpSettings {
+                method: IpMethod::Manual,
+                addresses: vec!["192.168.1.50/24".parse().unwrap()],
+                gateway: Some("192.168.1.1".parse().unwrap()),
+            },
+            dns: vec![
+                "192.168.1.1".parse().unwrap(),
+                "2001:db8::53".parse().unwrap(),
+            ],
+            dns_search: vec!["lan".into(), "example.com".into()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_prefixes() {
+        let prefix: IpPrefix = "10.0.0.2/8".parse().unwrap();
+        assert_eq!(prefix.addr, "10.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(prefix.prefix, 8);
+        assert_eq!(prefix.to_string(), "10.0.0.2/8");
+        assert_eq!("2001:db8::2/64".parse::<IpPrefix>().unwrap().prefix, 64);
+
+        assert!("10.0.0.2".parse::<IpPrefix>().is_err());
+        assert!("10.0.0.2/33".parse::<IpPrefix>().is_err());
+        assert!("nope/24".parse::<IpPrefix>().is_err());
+    }
+
+    #[test]
+    fn deserializes_from_json() {
+        let json = r#"{
+            "ipv4": {
+                "method": "manual",
+                "addresses": ["192.168.1.50/24"],
+                "gateway": "192.168.1.1"
+            },
+            "dns": ["192.168.1.1", "2001:db8::53"],
+            "dns_search": ["lan", "example.com"]
+        }"#;
+        let config: IpConfig = serde_json::from_str(json).unwrap();
+
+        assert_eq!(config, static_v4());
+        assert!(config.validate().is_ok());
+        assert_eq!(config.dns_for(false).count(), 1);
+        assert_eq!(config.dns_for(true).count(), 1);
+        assert!(serde_json::from_str::<IpConfig>("{}").unwrap().is_default());
+    }
+
+    #[test]
+    fn rejects_inconsistent_settings() {
+        let check = |edit: fn(&mut IpConfig)| {
+            let mut config = static_v4();
+            edit(&mut config);
+            config.validate()
+        };
+
+        assert!(check(|c| c.ipv4.addresses.clear()).is_err());
+        assert!(check(|c| c.ipv4.gateway = Some("2001:db8::1".parse().unwrap())).is_err());
+        assert!(check(|c| c.ipv4.addresses[0] = "2001:db8::2/64".parse().unwrap()).is_err());
+        assert!(check(|c| c.ipv6.method = IpMethod::Disabled).is_err());
+        assert!(check(|c| c.dns = vec!["0.0.0.0".parse().unwrap()]).is_err());
+        assert!(check(|c| c.dns_search = vec!["bad domain".into()]).is_err());
+        assert!(check(|c| c.proxy_pac_url = Some("ftp://wpad/wpad.dat".into())).is_err());
+        assert!(check(|c| c.proxy_pac_url = Some("http://wpad/wpad.dat".into())).is_ok());
+
+        let disabled = IpConfig {
+            ipv4: IpSettings {
+                method: IpMethod::Disabled,
+                ..Default::default()
+            },
+            ipv6: IpSettings {
+                method: IpMethod::Disabled,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(disabled.validate().is_err());
+    }
+
+    #[test]
+    fn gateway_needs_an_address() {
+        let config = IpConfig {
+            ipv4: IpSettings {
+                gateway: Some("192.168.1.1".parse().unwrap()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn parses_lists() {
+        let servers: Vec<IpAddr> = parse_list("1.1.1.1, 2001:db8::53,", "DNS server").unwrap();
+        assert_eq!(servers.len(), 2);
+        assert!(parse_list::<IpAddr>("1.1.1.1,nope", "DNS server").is_err());
+        assert!(parse_list::<IpAddr>("", "DNS server").unwrap().is_empty());
+    }
+}
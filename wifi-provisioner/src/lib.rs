@@ -3,7 +3,19 @@
 //! Exposes modules for integration testing and potential reuse.
 
 pub mod ble;
+pub mod button;
+pub mod connectivity;
+pub mod hostname;
 pub mod improv;
+pub mod ipconfig;
+pub mod networkmanager;
 pub mod protocol;
+pub mod regdomain;
+pub mod secret;
+pub mod serial;
+pub mod session;
+pub mod ssid;
+pub mod state_machine;
 pub mod websocket;
 pub mod wifi;
+pub mod wpa_supplicant;
@@ -3,12 +3,7 @@
 //! Implements the Improv WiFi protocol for configuring WiFi credentials
 //! via Bluetooth LE from a phone or computer.
 
-mod ble;
-mod improv;
-mod protocol;
-mod websocket;
-mod wifi;
-
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -16,28 +11,49 @@ use tokio::sync::{mpsc, RwLock};
 use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 
-use ble::{BleConfig, BleEvent, BleManager};
-use protocol::State;
-use websocket::{DaemonState, ServerConfig};
-use wifi::{NmcliWifiManager, WifiManager};
+use wifi_provisioner::ble::{BleConfig, BleControl, BleEvent, BleManager};
+use wifi_provisioner::button;
+use wifi_provisioner::networkmanager::NmDbusWifiManager;
+use wifi_provisioner::protocol::{Event, State, Transport};
+use wifi_provisioner::regdomain;
+use wifi_provisioner::secret::RedactingWriter;
+use wifi_provisioner::serial::{self, SerialConfig, SerialPort, DEFAULT_BAUD_RATE};
+use wifi_provisioner::state_machine::{Input, StateMachine};
+use wifi_provisioner::websocket::{self, ServerConfig};
+use wifi_provisioner::wifi::{NmcliWifiManager, WifiBackend, WifiManager};
+use wifi_provisioner::wpa_supplicant::{self, WpaSupplicantWifiManager};
 
 /// Default advertising timeout in seconds.
 const DEFAULT_ADVERTISING_TIMEOUT: u32 = 300;
 
+/// Default authorization window in seconds.
+const DEFAULT_AUTHORIZATION_TIMEOUT: u64 = 60;
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // Initialize logging.
+    // Mask any credential that ends up in a log line, at every level.
     tracing_subscriber::fmt()
         .with_env_filter(
             EnvFilter::from_default_env()
                 .add_directive("wifi_provisioner=info".parse().unwrap()),
         )
+        .with_writer(RedactingWriter::new(std::io::stdout))
         .init();
 
     info!("wifi-provisioner starting");
 
     // WiFi manager (shared between WebSocket and BLE).
-    let wifi = Arc::new(NmcliWifiManager::new());
+    let wifi = Arc::new(select_wifi_backend());
+
+    // Reapply the saved regulatory country so the first scan sees every
+    // channel.
+    if let Some(country) = regdomain::load(Path::new(regdomain::COUNTRY_FILE)) {
+        match wifi.set_country(country).await {
+            Ok(()) => info!("WiFi country set to {}", country),
+            Err(e) => warn!("Failed to restore WiFi country {}: {}", country, e),
+        }
+    }
 
     // Check initial WiFi connectivity.
     let wifi_connected = match wifi.status().await {
@@ -57,11 +73,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     };
 
     // Shared daemon state.
-    let state = Arc::new(RwLock::new(DaemonState {
-        state: if wifi_connected { State::Idle } else { State::Idle },
-        advertising_remaining: None,
-        wifi_connected,
-    }));
+    let state = Arc::new(RwLock::new(StateMachine::new(wifi_connected)));
 
     // BLE event channel.
     let (ble_event_tx, mut ble_event_rx) = mpsc::channel::<BleEvent>(16);
@@ -71,112 +83,246 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     // BLE configuration.
     let ble_config = BleConfig {
-        device_name: device_name.clone(),
+        device_name,
         firmware_name: "wifi-provisioner".to_string(),
         firmware_version: env!("CARGO_PKG_VERSION").to_string(),
         hardware_type: "RaspberryPi".to_string(),
-        redirect_url: format!("http://{}.local:8081", device_name.to_lowercase()),
+        redirect_url: "http://{hostname}.local:8081".to_string(),
+        require_authorization: std::env::var("WIFI_PROVISIONER_REQUIRE_AUTH")
+            .is_ok_and(|v| v == "1" || v.eq_ignore_ascii_case("true")),
+        authorization_timeout: Duration::from_secs(
+            std::env::var("WIFI_PROVISIONER_AUTH_TIMEOUT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_AUTHORIZATION_TIMEOUT),
+        ),
     };
 
+    if ble_config.require_authorization {
+        info!(
+            "Authorization required; confirmations last {}s",
+            ble_config.authorization_timeout.as_secs()
+        );
+    }
+
     // Create BLE manager.
     let ble_manager = Arc::new(BleManager::new(
         ble_config,
         Arc::clone(&wifi),
-        ble_event_tx,
+        Arc::clone(&state),
+        ble_event_tx.clone(),
     ));
-    let _ble_state = ble_manager.state();
+
+    // Register the GATT service before anything can ask to advertise.
+    ble_manager.init().await?;
 
     // Clone refs for the spawned tasks.
     let state_for_ws = Arc::clone(&state);
     let wifi_for_ws = Arc::clone(&wifi);
+    let ble_for_ws = Arc::clone(&ble_manager);
     let state_for_events = Arc::clone(&state);
+    let ble_for_events = Arc::clone(&ble_manager);
     let state_for_timeout = Arc::clone(&state);
+    let ble_for_timeout = Arc::clone(&ble_manager);
+
+    // Transports reported to WebSocket clients.
+    let mut transports = vec![Transport::Websocket, Transport::Ble];
+
+    // Spawn Improv Serial if a tty is configured.
+    if let Ok(path) = std::env::var("WIFI_PROVISIONER_SERIAL") {
+        let serial_config = SerialConfig {
+            path: path.into(),
+            baud_rate: std::env::var("WIFI_PROVISIONER_SERIAL_BAUD")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_BAUD_RATE),
+        };
+
+        match SerialPort::open(&serial_config) {
+            Ok(port) => {
+                transports.push(Transport::Serial);
+                let config = ble_manager.config();
+                let wifi_for_serial = Arc::clone(&wifi);
+                let state_for_serial = Arc::clone(&state);
+                let event_tx = ble_event_tx.clone();
+                tokio::spawn(async move {
+                    let result = serial::run_serial(
+                        port,
+                        config,
+                        wifi_for_serial,
+                        state_for_serial,
+                        event_tx,
+                    )
+                    .await;
+                    if let Err(e) = result {
+                        error!("Improv Serial error: {}", e);
+                    }
+                });
+            }
+            Err(e) => error!(
+                "Failed to open serial port {}: {}",
+                serial_config.path.display(),
+                e
+            ),
+        }
+    }
 
     // Spawn WebSocket server.
-    let ws_config = ServerConfig::default();
+    let ws_config = ServerConfig {
+        transports,
+        wifi_backend: wifi.name().to_string(),
+        ..Default::default()
+    };
     tokio::spawn(async move {
-        if let Err(e) = websocket::run_server(ws_config, state_for_ws, wifi_for_ws).await {
+        if let Err(e) =
+            websocket::run_server(ws_config, state_for_ws, wifi_for_ws, ble_for_ws).await
+        {
             error!("WebSocket server error: {}", e);
         }
     });
 
     info!("WebSocket server started on 127.0.0.1:8888");
 
-    // Spawn BLE event handler.
+    // Spawn BLE event handler. Events also go out to subscribed WebSocket
+    // clients, e.g. so dirtsim can show identify requests. The Improv
+    // sessions report provisioning to the state machine themselves.
     tokio::spawn(async move {
         while let Some(event) = ble_event_rx.recv().await {
             match event {
                 BleEvent::Identify => {
-                    info!("Identify requested - would flash LED/display message");
-                    // TODO: Send WebSocket message to dirtsim UI.
+                    info!("Identify requested");
+                    state_for_events.read().await.publish(Event::Identify);
                 }
                 BleEvent::ClientConnected => {
                     info!("BLE client connected");
-                    let mut s = state_for_events.write().await;
-                    s.state = State::Connected;
+                    submit(&state_for_events, Input::ClientConnected).await;
                 }
                 BleEvent::ClientDisconnected => {
                     info!("BLE client disconnected");
-                    let mut s = state_for_events.write().await;
-                    if s.state == State::Connected {
-                        s.state = State::Advertising;
+                    submit(&state_for_events, Input::ClientDisconnected).await;
+                }
+                BleEvent::ProvisioningStarted(ssid) => {
+                    info!("Provisioning started for {}", ssid);
+                }
+                BleEvent::HostnameChanged(name) => {
+                    info!("Hostname changed to: {}", name);
+                    if let Err(e) = ble_for_events.apply_device_name().await {
+                        warn!("Failed to update BLE device name: {}", e);
                     }
                 }
                 BleEvent::ProvisioningComplete(url) => {
                     info!("Provisioning complete! Redirect URL: {}", url);
-                    let mut s = state_for_events.write().await;
-                    s.state = State::Idle;
-                    s.wifi_connected = true;
-                    s.advertising_remaining = None;
+                    // Unless advertising was started again since.
+                    let _advertising = ble_for_events.advertising_lock().lock().await;
+                    if state_for_events.read().await.advertising_remaining.is_none() {
+                        if let Err(e) = ble_for_events.stop_advertising().await {
+                            warn!("Failed to stop advertising: {}", e);
+                        }
+                    }
+                }
+                BleEvent::ProvisioningFailed(failure) => {
+                    // The client stays connected to retry.
+                    warn!("Provisioning failed: {}", failure);
                 }
             }
         }
     });
 
+    // Spawn button handler for local authorization.
+    if let Ok(path) = std::env::var("WIFI_PROVISIONER_AUTH_BUTTON") {
+        let mut presses = button::spawn_button_listener(path.into());
+        let ble_for_button = Arc::clone(&ble_manager);
+        tokio::spawn(async move {
+            while presses.recv().await.is_some() {
+                info!("Authorization button pressed");
+                ble_for_button.authorize().await;
+            }
+        });
+    }
+
     // Spawn advertising timeout handler.
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(Duration::from_secs(1));
         loop {
             interval.tick().await;
 
-            let mut s = state_for_timeout.write().await;
-            if let Some(remaining) = s.advertising_remaining {
-                if remaining > 0 {
-                    s.advertising_remaining = Some(remaining - 1);
-                } else {
-                    // Timeout expired.
-                    info!("Advertising timeout expired");
-                    s.state = State::Idle;
-                    s.advertising_remaining = None;
-                    // TODO: Actually stop BLE advertising.
+            // Only the advertising lock is held across BlueZ.
+            let _advertising = ble_for_timeout.advertising_lock().lock().await;
+            let expiring = {
+                let s = state_for_timeout.read().await;
+                s.state != State::Provisioning && s.advertising_remaining == Some(0)
+            };
+            if expiring {
+                info!("Advertising timeout expired");
+                if let Err(e) = ble_for_timeout.stop_advertising().await {
+                    warn!("Failed to stop advertising: {}", e);
+                    continue;
                 }
             }
+            // Counting down is valid in every state.
+            let _ = state_for_timeout.write().await.handle(Input::Tick);
         }
     });
 
     // Auto-start advertising if WiFi not connected.
     if !wifi_connected {
         info!("WiFi not connected, auto-starting BLE advertising");
-        let mut s = state.write().await;
-        s.state = State::Advertising;
-        s.advertising_remaining = Some(DEFAULT_ADVERTISING_TIMEOUT);
-        drop(s);
+        let _advertising = ble_manager.advertising_lock().lock().await;
+        ble_manager.start_advertising().await?;
 
-        // Run BLE server (this will block and advertise).
-        ble_manager.run().await?;
+        let input = Input::StartAdvertising {
+            timeout: DEFAULT_ADVERTISING_TIMEOUT,
+        };
+        submit(&state, input).await;
     } else {
         info!("WiFi connected, BLE advertising on standby");
         info!("Send {{\"cmd\":\"start\"}} to WebSocket to begin advertising");
-
-        // Just run the BLE server in standby mode.
-        // In a full implementation, we'd only start advertising when triggered.
-        // For now, run it anyway so the service is available.
-        ble_manager.run().await?;
     }
 
+    // The spawned tasks and BLE handles do the work from here on.
+    std::future::pending::<()>().await;
+
     Ok(())
 }
 
+/// Submit a BLE connection or startup input to the state machine, logging
+/// rejections.
+async fn submit(state: &RwLock<StateMachine>, input: Input) {
+    if let Err(e) = state.write().await.handle(input) {
+        warn!("Ignoring event: {}", e);
+    }
+}
+
+/// Pick the WiFi backend from `WIFI_PROVISIONER_WIFI_BACKEND`.
+///
+/// `nmcli` (default) spawns nmcli per call; `dbus` talks to NetworkManager
+/// over D-Bus; `wpa_supplicant` uses the control socket at
+/// `WIFI_PROVISIONER_WPA_CTRL`. Falls back to nmcli if the system bus is
+/// unavailable.
+fn select_wifi_backend() -> WifiBackend {
+    let backend = std::env::var("WIFI_PROVISIONER_WIFI_BACKEND").unwrap_or_default();
+    match backend.as_str() {
+        "dbus" => match NmDbusWifiManager::new() {
+            Ok(wifi) => {
+                info!("Using NetworkManager D-Bus WiFi backend");
+                return WifiBackend::NetworkManager(wifi);
+            }
+            Err(e) => warn!("D-Bus WiFi backend unavailable, using nmcli: {}", e),
+        },
+        "wpa_supplicant" => {
+            let ctrl_path = std::env::var("WIFI_PROVISIONER_WPA_CTRL")
+                .unwrap_or_else(|_| wpa_supplicant::DEFAULT_CTRL_PATH.to_string());
+            info!("Using wpa_supplicant WiFi backend ({})", ctrl_path);
+            return WifiBackend::WpaSupplicant(WpaSupplicantWifiManager::new(ctrl_path));
+        }
+        "" | "nmcli" => {}
+        other => warn!("Unknown WiFi backend '{}', using nmcli", other),
+    }
+
+    info!("Using nmcli WiFi backend");
+    WifiBackend::Nmcli(NmcliWifiManager::new())
+}
+
 /// Get the device name for BLE advertising.
 async fn get_device_name() -> String {
     // Try to read hostname.
@@ -0,0 +1,1091 @@
+//! WiFi management via NetworkManager's D-Bus API.
+//!
+//! Talks to NetworkManager directly instead of spawning nmcli, and watches
+//! the active connection's state so a failed activation reports NetworkManager's
+//! own reason (e.g., missing secrets) instead of scraped text.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use dbus::arg::{prop_cast, PropMap, RefArg, Variant};
+use dbus::message::MatchRule;
+use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
+use dbus::nonblock::{MethodReply, Proxy, SyncConnection};
+use dbus::Path;
+use futures_util::{Stream, StreamExt};
+use tracing::{debug, error, info, warn};
+
+use crate::ipconfig::{IpConfig, IpMethod, IpSettings};
+use crate::protocol::{AccessPointInfo, EnterpriseCredentials, Network, SavedNetwork};
+use crate::secret::zeroize_string;
+use crate::ssid::Ssid;
+use crate::wifi::{
+    band_for_frequency, channel_for_frequency, group_access_points, is_persistent,
+    measured_signals, store_ca_cert, ConnectRequest, ConnectionSnapshot, Credentials,
+    SavedConnection, WifiError, WifiManager, WifiResult, WifiStatus,
+};
+
+const NM_BUS: &str = "org.freedesktop.NetworkManager";
+const NM_PATH: &str = "/org/freedesktop/NetworkManager";
+const NM_IFACE: &str = "org.freedesktop.NetworkManager";
+const DEVICE_IFACE: &str = "org.freedesktop.NetworkManager.Device";
+const WIRELESS_IFACE: &str = "org.freedesktop.NetworkManager.Device.Wireless";
+const AP_IFACE: &str = "org.freedesktop.NetworkManager.AccessPoint";
+const ACTIVE_IFACE: &str = "org.freedesktop.NetworkManager.Connection.Active";
+const CONNECTION_IFACE: &str = "org.freedesktop.NetworkManager.Settings.Connection";
+const SETTINGS_PATH: &str = "/org/freedesktop/NetworkManager/Settings";
+const SETTINGS_IFACE: &str = "org.freedesktop.NetworkManager.Settings";
+
+/// `NM_DEVICE_TYPE_WIFI`.
+const DEVICE_TYPE_WIFI: u32 = 2;
+
+/// `NM_DEVICE_STATE_ACTIVATED`.
+const DEVICE_STATE_ACTIVATED: u32 = 100;
+
+/// `NM_ACTIVE_CONNECTION_STATE_ACTIVATED`.
+const ACTIVE_STATE_ACTIVATED: u32 = 2;
+
+/// `NM_ACTIVE_CONNECTION_STATE_DEACTIVATED`.
+const ACTIVE_STATE_DEACTIVATED: u32 = 4;
+
+/// `NM_SETTING_PROXY_METHOD_AUTO`.
+const PROXY_METHOD_AUTO: i32 = 1;
+
+/// `NM_802_11_AP_FLAGS_PRIVACY`.
+const AP_FLAGS_PRIVACY: u32 = 0x1;
+
+/// `NM_802_11_AP_SEC_KEY_MGMT_802_1X` (WPA/WPA2-Enterprise).
+const AP_SEC_KEY_MGMT_802_1X: u32 = 0x200;
+
+/// `NM_802_11_AP_SEC_KEY_MGMT_SAE` (WPA3-Personal).
+const AP_SEC_KEY_MGMT_SAE: u32 = 0x400;
+
+/// `NM_802_11_AP_SEC_KEY_MGMT_EAP_SUITE_B_192` (WPA3-Enterprise).
+const AP_SEC_KEY_MGMT_EAP_SUITE_B_192: u32 = 0x2000;
+
+/// Timeout for a single D-Bus method call.
+const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for an activation to succeed or fail.
+const ACTIVATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait for a requested scan to finish.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to check whether a requested scan has finished.
+const SCAN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Properties of a NetworkManager access point.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AccessPoint {
+    /// Raw SSID bytes.
+    pub ssid: Vec<u8>,
+    /// Signal quality in percent.
+    pub strength: u8,
+    /// BSSID, e.g. "AA:BB:CC:DD:EE:FF".
+    pub hw_address: String,
+    /// Center frequency in MHz.
+    pub frequency: u32,
+    /// `NM80211ApFlags`.
+    pub flags: u32,
+    /// `NM80211ApSecurityFlags` for WPA.
+    pub wpa_flags: u32,
+    /// `NM80211ApSecurityFlags` for RSN (WPA2/WPA3).
+    pub rsn_flags: u32,
+}
+
+impl AccessPoint {
+    /// Read an access point from its D-Bus properties.
+    fn from_props(props: &PropMap) -> Self {
+        Self {
+            ssid: prop_cast::<Vec<u8>>(props, "Ssid")
+                .cloned()
+                .unwrap_or_default(),
+            strength: prop_cast::<u8>(props, "Strength").copied().unwrap_or(0),
+            hw_address: prop_cast::<String>(props, "HwAddress")
+                .cloned()
+                .unwrap_or_default(),
+            frequency: prop_cast::<u32>(props, "Frequency").copied().unwrap_or(0),
+            flags: prop_cast::<u32>(props, "Flags").copied().unwrap_or(0),
+            wpa_flags: prop_cast::<u32>(props, "WpaFlags").copied().unwrap_or(0),
+            rsn_flags: prop_cast::<u32>(props, "RsnFlags").copied().unwrap_or(0),
+        }
+    }
+
+    /// Security type in the same format as the nmcli backend.
+    pub fn security(&self) -> String {
+        let security = if self.rsn_flags & AP_SEC_KEY_MGMT_EAP_SUITE_B_192 != 0 {
+            "wpa3-enterprise"
+        } else if self.rsn_flags & AP_SEC_KEY_MGMT_802_1X != 0 {
+            "wpa2-enterprise"
+        } else if self.wpa_flags & AP_SEC_KEY_MGMT_802_1X != 0 {
+            "wpa-enterprise"
+        } else if self.rsn_flags & AP_SEC_KEY_MGMT_SAE != 0 {
+            "wpa3"
+        } else if self.rsn_flags != 0 {
+            "wpa2"
+        } else if self.wpa_flags != 0 {
+            "wpa"
+        } else if self.flags & AP_FLAGS_PRIVACY != 0 {
+            "wep"
+        } else {
+            "open"
+        };
+        security.to_string()
+    }
+}
+
+/// Convert access points into networks.
+///
+/// Groups access points by SSID, with hidden ones in a single entry, and
+/// sorts by signal strength (strongest first).
+///
+/// NetworkManager only exposes quality; dBm is taken from `signals`, the
+/// measured strength per BSSID, and left out for access points missing
+/// from it.
+pub fn networks_from_access_points(
+    access_points: &[AccessPoint],
+    signals: &HashMap<String, i32>,
+) -> Vec<Network> {
+    let access_points = access_points
+        .iter()
+        .map(|ap| {
+            let bssid = ap.hw_address.to_lowercase();
+            let info = AccessPointInfo {
+                signal: signals.get(&bssid).copied(),
+                bssid,
+                frequency: ap.frequency,
+                band: band_for_frequency(ap.frequency).to_string(),
+                channel: channel_for_frequency(ap.frequency),
+                quality: ap.strength.min(100),
+            };
+            (Ssid::new(ap.ssid.clone()), ap.security(), info)
+        })
+        .collect();
+
+    group_access_points(access_points)
+}
+
+/// Describe an `NMActiveConnectionStateReason`.
+pub fn describe_reason(reason: u32) -> &'static str {
+    match reason {
+        1 => "no reason given",
+        2 => "disconnected by user",
+        3 => "device disconnected",
+        4 => "NetworkManager stopped",
+        5 => "IP configuration invalid",
+        6 => "connection attempt timed out",
+        7 => "service start timed out",
+        8 => "service failed to start",
+        9 => "secrets were required but not provided (wrong password?)",
+        10 => "login failed",
+        11 => "connection removed",
+        12 => "dependency failed",
+        13 => "device could not be realized",
+        14 => "device removed",
+        _ => "unknown reason",
+    }
+}
+
+fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<dyn RefArg>> {
+    Variant(Box::new(value))
+}
+
+/// Build the settings for `AddAndActivateConnection`.
+///
+/// A hidden network makes NetworkManager probe for the SSID instead of
+/// waiting to see it in a scan. `ca_cert` is the path of the stored 802.1X
+/// CA certificate, if any.
+fn connection_settings(
+    request: &ConnectRequest,
+    ca_cert: Option<&str>,
+) -> HashMap<&'static str, PropMap> {
+    let ssid = &request.ssid;
+    let mut settings = HashMap::new();
+
+    let mut connection = PropMap::new();
+    connection.insert("id".into(), variant(ssid.to_string()));
+    connection.insert("type".into(), variant("802-11-wireless".to_string()));
+    settings.insert("connection", connection);
+
+    let mut wireless = PropMap::new();
+    wireless.insert("ssid".into(), variant(ssid.as_bytes().to_vec()));
+    wireless.insert("mode".into(), variant("infrastructure".to_string()));
+    if request.hidden {
+        wireless.insert("hidden".into(), variant(true));
+    }
+    settings.insert("802-11-wireless", wireless);
+
+    match &request.credentials {
+        Credentials::Psk(password) if password.is_empty() => {}
+        Credentials::Psk(password) => {
+            let mut security = PropMap::new();
+            security.insert("key-mgmt".into(), variant("wpa-psk".to_string()));
+            security.insert("psk".into(), variant(password.expose().to_string()));
+            settings.insert("802-11-wireless-security", security);
+        }
+        Credentials::Enterprise(enterprise) => {
+            let mut security = PropMap::new();
+            security.insert("key-mgmt".into(), variant("wpa-eap".to_string()));
+            settings.insert("802-11-wireless-security", security);
+            settings.insert("802-1x", eap_settings(enterprise, ca_cert));
+        }
+    }
+
+    let ip = &request.ip;
+    for (name, family, ipv6) in [("ipv4", &ip.ipv4, false), ("ipv6", &ip.ipv6, true)] {
+        let customized = *family != IpSettings::default()
+            || ip.dns_for(ipv6).next().is_some()
+            || !ip.dns_search.is_empty();
+        if customized {
+            settings.insert(name, ip_settings(ip, family, ipv6));
+        }
+    }
+    if let Some(url) = &ip.proxy_pac_url {
+        let mut proxy = PropMap::new();
+        proxy.insert("method".into(), variant(PROXY_METHOD_AUTO));
+        proxy.insert("pac-url".into(), variant(url.clone()));
+        settings.insert("proxy", proxy);
+    }
+
+    settings
+}
+
+/// Wipe the password copies in connection settings once they've been sent.
+fn wipe_credentials(settings: &mut HashMap<&'static str, PropMap>) {
+    for (setting, key) in [("802-11-wireless-security", "psk"), ("802-1x", "password")] {
+        let value = settings
+            .get_mut(setting)
+            .and_then(|setting| setting.get_mut(key));
+        if let Some(Variant(value)) = value {
+            if let Some(text) = value.as_mut().as_any_mut().downcast_mut::<String>() {
+                zeroize_string(text);
+            }
+        }
+    }
+}
+
+/// The `ipv4` or `ipv6` setting for one address family.
+///
+/// NetworkManager takes IPv4 DNS servers as `u32`s in network byte order
+/// and IPv6 ones as byte arrays.
+fn ip_settings(ip: &IpConfig, family: &IpSettings, ipv6: bool) -> PropMap {
+    let mut settings = PropMap::new();
+    settings.insert("method".into(), variant(family.method.as_str().to_string()));
+
+    if !family.addresses.is_empty() {
+        let addresses: Vec<PropMap> = family
+            .addresses
+            .iter()
+            .map(|address| {
+                let mut data = PropMap::new();
+                data.insert("address".into(), variant(address.addr.to_string()));
+                data.insert("prefix".into(), variant(u32::from(address.prefix)));
+                data
+            })
+            .collect();
+        settings.insert("address-data".into(), variant(addresses));
+    }
+    if let Some(gateway) = family.gateway {
+        settings.insert("gateway".into(), variant(gateway.to_string()));
+    }
+
+    let dns = ip.dns_for(ipv6);
+    if ipv6 {
+        let servers: Vec<Vec<u8>> = dns
+            .filter_map(|addr| match addr {
+                IpAddr::V6(v6) => Some(v6.octets().to_vec()),
+                IpAddr::V4(_) => None,
+            })
+            .collect();
+        if !servers.is_empty() {
+            settings.insert("dns".into(), variant(servers));
+        }
+    } else {
+        let servers: Vec<u32> = dns
+            .filter_map(|addr| match addr {
+                IpAddr::V4(v4) => Some(u32::from_ne_bytes(v4.octets())),
+                IpAddr::V6(_) => None,
+            })
+            .collect();
+        if !servers.is_empty() {
+            settings.insert("dns".into(), variant(servers));
+        }
+    }
+    if !ip.dns_search.is_empty() && family.method != IpMethod::Disabled {
+        settings.insert("dns-search".into(), variant(ip.dns_search.clone()));
+    }
+    settings
+}
+
+/// The `802-1x` setting for an enterprise network.
+fn eap_settings(enterprise: &EnterpriseCredentials, ca_cert: Option<&str>) -> PropMap {
+    let mut eap = PropMap::new();
+    eap.insert("eap".into(), variant(vec![enterprise.eap.as_str().to_string()]));
+    eap.insert("phase2-auth".into(), variant(enterprise.phase2.as_str().to_string()));
+    eap.insert("identity".into(), variant(enterprise.identity.clone()));
+    eap.insert("password".into(), variant(enterprise.password.expose().to_string()));
+    if let Some(anonymous) = &enterprise.anonymous_identity {
+        eap.insert("anonymous-identity".into(), variant(anonymous.clone()));
+    }
+    if let Some(path) = ca_cert {
+        // Certificates are given as a NUL-terminated file:// URI.
+        let uri = format!("file://{}\0", path);
+        eap.insert("ca-cert".into(), variant(uri.into_bytes()));
+    }
+    eap
+}
+
+/// Describe a WiFi profile from its `GetSettings` result and file name.
+///
+/// Returns `None` for non-WiFi profiles.
+pub fn saved_network(settings: &HashMap<String, PropMap>, filename: &str) -> Option<SavedNetwork> {
+    let connection = settings.get("connection")?;
+    if prop_cast::<String>(connection, "type")? != "802-11-wireless" {
+        return None;
+    }
+
+    let ssid = profile_ssid(settings).unwrap_or_default();
+
+    Some(SavedNetwork {
+        id: prop_cast::<String>(connection, "uuid")?.clone(),
+        ssid: ssid.to_string(),
+        ssid_hex: ssid.hex_if_not_utf8(),
+        priority: prop_cast::<i32>(connection, "autoconnect-priority")
+            .copied()
+            .unwrap_or(0),
+        // NetworkManager omits defaults; autoconnect defaults to on.
+        autoconnect: prop_cast::<bool>(connection, "autoconnect")
+            .copied()
+            .unwrap_or(true),
+        persistent: is_persistent(filename),
+    })
+}
+
+/// Raw SSID bytes of a saved profile's `802-11-wireless` settings.
+pub fn profile_ssid(settings: &HashMap<String, PropMap>) -> Option<Ssid> {
+    settings
+        .get("802-11-wireless")
+        .and_then(|wireless| prop_cast::<Vec<u8>>(wireless, "ssid"))
+        .map(|ssid| Ssid::from(ssid.as_slice()))
+}
+
+/// Convert a D-Bus error into a WiFi error.
+fn dbus_error(e: dbus::Error) -> WifiError {
+    WifiError::DbusError(e.to_string())
+}
+
+/// WiFi manager using NetworkManager over D-Bus.
+pub struct NmDbusWifiManager {
+    conn: Arc<SyncConnection>,
+}
+
+impl NmDbusWifiManager {
+    /// Connect to the system bus.
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn new() -> WifiResult<Self> {
+        let (resource, conn) = dbus_tokio::connection::new_system_sync().map_err(dbus_error)?;
+
+        tokio::spawn(async move {
+            let err = resource.await;
+            error!("Lost connection to D-Bus: {}", err);
+        });
+
+        Ok(Self { conn })
+    }
+
+    fn proxy<'a>(&self, path: impl Into<Path<'a>>) -> Proxy<'a, Arc<SyncConnection>> {
+        Proxy::new(NM_BUS, path, CALL_TIMEOUT, Arc::clone(&self.conn))
+    }
+
+    /// Find the first WiFi device.
+    async fn wifi_device(&self) -> WifiResult<Path<'static>> {
+        let (devices,): (Vec<Path<'static>>,) = self
+            .proxy(NM_PATH)
+            .method_call(NM_IFACE, "GetDevices", ())
+            .await
+            .map_err(dbus_error)?;
+
+        for device in devices {
+            let device_type: u32 = self
+                .proxy(device.clone())
+                .get(DEVICE_IFACE, "DeviceType")
+                .await
+                .map_err(dbus_error)?;
+            if device_type == DEVICE_TYPE_WIFI {
+                debug!("Using WiFi device {}", device);
+                return Ok(device);
+            }
+        }
+
+        Err(WifiError::NoWifiDevice)
+    }
+
+    /// Read an access point's properties.
+    async fn access_point(&self, path: Path<'static>) -> WifiResult<AccessPoint> {
+        let props: PropMap = self
+            .proxy(path)
+            .get_all(AP_IFACE)
+            .await
+            .map_err(dbus_error)?;
+        Ok(AccessPoint::from_props(&props))
+    }
+
+    /// Request a scan and wait for it to finish.
+    ///
+    /// NetworkManager refuses scans requested too soon after the last one;
+    /// the cached results are used in that case.
+    async fn rescan(&self, device: &Path<'static>) {
+        let wireless = self.proxy(device.clone());
+        let last_scan: i64 = wireless.get(WIRELESS_IFACE, "LastScan").await.unwrap_or(0);
+
+        let request: MethodReply<()> = {
+            let options = PropMap::new();
+            wireless.method_call(WIRELESS_IFACE, "RequestScan", (options,))
+        };
+        if let Err(e) = request.await {
+            debug!("Scan request refused, using cached results: {}", e);
+            return;
+        }
+
+        let deadline = tokio::time::Instant::now() + SCAN_TIMEOUT;
+        while tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(SCAN_POLL_INTERVAL).await;
+            match wireless.get::<i64>(WIRELESS_IFACE, "LastScan").await {
+                Ok(scan) if scan != last_scan => return,
+                Ok(_) => {}
+                Err(e) => {
+                    debug!("Failed to read LastScan: {}", e);
+                    return;
+                }
+            }
+        }
+        warn!("Scan did not finish within {}s", SCAN_TIMEOUT.as_secs());
+    }
+
+    /// Paths of all saved connection profiles.
+    async fn list_connections(&self) -> WifiResult<Vec<Path<'static>>> {
+        let (connections,): (Vec<Path<'static>>,) = self
+            .proxy(SETTINGS_PATH)
+            .method_call(SETTINGS_IFACE, "ListConnections", ())
+            .await
+            .map_err(dbus_error)?;
+        Ok(connections)
+    }
+
+    /// Settings path of the profile with this UUID.
+    async fn connection_by_uuid(&self, uuid: &str) -> WifiResult<Path<'static>> {
+        let reply: Result<(Path<'static>,), _> = self
+            .proxy(SETTINGS_PATH)
+            .method_call(SETTINGS_IFACE, "GetConnectionByUuid", (uuid,))
+            .await;
+        reply
+            .map(|(path,)| path)
+            .map_err(|_| WifiError::UnknownProfile(uuid.to_string()))
+    }
+
+    /// Change a profile's `connection` settings.
+    async fn update_connection(
+        &self,
+        uuid: &str,
+        edit: impl FnOnce(&mut PropMap) + Send,
+    ) -> WifiResult<()> {
+        let proxy = self.proxy(self.connection_by_uuid(uuid).await?);
+
+        // Settings hold non-Send values, so they must not live across an await.
+        let reply: MethodReply<()> = {
+            let (mut settings,): (HashMap<String, PropMap>,) = proxy
+                .method_call(CONNECTION_IFACE, "GetSettings", ())
+                .await
+                .map_err(dbus_error)?;
+            edit(settings.entry("connection".to_string()).or_default());
+            proxy.method_call(CONNECTION_IFACE, "Update", (settings,))
+        };
+        reply.await.map_err(dbus_error)
+    }
+
+    /// Add and activate a connection, then wait for the outcome.
+    async fn activate(
+        &self,
+        request: &ConnectRequest,
+        device: Path<'static>,
+        changes: &mut (impl Stream<Item = (dbus::Message, (u32, u32))> + Unpin),
+    ) -> WifiResult<()> {
+        let ssid = &request.ssid;
+        let ca_cert = match &request.credentials {
+            Credentials::Enterprise(EnterpriseCredentials {
+                ca_cert: Some(pem), ..
+            }) => Some(store_ca_cert(ssid, pem)?),
+            _ => None,
+        };
+
+        let reply: MethodReply<(Path<'static>, Path<'static>)> = {
+            let mut settings = connection_settings(request, ca_cert.as_deref());
+            let reply = self.proxy(NM_PATH).method_call(
+                NM_IFACE,
+                "AddAndActivateConnection",
+                (&settings, device, Path::from("/")),
+            );
+            wipe_credentials(&mut settings);
+            reply
+        };
+        let (connection, active) = reply.await.map_err(dbus_error)?;
+        debug!("Activating {} as {}", connection, active);
+
+        let outcome = tokio::time::timeout(ACTIVATION_TIMEOUT, async {
+            while let Some((msg, (state, reason))) = changes.next().await {
+                if msg.path().as_ref() != Some(&active) {
+                    continue;
+                }
+                debug!("Active connection state {} (reason {})", state, reason);
+                match state {
+                    ACTIVE_STATE_ACTIVATED => return Ok(()),
+                    ACTIVE_STATE_DEACTIVATED => return Err(describe_reason(reason)),
+                    _ => {}
+                }
+            }
+            Err("lost NetworkManager signals")
+        })
+        .await
+        .unwrap_or(Err("timed out waiting for activation"));
+
+        match outcome {
+            Ok(()) => Ok(()),
+            Err(reason) => {
+                // Don't leave a profile with bad credentials behind.
+                let delete: Result<(), _> = self
+                    .proxy(connection)
+                    .method_call(CONNECTION_IFACE, "Delete", ())
+                    .await;
+                if let Err(e) = delete {
+                    warn!("Failed to delete failed connection profile: {}", e);
+                }
+                Err(WifiError::ConnectionFailed(format!(
+                    "Failed to connect to {}: {}",
+                    ssid, reason
+                )))
+            }
+        }
+    }
+}
+
+impl WifiManager for NmDbusWifiManager {
+    async fn status(&self) -> WifiResult<WifiStatus> {
+        let device = self.wifi_device().await?;
+        let proxy = self.proxy(device);
+
+        let state: u32 = proxy.get(DEVICE_IFACE, "State").await.map_err(dbus_error)?;
+        if state == DEVICE_STATE_ACTIVATED {
+            let ap: Path<'static> = proxy
+                .get(WIRELESS_IFACE, "ActiveAccessPoint")
+                .await
+                .map_err(dbus_error)?;
+            if &*ap != "/" {
+                let ssid = Ssid::new(self.access_point(ap).await?.ssid);
+                info!("WiFi connected to: {}", ssid);
+                return Ok(WifiStatus {
+                    connected: true,
+                    ssid: Some(ssid),
+                });
+            }
+        }
+
+        info!("WiFi not connected");
+        Ok(WifiStatus {
+            connected: false,
+            ssid: None,
+        })
+    }
+
+    async fn scan(&self) -> WifiResult<Vec<Network>> {
+        let device = self.wifi_device().await?;
+        self.rescan(&device).await;
+
+        let (paths,): (Vec<Path<'static>>,) = self
+            .proxy(device)
+            .method_call(WIRELESS_IFACE, "GetAllAccessPoints", ())
+            .await
+            .map_err(dbus_error)?;
+
+        let mut access_points = Vec::with_capacity(paths.len());
+        for path in paths {
+            // Access points can disappear between listing and reading them.
+            match self.access_point(path).await {
+                Ok(ap) => access_points.push(ap),
+                Err(e) => debug!("Skipping access point: {}", e),
+            }
+        }
+
+        let signals = measured_signals(self.interface().await).await;
+        let networks = networks_from_access_points(&access_points, &signals);
+        info!("Found {} WiFi networks", networks.len());
+        Ok(networks)
+    }
+
+    async fn connect(&self, request: &ConnectRequest) -> WifiResult<()> {
+        let ssid = &request.ssid;
+        info!("Connecting to WiFi network: {}", ssid);
+
+        let device = self.wifi_device().await?;
+
+        // Subscribe before activating so no state change is missed.
+        let rule = MatchRule::new_signal(ACTIVE_IFACE, "StateChanged");
+        let (signal, mut changes) = self
+            .conn
+            .add_match(rule)
+            .await
+            .map_err(dbus_error)?
+            .stream::<(u32, u32)>();
+
+        let result = self.activate(request, device, &mut changes).await;
+
+        if let Err(e) = self.conn.remove_match(signal.token()).await {
+            debug!("Failed to remove signal match: {}", e);
+        }
+
+        match &result {
+            Ok(()) => info!("Successfully connected to {}", ssid),
+            Err(e) => error!("{}", e),
+        }
+        result
+    }
+
+    async fn snapshot(&self) -> WifiResult<ConnectionSnapshot> {
+        let profiles = self
+            .list_connections()
+            .await?
+            .into_iter()
+            .map(|path| path.to_string())
+            .collect();
+
+        let device = self.wifi_device().await?;
+        let active: Path<'static> = self
+            .proxy(device)
+            .get(DEVICE_IFACE, "ActiveConnection")
+            .await
+            .map_err(dbus_error)?;
+
+        let active = if &*active == "/" {
+            None
+        } else {
+            let connection: Path<'static> = self
+                .proxy(active)
+                .get(ACTIVE_IFACE, "Connection")
+                .await
+                .map_err(dbus_error)?;
+            self.status().await?.ssid.map(|ssid| SavedConnection {
+                id: connection.to_string(),
+                ssid,
+            })
+        };
+
+        Ok(ConnectionSnapshot { active, profiles })
+    }
+
+    async fn restore(&self, snapshot: &ConnectionSnapshot) -> WifiResult<()> {
+        for path in self.list_connections().await? {
+            if snapshot.profiles.iter().any(|p| *p == *path) {
+                continue;
+            }
+            info!("Deleting connection profile {}", path);
+            let delete: Result<(), _> = self
+                .proxy(path)
+                .method_call(CONNECTION_IFACE, "Delete", ())
+                .await;
+            if let Err(e) = delete {
+                warn!("Failed to delete connection profile: {}", e);
+            }
+        }
+
+        if let Some(active) = &snapshot.active {
+            info!("Reactivating previous connection to {}", active.ssid);
+            let connection = Path::new(active.id.clone())
+                .map_err(|e| WifiError::DbusError(format!("Invalid connection path: {}", e)))?;
+            let device = self.wifi_device().await?;
+            let (_active,): (Path<'static>,) = self
+                .proxy(NM_PATH)
+                .method_call(
+                    NM_IFACE,
+                    "ActivateConnection",
+                    (connection, device, Path::from("/")),
+                )
+                .await
+                .map_err(dbus_error)?;
+        }
+        Ok(())
+    }
+
+    async fn remove_superseded(
+        &self,
+        ssid: &Ssid,
+        previous: &ConnectionSnapshot,
+    ) -> WifiResult<()> {
+        // Every connect adds a new profile, so older ones for the same SSID
+        // are deleted once the new one is verified.
+        for path in self.list_connections().await? {
+            if !previous.profiles.iter().any(|p| *p == *path) {
+                continue;
+            }
+            let proxy = self.proxy(path.clone());
+            let same_ssid = {
+                let (settings,): (HashMap<String, PropMap>,) = proxy
+                    .method_call(CONNECTION_IFACE, "GetSettings", ())
+                    .await
+                    .map_err(dbus_error)?;
+                profile_ssid(&settings).as_ref() == Some(ssid)
+            };
+            if !same_ssid {
+                continue;
+            }
+            info!("Deleting superseded connection profile {}", path);
+            let delete: Result<(), _> = proxy.method_call(CONNECTION_IFACE, "Delete", ()).await;
+            if let Err(e) = delete {
+                warn!("Failed to delete connection profile {}: {}", path, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn list_saved(&self) -> WifiResult<Vec<SavedNetwork>> {
+        let mut saved = Vec::new();
+
+        for path in self.list_connections().await? {
+            let proxy = self.proxy(path);
+            let filename: String = proxy
+                .get(CONNECTION_IFACE, "Filename")
+                .await
+                .unwrap_or_default();
+            let network = {
+                let (settings,): (HashMap<String, PropMap>,) = proxy
+                    .method_call(CONNECTION_IFACE, "GetSettings", ())
+                    .await
+                    .map_err(dbus_error)?;
+                saved_network(&settings, &filename)
+            };
+            saved.extend(network);
+        }
+
+        Ok(saved)
+    }
+
+    async fn forget(&self, id: &str) -> WifiResult<()> {
+        info!("Deleting connection profile {}", id);
+        let path = self.connection_by_uuid(id).await?;
+        self.proxy(path)
+            .method_call(CONNECTION_IFACE, "Delete", ())
+            .await
+            .map_err(dbus_error)
+    }
+
+    async fn set_priority(&self, id: &str, priority: i32) -> WifiResult<()> {
+        self.update_connection(id, move |connection| {
+            connection.insert("autoconnect-priority".into(), variant(priority));
+        })
+        .await
+    }
+
+    async fn set_autoconnect(&self, id: &str, autoconnect: bool) -> WifiResult<()> {
+        self.update_connection(id, move |connection| {
+            connection.insert("autoconnect".into(), variant(autoconnect));
+        })
+        .await
+    }
+
+    async fn interface(&self) -> Option<String> {
+        let interface = async {
+            let device = self.wifi_device().await?;
+            self.proxy(device)
+                .get::<String>(DEVICE_IFACE, "Interface")
+                .await
+                .map_err(dbus_error)
+        };
+        match interface.await {
+            Ok(interface) => Some(interface),
+            Err(e) => {
+                warn!("Failed to find the WiFi interface: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{EapMethod, Phase2Auth};
+
+    fn ap(ssid: &str, strength: u8, flags: u32, wpa_flags: u32, rsn_flags: u32) -> AccessPoint {
+        AccessPoint {
+            ssid: ssid.as_bytes().to_vec(),
+            strength,
+            hw_address: format!("AA:BB:CC:DD:EE:{:02X}", strength),
+            frequency: if strength > 50 { 5180 } else { 2412 },
+            flags,
+            wpa_flags,
+            rsn_flags,
+        }
+    }
+
+    #[test]
+    fn security_from_flags() {
+        // PSK key management.
+        assert_eq!(ap("a", 50, 1, 0, 0x100).security(), "wpa2");
+        assert_eq!(ap("a", 50, 1, 0x100, 0).security(), "wpa");
+        assert_eq!(ap("a", 50, 1, 0, 0x400).security(), "wpa3");
+        assert_eq!(ap("a", 50, 1, 0, 0).security(), "wep");
+        assert_eq!(ap("a", 50, 0, 0, 0).security(), "open");
+
+        // 802.1X key management.
+        assert_eq!(ap("a", 50, 1, 0, 0x200).security(), "wpa2-enterprise");
+        assert_eq!(ap("a", 50, 1, 0x200, 0).security(), "wpa-enterprise");
+        assert_eq!(ap("a", 50, 1, 0, 0x2200).security(), "wpa3-enterprise");
+    }
+
+    #[test]
+    fn groups_access_points_per_ssid() {
+        let signals = HashMap::from([("aa:bb:cc:dd:ee:50".to_string(), -38)]);
+        let networks = networks_from_access_points(
+            &[
+                ap("home", 40, 1, 0, 0x100),
+                ap("cafe", 70, 0, 0, 0),
+                ap("home", 80, 1, 0, 0x100),
+                ap("", 90, 0, 0, 0),
+            ],
+            &signals,
+        );
+
+        assert_eq!(networks.len(), 3);
+        assert!(networks[0].hidden);
+        assert_eq!(networks[0].ssid, "");
+        assert_eq!(networks[1].ssid, "home");
+        assert_eq!(networks[1].signal, Some(-38));
+        assert_eq!(networks[1].quality, 80);
+        assert_eq!(networks[1].security, "wpa2");
+        assert_eq!(networks[2].ssid, "cafe");
+        assert_eq!(networks[2].security, "open");
+        assert_eq!(networks[2].signal, None);
+
+        let aps = &networks[1].access_points;
+        assert_eq!(aps.len(), 2);
+        assert_eq!(aps[0].bssid, "aa:bb:cc:dd:ee:50");
+        assert_eq!((aps[0].band.as_str(), aps[0].channel), ("5GHz", 36));
+        assert_eq!((aps[1].band.as_str(), aps[1].channel), ("2.4GHz", 1));
+    }
+
+    #[test]
+    fn keeps_non_utf8_ssid_bytes() {
+        let raw = AccessPoint {
+            ssid: vec![b'c', b'a', b'f', 0xe9],
+            ..ap("", 60, 0, 0, 0)
+        };
+        let networks = networks_from_access_points(&[raw], &HashMap::new());
+
+        assert_eq!(networks[0].ssid_hex.as_deref(), Some("636166e9"));
+
+        let settings = connection_settings(&ConnectRequest::psk(networks[0].raw_ssid(), ""), None);
+        assert_eq!(
+            prop_cast::<Vec<u8>>(&settings["802-11-wireless"], "ssid").unwrap(),
+            &[b'c', b'a', b'f', 0xe9]
+        );
+    }
+
+    #[test]
+    fn describes_failure_reasons() {
+        assert!(describe_reason(9).contains("secrets"));
+        assert_eq!(describe_reason(6), "connection attempt timed out");
+        assert_eq!(describe_reason(999), "unknown reason");
+    }
+
+    #[test]
+    fn builds_psk_connection_settings() {
+        let settings = connection_settings(&ConnectRequest::psk("home", "hunter22"), None);
+
+        assert_eq!(
+            prop_cast::<String>(&settings["connection"], "type").unwrap(),
+            "802-11-wireless"
+        );
+        assert_eq!(
+            prop_cast::<Vec<u8>>(&settings["802-11-wireless"], "ssid").unwrap(),
+            b"home"
+        );
+        let security = &settings["802-11-wireless-security"];
+        assert_eq!(
+            prop_cast::<String>(security, "key-mgmt").unwrap(),
+            "wpa-psk"
+        );
+        assert_eq!(prop_cast::<String>(security, "psk").unwrap(), "hunter22");
+    }
+
+    #[test]
+    fn describes_saved_wifi_profiles() {
+        let mut settings: HashMap<String, PropMap> =
+            connection_settings(&ConnectRequest::psk("home", "hunter22"), None)
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect();
+        let connection = settings.get_mut("connection").unwrap();
+        connection.insert("uuid".into(), variant("0b1c".to_string()));
+        connection.insert("autoconnect-priority".into(), variant(5i32));
+
+        let saved = saved_network(
+            &settings,
+            "/etc/NetworkManager/system-connections/home.nmconnection",
+        )
+        .unwrap();
+
+        assert_eq!(saved.id, "0b1c");
+        assert_eq!(saved.ssid, "home");
+        assert_eq!(saved.ssid_hex, None);
+        assert_eq!(saved.priority, 5);
+        assert!(saved.autoconnect);
+        assert!(saved.persistent);
+
+        let saved = saved_network(
+            &settings,
+            "/run/NetworkManager/system-connections/home.nmconnection",
+        )
+        .unwrap();
+        assert!(!saved.persistent);
+    }
+
+    #[test]
+    fn profile_ssid_keeps_raw_bytes() {
+        let ssid = Ssid::from(&b"caf\xe9"[..]);
+        let settings: HashMap<String, PropMap> =
+            connection_settings(&ConnectRequest::psk(ssid.clone(), "hunter22"), None)
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect();
+
+        assert_eq!(profile_ssid(&settings), Some(ssid));
+        // A different network whose lossy text is the same doesn't match.
+        assert_ne!(profile_ssid(&settings), Some(Ssid::from("caf\u{fffd}")));
+        assert_eq!(profile_ssid(&HashMap::new()), None);
+    }
+
+    #[test]
+    fn skips_non_wifi_profiles() {
+        let mut connection = PropMap::new();
+        connection.insert("type".into(), variant("802-3-ethernet".to_string()));
+        connection.insert("uuid".into(), variant("77aa".to_string()));
+        let settings = HashMap::from([("connection".to_string(), connection)]);
+
+        assert_eq!(saved_network(&settings, ""), None);
+    }
+
+    #[test]
+    fn open_network_has_no_security_settings() {
+        let settings = connection_settings(&ConnectRequest::psk("cafe", ""), None);
+        assert!(!settings.contains_key("802-11-wireless-security"));
+    }
+
+    #[test]
+    fn hidden_network_settings() {
+        let request = ConnectRequest {
+            hidden: true,
+            ..ConnectRequest::psk("secret", "hunter22")
+        };
+        let wireless = &connection_settings(&request, None)["802-11-wireless"];
+        assert_eq!(prop_cast::<bool>(wireless, "hidden"), Some(&true));
+
+        let settings = connection_settings(&ConnectRequest::psk("home", "hunter22"), None);
+        let wireless = &settings["802-11-wireless"];
+        assert_eq!(prop_cast::<bool>(wireless, "hidden"), None);
+    }
+
+    #[test]
+    fn builds_enterprise_connection_settings() {
+        let enterprise = EnterpriseCredentials {
+            eap: EapMethod::Ttls,
+            phase2: Phase2Auth::Pap,
+            identity: "alice".to_string(),
+            anonymous_identity: None,
+            password: "hunter22".into(),
+            ca_cert: None,
+        };
+        let request = ConnectRequest::new("eduroam", Credentials::Enterprise(enterprise));
+        let settings = connection_settings(&request, Some("/data/certs/ca.pem"));
+
+        assert_eq!(
+            prop_cast::<String>(&settings["802-11-wireless-security"], "key-mgmt").unwrap(),
+            "wpa-eap"
+        );
+        let eap = &settings["802-1x"];
+        assert_eq!(prop_cast::<Vec<String>>(eap, "eap").unwrap(), &["ttls"]);
+        assert_eq!(prop_cast::<String>(eap, "phase2-auth").unwrap(), "pap");
+        assert_eq!(prop_cast::<String>(eap, "identity").unwrap(), "alice");
+        assert_eq!(prop_cast::<String>(eap, "password").unwrap(), "hunter22");
+        assert_eq!(prop_cast::<String>(eap, "anonymous-identity"), None);
+        assert_eq!(
+            prop_cast::<Vec<u8>>(eap, "ca-cert").unwrap(),
+            b"file:///data/certs/ca.pem\0"
+        );
+    }
+
+    #[test]
+    fn wipes_sent_credentials() {
+        let mut settings = connection_settings(&ConnectRequest::psk("home", "hunter22"), None);
+        wipe_credentials(&mut settings);
+        let security = &settings["802-11-wireless-security"];
+        assert_eq!(prop_cast::<String>(security, "psk").unwrap(), "");
+        assert_eq!(
+            prop_cast::<String>(security, "key-mgmt").unwrap(),
+            "wpa-psk"
+        );
+
+        let enterprise = EnterpriseCredentials {
+            eap: EapMethod::Peap,
+            phase2: Phase2Auth::default(),
+            identity: "alice".to_string(),
+            anonymous_identity: None,
+            password: "hunter22".into(),
+            ca_cert: None,
+        };
+        let request = ConnectRequest::new("eduroam", Credentials::Enterprise(enterprise));
+        let mut settings = connection_settings(&request, None);
+        wipe_credentials(&mut settings);
+        let eap = &settings["802-1x"];
+        assert_eq!(prop_cast::<String>(eap, "password").unwrap(), "");
+        assert_eq!(prop_cast::<String>(eap, "identity").unwrap(), "alice");
+    }
+
+    #[test]
+    fn builds_ip_settings() {
+        let mut request = ConnectRequest::psk("home", "hunter22");
+        request.ip = serde_json::from_str(
+            r#"{"ipv4":{"method":"manual","addresses":["192.168.1.50/24"],"gateway":"192.168.1.1"},
+                "dns":["192.168.1.1","2001:db8::53"],"dns_search":["lan"],
+                "proxy_pac_url":"http://wpad/wpad.dat"}"#,
+        )
+        .unwrap();
+        let settings = connection_settings(&request, None);
+
+        let ipv4 = &settings["ipv4"];
+        assert_eq!(prop_cast::<String>(ipv4, "method").unwrap(), "manual");
+        assert_eq!(prop_cast::<String>(ipv4, "gateway").unwrap(), "192.168.1.1");
+        assert_eq!(
+            prop_cast::<Vec<u32>>(ipv4, "dns").unwrap(),
+            &[u32::from_ne_bytes([192, 168, 1, 1])]
+        );
+        assert_eq!(prop_cast::<Vec<String>>(ipv4, "dns-search").unwrap(), &["lan"]);
+        let addresses = prop_cast::<Vec<PropMap>>(ipv4, "address-data").unwrap();
+        assert_eq!(prop_cast::<String>(&addresses[0], "address").unwrap(), "192.168.1.50");
+        assert_eq!(prop_cast::<u32>(&addresses[0], "prefix"), Some(&24));
+
+        let ipv6 = &settings["ipv6"];
+        assert_eq!(prop_cast::<String>(ipv6, "method").unwrap(), "auto");
+        let dns = prop_cast::<Vec<Vec<u8>>>(ipv6, "dns").unwrap();
+        assert_eq!(dns[0][..2], [0x20, 0x01]);
+
+        let proxy = &settings["proxy"];
+        assert_eq!(prop_cast::<i32>(proxy, "method"), Some(&PROXY_METHOD_AUTO));
+        assert_eq!(prop_cast::<String>(proxy, "pac-url").unwrap(), "http://wpad/wpad.dat");
+
+        let settings = connection_settings(&ConnectRequest::psk("home", "hunter22"), None);
+        assert!(!settings.contains_key("ipv4"));
+        assert!(!settings.contains_key("proxy"));
+    }
+}
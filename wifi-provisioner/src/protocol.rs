@@ -2,10 +2,67 @@
 //!
 //! Defines the JSON command/response format for local IPC.
 
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 
+use crate::connectivity::ConnectivityFailure;
+use crate::ipconfig::{IpConfig, IpPrefix};
+use crate::regdomain::CountryCode;
+use crate::secret::Secret;
+use crate::ssid::Ssid;
+use crate::wifi::{ErrorCode, WifiStatus};
+
+/// Client-chosen id of a request, echoed in its response.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[serde(untagged)]
+pub enum RequestId {
+    Number(u64),
+    Text(String),
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestId::Number(n) => write!(f, "{}", n),
+            RequestId::Text(text) => write!(f, "{:?}", text),
+        }
+    }
+}
+
+/// A command with its optional id, as sent by clients.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+pub struct Request {
+    #[serde(default)]
+    pub id: Option<RequestId>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+/// The id and command name of a request, read even when the rest of it
+/// doesn't parse, so the error can carry the id.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+pub struct RequestHeader {
+    #[serde(default)]
+    pub id: Option<RequestId>,
+    #[serde(default)]
+    pub cmd: Option<String>,
+}
+
+impl RequestHeader {
+    pub fn parse(text: &str) -> Self {
+        serde_json::from_str(text).unwrap_or_default()
+    }
+}
+
 /// Commands received from local clients (e.g., dirtsim UI).
+///
+/// Unknown fields are ignored, so clients can send fields newer daemons
+/// understand.
 #[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
 #[serde(tag = "cmd", rename_all = "snake_case")]
 pub enum Command {
     /// Start BLE advertising with optional timeout in seconds.
@@ -19,14 +76,146 @@ pub enum Command {
     Status,
     /// Scan for available WiFi networks.
     Scan,
+    /// Locally confirm that the connected BLE client may send credentials.
+    Authorize,
+    /// List saved network profiles.
+    ListSaved,
+    /// Delete a saved network profile.
+    ///
+    /// Saved-profile commands name the profile by its `SavedNetwork::id` in
+    /// `profile`, since `id` is the request id.
+    Forget { profile: String },
+    /// Change a saved profile's autoconnect priority (higher is preferred).
+    SetPriority { profile: String, priority: i32 },
+    /// Enable or disable automatic connection to a saved profile.
+    SetAutoconnect { profile: String, autoconnect: bool },
+    /// Join a network, verify connectivity, and roll back on failure.
+    ///
+    /// `ssid_hex` gives the raw SSID bytes and overrides `ssid`. With
+    /// `enterprise` set, the network is joined with 802.1X and `password` is
+    /// ignored. `ip` replaces DHCP with static addresses, DNS servers and a
+    /// proxy. `hidden` skips looking for the SSID in a scan, and `security`
+    /// (as reported by `scan`) is checked against the credentials.
+    Connect {
+        ssid: String,
+        #[serde(default)]
+        ssid_hex: Option<String>,
+        #[serde(default)]
+        password: Secret,
+        #[serde(default)]
+        enterprise: Option<EnterpriseCredentials>,
+        #[serde(default)]
+        ip: Box<IpConfig>,
+        #[serde(default)]
+        hidden: bool,
+        #[serde(default)]
+        security: Option<Security>,
+    },
+    /// Set the WiFi regulatory country (ISO 3166-1 alpha-2, e.g. "US").
+    SetCountry { country: String },
+    /// Receive `Event`s on this connection from now on.
+    Subscribe,
+    /// Cancel the `scan` or `connect` sent on this connection with id `target`.
+    Cancel { target: RequestId },
+    /// Report the protocol version and what this daemon supports.
+    Hello,
+}
+
+impl Command {
+    /// Every command's `cmd` name, as reported by `hello`.
+    pub const NAMES: &'static [&'static str] = &[
+        "start",
+        "stop",
+        "status",
+        "scan",
+        "authorize",
+        "list_saved",
+        "forget",
+        "set_priority",
+        "set_autoconnect",
+        "connect",
+        "set_country",
+        "subscribe",
+        "cancel",
+        "hello",
+    ];
+
+    /// The command's `cmd` name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Start { .. } => "start",
+            Command::Stop => "stop",
+            Command::Status => "status",
+            Command::Scan => "scan",
+            Command::Authorize => "authorize",
+            Command::ListSaved => "list_saved",
+            Command::Forget { .. } => "forget",
+            Command::SetPriority { .. } => "set_priority",
+            Command::SetAutoconnect { .. } => "set_autoconnect",
+            Command::Connect { .. } => "connect",
+            Command::SetCountry { .. } => "set_country",
+            Command::Subscribe => "subscribe",
+            Command::Cancel { .. } => "cancel",
+            Command::Hello => "hello",
+        }
+    }
 }
 
 fn default_timeout() -> u32 {
     300 // 5 minutes.
 }
 
+/// Version of this protocol, reported by `hello`.
+///
+/// Bumped only for changes old clients can't cope with. New commands,
+/// fields and events are additions within a version: clients should ignore
+/// fields and events they don't know, and check `hello` for commands.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// A way clients reach the daemon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// This WebSocket API.
+    Websocket,
+    /// Improv over Bluetooth LE.
+    Ble,
+    /// Improv Serial.
+    Serial,
+}
+
+/// What this daemon build supports, returned by `hello`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+pub struct Hello {
+    pub protocol_version: u32,
+    /// Version of the wifi-provisioner package.
+    pub daemon_version: String,
+    /// `cmd` names this daemon accepts.
+    pub commands: Vec<String>,
+    /// Enabled transports.
+    pub transports: Vec<Transport>,
+    /// WiFi backend in use: "nmcli", "dbus" or "wpa_supplicant".
+    pub wifi_backend: String,
+}
+
+impl Hello {
+    /// Capabilities of this build, with the enabled transports and backend.
+    pub fn new(transports: Vec<Transport>, wifi_backend: impl Into<String>) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            daemon_version: env!("CARGO_PKG_VERSION").to_string(),
+            commands: Command::NAMES.iter().map(|name| name.to_string()).collect(),
+            transports,
+            wifi_backend: wifi_backend.into(),
+        }
+    }
+}
+
 /// Daemon state reported in responses.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
 #[serde(rename_all = "snake_case")]
 pub enum State {
     /// Not advertising, waiting for trigger.
@@ -37,10 +226,26 @@ pub enum State {
     Connected,
     /// WiFi provisioning in progress.
     Provisioning,
+    /// WiFi was provisioned; advertising has stopped.
+    Provisioned,
+}
+
+impl State {
+    /// Name as used in responses (e.g., "advertising").
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Idle => "idle",
+            State::Advertising => "advertising",
+            State::Connected => "connected",
+            State::Provisioning => "provisioning",
+            State::Provisioned => "provisioned",
+        }
+    }
 }
 
 /// Response to a command.
 #[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
 #[serde(untagged)]
 pub enum Response {
     /// Successful response with state info.
@@ -49,9 +254,92 @@ pub enum Response {
     Error(ErrorResponse),
 }
 
+impl Response {
+    /// Echo the id of the request this answers.
+    pub fn with_id(mut self, id: Option<RequestId>) -> Self {
+        match &mut self {
+            Response::Ok(ok) => ok.id = id,
+            Response::Error(err) => err.id = id,
+        }
+        self
+    }
+}
+
+/// Stage of a provisioning attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ProvisioningStage {
+    /// Associating with the network.
+    Provisioning,
+    /// Associated; waiting for an address, a route and DNS.
+    Verifying,
+    /// Joined and verified.
+    Connected,
+    /// Gave up; the previous connection has been restored if possible.
+    Failed,
+}
+
+/// Event pushed to clients, tagged with `event`.
+///
+/// `progress` goes to the connection that sent `connect`; the rest go to
+/// subscribed connections.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The daemon state changed.
+    State { state: State },
+    /// A client asked the device to identify itself (flash an LED, show a
+    /// message).
+    Identify,
+    /// A BLE client connected.
+    ClientConnected,
+    /// The BLE client disconnected.
+    ClientDisconnected,
+    /// Joining a network started.
+    ProvisioningStarted {
+        ssid: String,
+        /// Hex of the raw SSID bytes, present only when they are not UTF-8.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ssid_hex: Option<String>,
+    },
+    /// The network was joined and verified.
+    ProvisioningComplete {
+        /// Where the Improv client is sent next (Improv provisioning only).
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        redirect_url: Option<String>,
+    },
+    /// Joining the network failed at this connectivity step.
+    ProvisioningFailed(ConnectivityFailure),
+    /// Seconds left until advertising stops.
+    Countdown { remaining: u32 },
+    /// A `connect` sent on this connection reached `stage`.
+    Progress {
+        /// Id of the `connect` request, if it had one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<RequestId>,
+        stage: ProvisioningStage,
+    },
+}
+
+impl Event {
+    /// Provisioning of `ssid` started.
+    pub fn provisioning_started(ssid: &Ssid) -> Self {
+        Event::ProvisioningStarted {
+            ssid: ssid.to_string(),
+            ssid_hex: ssid.hex_if_not_utf8(),
+        }
+    }
+}
+
 /// Successful response payload.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
 pub struct OkResponse {
+    /// Id of the request, if it had one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
     pub ok: bool,
     pub state: State,
     /// Seconds remaining until advertising timeout (only when advertising).
@@ -63,17 +351,46 @@ pub struct OkResponse {
     /// Available networks (only for scan response).
     #[serde(skip_serializing_if = "Option::is_none")]
     pub networks: Option<Vec<Network>>,
+    /// Saved network profiles (only for saved-profile commands).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub saved: Option<Vec<SavedNetwork>>,
+    /// Step that failed in the last provisioning attempt (status only).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connectivity_error: Option<ConnectivityFailure>,
+    /// Addresses in use, with prefix lengths (status only, when connected).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub addresses: Option<Vec<IpPrefix>>,
+    /// WiFi regulatory country (status and set_country only, when set).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<CountryCode>,
+    /// Network WiFi is connected to (connect only).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssid: Option<String>,
+    /// Hex of the raw SSID bytes, present only when they are not UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssid_hex: Option<String>,
+    /// Protocol version and capabilities (hello only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hello: Option<Box<Hello>>,
 }
 
 impl OkResponse {
     /// Create a simple OK response with just state.
     pub fn new(state: State) -> Self {
         Self {
+            id: None,
             ok: true,
             state,
             remaining: None,
             wifi_connected: None,
             networks: None,
+            saved: None,
+            connectivity_error: None,
+            addresses: None,
+            country: None,
+            ssid: None,
+            ssid_hex: None,
+            hello: None,
         }
     }
 
@@ -94,32 +411,270 @@ impl OkResponse {
         self.networks = Some(networks);
         self
     }
+
+    /// Add saved network profiles.
+    pub fn with_saved(mut self, saved: Vec<SavedNetwork>) -> Self {
+        self.saved = Some(saved);
+        self
+    }
+
+    /// Add the failed connectivity step.
+    pub fn with_connectivity_error(mut self, failure: ConnectivityFailure) -> Self {
+        self.connectivity_error = Some(failure);
+        self
+    }
+
+    /// Add the addresses in use.
+    pub fn with_addresses(mut self, addresses: Vec<IpPrefix>) -> Self {
+        self.addresses = Some(addresses);
+        self
+    }
+
+    /// Add the regulatory country.
+    pub fn with_country(mut self, country: CountryCode) -> Self {
+        self.country = Some(country);
+        self
+    }
+
+    /// Add the protocol version and capabilities.
+    pub fn with_hello(mut self, hello: Hello) -> Self {
+        self.hello = Some(Box::new(hello));
+        self
+    }
+
+    /// Add WiFi connection status and the connected SSID.
+    pub fn with_wifi_status(mut self, status: &WifiStatus) -> Self {
+        self.wifi_connected = Some(status.connected);
+        if let Some(ssid) = status.ssid.as_ref().filter(|_| status.connected) {
+            self.ssid = Some(ssid.to_string());
+            self.ssid_hex = ssid.hex_if_not_utf8();
+        }
+        self
+    }
 }
 
 /// Error response payload.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
 pub struct ErrorResponse {
+    /// Id of the request, if it had one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<RequestId>,
     pub ok: bool,
     pub error: String,
+    /// Numeric failure class, for WiFi failures.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
 }
 
 impl ErrorResponse {
     pub fn new(message: impl Into<String>) -> Self {
         Self {
+            id: None,
             ok: false,
             error: message.into(),
+            code: None,
         }
     }
+
+    /// Add the failure class.
+    pub fn with_code(mut self, code: ErrorCode) -> Self {
+        self.code = Some(code);
+        self
+    }
 }
 
 /// WiFi network info from scan.
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+///
+/// One entry per SSID, plus one grouping access points with a hidden SSID;
+/// `access_points` lists every BSS behind the entry.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
 pub struct Network {
+    /// SSID as text; invalid UTF-8 is replaced with U+FFFD.
     pub ssid: String,
-    /// Signal strength in dBm (e.g., -45).
-    pub signal: i32,
+    /// Hex of the raw SSID bytes, present only when they are not UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssid_hex: Option<String>,
+    /// Access points that hide their SSID; `ssid` is empty.
+    #[serde(default)]
+    pub hidden: bool,
+    /// Measured signal strength of the strongest access point in dBm
+    /// (e.g., -45); omitted if it wasn't measured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<i32>,
     /// Security type (e.g., "wpa2", "open").
     pub security: String,
+    /// Signal quality of the strongest access point in percent.
+    #[serde(default)]
+    pub quality: u8,
+    /// Access points advertising this SSID, strongest first.
+    #[serde(default)]
+    pub access_points: Vec<AccessPointInfo>,
+}
+
+impl Network {
+    /// The network's raw SSID bytes.
+    pub fn raw_ssid(&self) -> Ssid {
+        Ssid::from_json(&self.ssid, self.ssid_hex.as_deref())
+            .unwrap_or_else(|| Ssid::from(self.ssid.as_str()))
+    }
+}
+
+/// A single access point (BSS) seen in a scan.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+pub struct AccessPointInfo {
+    /// MAC address, e.g. "aa:bb:cc:dd:ee:ff".
+    pub bssid: String,
+    /// Center frequency in MHz.
+    pub frequency: u32,
+    /// "2.4GHz", "5GHz", "6GHz" or "unknown".
+    pub band: String,
+    /// Channel number, or 0 if unknown.
+    pub channel: u32,
+    /// Measured signal strength in dBm; omitted if it wasn't measured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<i32>,
+    /// Signal quality in percent.
+    pub quality: u8,
+}
+
+/// Security of a network, as reported in scan results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum Security {
+    Open,
+    Wep,
+    Wpa,
+    Wpa2,
+    Wpa3,
+    WpaEnterprise,
+    Wpa2Enterprise,
+    Wpa3Enterprise,
+}
+
+impl Security {
+    /// Name as used in scan results (e.g., "wpa2-enterprise").
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Security::Open => "open",
+            Security::Wep => "wep",
+            Security::Wpa => "wpa",
+            Security::Wpa2 => "wpa2",
+            Security::Wpa3 => "wpa3",
+            Security::WpaEnterprise => "wpa-enterprise",
+            Security::Wpa2Enterprise => "wpa2-enterprise",
+            Security::Wpa3Enterprise => "wpa3-enterprise",
+        }
+    }
+
+    /// Whether the network uses 802.1X.
+    pub fn is_enterprise(&self) -> bool {
+        matches!(
+            self,
+            Security::WpaEnterprise | Security::Wpa2Enterprise | Security::Wpa3Enterprise
+        )
+    }
+}
+
+/// Outer EAP method for WPA2/WPA3-Enterprise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum EapMethod {
+    Peap,
+    Ttls,
+}
+
+impl EapMethod {
+    /// Name as used by NetworkManager ("peap", "ttls").
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EapMethod::Peap => "peap",
+            EapMethod::Ttls => "ttls",
+        }
+    }
+
+    /// Parse a method name, ignoring case.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "peap" => Some(EapMethod::Peap),
+            "ttls" => Some(EapMethod::Ttls),
+            _ => None,
+        }
+    }
+}
+
+/// Inner (phase 2) authentication inside the EAP tunnel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Phase2Auth {
+    #[default]
+    Mschapv2,
+    Pap,
+    Gtc,
+}
+
+impl Phase2Auth {
+    /// Name as used by NetworkManager ("mschapv2", "pap", "gtc").
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase2Auth::Mschapv2 => "mschapv2",
+            Phase2Auth::Pap => "pap",
+            Phase2Auth::Gtc => "gtc",
+        }
+    }
+
+    /// Parse a method name, ignoring case.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "mschapv2" => Some(Phase2Auth::Mschapv2),
+            "pap" => Some(Phase2Auth::Pap),
+            "gtc" => Some(Phase2Auth::Gtc),
+            _ => None,
+        }
+    }
+}
+
+/// Credentials for a WPA2/WPA3-Enterprise (802.1X) network.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+pub struct EnterpriseCredentials {
+    pub eap: EapMethod,
+    /// Defaults to MSCHAPv2.
+    #[serde(default)]
+    pub phase2: Phase2Auth,
+    pub identity: String,
+    /// Outer identity sent before the tunnel is up, e.g. "anonymous@example.edu".
+    #[serde(default)]
+    pub anonymous_identity: Option<String>,
+    pub password: Secret,
+    /// PEM CA certificate to validate the authentication server with.
+    #[serde(default)]
+    pub ca_cert: Option<String>,
+}
+
+/// A saved network profile.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(schemars::JsonSchema))]
+pub struct SavedNetwork {
+    /// Backend-specific profile id, used by forget/set_priority/set_autoconnect.
+    pub id: String,
+    /// SSID as text; invalid UTF-8 is replaced with U+FFFD.
+    pub ssid: String,
+    /// Hex of the raw SSID bytes, present only when they are not UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssid_hex: Option<String>,
+    /// Autoconnect priority; higher is preferred.
+    pub priority: i32,
+    /// Whether the profile is connected to automatically.
+    pub autoconnect: bool,
+    /// Whether the profile is stored on disk (in /data, through the
+    /// NetworkManager system-connections bind mount) rather than in memory.
+    pub persistent: bool,
 }
 
 #[cfg(test)]
@@ -161,6 +716,50 @@ mod tests {
         assert_eq!(cmd, Command::Scan);
     }
 
+    #[test]
+    fn parse_authorize() {
+        let json = r#"{"cmd":"authorize"}"#;
+        let cmd: Command = serde_json::from_str(json).unwrap();
+        assert_eq!(cmd, Command::Authorize);
+    }
+
+    #[test]
+    fn parse_saved_profile_commands() {
+        let cmd: Command = serde_json::from_str(r#"{"cmd":"list_saved"}"#).unwrap();
+        assert_eq!(cmd, Command::ListSaved);
+
+        let cmd: Command = serde_json::from_str(r#"{"cmd":"forget","profile":"abc"}"#).unwrap();
+        assert_eq!(cmd, Command::Forget {
+                profile: "abc".into()
+            });
+
+        let json = r#"{"cmd":"set_priority","profile":"abc","priority":10}"#;
+        let cmd: Command = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            cmd,
+            Command::SetPriority {
+                profile: "abc".into(),
+                priority: 10
+            }
+        );
+
+        let json = r#"{"cmd":"set_autoconnect","profile":"abc","autoconnect":false}"#;
+        let cmd: Command = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            cmd,
+            Command::SetAutoconnect {
+                profile: "abc".into(),
+                autoconnect: false
+            }
+        );
+    }
+
+    #[test]
+    fn forget_requires_id() {
+        let result: Result<Command, _> = serde_json::from_str(r#"{"cmd":"forget"}"#);
+        assert!(result.is_err());
+    }
+
     #[test]
     fn parse_invalid_command() {
         let json = r#"{"cmd":"invalid"}"#;
@@ -201,19 +800,309 @@ mod tests {
         let networks = vec![
             Network {
                 ssid: "MyWiFi".into(),
-                signal: -45,
+                ssid_hex: None,
+                hidden: false,
+                signal: Some(-45),
                 security: "wpa2".into(),
+                quality: 64,
+                access_points: vec![AccessPointInfo {
+                    bssid: "aa:bb:cc:dd:ee:01".into(),
+                    frequency: 5180,
+                    band: "5GHz".into(),
+                    channel: 36,
+                    signal: Some(-45),
+                    quality: 64,
+                }],
             },
             Network {
                 ssid: "Guest".into(),
-                signal: -72,
+                quality: 26,
                 security: "open".into(),
+                ..Default::default()
             },
         ];
         let resp = Response::Ok(OkResponse::new(State::Idle).with_networks(networks));
         let json = serde_json::to_string(&resp).unwrap();
         assert!(json.contains(r#""ssid":"MyWiFi""#));
         assert!(json.contains(r#""signal":-45"#));
+        // Guest's signal wasn't measured.
+        assert_eq!(json.matches(r#""signal""#).count(), 2);
+        assert!(json.contains(r#""band":"5GHz""#));
+        assert!(json.contains(r#""channel":36"#));
+        assert!(!json.contains("ssid_hex"));
+    }
+
+    #[test]
+    fn non_utf8_network_carries_hex_ssid() {
+        let network = Network {
+            ssid: "caf\u{fffd}".into(),
+            ssid_hex: Some("636166e9".into()),
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&network).unwrap();
+        assert!(json.contains(r#""ssid_hex":"636166e9""#));
+
+        let parsed: Network = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.raw_ssid().as_bytes(), b"caf\xe9");
+    }
+
+    #[test]
+    fn network_without_access_points_deserializes() {
+        let json = r#"{"ssid":"MyWiFi","signal":-45,"security":"wpa2"}"#;
+        let network: Network = serde_json::from_str(json).unwrap();
+        assert_eq!(network.quality, 0);
+        assert!(network.access_points.is_empty());
+    }
+
+    #[test]
+    fn deserialize_connect_command() {
+        let json = r#"{"cmd":"connect","ssid":"home","password":"hunter22"}"#;
+        assert_eq!(
+            serde_json::from_str::<Command>(json).unwrap(),
+            Command::Connect {
+                ssid: "home".into(),
+                ssid_hex: None,
+                password: "hunter22".into(),
+                enterprise: None,
+                ip: Box::default(),
+                hidden: false,
+                security: None,
+            }
+        );
+
+        let json = r#"{"cmd":"connect","ssid":"home","password":"pw","hidden":true,
+            "security":"wpa2-enterprise"}"#;
+        let Command::Connect {
+            hidden, security, ..
+        } = serde_json::from_str(json).unwrap()
+        else {
+            panic!("expected connect");
+        };
+        assert!(hidden);
+        assert_eq!(security, Some(Security::Wpa2Enterprise));
+
+        let json = r#"{"cmd":"connect","ssid":"home","security":"wpa4"}"#;
+        assert!(serde_json::from_str::<Command>(json).is_err());
+    }
+
+    #[test]
+    fn deserialize_static_ip_connect_command() {
+        let json = r#"{"cmd":"connect","ssid":"home","password":"pw","ip":{
+            "ipv4":{"method":"manual","addresses":["192.168.1.50/24"],"gateway":"192.168.1.1"},
+            "dns":["192.168.1.1"],"dns_search":["lan"]}}"#;
+        let Command::Connect { ip, .. } = serde_json::from_str(json).unwrap() else {
+            panic!("expected connect");
+        };
+
+        assert_eq!(ip.ipv4.method, crate::ipconfig::IpMethod::Manual);
+        assert_eq!(ip.ipv4.addresses[0].to_string(), "192.168.1.50/24");
+        assert_eq!(ip.dns_search, vec!["lan"]);
+
+        let json = r#"{"cmd":"connect","ssid":"home","ip":{"ipv4":{"addresses":["10.0.0.1"]}}}"#;
+        assert!(serde_json::from_str::<Command>(json).is_err());
+    }
+
+    #[test]
+    fn deserialize_enterprise_connect_command() {
+        let json = r#"{"cmd":"connect","ssid":"eduroam","enterprise":
+            {"eap":"ttls","phase2":"pap","identity":"jo@example.edu","password":"pw"}}"#;
+        let Command::Connect { enterprise, .. } = serde_json::from_str(json).unwrap() else {
+            panic!("expected connect");
+        };
+
+        let enterprise = enterprise.unwrap();
+        assert_eq!(enterprise.eap, EapMethod::Ttls);
+        assert_eq!(enterprise.phase2, Phase2Auth::Pap);
+        assert_eq!(enterprise.identity, "jo@example.edu");
+        assert_eq!(enterprise.anonymous_identity, None);
+        assert_eq!(enterprise.ca_cert, None);
+
+        let json = r#"{"cmd":"connect","ssid":"x","enterprise":
+            {"eap":"tls","identity":"a","password":"b"}}"#;
+        assert!(serde_json::from_str::<Command>(json).is_err());
+    }
+
+    #[test]
+    fn parse_subscribe() {
+        let cmd: Command = serde_json::from_str(r#"{"cmd":"subscribe"}"#).unwrap();
+        assert_eq!(cmd, Command::Subscribe);
+    }
+
+    #[test]
+    fn parse_requests_with_ids() {
+        let request: Request =
+            serde_json::from_str(r#"{"cmd":"start","timeout":60,"id":7}"#).unwrap();
+        assert_eq!(request.id, Some(RequestId::Number(7)));
+        assert_eq!(request.command, Command::Start { timeout: 60 });
+
+        let request: Request = serde_json::from_str(r#"{"id":"a1","cmd":"scan"}"#).unwrap();
+        assert_eq!(request.id, Some(RequestId::Text("a1".into())));
+
+        let request: Request = serde_json::from_str(r#"{"cmd":"status"}"#).unwrap();
+        assert_eq!(request.id, None);
+
+        let request: Request = serde_json::from_str(r#"{"cmd":"cancel","target":7}"#).unwrap();
+        assert_eq!(
+            request.command,
+            Command::Cancel {
+                target: RequestId::Number(7)
+            }
+        );
+
+        assert!(serde_json::from_str::<Request>(r#"{"cmd":"scan","id":1.5}"#).is_err());
+
+        let header = RequestHeader::parse(r#"{"cmd":"bogus","id":3}"#);
+        assert_eq!(header.id, Some(RequestId::Number(3)));
+        assert_eq!(header.cmd.as_deref(), Some("bogus"));
+        assert_eq!(RequestHeader::parse(r#"{"cmd":"bogus""#), RequestHeader::default());
+    }
+
+    #[test]
+    fn command_names_match_the_wire_format() {
+        for name in Command::NAMES {
+            let json = serde_json::json!({
+                "cmd": name,
+                "profile": "0b1c",
+                "priority": 0,
+                "autoconnect": true,
+                "ssid": "home",
+                "country": "US",
+                "target": 1,
+            });
+            let cmd: Command = serde_json::from_value(json).unwrap();
+            assert_eq!(cmd.name(), *name);
+        }
+    }
+
+    /// `NAMES` is kept by hand; the schema lists every variant's `cmd`.
+    #[test]
+    fn command_names_cover_every_variant() {
+        let schema = serde_json::to_value(schemars::schema_for!(Command)).unwrap();
+        let mut variants: Vec<&str> = schema["oneOf"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|variant| variant["properties"]["cmd"]["enum"][0].as_str().unwrap())
+            .collect();
+        let mut names = Command::NAMES.to_vec();
+
+        variants.sort_unstable();
+        names.sort_unstable();
+        assert_eq!(variants, names);
+    }
+
+    #[test]
+    fn unknown_fields_are_ignored() {
+        let request: Request =
+            serde_json::from_str(r#"{"cmd":"scan","id":1,"band":"5GHz"}"#).unwrap();
+        assert_eq!(request.command, Command::Scan);
+
+        // Clients built from these types accept replies and events from newer
+        // daemons.
+        let resp: OkResponse =
+            serde_json::from_str(r#"{"ok":true,"state":"idle","battery":80}"#).unwrap();
+        assert_eq!(resp.state, State::Idle);
+        let event: Event = serde_json::from_str(r#"{"event":"countdown","remaining":5,"of":300}"#)
+            .unwrap();
+        assert_eq!(event, Event::Countdown { remaining: 5 });
+    }
+
+    #[test]
+    fn serialize_hello() {
+        let hello = Hello::new(vec![Transport::Websocket, Transport::Ble], "dbus");
+        let json = serde_json::to_value(OkResponse::new(State::Idle).with_hello(hello)).unwrap();
+        assert_eq!(json["hello"]["protocol_version"], PROTOCOL_VERSION);
+        assert_eq!(json["hello"]["transports"], serde_json::json!(["websocket", "ble"]));
+        assert_eq!(json["hello"]["wifi_backend"], "dbus");
+        assert_eq!(json["hello"]["commands"].as_array().unwrap().len(), Command::NAMES.len());
+    }
+
+    /// JSON Schema of every message: requests, responses and events.
+    fn schema() -> schemars::schema::RootSchema {
+        use schemars::schema::{Metadata, SchemaObject, SubschemaValidation};
+
+        let mut gen = schemars::gen::SchemaSettings::draft07().into_generator();
+        let messages = vec![
+            gen.subschema_for::<Request>(),
+            gen.subschema_for::<Response>(),
+            gen.subschema_for::<Event>(),
+        ];
+        schemars::schema::RootSchema {
+            meta_schema: gen.settings().meta_schema.clone(),
+            schema: SchemaObject {
+                metadata: Some(Box::new(Metadata {
+                    title: Some("wifi-provisioner WebSocket protocol".into()),
+                    description: Some(format!(
+                        "Protocol version {}. A message is a Request, a Response or an Event.",
+                        PROTOCOL_VERSION
+                    )),
+                    ..Default::default()
+                })),
+                subschemas: Some(Box::new(SubschemaValidation {
+                    any_of: Some(messages),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            },
+            definitions: gen.take_definitions(),
+        }
+    }
+
+    /// `schema/protocol.json` is generated from these types; run the tests
+    /// with `UPDATE_SCHEMA=1` to regenerate it after changing them.
+    #[test]
+    fn schema_is_up_to_date() {
+        let schema = serde_json::to_string_pretty(&schema()).unwrap() + "\n";
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("schema/protocol.json");
+        if std::env::var_os("UPDATE_SCHEMA").is_some() {
+            std::fs::write(&path, &schema).unwrap();
+        }
+        let published = std::fs::read_to_string(&path).unwrap_or_default();
+        assert!(
+            published == schema,
+            "{} is out of date; rerun the tests with UPDATE_SCHEMA=1",
+            path.display()
+        );
+    }
+
+    #[test]
+    fn responses_echo_ids() {
+        let resp = Response::Ok(OkResponse::new(State::Idle)).with_id(Some(RequestId::Number(7)));
+        let json = serde_json::to_string(&resp).unwrap();
+        assert_eq!(json, r#"{"id":7,"ok":true,"state":"idle"}"#);
+
+        let resp = Response::Error(ErrorResponse::new("Cancelled"))
+            .with_id(Some(RequestId::Text("a1".into())));
+        let json = serde_json::to_string(&resp).unwrap();
+        assert_eq!(json, r#"{"id":"a1","ok":false,"error":"Cancelled"}"#);
+    }
+
+    #[test]
+    fn serialize_events() {
+        let json = serde_json::to_string(&Event::State {
+            state: State::Advertising,
+        })
+        .unwrap();
+        assert_eq!(json, r#"{"event":"state","state":"advertising"}"#);
+
+        let json = serde_json::to_string(&Event::Countdown { remaining: 42 }).unwrap();
+        assert_eq!(json, r#"{"event":"countdown","remaining":42}"#);
+
+        let event = Event::provisioning_started(&Ssid::from("home"));
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(json, r#"{"event":"provisioning_started","ssid":"home"}"#);
+
+        let failure = ConnectivityFailure::new(
+            crate::connectivity::ConnectivityStep::Gateway,
+            "no default route",
+        );
+        let json = serde_json::to_value(Event::ProvisioningFailed(failure.clone())).unwrap();
+        assert_eq!(json["event"], "provisioning_failed");
+        assert_eq!(json["step"], "gateway");
+        assert_eq!(
+            serde_json::from_value::<Event>(json).unwrap(),
+            Event::ProvisioningFailed(failure)
+        );
     }
 
     #[test]
@@ -241,5 +1130,9 @@ mod tests {
             serde_json::to_string(&State::Provisioning).unwrap(),
             r#""provisioning""#
         );
+        assert_eq!(
+            serde_json::to_string(&State::Provisioned).unwrap(),
+            r#""provisioned""#
+        );
     }
 }
@@ -0,0 +1,189 @@
+//! Wireless regulatory domain (country code).
+//!
+//! Until a country is set, the kernel uses the "world" domain, which leaves
+//! out most 5 GHz channels, so networks on them never show up in scans. The
+//! chosen country is kept under `/data/config` and reapplied at startup.
+
+use std::fmt;
+use std::path::Path;
+use std::process::Stdio;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::process::Command;
+use tracing::{debug, error, warn};
+
+use crate::wifi::{WifiError, WifiResult};
+
+/// File the country code is persisted in.
+pub const COUNTRY_FILE: &str = "/data/config/wifi-country";
+
+/// An ISO 3166-1 alpha-2 country code, in uppercase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CountryCode([u8; 2]);
+
+impl CountryCode {
+    pub fn as_str(&self) -> &str {
+        // Only ASCII letters are ever stored.
+        std::str::from_utf8(&self.0).unwrap_or_default()
+    }
+}
+
+impl FromStr for CountryCode {
+    type Err = WifiError;
+
+    /// Parse two ASCII letters in either case. The kernel's "00" (world)
+    /// is not a country and is rejected.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.trim().as_bytes() {
+            &[a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => {
+                Ok(Self([a.to_ascii_uppercase(), b.to_ascii_uppercase()]))
+            }
+            _ => Err(WifiError::Invalid(format!(
+                "Invalid country code {:?}",
+                text
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for CountryCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for CountryCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for CountryCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+impl schemars::JsonSchema for CountryCode {
+    fn schema_name() -> String {
+        "CountryCode".into()
+    }
+
+    fn json_schema(_: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        serde_json::from_value(serde_json::json!({
+            "description": "An ISO 3166-1 alpha-2 country code.",
+            "type": "string",
+            "pattern": "^[A-Za-z]{2}$",
+        }))
+        .unwrap()
+    }
+}
+
+/// Read the persisted country code, if any.
+pub fn load(path: &Path) -> Option<CountryCode> {
+    let text = std::fs::read_to_string(path).ok()?;
+    match text.parse() {
+        Ok(country) => Some(country),
+        Err(e) => {
+            warn!("Ignoring {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Persist the country code.
+pub fn store(path: &Path, country: CountryCode) -> WifiResult<()> {
+    let write = || {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, format!("{}\n", country))
+    };
+    write()
+        .map_err(|e| WifiError::CommandFailed(format!("Failed to save {}: {}", path.display(), e)))
+}
+
+/// Country of the global domain in `iw reg get` output.
+///
+/// The global section comes first; "00" means no country is set.
+pub fn parse_iw_reg(output: &str) -> Option<CountryCode> {
+    let line = output.lines().find(|line| line.starts_with("country "))?;
+    let code = line.strip_prefix("country ")?.split(':').next()?;
+    code.parse().ok()
+}
+
+/// Current country, as reported by `iw`.
+pub async fn get_with_iw() -> WifiResult<Option<CountryCode>> {
+    Ok(parse_iw_reg(&run_iw(&["reg", "get"]).await?))
+}
+
+/// Set the country with `iw` and persist it.
+pub async fn set_with_iw(country: CountryCode) -> WifiResult<()> {
+    run_iw(&["reg", "set", country.as_str()]).await?;
+    store(Path::new(COUNTRY_FILE), country)
+}
+
+/// Run `iw`, returning its output.
+pub async fn run_iw(args: &[&str]) -> WifiResult<String> {
+    debug!("Running: iw {}", args.join(" "));
+
+    let output = Command::new("iw")
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(|e| WifiError::CommandFailed(format!("Failed to execute iw: {}", e)))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        error!("iw failed: {}", stderr);
+        return Err(WifiError::CommandFailed(stderr.into_owned()));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_country_codes() {
+        assert_eq!("us".parse::<CountryCode>().unwrap().as_str(), "US");
+        assert_eq!(" DE\n".parse::<CountryCode>().unwrap().to_string(), "DE");
+        assert!("00".parse::<CountryCode>().is_err());
+        assert!("USA".parse::<CountryCode>().is_err());
+        assert!("".parse::<CountryCode>().is_err());
+        assert!("é".parse::<CountryCode>().is_err());
+    }
+
+    #[test]
+    fn parses_iw_reg_output() {
+        let output = "global\ncountry GB: DFS-ETSI\n\t(2400 - 2483 @ 40), (N/A, 20), (N/A)\n\n\
+            phy#0\ncountry 99: DFS-UNSET\n";
+        assert_eq!(parse_iw_reg(output), Some("GB".parse().unwrap()));
+
+        let unset = "global\ncountry 00: DFS-UNSET\n\t(2402 - 2472 @ 40), (N/A, 20), (N/A)\n";
+        assert_eq!(parse_iw_reg(unset), None);
+        assert_eq!(parse_iw_reg(""), None);
+    }
+
+    #[test]
+    fn stores_and_loads_the_country() {
+        let dir = std::env::temp_dir().join(format!("regdomain-test-{}", std::process::id()));
+        let path = dir.join("config/wifi-country");
+
+        assert_eq!(load(&path), None);
+        store(&path, "NZ".parse().unwrap()).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "NZ\n");
+        assert_eq!(load(&path), Some("NZ".parse().unwrap()));
+
+        std::fs::write(&path, "bogus").unwrap();
+        assert_eq!(load(&path), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
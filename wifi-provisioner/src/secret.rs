@@ -0,0 +1,351 @@
+//! Passwords and other credentials.
+//!
+//! A `Secret` wipes its bytes when dropped. While alive it is also known to
+//! the log redactor, so `RedactingWriter` masks it in every tracing line,
+//! whatever the level. The redactor only keeps each secret's length and a
+//! keyed hash, never a copy of it.
+
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::io;
+use std::sync::atomic::{compiler_fence, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use serde::{Deserialize, Deserializer};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Replacement for secrets in log output.
+pub const REDACTED: &str = "[redacted]";
+
+/// Secrets this long are masked wherever they appear; shorter ones only as
+/// whole tokens.
+const MIN_ANYWHERE_LEN: usize = 8;
+
+/// Fingerprints of the secrets currently alive, as is and as `Debug`
+/// escapes them.
+static REGISTRY: Mutex<Vec<Fingerprint>> = Mutex::new(Vec::new());
+
+fn registry() -> MutexGuard<'static, Vec<Fingerprint>> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Length and keyed hash of a secret, enough to recognize it in a log line.
+#[derive(PartialEq, Eq)]
+struct Fingerprint {
+    len: usize,
+    hash: u64,
+}
+
+impl Fingerprint {
+    fn of(text: &str) -> Self {
+        // Keyed per process, so the hashes can't be looked up offline.
+        static KEY: OnceLock<RandomState> = OnceLock::new();
+        Self {
+            len: text.len(),
+            hash: KEY.get_or_init(RandomState::new).hash_one(text),
+        }
+    }
+
+    /// Fingerprints of `secret` and, if it differs, its `Debug` escape.
+    fn forms(secret: &str) -> Vec<Self> {
+        let mut forms = vec![Self::of(secret)];
+        let mut escaped = secret.escape_debug().to_string();
+        if escaped != secret {
+            forms.push(Self::of(&escaped));
+        }
+        zeroize_string(&mut escaped);
+        forms
+    }
+}
+
+/// Overwrite bytes with zeros in a way the compiler cannot optimize out.
+pub fn zeroize(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        // SAFETY: `byte` is a valid, exclusive reference to a `u8`.
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Zero a string's bytes and empty it.
+pub fn zeroize_string(text: &mut String) {
+    // SAFETY: zero bytes are valid UTF-8.
+    zeroize(unsafe { text.as_bytes_mut() });
+    text.clear();
+}
+
+/// A credential that is wiped on drop and redacted from logs.
+#[derive(Default, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(secret: impl Into<String>) -> Self {
+        let secret = secret.into();
+        if !secret.is_empty() {
+            registry().extend(Fingerprint::forms(&secret));
+        }
+        Self(secret)
+    }
+
+    /// A secret from raw bytes, with invalid UTF-8 replaced by U+FFFD.
+    ///
+    /// Built in place, so no unwiped copy of the bytes is left behind.
+    pub fn from_utf8_lossy(bytes: &[u8]) -> Self {
+        // Each invalid sequence is replaced by three bytes at most; reserving
+        // for that up front means the string is never reallocated.
+        let mut secret = String::with_capacity(bytes.len() * 3);
+        for chunk in bytes.utf8_chunks() {
+            secret.push_str(chunk.valid());
+            if !chunk.invalid().is_empty() {
+                secret.push(char::REPLACEMENT_CHARACTER);
+            }
+        }
+        Self::new(secret)
+    }
+
+    /// The secret itself, for handing to a backend.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Clone for Secret {
+    fn clone(&self) -> Self {
+        Self::new(self.0.clone())
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        if !self.0.is_empty() {
+            let mut registry = registry();
+            for form in Fingerprint::forms(&self.0) {
+                if let Some(i) = registry.iter().position(|f| *f == form) {
+                    registry.swap_remove(i);
+                }
+            }
+        }
+        zeroize_string(&mut self.0);
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(secret: &str) -> Self {
+        Self::new(secret)
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Self::new(secret)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::new)
+    }
+}
+
+#[cfg(test)]
+impl schemars::JsonSchema for Secret {
+    fn is_referenceable() -> bool {
+        false
+    }
+
+    fn schema_name() -> String {
+        "Secret".into()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        String::json_schema(gen)
+    }
+}
+
+/// Replace every live secret in `text`, as is and as `Debug` escapes it.
+///
+/// Secrets of `MIN_ANYWHERE_LEN` bytes or more are replaced wherever they
+/// appear. Shorter ones are only replaced as whole tokens, with no letter or
+/// digit right before or after them, so they don't mask parts of words.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let registry = registry();
+    if registry.is_empty() {
+        return Cow::Borrowed(text);
+    }
+
+    let mut redacted = String::new();
+    // Start of the text not yet copied to `redacted`.
+    let mut copied = 0;
+    let mut start = 0;
+    while start < text.len() {
+        let after_boundary = is_boundary(text[..start].chars().next_back());
+        let match_end = registry
+            .iter()
+            .filter_map(|f| {
+                let anywhere = f.len >= MIN_ANYWHERE_LEN;
+                if !anywhere && !after_boundary {
+                    return None;
+                }
+                let end = start + f.len;
+                let candidate = text.get(start..end)?;
+                let whole = anywhere || is_boundary(text[end..].chars().next());
+                (whole && Fingerprint::of(candidate) == *f).then_some(end)
+            })
+            .max();
+
+        match match_end {
+            Some(end) => {
+                redacted.push_str(&text[copied..start]);
+                redacted.push_str(REDACTED);
+                copied = end;
+                start = end;
+            }
+            None => start += text[start..].chars().next().map_or(1, char::len_utf8),
+        }
+    }
+
+    if copied == 0 {
+        return Cow::Borrowed(text);
+    }
+    redacted.push_str(&text[copied..]);
+    Cow::Owned(redacted)
+}
+
+/// Whether a short secret may end or start next to `c` (`None` is the
+/// text's end).
+fn is_boundary(c: Option<char>) -> bool {
+    c.is_none_or(|c| !c.is_alphanumeric())
+}
+
+/// `MakeWriter` that redacts secrets from log output.
+///
+/// The fmt subscriber writes each event in one call, so secrets are never
+/// split across writes.
+pub struct RedactingWriter<M>(M);
+
+impl<M> RedactingWriter<M> {
+    pub fn new(make_writer: M) -> Self {
+        Self(make_writer)
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for RedactingWriter<M> {
+    type Writer = Redacted<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Redacted(self.0.make_writer())
+    }
+}
+
+/// Writer produced by `RedactingWriter`.
+pub struct Redacted<W>(W);
+
+impl<W: io::Write> io::Write for Redacted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.0.write_all(redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn zeroizes_strings() {
+        let mut text = String::from("hunter22");
+        zeroize_string(&mut text);
+        assert!(text.is_empty());
+    }
+
+    #[test]
+    fn debug_never_shows_the_secret() {
+        let secret = Secret::new("debug-secret-1");
+        assert_eq!(format!("{:?}", secret), REDACTED);
+        assert_eq!(secret.expose(), "debug-secret-1");
+    }
+
+    #[test]
+    fn builds_from_bytes() {
+        assert_eq!(
+            Secret::from_utf8_lossy(b"bytes-secret-1").expose(),
+            "bytes-secret-1"
+        );
+        assert_eq!(
+            Secret::from_utf8_lossy(b"caf\xe9-secret").expose(),
+            "caf\u{fffd}-secret"
+        );
+    }
+
+    #[test]
+    fn redacts_live_secrets_only() {
+        let secret = Secret::new("pa\"ss-redact-1");
+        let copy = secret.clone();
+
+        assert_eq!(redact("psk=pa\"ss-redact-1"), format!("psk={}", REDACTED));
+        assert_eq!(redact("psk=pa\\\"ss-redact-1"), format!("psk={}", REDACTED));
+
+        drop(secret);
+        assert_eq!(redact("pa\"ss-redact-1"), REDACTED);
+        drop(copy);
+        assert_eq!(redact("pa\"ss-redact-1"), "pa\"ss-redact-1");
+    }
+
+    #[test]
+    fn redacts_long_secrets_anywhere() {
+        let _secret = Secret::new("token-secret-1");
+
+        assert_eq!(
+            redact("psk=\"token-secret-1\", again token-secret-1"),
+            format!("psk=\"{}\", again {}", REDACTED, REDACTED)
+        );
+        assert_eq!(redact("xtoken-secret-1"), format!("x{}", REDACTED));
+        assert_eq!(redact("token-secret-12"), format!("{}2", REDACTED));
+    }
+
+    #[test]
+    fn redacts_short_secrets_as_whole_tokens_only() {
+        let _secret = Secret::new("pin4");
+
+        assert_eq!(redact("pin=pin4."), format!("pin={}.", REDACTED));
+        assert_eq!(redact("spin4"), "spin4");
+        assert_eq!(redact("pin42"), "pin42");
+    }
+
+    #[test]
+    fn writer_redacts_log_lines() {
+        let _secret = Secret::new("writer-secret-1");
+        let mut out = Vec::new();
+
+        Redacted(&mut out)
+            .write_all(b"INFO connecting with writer-secret-1\n")
+            .unwrap();
+
+        assert_eq!(out, format!("INFO connecting with {}\n", REDACTED).as_bytes());
+    }
+
+    #[test]
+    fn deserializes_from_a_string() {
+        let secret: Secret = serde_json::from_str("\"json-secret-1\"").unwrap();
+        assert_eq!(secret.expose(), "json-secret-1");
+        assert_eq!(redact("json-secret-1"), REDACTED);
+    }
+}
@@ -0,0 +1,657 @@
+//! Improv Serial transport.
+//!
+//! Implements the Improv WiFi serial protocol over a tty (e.g. a USB gadget
+//! console), so browser flows like ESP Web Tools can provision the device
+//! without Bluetooth. RPC handling is shared with the BLE transport.
+//!
+//! See https://www.improv-wifi.com/serial/ for the protocol specification.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, RwLock};
+use tracing::{debug, error, info, warn};
+
+use crate::ble::{BleConfig, BleEvent};
+use crate::improv::{
+    build_raw_response, calculate_checksum, ImprovError, ImprovState, RpcError, RpcRequest,
+};
+use crate::secret;
+use crate::session::{ImprovSession, SessionOutput};
+use crate::state_machine::StateMachine;
+use crate::wifi::WifiManager;
+
+/// Frame header that starts every Improv Serial packet.
+pub const HEADER: &[u8; 6] = b"IMPROV";
+
+/// Improv Serial protocol version.
+pub const VERSION: u8 = 0x01;
+
+/// Serial-only RPC command: request current state (and redirect URL).
+///
+/// Shares its ID with the BLE-only Identify command, so it is handled here
+/// before the request reaches the shared RPC handling.
+pub const GET_CURRENT_STATE: u8 = 0x02;
+
+/// Default baud rate (what ESP Web Tools uses).
+pub const DEFAULT_BAUD_RATE: u32 = 115200;
+
+/// Header + version + type + length.
+const FRAME_PREFIX_LEN: usize = HEADER.len() + 3;
+
+/// Improv Serial packet types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PacketType {
+    /// Current state (device to client).
+    CurrentState = 0x01,
+    /// Error state (device to client).
+    ErrorState = 0x02,
+    /// RPC command (client to device).
+    Rpc = 0x03,
+    /// RPC result (device to client).
+    RpcResult = 0x04,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(PacketType::CurrentState),
+            0x02 => Ok(PacketType::ErrorState),
+            0x03 => Ok(PacketType::Rpc),
+            0x04 => Ok(PacketType::RpcResult),
+            _ => Err(value),
+        }
+    }
+}
+
+/// A decoded Improv Serial packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub packet_type: PacketType,
+    pub data: Vec<u8>,
+}
+
+/// Build an Improv Serial frame.
+///
+/// Frame format:
+/// - Bytes 0-5: "IMPROV"
+/// - Byte 6: Version
+/// - Byte 7: Packet type
+/// - Byte 8: Data length
+/// - Bytes 9..9+len: Data
+/// - Final byte: Checksum (sum of all preceding bytes, LSB only)
+pub fn build_packet(packet_type: PacketType, data: &[u8]) -> Result<Vec<u8>, RpcError> {
+    if data.len() > u8::MAX as usize {
+        return Err(RpcError::DataTooLong(data.len()));
+    }
+
+    let mut frame = Vec::with_capacity(FRAME_PREFIX_LEN + data.len() + 1);
+    frame.extend_from_slice(HEADER);
+    frame.push(VERSION);
+    frame.push(packet_type as u8);
+    frame.push(data.len() as u8);
+    frame.extend_from_slice(data);
+    frame.push(calculate_checksum(&frame));
+
+    Ok(frame)
+}
+
+/// Build the frame for a session output.
+///
+/// Returns `None` for application events, which are not sent to the client.
+pub fn build_output_packet(output: &SessionOutput) -> Option<Result<Vec<u8>, RpcError>> {
+    let frame = match output {
+        SessionOutput::State(state) => build_packet(PacketType::CurrentState, &[(*state).into()]),
+        SessionOutput::Error(error) => build_packet(PacketType::ErrorState, &[(*error).into()]),
+        // Serial RPC results carry no inner checksum.
+        SessionOutput::RpcResult(packet) => {
+            let body = packet.split_last().map_or(&[][..], |(_, body)| body);
+            build_packet(PacketType::RpcResult, body)
+        }
+        SessionOutput::Event(_) => return None,
+    };
+    Some(frame)
+}
+
+/// Incremental decoder for Improv Serial frames.
+///
+/// Bytes that are not part of a valid frame (console noise, bad checksums)
+/// are skipped.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add received bytes and return every complete packet.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Packet> {
+        self.buf.extend_from_slice(bytes);
+        let mut packets = Vec::new();
+
+        loop {
+            // Drop everything before the next header.
+            match self.buf.windows(HEADER.len()).position(|w| w == HEADER) {
+                Some(start) => {
+                    self.buf.drain(..start);
+                }
+                None => {
+                    // Keep a tail that could be the start of a header.
+                    let keep = self.buf.len().min(HEADER.len() - 1);
+                    self.buf.drain(..self.buf.len() - keep);
+                    break;
+                }
+            }
+
+            if self.buf.len() < FRAME_PREFIX_LEN {
+                break;
+            }
+
+            let data_len = self.buf[FRAME_PREFIX_LEN - 1] as usize;
+            let frame_len = FRAME_PREFIX_LEN + data_len + 1;
+            if self.buf.len() < frame_len {
+                break;
+            }
+
+            let checksum = calculate_checksum(&self.buf[..frame_len - 1]);
+            let version = self.buf[HEADER.len()];
+            let packet_type = PacketType::try_from(self.buf[HEADER.len() + 1]);
+
+            match packet_type {
+                Ok(packet_type) if checksum == self.buf[frame_len - 1] && version == VERSION => {
+                    let data = self.buf[FRAME_PREFIX_LEN..frame_len - 1].to_vec();
+                    self.buf.drain(..frame_len);
+                    packets.push(Packet { packet_type, data });
+                }
+                _ => {
+                    // Not a valid frame; resync after this header byte.
+                    debug!("Skipping invalid Improv Serial frame");
+                    self.buf.drain(..1);
+                }
+            }
+        }
+
+        packets
+    }
+}
+
+/// Serial transport configuration.
+#[derive(Debug, Clone)]
+pub struct SerialConfig {
+    /// tty to serve (e.g., "/dev/ttyGS0").
+    pub path: PathBuf,
+    /// Baud rate.
+    pub baud_rate: u32,
+}
+
+/// An open, raw-mode serial port.
+pub struct SerialPort {
+    file: File,
+}
+
+impl SerialPort {
+    /// Open a tty and put it in raw mode at the configured baud rate.
+    pub fn open(config: &SerialConfig) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&config.path)?;
+
+        configure_raw(&file, config.baud_rate)?;
+        info!(
+            "Improv Serial on {} at {} baud",
+            config.path.display(),
+            config.baud_rate
+        );
+
+        Ok(Self { file })
+    }
+}
+
+/// Map a baud rate to its termios speed constant.
+fn baud_constant(baud_rate: u32) -> io::Result<libc::speed_t> {
+    let speed = match baud_rate {
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        460800 => libc::B460800,
+        921600 => libc::B921600,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unsupported baud rate: {}", baud_rate),
+            ))
+        }
+    };
+    Ok(speed)
+}
+
+/// Put a tty in raw mode (no echo, no line editing, no output processing).
+fn configure_raw(file: &File, baud_rate: u32) -> io::Result<()> {
+    let speed = baud_constant(baud_rate)?;
+    let fd = file.as_raw_fd();
+
+    // SAFETY: `fd` is a valid open descriptor for the lifetime of `file`,
+    // and `termios` is fully initialized by tcgetattr before use.
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        if libc::cfsetspeed(&mut termios, speed) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Serve Improv Serial on an open port.
+///
+/// Runs until the port is closed. The serial client is treated as
+/// authorized, since it needs physical access to the device.
+pub async fn run_serial<W: WifiManager + 'static>(
+    port: SerialPort,
+    config: Arc<RwLock<BleConfig>>,
+    wifi: Arc<W>,
+    state: Arc<RwLock<StateMachine>>,
+    event_tx: mpsc::Sender<BleEvent>,
+) -> io::Result<()> {
+    let mut reader = port.file.try_clone()?;
+    let mut writer = port.file;
+
+    // tty reads and writes block, so they run on their own threads.
+    let (in_tx, mut in_rx) = mpsc::channel::<Vec<u8>>(16);
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 256];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => {
+                    if in_tx.blocking_send(buf[..n].to_vec()).is_err() {
+                        return;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Serial read failed: {}", e);
+                    return;
+                }
+            }
+        }
+    });
+
+    let (frame_tx, mut frame_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    std::thread::spawn(move || {
+        while let Some(frame) = frame_rx.blocking_recv() {
+            if let Err(e) = writer.write_all(&frame).and_then(|_| writer.flush()) {
+                error!("Serial write failed: {}", e);
+                return;
+            }
+        }
+    });
+
+    // The serial client needs physical access, so it is always authorized.
+    let (session, mut outputs) = ImprovSession::new(Arc::clone(&config), wifi, state, false);
+    // Frames carry a one-byte length, so longer results must be split.
+    session.set_max_packet_len(u8::MAX as usize);
+    let session = Arc::new(session);
+    session.watch_machine();
+
+    tokio::spawn(async move {
+        while let Some(output) = outputs.recv().await {
+            match build_output_packet(&output) {
+                Some(Ok(frame)) => {
+                    let _ = frame_tx.send(frame);
+                }
+                Some(Err(e)) => warn!("Dropping serial output {:?}: {}", output, e),
+                None => {
+                    if let SessionOutput::Event(event) = output {
+                        let _ = event_tx.send(event).await;
+                    }
+                }
+            }
+        }
+    });
+
+    let mut decoder = FrameDecoder::new();
+    while let Some(mut bytes) = in_rx.recv().await {
+        for mut packet in decoder.push(&bytes) {
+            if packet.packet_type != PacketType::Rpc {
+                debug!("Ignoring serial packet type {:?}", packet.packet_type);
+                continue;
+            }
+
+            handle_serial_rpc(&packet.data, &session, &config).await;
+            // RPC data may carry credentials.
+            secret::zeroize(&mut packet.data);
+        }
+        secret::zeroize(&mut bytes);
+    }
+
+    info!("Serial port closed");
+    Ok(())
+}
+
+/// Handle the data of one serial RPC packet.
+async fn handle_serial_rpc<W: WifiManager>(
+    data: &[u8],
+    session: &ImprovSession<W>,
+    config: &RwLock<BleConfig>,
+) {
+    debug!("Received serial RPC packet ({} bytes)", data.len());
+
+    // Clear any previous error so a repeated failure is reported again.
+    session.set_error_state(ImprovError::None).await;
+
+    if data.first() == Some(&GET_CURRENT_STATE) {
+        session.report_state().await;
+
+        if session.improv_state().await == ImprovState::Provisioned {
+            let redirect_url = config.read().await.redirect_url();
+            session
+                .send_rpc_result(build_raw_response(GET_CURRENT_STATE, &[&redirect_url]))
+                .await;
+        }
+        return;
+    }
+
+    match RpcRequest::parse_body(data) {
+        Ok(request) => session.handle_request(request).await,
+        Err(e) => {
+            error!("Failed to parse serial RPC command: {}", e);
+            let error = match e {
+                RpcError::UnknownCommand(_) => ImprovError::UnknownCommand,
+                _ => ImprovError::InvalidRpc,
+            };
+            session.set_error_state(error).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::improv::RpcCommand;
+    use crate::protocol::Network;
+    use crate::session::SessionHarness;
+    use crate::wifi::MockWifiManager;
+    use std::ffi::CStr;
+    use std::os::fd::FromRawFd;
+    use std::time::Duration;
+
+    #[test]
+    fn build_packet_layout() {
+        let frame = build_packet(PacketType::CurrentState, &[0x02]).unwrap();
+
+        assert_eq!(&frame[..6], b"IMPROV");
+        assert_eq!(frame[6], VERSION);
+        assert_eq!(frame[7], PacketType::CurrentState as u8);
+        assert_eq!(frame[8], 1);
+        assert_eq!(frame[9], 0x02);
+        assert_eq!(frame[10], calculate_checksum(&frame[..10]));
+    }
+
+    #[test]
+    fn build_packet_rejects_long_data() {
+        assert_eq!(
+            build_packet(PacketType::RpcResult, &[0u8; 256]),
+            Err(RpcError::DataTooLong(256))
+        );
+    }
+
+    #[test]
+    fn rpc_result_output_strips_inner_checksum() {
+        let frame = build_output_packet(&SessionOutput::RpcResult(vec![0x03, 0x00, 0x03]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame[8], 2);
+        assert_eq!(&frame[9..11], &[0x03, 0x00]);
+
+        assert!(build_output_packet(&SessionOutput::Event(BleEvent::Identify)).is_none());
+    }
+
+    #[test]
+    fn decoder_round_trip() {
+        let frame = build_packet(PacketType::Rpc, &[0x03, 0x00]).unwrap();
+        let mut decoder = FrameDecoder::new();
+
+        let packets = decoder.push(&frame);
+        assert_eq!(
+            packets,
+            vec![Packet {
+                packet_type: PacketType::Rpc,
+                data: vec![0x03, 0x00],
+            }]
+        );
+    }
+
+    #[test]
+    fn decoder_handles_split_frames_and_noise() {
+        let frame = build_packet(PacketType::Rpc, &[0x04, 0x00]).unwrap();
+        let mut input = b"login: \r\nIMPRO".to_vec();
+        input.extend_from_slice(&frame);
+
+        let mut decoder = FrameDecoder::new();
+        let (first, second) = input.split_at(10);
+        assert!(decoder.push(first).is_empty());
+
+        let packets = decoder.push(second);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].data, vec![0x04, 0x00]);
+    }
+
+    #[test]
+    fn decoder_skips_bad_checksum() {
+        let mut bad = build_packet(PacketType::Rpc, &[0x03, 0x00]).unwrap();
+        *bad.last_mut().unwrap() ^= 0xFF;
+        let good = build_packet(PacketType::Rpc, &[0x04, 0x00]).unwrap();
+
+        let mut decoder = FrameDecoder::new();
+        let mut input = bad;
+        input.extend_from_slice(&good);
+
+        let packets = decoder.push(&input);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].data, vec![0x04, 0x00]);
+    }
+
+    #[test]
+    fn rejects_unsupported_baud_rate() {
+        assert!(baud_constant(115200).is_ok());
+        assert!(baud_constant(12345).is_err());
+    }
+
+    /// Open a pseudo-terminal, returning the master and the slave path.
+    fn open_pty() -> (File, PathBuf) {
+        // SAFETY: standard posix_openpt sequence; the returned fd is owned
+        // by the File, and ptsname_r writes a NUL-terminated path into `name`.
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            assert!(fd >= 0, "posix_openpt failed");
+            assert_eq!(libc::grantpt(fd), 0);
+            assert_eq!(libc::unlockpt(fd), 0);
+
+            let mut name = [0 as libc::c_char; 128];
+            assert_eq!(libc::ptsname_r(fd, name.as_mut_ptr(), name.len()), 0);
+            let path = CStr::from_ptr(name.as_ptr()).to_str().unwrap().into();
+
+            (File::from_raw_fd(fd), path)
+        }
+    }
+
+    /// The host end of a pty served by `run_serial`.
+    struct PtyClient {
+        master: File,
+        rx: mpsc::Receiver<Vec<u8>>,
+        decoder: FrameDecoder,
+    }
+
+    impl PtyClient {
+        /// Serve Improv over a new pty and connect to it.
+        fn start(
+            wifi: MockWifiManager,
+            config: BleConfig,
+            event_tx: mpsc::Sender<BleEvent>,
+        ) -> Self {
+            let (master, slave_path) = open_pty();
+            let port = SerialPort::open(&SerialConfig {
+                path: slave_path,
+                baud_rate: DEFAULT_BAUD_RATE,
+            })
+            .unwrap();
+            let config = Arc::new(RwLock::new(config));
+            let state = Arc::new(RwLock::new(StateMachine::default()));
+            tokio::spawn(run_serial(port, config, Arc::new(wifi), state, event_tx));
+
+            // The master is a blocking fd, so read it on a thread.
+            let mut reader = master.try_clone().unwrap();
+            let (tx, rx) = mpsc::channel(16);
+            std::thread::spawn(move || {
+                let mut buf = [0u8; 256];
+                while let Ok(n) = reader.read(&mut buf) {
+                    if n == 0 || tx.blocking_send(buf[..n].to_vec()).is_err() {
+                        return;
+                    }
+                }
+            });
+
+            Self {
+                master,
+                rx,
+                decoder: FrameDecoder::new(),
+            }
+        }
+
+        /// Send an RPC frame for `command` with its data.
+        fn send_rpc(&mut self, command: u8, data: &[u8]) {
+            let mut rpc = vec![command, data.len() as u8];
+            rpc.extend_from_slice(data);
+            self.master
+                .write_all(&build_packet(PacketType::Rpc, &rpc).unwrap())
+                .unwrap();
+        }
+
+        /// Read frames until `pred` matches one.
+        async fn read_until(&mut self, pred: impl Fn(&Packet) -> bool) -> Vec<Packet> {
+            let mut seen = Vec::new();
+            loop {
+                let bytes = tokio::time::timeout(Duration::from_secs(5), self.rx.recv())
+                    .await
+                    .expect("Timeout waiting for serial response")
+                    .expect("pty closed");
+                for packet in self.decoder.push(&bytes) {
+                    let done = pred(&packet);
+                    seen.push(packet);
+                    if done {
+                        return seen;
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_improv_over_pty() {
+        let wifi = MockWifiManager {
+            networks: vec![Network {
+                ssid: "MyWiFi".into(),
+                signal: Some(-45),
+                security: "wpa2".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let config = BleConfig {
+            device_name: "dirtsim".into(),
+            redirect_url: "http://{hostname}.local:8081".into(),
+            ..Default::default()
+        };
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        let mut client = PtyClient::start(wifi, config, event_tx);
+
+        // Current state.
+        client.send_rpc(GET_CURRENT_STATE, &[]);
+        let packets = client
+            .read_until(|p| p.packet_type == PacketType::CurrentState)
+            .await;
+        assert_eq!(
+            packets.last().unwrap().data,
+            vec![ImprovState::Authorized as u8]
+        );
+
+        // Scan: one result per network, then an empty terminator.
+        client.send_rpc(RpcCommand::ScanWifiNetworks as u8, &[]);
+        let packets = client
+            .read_until(|p| p.packet_type == PacketType::RpcResult && p.data == [0x04, 0x00])
+            .await;
+        let results: Vec<_> = packets
+            .iter()
+            .filter(|p| p.packet_type == PacketType::RpcResult)
+            .collect();
+        assert_eq!(results.len(), 2);
+        assert_eq!(&results[0].data[3..9], b"MyWiFi");
+
+        // Provision.
+        client.send_rpc(
+            RpcCommand::SendWifiSettings as u8,
+            &SessionHarness::wifi_settings("home", "hunter22"),
+        );
+        let packets = client
+            .read_until(|p| p.packet_type == PacketType::RpcResult)
+            .await;
+
+        assert!(packets.contains(&Packet {
+            packet_type: PacketType::CurrentState,
+            data: vec![ImprovState::Provisioned as u8],
+        }));
+        let result = packets.last().unwrap();
+        assert_eq!(result.data[0], RpcCommand::SendWifiSettings as u8);
+        assert_eq!(&result.data[3..], b"http://dirtsim.local:8081");
+
+        assert_eq!(
+            event_rx.recv().await,
+            Some(BleEvent::ProvisioningStarted("home".into()))
+        );
+        match event_rx.recv().await {
+            Some(BleEvent::ProvisioningComplete(url)) => {
+                assert_eq!(url, "http://dirtsim.local:8081");
+            }
+            other => panic!("Expected ProvisioningComplete, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_unknown_command_over_pty() {
+        let (event_tx, _event_rx) = mpsc::channel(16);
+        let mut client =
+            PtyClient::start(MockWifiManager::default(), BleConfig::default(), event_tx);
+
+        client.send_rpc(0x7F, &[]);
+        let packets = client
+            .read_until(|p| p.packet_type == PacketType::ErrorState)
+            .await;
+        assert_eq!(
+            packets.last().unwrap().data,
+            vec![ImprovError::UnknownCommand as u8]
+        );
+    }
+}
@@ -0,0 +1,1623 @@
+//! Transport-agnostic Improv session.
+//!
+//! `ImprovSession` handles Improv RPCs for one transport. A transport feeds
+//! it raw RPC bytes and forwards the `SessionOutput`s it emits: BLE turns
+//! them into GATT notifications, serial into frames.
+//!
+//! The Improv state and error state live in the daemon's `StateMachine`;
+//! the session only adds its authorization window. It forwards every change
+//! to them to its transport, whichever client or transport made it.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, RwLock};
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
+
+use crate::ble::{BleConfig, BleEvent};
+use crate::connectivity::VERIFY_TIMEOUT;
+use crate::hostname::{HostnameControl, HostnameError, SystemHostname};
+use crate::improv::{
+    build_country_response, build_device_info_response, build_hostname_response,
+    build_provision_response, build_response, build_scan_responses, ImprovError, ImprovState,
+    RpcCommand, RpcError, RpcRequest, UNMEASURED_RSSI,
+};
+use crate::ipconfig::IpConfig;
+use crate::protocol::State;
+use crate::ssid::Ssid;
+use crate::state_machine::{Input, InvalidTransition, StateMachine};
+use crate::wifi::{self, ConnectRequest, Credentials, WifiManager};
+
+/// Largest CA certificate accepted over CaCertificate RPCs.
+pub const MAX_CA_CERT_LEN: usize = 16 * 1024;
+
+/// Something the session wants its transport to deliver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionOutput {
+    /// Current state changed.
+    State(ImprovState),
+    /// Error state changed.
+    Error(ImprovError),
+    /// RPC result packet (Improv BLE format, including checksum).
+    RpcResult(Vec<u8>),
+    /// Event for the main application.
+    Event(BleEvent),
+}
+
+/// Mutable session state.
+///
+/// Kept behind a std mutex that is never held across an await, so transports
+/// can read it while a long RPC (e.g., connecting) runs.
+struct SessionState {
+    /// Latest RPC result, for transports that let clients read it back.
+    rpc_result: Vec<u8>,
+    /// End of the current authorization window (authorization mode only).
+    authorized_until: Option<Instant>,
+    /// Largest RPC result packet the transport can deliver.
+    max_packet_len: usize,
+    /// How long a new connection has to come fully online.
+    verify_timeout: Duration,
+    /// CA certificate uploaded for the next SendEnterpriseSettings.
+    pending_ca_cert: Vec<u8>,
+    /// IP settings for the next network, from an IpSettings RPC.
+    pending_ip_config: IpConfig,
+    /// State machine version the transport was last told about.
+    notified_version: u64,
+    /// Improv state the transport was last told about.
+    notified_state: ImprovState,
+    /// Error state the transport was last told about.
+    notified_error: ImprovError,
+}
+
+/// Improv RPC handling for one transport.
+pub struct ImprovSession<W: WifiManager, H: HostnameControl = SystemHostname> {
+    config: Arc<RwLock<BleConfig>>,
+    wifi: Arc<W>,
+    hostname: Arc<H>,
+    /// Daemon state, shared with the other transports and the WebSocket.
+    machine: Arc<RwLock<StateMachine>>,
+    require_authorization: bool,
+    state: Mutex<SessionState>,
+    output_tx: mpsc::UnboundedSender<SessionOutput>,
+}
+
+impl<W: WifiManager> ImprovSession<W> {
+    /// Create a session, starting unauthorized if authorization is required.
+    ///
+    /// Returns the session and the receiver for its outputs.
+    pub fn new(
+        config: Arc<RwLock<BleConfig>>,
+        wifi: Arc<W>,
+        machine: Arc<RwLock<StateMachine>>,
+        require_authorization: bool,
+    ) -> (Self, mpsc::UnboundedReceiver<SessionOutput>) {
+        Self::with_hostname(
+            config,
+            wifi,
+            Arc::new(SystemHostname),
+            machine,
+            require_authorization,
+        )
+    }
+}
+
+impl<W: WifiManager, H: HostnameControl> ImprovSession<W, H> {
+    /// Create a session that reads and sets the hostname through `hostname`.
+    pub fn with_hostname(
+        config: Arc<RwLock<BleConfig>>,
+        wifi: Arc<W>,
+        hostname: Arc<H>,
+        machine: Arc<RwLock<StateMachine>>,
+        require_authorization: bool,
+    ) -> (Self, mpsc::UnboundedReceiver<SessionOutput>) {
+        let (output_tx, output_rx) = mpsc::unbounded_channel();
+        let session = Self {
+            config,
+            wifi,
+            hostname,
+            machine,
+            require_authorization,
+            state: Mutex::new(SessionState {
+                rpc_result: Vec::new(),
+                authorized_until: None,
+                max_packet_len: usize::MAX,
+                verify_timeout: VERIFY_TIMEOUT,
+                pending_ca_cert: Vec::new(),
+                pending_ip_config: IpConfig::default(),
+                notified_version: 0,
+                notified_state: if require_authorization {
+                    ImprovState::AuthorizationRequired
+                } else {
+                    ImprovState::Authorized
+                },
+                notified_error: ImprovError::None,
+            }),
+            output_tx,
+        };
+        (session, output_rx)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SessionState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn emit(&self, output: SessionOutput) {
+        let _ = self.output_tx.send(output);
+    }
+
+    /// Current Improv state.
+    pub async fn improv_state(&self) -> ImprovState {
+        let state = self.machine.read().await.state;
+        self.improv_state_in(state)
+    }
+
+    /// Current error state.
+    pub async fn error_state(&self) -> ImprovError {
+        self.machine.read().await.improv_error
+    }
+
+    /// Latest RPC result packet.
+    pub fn rpc_result(&self) -> Vec<u8> {
+        self.lock().rpc_result.clone()
+    }
+
+    /// Limit RPC result packets to what the transport can deliver at once.
+    pub fn set_max_packet_len(&self, max_packet_len: usize) {
+        self.lock().max_packet_len = max_packet_len;
+    }
+
+    /// Limit how long a new connection has to pass connectivity verification.
+    pub fn set_verify_timeout(&self, verify_timeout: Duration) {
+        self.lock().verify_timeout = verify_timeout;
+    }
+
+    /// Submit `input` to the state machine.
+    ///
+    /// The transport hears of the change before this returns, rather than
+    /// whenever the machine watcher gets to it.
+    async fn submit(&self, input: Input) -> Result<(), InvalidTransition> {
+        let mut machine = self.machine.write().await;
+        machine.handle(input)?;
+        self.notify(machine.version(), machine.state, machine.improv_error);
+        Ok(())
+    }
+
+    /// Emit the Improv state and error state at machine `version`, where
+    /// they differ from what the transport was last told.
+    ///
+    /// The one place State and Error outputs come from. Versions older than
+    /// the last one notified are stale and ignored.
+    fn notify(&self, version: u64, state: State, error: ImprovError) {
+        let improv_state = self.improv_state_in(state);
+        let mut s = self.lock();
+        if version < s.notified_version {
+            return;
+        }
+        s.notified_version = version;
+
+        if improv_state != s.notified_state {
+            debug!("Improv state: {:?} -> {:?}", s.notified_state, improv_state);
+            s.notified_state = improv_state;
+            self.emit(SessionOutput::State(improv_state));
+        }
+        if error != s.notified_error {
+            debug!("Improv error: {:?} -> {:?}", s.notified_error, error);
+            s.notified_error = error;
+            self.emit(SessionOutput::Error(error));
+        }
+    }
+
+    /// Change the error state, emitting it if it changed.
+    pub async fn set_error_state(&self, error_state: ImprovError) {
+        // Valid in every state.
+        let _ = self.submit(Input::ImprovError(error_state)).await;
+    }
+
+    /// Emit the current state even if it has not changed.
+    pub async fn report_state(&self) {
+        let improv_state = self.improv_state().await;
+        self.emit(SessionOutput::State(improv_state));
+    }
+
+    /// Improv state while the daemon is in `state`.
+    fn improv_state_in(&self, state: State) -> ImprovState {
+        match state {
+            State::Provisioning => ImprovState::Provisioning,
+            State::Provisioned => ImprovState::Provisioned,
+            State::Idle | State::Advertising | State::Connected => self.ready_state(),
+        }
+    }
+
+    /// State to return to when not provisioning.
+    ///
+    /// In authorization mode this is AuthorizationRequired once the window
+    /// has closed; otherwise the client stays Authorized.
+    pub fn ready_state(&self) -> ImprovState {
+        match self.lock().authorized_until {
+            Some(until) if until > Instant::now() => ImprovState::Authorized,
+            _ if self.require_authorization => ImprovState::AuthorizationRequired,
+            _ => ImprovState::Authorized,
+        }
+    }
+
+    /// Whether the client may change `what` now, reporting why not.
+    ///
+    /// Changes need an open authorization window (in authorization mode)
+    /// and are refused while a network is being joined.
+    async fn may_change(&self, what: &str) -> bool {
+        if self.improv_state().await == ImprovState::Provisioning {
+            // Improv has no "busy" error.
+            warn!("Rejecting {}: provisioning in progress", what);
+            self.set_error_state(ImprovError::Unknown).await;
+            return false;
+        }
+        if self.ready_state() == ImprovState::AuthorizationRequired {
+            warn!("Rejecting {}: not authorized", what);
+            self.set_error_state(ImprovError::NotAuthorized).await;
+            return false;
+        }
+        true
+    }
+
+    /// Store and emit an RPC result.
+    ///
+    /// A response that could not be built is reported as an Unknown error.
+    pub async fn send_rpc_result(&self, response: Result<Vec<u8>, RpcError>) {
+        match response {
+            Ok(response) => {
+                let mut s = self.lock();
+                s.rpc_result = response.clone();
+                debug!("Sending RPC result ({} bytes)", response.len());
+                self.emit(SessionOutput::RpcResult(response));
+            }
+            Err(e) => {
+                error!("Failed to build RPC response: {}", e);
+                self.set_error_state(ImprovError::Unknown).await;
+            }
+        }
+    }
+
+    /// Handle an RPC packet in Improv BLE format (with checksum).
+    pub async fn handle_rpc(&self, data: &[u8]) {
+        debug!("Received RPC packet ({} bytes)", data.len());
+
+        match RpcRequest::parse(data) {
+            Ok(request) => self.handle_request(request).await,
+            Err(e) => {
+                error!("Failed to parse RPC command: {}", e);
+                self.set_error_state(ImprovError::InvalidRpc).await;
+            }
+        }
+    }
+
+    /// Handle a parsed RPC request.
+    pub async fn handle_request(&self, request: RpcRequest) {
+        info!("Processing RPC command: {:?}", request.command);
+
+        match request.command {
+            RpcCommand::Identify => {
+                self.emit(SessionOutput::Event(BleEvent::Identify));
+
+                // Send empty response to acknowledge.
+                self.send_rpc_result(build_response(RpcCommand::Identify, &[])).await;
+            }
+
+            RpcCommand::GetDeviceInfo => {
+                let response = {
+                    let config = self.config.read().await;
+                    build_device_info_response(
+                        &config.firmware_name,
+                        &config.firmware_version,
+                        &config.hardware_type,
+                        &config.device_name,
+                    )
+                };
+
+                self.set_error_state(ImprovError::None).await;
+                self.send_rpc_result(response).await;
+            }
+
+            RpcCommand::ScanWifiNetworks => self.handle_scan().await,
+
+            RpcCommand::SendWifiSettings => self.handle_wifi_settings(&request).await,
+
+            RpcCommand::Hostname => self.handle_hostname(&request).await,
+
+            RpcCommand::SendEnterpriseSettings => self.handle_enterprise_settings(&request).await,
+
+            RpcCommand::CaCertificate => self.handle_ca_certificate(&request).await,
+
+            RpcCommand::IpSettings => self.handle_ip_settings(&request).await,
+
+            RpcCommand::Country => self.handle_country(&request).await,
+        }
+    }
+
+    async fn handle_scan(&self) {
+        self.set_error_state(ImprovError::None).await;
+
+        match self.wifi.scan().await {
+            Ok(networks) => {
+                // `signal` is the strongest access point's measured RSSI in
+                // dBm. Hidden networks have no SSID to offer.
+                let network_tuples: Vec<(Ssid, i32, bool)> = networks
+                    .iter()
+                    .filter(|n| !n.hidden)
+                    .map(|n| {
+                        let rssi = n.signal.unwrap_or(UNMEASURED_RSSI);
+                        (n.raw_ssid(), rssi, n.security != "open")
+                    })
+                    .collect();
+
+                // Packets must fit in a single notification.
+                let max_packet_len = self.lock().max_packet_len;
+                let responses = build_scan_responses(&network_tuples, max_packet_len);
+                debug!("Sending {} scan results", responses.len() - 1);
+
+                for response in responses {
+                    self.send_rpc_result(Ok(response)).await;
+                }
+            }
+            Err(e) => {
+                error!("WiFi scan failed: {}", e);
+                self.set_error_state(ImprovError::Unknown).await;
+            }
+        }
+    }
+
+    async fn handle_wifi_settings(&self, request: &RpcRequest) {
+        if !self.may_change("WiFi settings").await {
+            return;
+        }
+
+        let creds = match request.parse_wifi_credentials() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Failed to parse WiFi credentials: {}", e);
+                self.set_error_state(ImprovError::InvalidRpc).await;
+                return;
+            }
+        };
+
+        self.provision(
+            RpcCommand::SendWifiSettings,
+            ConnectRequest::psk(creds.ssid, creds.password),
+        )
+        .await;
+    }
+
+    async fn handle_enterprise_settings(&self, request: &RpcRequest) {
+        if !self.may_change("enterprise settings").await {
+            return;
+        }
+
+        let (ssid, mut creds) = match request.parse_enterprise_credentials() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Failed to parse enterprise credentials: {}", e);
+                self.set_error_state(ImprovError::InvalidRpc).await;
+                return;
+            }
+        };
+
+        let ca_cert = std::mem::take(&mut self.lock().pending_ca_cert);
+        if !ca_cert.is_empty() {
+            match String::from_utf8(ca_cert) {
+                Ok(pem) => creds.ca_cert = Some(pem),
+                Err(_) => {
+                    error!("CA certificate is not PEM text");
+                    self.set_error_state(ImprovError::InvalidRpc).await;
+                    return;
+                }
+            }
+        }
+
+        self.provision(
+            RpcCommand::SendEnterpriseSettings,
+            ConnectRequest::new(ssid, Credentials::Enterprise(creds)),
+        )
+        .await;
+    }
+
+    /// Append a chunk to the pending CA certificate; empty data clears it.
+    ///
+    /// Replies with the total length received so far, so the client can
+    /// tell a lost chunk from a slow one.
+    async fn handle_ca_certificate(&self, request: &RpcRequest) {
+        if !self.may_change("CA certificate").await {
+            return;
+        }
+
+        let total = {
+            let mut s = self.lock();
+            if request.data.is_empty() {
+                s.pending_ca_cert.clear();
+                Some(0)
+            } else if s.pending_ca_cert.len() + request.data.len() > MAX_CA_CERT_LEN {
+                s.pending_ca_cert.clear();
+                None
+            } else {
+                s.pending_ca_cert.extend_from_slice(&request.data);
+                Some(s.pending_ca_cert.len())
+            }
+        };
+        let Some(total) = total else {
+            error!("CA certificate exceeds {} bytes", MAX_CA_CERT_LEN);
+            self.set_error_state(ImprovError::InvalidRpc).await;
+            return;
+        };
+
+        debug!("CA certificate: {} bytes pending", total);
+        self.set_error_state(ImprovError::None).await;
+        self.send_rpc_result(build_response(
+            RpcCommand::CaCertificate,
+            &[&total.to_string()],
+        ))
+        .await;
+    }
+
+    /// Store IP settings for the next network; all-empty fields reset them
+    /// to DHCP. Replies with an empty result once they are accepted.
+    async fn handle_ip_settings(&self, request: &RpcRequest) {
+        if !self.may_change("IP settings").await {
+            return;
+        }
+
+        let ip = match request.parse_ip_config() {
+            Ok(ip) => ip,
+            Err(e) => {
+                error!("Failed to parse IP settings: {}", e);
+                self.lock().pending_ip_config = IpConfig::default();
+                self.set_error_state(ImprovError::InvalidRpc).await;
+                return;
+            }
+        };
+
+        debug!("IP settings pending: {:?}", ip);
+        self.lock().pending_ip_config = ip;
+        self.set_error_state(ImprovError::None).await;
+        self.send_rpc_result(build_response(RpcCommand::IpSettings, &[])).await;
+    }
+
+    /// Get or set the regulatory country.
+    ///
+    /// Clients set it before scanning, since channels outside the world
+    /// domain (most of 5 GHz) are not scanned until it is set.
+    async fn handle_country(&self, request: &RpcRequest) {
+        let country = match request.parse_country() {
+            Ok(country) => country,
+            Err(e) => {
+                error!("Failed to parse country code: {}", e);
+                self.set_error_state(ImprovError::InvalidRpc).await;
+                return;
+            }
+        };
+
+        let result = match country {
+            Some(country) => {
+                if !self.may_change("country code").await {
+                    return;
+                }
+                info!("Setting WiFi country to {}", country);
+                self.wifi.set_country(country).await.map(|()| Some(country))
+            }
+            None => self.wifi.country().await,
+        };
+
+        match result {
+            Ok(country) => {
+                self.set_error_state(ImprovError::None).await;
+                self.send_rpc_result(build_country_response(country)).await;
+            }
+            Err(e) => {
+                error!("WiFi country request failed: {}", e);
+                self.set_error_state(ImprovError::Unknown).await;
+            }
+        }
+    }
+
+    /// Join a network and report the outcome.
+    ///
+    /// `command` is the RPC that carried the credentials; the redirect URL
+    /// is sent in its result.
+    async fn provision(&self, command: RpcCommand, mut request: ConnectRequest) {
+        info!("Attempting to connect to WiFi: {}", request.ssid);
+        request.ip = std::mem::take(&mut self.lock().pending_ip_config);
+        if let Err(e) = request.validate() {
+            error!("Rejecting WiFi settings: {}", e);
+            self.set_error_state(ImprovError::InvalidRpc).await;
+            return;
+        }
+
+        // Only one network is joined at a time, whichever client asked.
+        let started = Input::ProvisioningStarted(request.ssid.clone());
+        if let Err(e) = self.submit(started).await {
+            warn!("Rejecting WiFi settings: {}", e);
+            // Improv has no "busy" error.
+            self.set_error_state(ImprovError::Unknown).await;
+            return;
+        }
+        self.emit(SessionOutput::Event(BleEvent::ProvisioningStarted(
+            request.ssid.clone(),
+        )));
+
+        let verify_timeout = self.lock().verify_timeout;
+        let result = wifi::connect_verified(&*self.wifi, &request, verify_timeout).await;
+
+        match result {
+            Ok(()) => {
+                info!("Successfully connected to WiFi: {}", request.ssid);
+
+                let redirect_url = self.config.read().await.redirect_url();
+                let response = build_provision_response(command, &redirect_url).unwrap_or_else(|e| {
+                    warn!("Redirect URL not sent: {}", e);
+                    build_provision_response(command, "").unwrap_or_default()
+                });
+
+                // Provisioning can only end through the attempt that started it.
+                let input = Input::ProvisioningSucceeded {
+                    redirect_url: Some(redirect_url.clone()),
+                };
+                let _ = self.submit(input).await;
+
+                // Send the result BEFORE emitting the event.
+                self.send_rpc_result(Ok(response)).await;
+                self.emit(SessionOutput::Event(BleEvent::ProvisioningComplete(
+                    redirect_url,
+                )));
+            }
+            Err(failure) => {
+                // Improv has only a few error codes; the event carries the
+                // failing step, its class and any restored network for local
+                // clients.
+                error!("Failed to connect to WiFi: {}", failure);
+                let _ = self.submit(Input::ProvisioningFailed(failure.clone())).await;
+                self.emit(SessionOutput::Event(BleEvent::ProvisioningFailed(failure)));
+            }
+        }
+    }
+
+    async fn handle_hostname(&self, request: &RpcRequest) {
+        let new_name = match request.parse_hostname() {
+            Ok(name) => name,
+            Err(e) => {
+                error!("Failed to parse hostname: {}", e);
+                self.set_error_state(ImprovError::InvalidRpc).await;
+                return;
+            }
+        };
+
+        let hostname = match new_name {
+            Some(name) => {
+                if !self.may_change("hostname").await {
+                    return;
+                }
+                info!("Setting hostname to: {}", name);
+                match self.hostname.set(&name).await {
+                    Ok(()) => {
+                        self.config.write().await.device_name = name.clone();
+                        self.emit(SessionOutput::Event(BleEvent::HostnameChanged(
+                            name.clone(),
+                        )));
+                        name
+                    }
+                    Err(HostnameError::Invalid(_)) => {
+                        warn!("Rejected invalid hostname: {:?}", name);
+                        self.set_error_state(ImprovError::BadHostname).await;
+                        return;
+                    }
+                    Err(e) => {
+                        error!("Failed to set hostname: {}", e);
+                        self.set_error_state(ImprovError::Unknown).await;
+                        return;
+                    }
+                }
+            }
+            None => match self.hostname.get() {
+                Ok(name) => name,
+                Err(e) => {
+                    error!("Failed to get hostname: {}", e);
+                    self.set_error_state(ImprovError::Unknown).await;
+                    return;
+                }
+            },
+        };
+
+        self.set_error_state(ImprovError::None).await;
+        self.send_rpc_result(build_hostname_response(&hostname)).await;
+    }
+}
+
+impl<W: WifiManager + 'static, H: HostnameControl + 'static> ImprovSession<W, H> {
+    /// Authorize the client after local confirmation.
+    ///
+    /// Opens (or extends) the authorization window, and schedules a return
+    /// to AuthorizationRequired once it closes. Does nothing when
+    /// authorization is not required.
+    pub async fn authorize(self: &Arc<Self>) -> ImprovState {
+        if !self.require_authorization {
+            return self.improv_state().await;
+        }
+
+        let timeout = self.config.read().await.authorization_timeout;
+        let until = Instant::now() + timeout;
+
+        let improv_state = {
+            let machine = self.machine.read().await;
+            self.lock().authorized_until = Some(until);
+            self.notify(machine.version(), machine.state, machine.improv_error);
+            self.improv_state_in(machine.state)
+        };
+        self.set_error_state(ImprovError::None).await;
+        info!("Client authorized for {}s", timeout.as_secs());
+
+        // A later authorization extends the window, so only expire if this
+        // timer's deadline is still the current one.
+        let session = Arc::clone(self);
+        tokio::spawn(async move {
+            tokio::time::sleep_until(until).await;
+            if session.ready_state() == ImprovState::AuthorizationRequired {
+                info!("Authorization window expired");
+                let machine = session.machine.read().await;
+                session.notify(machine.version(), machine.state, machine.improv_error);
+            }
+        });
+
+        improv_state
+    }
+
+    /// Forward changes other clients and transports make to the state
+    /// machine, e.g. a WebSocket connect, to this session's transport.
+    ///
+    /// Runs until the session is dropped.
+    pub fn watch_machine(self: &Arc<Self>) {
+        let session = Arc::downgrade(self);
+        let machine = Arc::clone(&self.machine);
+        tokio::spawn(async move {
+            let mut changes = {
+                let machine = machine.read().await;
+                let Some(session) = session.upgrade() else {
+                    return;
+                };
+                // Catch up on anything that changed before subscribing.
+                session.notify(machine.version(), machine.state, machine.improv_error);
+                machine.changes()
+            };
+
+            loop {
+                let change = match changes.recv().await {
+                    Ok(change) => Some(change),
+                    Err(RecvError::Lagged(_)) => None,
+                    Err(RecvError::Closed) => return,
+                };
+                let Some(session) = session.upgrade() else {
+                    return;
+                };
+                match change {
+                    Some(change) => session.notify(
+                        change.version,
+                        change.snapshot.state,
+                        change.snapshot.improv_error,
+                    ),
+                    // Missed some; the current state is what matters.
+                    None => {
+                        let machine = machine.read().await;
+                        session.notify(machine.version(), machine.state, machine.improv_error);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// In-memory transport for driving an `ImprovSession` in tests.
+#[cfg(test)]
+pub struct SessionHarness {
+    pub session: Arc<ImprovSession<crate::wifi::MockWifiManager, crate::hostname::MockHostname>>,
+    /// Hostname the session reads and sets.
+    pub hostname: Arc<crate::hostname::MockHostname>,
+    /// State machine the session submits to.
+    pub machine: Arc<RwLock<StateMachine>>,
+    outputs: mpsc::UnboundedReceiver<SessionOutput>,
+}
+
+#[cfg(test)]
+impl SessionHarness {
+    pub fn new(wifi: crate::wifi::MockWifiManager, config: BleConfig) -> Self {
+        let require_authorization = config.require_authorization;
+        let machine = Arc::new(RwLock::new(StateMachine::default()));
+        let hostname = Arc::new(crate::hostname::MockHostname::default());
+        let (session, outputs) = ImprovSession::with_hostname(
+            Arc::new(RwLock::new(config)),
+            Arc::new(wifi),
+            Arc::clone(&hostname),
+            Arc::clone(&machine),
+            require_authorization,
+        );
+        let session = Arc::new(session);
+        session.watch_machine();
+        Self {
+            session,
+            hostname,
+            machine,
+            outputs,
+        }
+    }
+
+    /// Build an RPC packet (with checksum) for a command and its data.
+    pub fn packet(command: RpcCommand, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![command as u8, data.len() as u8];
+        packet.extend_from_slice(data);
+        packet.push(crate::improv::calculate_checksum(&packet));
+        packet
+    }
+
+    /// Build SendWifiSettings data.
+    pub fn wifi_settings(ssid: impl AsRef<[u8]>, password: &str) -> Vec<u8> {
+        let ssid = ssid.as_ref();
+        let mut data = vec![ssid.len() as u8];
+        data.extend_from_slice(ssid);
+        data.push(password.len() as u8);
+        data.extend_from_slice(password.as_bytes());
+        data
+    }
+
+    /// Send raw RPC bytes and return everything the session emitted.
+    pub async fn send_raw(&mut self, data: &[u8]) -> Vec<SessionOutput> {
+        self.session.handle_rpc(data).await;
+        self.drain()
+    }
+
+    /// Send a command and return everything the session emitted.
+    pub async fn send(&mut self, command: RpcCommand, data: &[u8]) -> Vec<SessionOutput> {
+        self.send_raw(&Self::packet(command, data)).await
+    }
+
+    /// Let the machine watcher catch up, then take the outputs emitted so
+    /// far.
+    pub async fn settle(&mut self) -> Vec<SessionOutput> {
+        for _ in 0..4 {
+            tokio::task::yield_now().await;
+        }
+        self.drain()
+    }
+
+    /// Take the outputs emitted so far.
+    pub fn drain(&mut self) -> Vec<SessionOutput> {
+        let mut outputs = Vec::new();
+        while let Ok(output) = self.outputs.try_recv() {
+            outputs.push(output);
+        }
+        outputs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connectivity::{ConnectivityFailure, ConnectivityStep};
+    use crate::improv::{build_scan_result, calculate_checksum};
+    use crate::protocol::Network;
+    use crate::wifi::{ErrorCode, MockWifiManager};
+    use std::time::Duration;
+
+    fn config() -> BleConfig {
+        BleConfig {
+            device_name: "dirtsim".into(),
+            redirect_url: "http://{hostname}.local:8081".into(),
+            ..Default::default()
+        }
+    }
+
+    fn network(ssid: &str, signal: i32, security: &str) -> Network {
+        Network {
+            ssid: ssid.into(),
+            signal: Some(signal),
+            security: security.into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn initial_state_follows_authorization_mode() {
+        let harness = SessionHarness::new(MockWifiManager::default(), config());
+        assert_eq!(harness.session.improv_state().await, ImprovState::Authorized);
+
+        let harness = SessionHarness::new(
+            MockWifiManager::default(),
+            BleConfig {
+                require_authorization: true,
+                ..config()
+            },
+        );
+        assert_eq!(
+            harness.session.improv_state().await,
+            ImprovState::AuthorizationRequired
+        );
+    }
+
+    #[tokio::test]
+    async fn error_state_emits_only_changes() {
+        let mut harness = SessionHarness::new(MockWifiManager::default(), config());
+
+        harness.session.set_error_state(ImprovError::None).await;
+        harness
+            .session
+            .set_error_state(ImprovError::UnableToConnect)
+            .await;
+        harness
+            .session
+            .set_error_state(ImprovError::UnableToConnect)
+            .await;
+
+        assert_eq!(
+            harness.drain(),
+            vec![SessionOutput::Error(ImprovError::UnableToConnect)]
+        );
+        assert_eq!(
+            harness.machine.read().await.improv_error,
+            ImprovError::UnableToConnect
+        );
+    }
+
+    #[tokio::test]
+    async fn ready_state_expires_with_authorization_window() {
+        let harness = SessionHarness::new(
+            MockWifiManager::default(),
+            BleConfig {
+                require_authorization: true,
+                ..config()
+            },
+        );
+        assert_eq!(
+            harness.session.ready_state(),
+            ImprovState::AuthorizationRequired
+        );
+
+        harness.session.lock().authorized_until = Some(Instant::now() + Duration::from_secs(60));
+        assert_eq!(harness.session.ready_state(), ImprovState::Authorized);
+
+        harness.session.lock().authorized_until = Some(Instant::now() - Duration::from_secs(1));
+        assert_eq!(
+            harness.session.ready_state(),
+            ImprovState::AuthorizationRequired
+        );
+    }
+
+    #[tokio::test]
+    async fn provisions_successfully() {
+        let mut harness = SessionHarness::new(MockWifiManager::default(), config());
+
+        let outputs = harness
+            .send(
+                RpcCommand::SendWifiSettings,
+                &SessionHarness::wifi_settings("home", "hunter22"),
+            )
+            .await;
+
+        assert_eq!(
+            outputs,
+            vec![
+                SessionOutput::State(ImprovState::Provisioning),
+                SessionOutput::Event(BleEvent::ProvisioningStarted("home".into())),
+                SessionOutput::State(ImprovState::Provisioned),
+                SessionOutput::RpcResult(
+                    build_provision_response(
+                        RpcCommand::SendWifiSettings,
+                        "http://dirtsim.local:8081"
+                    )
+                    .unwrap()
+                ),
+                SessionOutput::Event(BleEvent::ProvisioningComplete(
+                    "http://dirtsim.local:8081".into()
+                )),
+            ]
+        );
+        assert_eq!(harness.session.improv_state().await, ImprovState::Provisioned);
+        assert_eq!(harness.machine.read().await.state, State::Provisioned);
+    }
+
+    #[tokio::test]
+    async fn non_utf8_ssid_reaches_backend_unchanged() {
+        let mut harness = SessionHarness::new(MockWifiManager::default(), config());
+        let ssid = [b'c', b'a', b'f', 0xe9];
+
+        harness
+            .send(
+                RpcCommand::SendWifiSettings,
+                &SessionHarness::wifi_settings(ssid, "hunter22"),
+            )
+            .await;
+
+        assert_eq!(harness.session.improv_state().await, ImprovState::Provisioned);
+        assert_eq!(
+            *harness.session.wifi.connected_to.lock().unwrap(),
+            Some(Ssid::new(ssid.to_vec()))
+        );
+    }
+
+    #[tokio::test]
+    async fn unlisted_ssid_is_joined_as_hidden() {
+        let wifi = MockWifiManager {
+            networks: vec![network("MyWiFi", -45, "wpa2")],
+            ..Default::default()
+        };
+        let mut harness = SessionHarness::new(wifi, config());
+
+        harness
+            .send(
+                RpcCommand::SendWifiSettings,
+                &SessionHarness::wifi_settings("secret", "hunter22"),
+            )
+            .await;
+
+        assert_eq!(harness.session.improv_state().await, ImprovState::Provisioned);
+        let request = harness.session.wifi.last_request.lock().unwrap().clone().unwrap();
+        assert!(request.hidden);
+    }
+
+    fn enterprise_settings(fields: &[&str]) -> Vec<u8> {
+        fields
+            .iter()
+            .flat_map(|field| std::iter::once(field.len() as u8).chain(field.bytes()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn enterprise_settings_use_uploaded_ca_certificate() {
+        let wifi = MockWifiManager {
+            networks: vec![network("eduroam", -50, "wpa2-enterprise")],
+            ..Default::default()
+        };
+        let mut harness = SessionHarness::new(wifi, config());
+        let pem = "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n";
+        let (first, second) = pem.as_bytes().split_at(20);
+
+        harness.send(RpcCommand::CaCertificate, first).await;
+        let outputs = harness.send(RpcCommand::CaCertificate, second).await;
+        assert_eq!(
+            outputs,
+            vec![SessionOutput::RpcResult(
+                build_response(RpcCommand::CaCertificate, &[&pem.len().to_string()]).unwrap()
+            )]
+        );
+
+        let data = enterprise_settings(&["eduroam", "peap", "", "alice", "", "hunter22"]);
+        let outputs = harness.send(RpcCommand::SendEnterpriseSettings, &data).await;
+
+        assert_eq!(harness.session.improv_state().await, ImprovState::Provisioned);
+        assert!(outputs.contains(&SessionOutput::RpcResult(
+            build_provision_response(
+                RpcCommand::SendEnterpriseSettings,
+                "http://dirtsim.local:8081"
+            )
+            .unwrap()
+        )));
+        let request = harness.session.wifi.last_request.lock().unwrap().clone().unwrap();
+        match request.credentials {
+            Credentials::Enterprise(creds) => {
+                assert_eq!(creds.identity, "alice");
+                assert_eq!(creds.ca_cert.as_deref(), Some(pem));
+            }
+            Credentials::Psk(_) => panic!("Expected enterprise credentials"),
+        }
+        assert!(harness.session.lock().pending_ca_cert.is_empty());
+    }
+
+    #[tokio::test]
+    async fn ip_settings_apply_to_the_next_network() {
+        let mut harness = SessionHarness::new(MockWifiManager::default(), config());
+        let data = enterprise_settings(&[
+            "manual",
+            "192.168.1.50/24",
+            "192.168.1.1",
+            "",
+            "",
+            "",
+            "192.168.1.1",
+            "",
+            "",
+        ]);
+
+        let outputs = harness.send(RpcCommand::IpSettings, &data).await;
+        assert_eq!(
+            outputs,
+            vec![SessionOutput::RpcResult(
+                build_response(RpcCommand::IpSettings, &[]).unwrap()
+            )]
+        );
+
+        harness
+            .send(
+                RpcCommand::SendWifiSettings,
+                &SessionHarness::wifi_settings("home", "hunter22"),
+            )
+            .await;
+        let request = harness.session.wifi.last_request.lock().unwrap().clone().unwrap();
+        assert_eq!(request.ip.ipv4.addresses[0].to_string(), "192.168.1.50/24");
+        assert!(harness.session.lock().pending_ip_config.is_default());
+    }
+
+    #[tokio::test]
+    async fn empty_ssid_is_rejected() {
+        let mut harness = SessionHarness::new(MockWifiManager::default(), config());
+
+        let outputs = harness
+            .send(
+                RpcCommand::SendWifiSettings,
+                &SessionHarness::wifi_settings("", "hunter22"),
+            )
+            .await;
+
+        assert_eq!(outputs, vec![SessionOutput::Error(ImprovError::InvalidRpc)]);
+        assert_eq!(*harness.session.wifi.connected_to.lock().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn invalid_ip_settings_are_rejected() {
+        let mut harness = SessionHarness::new(MockWifiManager::default(), config());
+        let data = enterprise_settings(&["manual", "", "", "", "", "", "", "", ""]);
+
+        let outputs = harness.send(RpcCommand::IpSettings, &data).await;
+
+        assert_eq!(outputs, vec![SessionOutput::Error(ImprovError::InvalidRpc)]);
+        assert!(harness.session.lock().pending_ip_config.is_default());
+    }
+
+    #[tokio::test]
+    async fn oversized_ca_certificate_is_rejected() {
+        let mut harness = SessionHarness::new(MockWifiManager::default(), config());
+
+        for _ in 0..MAX_CA_CERT_LEN / 255 {
+            harness.send(RpcCommand::CaCertificate, &[b'a'; 255]).await;
+        }
+        let outputs = harness.send(RpcCommand::CaCertificate, &[b'a'; 255]).await;
+
+        assert_eq!(outputs, vec![SessionOutput::Error(ImprovError::InvalidRpc)]);
+        assert!(harness.session.lock().pending_ca_cert.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_connect_reports_unable_to_connect() {
+        let wifi = MockWifiManager {
+            connect_result: Err("auth failed".into()),
+            ..Default::default()
+        };
+        let mut harness = SessionHarness::new(wifi, config());
+
+        let outputs = harness
+            .send(
+                RpcCommand::SendWifiSettings,
+                &SessionHarness::wifi_settings("home", "wrong"),
+            )
+            .await;
+
+        assert_eq!(
+            outputs,
+            vec![
+                SessionOutput::State(ImprovState::Provisioning),
+                SessionOutput::Event(BleEvent::ProvisioningStarted("home".into())),
+                SessionOutput::State(ImprovState::Authorized),
+                SessionOutput::Error(ImprovError::UnableToConnect),
+                SessionOutput::Event(BleEvent::ProvisioningFailed(ConnectivityFailure::new(
+                    ConnectivityStep::Association,
+                    "Connection failed: Mock connect to home failed: auth failed",
+                ))),
+            ]
+        );
+
+        // A retry clears the error before trying again.
+        let outputs = harness
+            .send(
+                RpcCommand::SendWifiSettings,
+                &SessionHarness::wifi_settings("home", "wrong"),
+            )
+            .await;
+        assert_eq!(outputs[0], SessionOutput::State(ImprovState::Provisioning));
+        assert_eq!(outputs[1], SessionOutput::Error(ImprovError::None));
+    }
+
+    #[tokio::test]
+    async fn unverified_connection_is_not_provisioned() {
+        let wifi = MockWifiManager {
+            network_check: Err(ConnectivityFailure::new(ConnectivityStep::Dns, "timed out")),
+            ..Default::default()
+        };
+        let mut harness = SessionHarness::new(wifi, config());
+        harness.session.set_verify_timeout(Duration::ZERO);
+
+        let outputs = harness
+            .send(
+                RpcCommand::SendWifiSettings,
+                &SessionHarness::wifi_settings("home", "hunter22"),
+            )
+            .await;
+
+        assert_eq!(
+            outputs,
+            vec![
+                SessionOutput::State(ImprovState::Provisioning),
+                SessionOutput::Event(BleEvent::ProvisioningStarted("home".into())),
+                SessionOutput::State(ImprovState::Authorized),
+                SessionOutput::Error(ImprovError::UnableToConnect),
+                SessionOutput::Event(BleEvent::ProvisioningFailed(ConnectivityFailure::new(
+                    ConnectivityStep::Dns,
+                    "timed out",
+                ))),
+            ]
+        );
+        assert_eq!(harness.session.rpc_result(), Vec::<u8>::new());
+    }
+
+    #[tokio::test]
+    async fn device_problems_are_unknown_improv_errors() {
+        let wifi = MockWifiManager {
+            connect_result: Err("Error: NetworkManager is not running.".into()),
+            ..Default::default()
+        };
+        let mut harness = SessionHarness::new(wifi, config());
+
+        let outputs = harness
+            .send(
+                RpcCommand::SendWifiSettings,
+                &SessionHarness::wifi_settings("home", "hunter22"),
+            )
+            .await;
+
+        assert_eq!(outputs[3], SessionOutput::Error(ImprovError::Unknown));
+        match &outputs[4] {
+            SessionOutput::Event(BleEvent::ProvisioningFailed(failure)) => {
+                assert_eq!(failure.code, ErrorCode::ServiceNotRunning);
+            }
+            other => panic!("Expected ProvisioningFailed, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_credentials_restore_previous_network() {
+        let wifi = MockWifiManager {
+            status: crate::wifi::WifiStatus {
+                connected: true,
+                ssid: Some("old".into()),
+            },
+            connect_result: Err("auth failed".into()),
+            ..Default::default()
+        };
+        let mut harness = SessionHarness::new(wifi, config());
+
+        let outputs = harness
+            .send(
+                RpcCommand::SendWifiSettings,
+                &SessionHarness::wifi_settings("new", "wrong"),
+            )
+            .await;
+
+        assert_eq!(outputs[3], SessionOutput::Error(ImprovError::UnableToConnect));
+        match &outputs[4] {
+            SessionOutput::Event(BleEvent::ProvisioningFailed(failure)) => {
+                assert_eq!(failure.restored.as_deref(), Some("old"));
+            }
+            other => panic!("Expected ProvisioningFailed, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn authorization_gates_wifi_settings() {
+        let mut harness = SessionHarness::new(
+            MockWifiManager::default(),
+            BleConfig {
+                require_authorization: true,
+                ..config()
+            },
+        );
+        let settings = SessionHarness::wifi_settings("home", "hunter22");
+
+        let outputs = harness.send(RpcCommand::SendWifiSettings, &settings).await;
+        assert_eq!(
+            outputs,
+            vec![SessionOutput::Error(ImprovError::NotAuthorized)]
+        );
+
+        assert_eq!(harness.session.authorize().await, ImprovState::Authorized);
+        assert_eq!(
+            harness.drain(),
+            vec![
+                SessionOutput::State(ImprovState::Authorized),
+                SessionOutput::Error(ImprovError::None),
+            ]
+        );
+
+        let outputs = harness.send(RpcCommand::SendWifiSettings, &settings).await;
+        assert!(outputs.contains(&SessionOutput::State(ImprovState::Provisioned)));
+    }
+
+    #[tokio::test]
+    async fn expired_window_gates_changes_after_provisioning() {
+        let mut harness = SessionHarness::new(
+            MockWifiManager::default(),
+            BleConfig {
+                require_authorization: true,
+                ..config()
+            },
+        );
+        harness.session.authorize().await;
+        let settings = SessionHarness::wifi_settings("home", "hunter22");
+        harness.send(RpcCommand::SendWifiSettings, &settings).await;
+        assert_eq!(harness.session.improv_state().await, ImprovState::Provisioned);
+
+        harness.session.lock().authorized_until = Some(Instant::now() - Duration::from_secs(1));
+        harness.drain();
+
+        let not_authorized = vec![SessionOutput::Error(ImprovError::NotAuthorized)];
+        let outputs = harness.send(RpcCommand::SendWifiSettings, &settings).await;
+        assert_eq!(outputs, not_authorized);
+        harness.session.set_error_state(ImprovError::None).await;
+        harness.drain();
+
+        let name = b"attacker";
+        let mut hostname = vec![name.len() as u8];
+        hostname.extend_from_slice(name);
+        for (command, data) in [
+            (RpcCommand::CaCertificate, &b"-----BEGIN"[..]),
+            (RpcCommand::Country, &[2, b'd', b'e'][..]),
+            (RpcCommand::Hostname, &hostname[..]),
+        ] {
+            let outputs = harness.send(command, data).await;
+            assert_eq!(outputs, not_authorized, "{:?}", command);
+            harness.session.set_error_state(ImprovError::None).await;
+            harness.drain();
+        }
+        assert_eq!(*harness.session.wifi.country.lock().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn notifies_changes_made_elsewhere() {
+        let mut harness = SessionHarness::new(MockWifiManager::default(), config());
+        harness.settle().await;
+
+        // As a WebSocket connect or the serial session would.
+        let failure = ConnectivityFailure::new(ConnectivityStep::Association, "wrong password")
+            .with_code(ErrorCode::WrongPassword);
+        for input in [
+            Input::ProvisioningStarted("home".into()),
+            Input::ProvisioningFailed(failure),
+        ] {
+            harness.machine.write().await.handle(input).unwrap();
+        }
+
+        assert_eq!(
+            harness.settle().await,
+            vec![
+                SessionOutput::State(ImprovState::Provisioning),
+                SessionOutput::State(ImprovState::Authorized),
+                SessionOutput::Error(ImprovError::UnableToConnect),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn own_changes_are_notified_once() {
+        let mut harness = SessionHarness::new(MockWifiManager::default(), config());
+        harness.settle().await;
+
+        let outputs = harness.send_raw(&[0xff]).await;
+        assert_eq!(outputs, vec![SessionOutput::Error(ImprovError::InvalidRpc)]);
+        // The watcher sees the same change, already notified.
+        assert_eq!(harness.settle().await, vec![]);
+    }
+
+    #[tokio::test]
+    async fn settings_are_rejected_while_provisioning() {
+        let mut harness = SessionHarness::new(MockWifiManager::default(), config());
+        // Another client, e.g. a WebSocket connect, is joining a network.
+        harness
+            .machine
+            .write()
+            .await
+            .handle(Input::ProvisioningStarted("home".into()))
+            .unwrap();
+        assert_eq!(harness.session.improv_state().await, ImprovState::Provisioning);
+        harness.settle().await;
+
+        let outputs = harness
+            .send(
+                RpcCommand::SendWifiSettings,
+                &SessionHarness::wifi_settings("other", "hunter22"),
+            )
+            .await;
+
+        assert_eq!(outputs, vec![SessionOutput::Error(ImprovError::Unknown)]);
+        assert_eq!(*harness.session.wifi.last_request.lock().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn country_can_be_set_before_scanning() {
+        let mut harness = SessionHarness::new(MockWifiManager::default(), config());
+
+        let outputs = harness.send(RpcCommand::Country, &[]).await;
+        assert_eq!(
+            outputs,
+            vec![SessionOutput::RpcResult(build_country_response(None).unwrap())]
+        );
+
+        let outputs = harness.send(RpcCommand::Country, &[2, b'd', b'e']).await;
+        let de = "DE".parse().unwrap();
+        assert_eq!(
+            outputs,
+            vec![SessionOutput::RpcResult(build_country_response(Some(de)).unwrap())]
+        );
+        assert_eq!(*harness.session.wifi.country.lock().unwrap(), Some(de));
+
+        let outputs = harness.send(RpcCommand::Country, &[1, b'd']).await;
+        assert_eq!(outputs, vec![SessionOutput::Error(ImprovError::InvalidRpc)]);
+    }
+
+    #[tokio::test]
+    async fn authorization_gates_setting_the_country() {
+        let mut harness = SessionHarness::new(
+            MockWifiManager::default(),
+            BleConfig {
+                require_authorization: true,
+                ..config()
+            },
+        );
+
+        let outputs = harness.send(RpcCommand::Country, &[2, b'd', b'e']).await;
+        assert_eq!(outputs, vec![SessionOutput::Error(ImprovError::NotAuthorized)]);
+        assert_eq!(*harness.session.wifi.country.lock().unwrap(), None);
+
+        let outputs = harness.send(RpcCommand::Country, &[]).await;
+        assert_eq!(
+            outputs,
+            vec![
+                SessionOutput::Error(ImprovError::None),
+                SessionOutput::RpcResult(build_country_response(None).unwrap()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn authorize_is_ignored_without_authorization_mode() {
+        let mut harness = SessionHarness::new(MockWifiManager::default(), config());
+
+        assert_eq!(harness.session.authorize().await, ImprovState::Authorized);
+        assert!(harness.drain().is_empty());
+    }
+
+    #[tokio::test]
+    async fn scan_sends_one_result_per_network() {
+        let wifi = MockWifiManager {
+            networks: vec![network("MyWiFi", -45, "wpa2"), network("Cafe", -70, "open")],
+            ..Default::default()
+        };
+        let mut harness = SessionHarness::new(wifi, config());
+
+        let outputs = harness.send(RpcCommand::ScanWifiNetworks, &[]).await;
+
+        assert_eq!(
+            outputs,
+            vec![
+                SessionOutput::RpcResult(build_scan_result(&"MyWiFi".into(), -45, true).unwrap()),
+                SessionOutput::RpcResult(build_scan_result(&"Cafe".into(), -70, false).unwrap()),
+                SessionOutput::RpcResult(
+                    build_response(RpcCommand::ScanWifiNetworks, &[]).unwrap()
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn scan_sends_raw_ssid_bytes() {
+        let wifi = MockWifiManager {
+            networks: vec![Network {
+                ssid: "caf\u{fffd}".into(),
+                ssid_hex: Some("636166e9".into()),
+                signal: Some(-45),
+                security: "wpa2".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut harness = SessionHarness::new(wifi, config());
+
+        let outputs = harness.send(RpcCommand::ScanWifiNetworks, &[]).await;
+
+        let ssid = Ssid::new(b"caf\xe9".to_vec());
+        assert_eq!(
+            outputs[0],
+            SessionOutput::RpcResult(build_scan_result(&ssid, -45, true).unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn scan_sends_unmeasured_signal_at_the_bottom_of_the_scale() {
+        let wifi = MockWifiManager {
+            networks: vec![Network {
+                signal: None,
+                ..network("MyWiFi", -45, "wpa2")
+            }],
+            ..Default::default()
+        };
+        let mut harness = SessionHarness::new(wifi, config());
+
+        let outputs = harness.send(RpcCommand::ScanWifiNetworks, &[]).await;
+
+        assert_eq!(
+            outputs[0],
+            SessionOutput::RpcResult(
+                build_scan_result(&"MyWiFi".into(), UNMEASURED_RSSI, true).unwrap()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn scan_omits_hidden_networks() {
+        let wifi = MockWifiManager {
+            networks: vec![
+                Network {
+                    hidden: true,
+                    ..network("", -40, "wpa2")
+                },
+                network("MyWiFi", -45, "wpa2"),
+            ],
+            ..Default::default()
+        };
+        let mut harness = SessionHarness::new(wifi, config());
+
+        let outputs = harness.send(RpcCommand::ScanWifiNetworks, &[]).await;
+
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(
+            outputs[0],
+            SessionOutput::RpcResult(build_scan_result(&"MyWiFi".into(), -45, true).unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn scan_skips_results_larger_than_packet_limit() {
+        let wifi = MockWifiManager {
+            networks: vec![
+                network("short", -45, "wpa2"),
+                network(&"x".repeat(40), -50, "wpa2"),
+            ],
+            ..Default::default()
+        };
+        let mut harness = SessionHarness::new(wifi, config());
+        harness.session.set_max_packet_len(20);
+
+        let outputs = harness.send(RpcCommand::ScanWifiNetworks, &[]).await;
+
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(
+            outputs[0],
+            SessionOutput::RpcResult(build_scan_result(&"short".into(), -45, true).unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn identify_emits_event_and_ack() {
+        let mut harness = SessionHarness::new(MockWifiManager::default(), config());
+
+        let outputs = harness.send(RpcCommand::Identify, &[]).await;
+
+        assert_eq!(
+            outputs,
+            vec![
+                SessionOutput::Event(BleEvent::Identify),
+                SessionOutput::RpcResult(build_response(RpcCommand::Identify, &[]).unwrap()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn device_info_uses_config() {
+        let mut harness = SessionHarness::new(MockWifiManager::default(), config());
+
+        let outputs = harness.send(RpcCommand::GetDeviceInfo, &[]).await;
+
+        let config = config();
+        let expected = build_device_info_response(
+            &config.firmware_name,
+            &config.firmware_version,
+            &config.hardware_type,
+            "dirtsim",
+        )
+        .unwrap();
+        assert_eq!(outputs, vec![SessionOutput::RpcResult(expected.clone())]);
+        assert_eq!(harness.session.rpc_result(), expected);
+    }
+
+    #[tokio::test]
+    async fn setting_the_hostname_updates_the_redirect_url() {
+        let mut harness = SessionHarness::new(MockWifiManager::default(), config());
+
+        let name = b"inky-soup";
+        let mut data = vec![name.len() as u8];
+        data.extend_from_slice(name);
+        let outputs = harness.send(RpcCommand::Hostname, &data).await;
+
+        assert_eq!(
+            outputs,
+            vec![
+                SessionOutput::Event(BleEvent::HostnameChanged("inky-soup".into())),
+                SessionOutput::RpcResult(build_hostname_response("inky-soup").unwrap()),
+            ]
+        );
+        assert_eq!(*harness.hostname.name.lock().unwrap(), "inky-soup");
+        assert_eq!(
+            harness.session.config.read().await.redirect_url(),
+            "http://inky-soup.local:8081"
+        );
+
+        let outputs = harness
+            .send(
+                RpcCommand::SendWifiSettings,
+                &SessionHarness::wifi_settings("home", "hunter22"),
+            )
+            .await;
+        assert!(
+            outputs.contains(&SessionOutput::Event(BleEvent::ProvisioningComplete(
+                "http://inky-soup.local:8081".into()
+            )))
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_hostname_is_rejected() {
+        let mut harness = SessionHarness::new(MockWifiManager::default(), config());
+
+        let name = b"not valid";
+        let mut data = vec![name.len() as u8];
+        data.extend_from_slice(name);
+        let outputs = harness.send(RpcCommand::Hostname, &data).await;
+
+        assert_eq!(
+            outputs,
+            vec![SessionOutput::Error(ImprovError::BadHostname)]
+        );
+    }
+
+    #[tokio::test]
+    async fn authorization_gates_setting_the_hostname() {
+        let mut harness = SessionHarness::new(
+            MockWifiManager::default(),
+            BleConfig {
+                require_authorization: true,
+                ..config()
+            },
+        );
+
+        let name = b"attacker";
+        let mut data = vec![name.len() as u8];
+        data.extend_from_slice(name);
+        let outputs = harness.send(RpcCommand::Hostname, &data).await;
+        assert_eq!(outputs, vec![SessionOutput::Error(ImprovError::NotAuthorized)]);
+        assert_eq!(harness.session.config.read().await.device_name, "dirtsim");
+
+        let outputs = harness.send(RpcCommand::Hostname, &[]).await;
+        assert!(matches!(
+            outputs.last(),
+            Some(SessionOutput::RpcResult(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn malformed_packets_report_invalid_rpc() {
+        let mut harness = SessionHarness::new(MockWifiManager::default(), config());
+
+        // Bad checksum.
+        let outputs = harness.send_raw(&[0x03, 0x00, 0x00]).await;
+        assert_eq!(outputs, vec![SessionOutput::Error(ImprovError::InvalidRpc)]);
+
+        // Unknown command.
+        let data = [0x7F, 0x00];
+        let mut packet = data.to_vec();
+        packet.push(calculate_checksum(&data));
+        harness.session.set_error_state(ImprovError::None).await;
+        harness.drain();
+        let outputs = harness.send_raw(&packet).await;
+        assert_eq!(outputs, vec![SessionOutput::Error(ImprovError::InvalidRpc)]);
+    }
+}
@@ -0,0 +1,166 @@
+//! Byte-accurate SSIDs.
+//!
+//! An SSID is up to 32 arbitrary bytes. Most are UTF-8, but nothing requires
+//! it, so SSIDs are carried as raw bytes from the Improv payload through to
+//! the backend. Text is only produced for display, and JSON carries a hex
+//! form alongside when the bytes are not valid UTF-8.
+
+use std::fmt;
+
+/// Raw SSID bytes.
+#[derive(Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Ssid(Vec<u8>);
+
+impl Ssid {
+    /// Longest SSID 802.11 allows, in bytes.
+    pub const MAX_LEN: usize = 32;
+
+    pub fn new(bytes: impl Into<Vec<u8>>) -> Self {
+        Self(bytes.into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The SSID as text, if it is valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+
+    /// Lowercase hex of the raw bytes.
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Hex of the raw bytes, only when they are not valid UTF-8.
+    ///
+    /// Used for the `ssid_hex` JSON field, which is omitted otherwise.
+    pub fn hex_if_not_utf8(&self) -> Option<String> {
+        match self.as_str() {
+            Some(_) => None,
+            None => Some(self.to_hex()),
+        }
+    }
+
+    /// Parse hex in either case, with an optional `0x` prefix.
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let hex = hex
+            .strip_prefix("0x")
+            .or_else(|| hex.strip_prefix("0X"))
+            .unwrap_or(hex);
+        if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+            return None;
+        }
+
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .map(Self)
+    }
+
+    /// The SSID from a JSON `ssid`/`ssid_hex` pair; the hex form wins.
+    pub fn from_json(ssid: &str, ssid_hex: Option<&str>) -> Option<Self> {
+        match ssid_hex {
+            Some(hex) => Self::from_hex(hex),
+            None => Some(Self::from(ssid)),
+        }
+    }
+}
+
+impl fmt::Display for Ssid {
+    /// Lossy text for logs and UI; invalid sequences become U+FFFD.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl fmt::Debug for Ssid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Ssid(\"{}\")", self.0.escape_ascii())
+    }
+}
+
+impl From<&str> for Ssid {
+    fn from(ssid: &str) -> Self {
+        Self(ssid.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for Ssid {
+    fn from(ssid: String) -> Self {
+        Self(ssid.into_bytes())
+    }
+}
+
+impl From<&[u8]> for Ssid {
+    fn from(ssid: &[u8]) -> Self {
+        Self(ssid.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Ssid {
+    fn from(ssid: Vec<u8>) -> Self {
+        Self(ssid)
+    }
+}
+
+impl PartialEq<str> for Ssid {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for Ssid {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf8_ssids_have_no_hex_form() {
+        let ssid = Ssid::from("café");
+        assert_eq!(ssid.as_str(), Some("café"));
+        assert_eq!(ssid.hex_if_not_utf8(), None);
+        assert_eq!(ssid.to_string(), "café");
+    }
+
+    #[test]
+    fn non_utf8_ssids_keep_their_bytes() {
+        let ssid = Ssid::new(vec![0x66, 0xff, 0x00, 0x3a]);
+        assert_eq!(ssid.as_str(), None);
+        assert_eq!(ssid.hex_if_not_utf8().as_deref(), Some("66ff003a"));
+        assert_eq!(ssid.to_string(), "f\u{fffd}\0:");
+        assert_eq!(format!("{:?}", ssid), "Ssid(\"f\\xff\\x00:\")");
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        let ssid = Ssid::new(vec![0x00, 0xab, 0xff]);
+        assert_eq!(Ssid::from_hex(&ssid.to_hex()), Some(ssid.clone()));
+        assert_eq!(Ssid::from_hex("0x00ABff"), Some(ssid));
+        assert_eq!(Ssid::from_hex(""), Some(Ssid::default()));
+        assert_eq!(Ssid::from_hex("abc"), None);
+        assert_eq!(Ssid::from_hex("zz"), None);
+        assert_eq!(Ssid::from_hex("é0"), None);
+    }
+
+    #[test]
+    fn json_hex_form_wins() {
+        assert_eq!(Ssid::from_json("home", None), Some(Ssid::from("home")));
+        assert_eq!(
+            Ssid::from_json("f\u{fffd}", Some("66ff")),
+            Some(Ssid::new(vec![0x66, 0xff]))
+        );
+        assert_eq!(Ssid::from_json("home", Some("nothex")), None);
+    }
+}
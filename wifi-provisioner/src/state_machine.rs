@@ -0,0 +1,602 @@
+//! Daemon state machine.
+//!
+//! `StateMachine` is the only owner of the daemon state reported to local
+//! clients: advertising, a connected BLE client, provisioning in progress or
+//! done, the advertising countdown and the last failure. The WebSocket
+//! handlers, the BLE event loop and the advertising timer submit `Input`s;
+//! an input that does not fit the current state is rejected and changes
+//! nothing. Every change is published as an `Event` to subscribed clients.
+//!
+//! The Improv sessions (`session::ImprovSession`) submit their provisioning
+//! attempts and error state here too, and derive the Improv state they
+//! report from this one, so only one network is ever being joined. They
+//! follow every change through `changes`, whichever client made it.
+
+use std::fmt;
+use std::ops::Deref;
+
+use tokio::sync::broadcast;
+use tracing::debug;
+
+use crate::connectivity::ConnectivityFailure;
+use crate::improv::ImprovError;
+use crate::protocol::{Event, State};
+use crate::ssid::Ssid;
+use crate::wifi::ErrorCode;
+
+/// Events buffered per subscriber before the slowest one starts missing them.
+const EVENT_CAPACITY: usize = 64;
+
+/// Something that happened, submitted to the state machine.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    /// Advertise for this many seconds, restarting the countdown.
+    StartAdvertising { timeout: u32 },
+    /// Stop advertising.
+    StopAdvertising,
+    /// A second of the advertising countdown passed.
+    Tick,
+    /// A BLE client connected.
+    ClientConnected,
+    /// The BLE client disconnected.
+    ClientDisconnected,
+    /// Joining this network started, over Improv or the WebSocket.
+    ProvisioningStarted(Ssid),
+    /// The network was joined and verified.
+    ProvisioningSucceeded { redirect_url: Option<String> },
+    /// Joining the network failed.
+    ProvisioningFailed(ConnectivityFailure),
+    /// An Improv RPC set this error state; `None` clears it.
+    ImprovError(ImprovError),
+}
+
+impl Input {
+    /// What the input does, for error messages.
+    fn action(&self) -> &'static str {
+        match self {
+            Input::StartAdvertising { .. } => "start advertising",
+            Input::StopAdvertising => "stop advertising",
+            Input::Tick => "count down",
+            Input::ClientConnected => "connect a client",
+            Input::ClientDisconnected => "disconnect a client",
+            Input::ProvisioningStarted(_) => "start provisioning",
+            Input::ProvisioningSucceeded { .. } => "finish provisioning",
+            Input::ProvisioningFailed(_) => "fail provisioning",
+            Input::ImprovError(_) => "report an Improv error",
+        }
+    }
+}
+
+/// An input that does not fit the current state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidTransition {
+    pub state: State,
+    pub action: &'static str,
+}
+
+impl fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cannot {} while {}", self.action, self.state.as_str())
+    }
+}
+
+impl std::error::Error for InvalidTransition {}
+
+/// Everything the state machine tracks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub state: State,
+    /// Seconds until advertising stops; `None` when not advertising. The
+    /// countdown pauses while provisioning.
+    pub advertising_remaining: Option<u32>,
+    pub wifi_connected: bool,
+    /// Why the last provisioning attempt failed, until one succeeds.
+    pub last_failure: Option<ConnectivityFailure>,
+    /// Error state reported to Improv clients.
+    pub improv_error: ImprovError,
+    /// State to return to if provisioning fails.
+    resume: State,
+}
+
+impl Snapshot {
+    fn new(wifi_connected: bool) -> Self {
+        Self {
+            state: State::Idle,
+            advertising_remaining: None,
+            wifi_connected,
+            last_failure: None,
+            improv_error: ImprovError::None,
+            resume: State::Idle,
+        }
+    }
+
+    /// The snapshot after `input`, and the events it causes besides the
+    /// state change itself.
+    fn next(&self, input: &Input) -> Result<(Snapshot, Vec<Event>), InvalidTransition> {
+        let invalid = InvalidTransition {
+            state: self.state,
+            action: input.action(),
+        };
+        let mut next = self.clone();
+        let mut events = Vec::new();
+
+        match input {
+            Input::StartAdvertising { timeout } => {
+                next.state = match self.state {
+                    State::Idle | State::Advertising | State::Provisioned => State::Advertising,
+                    State::Connected => State::Connected,
+                    State::Provisioning => return Err(invalid),
+                };
+                next.advertising_remaining = Some(*timeout);
+            }
+            Input::StopAdvertising => match self.state {
+                State::Advertising | State::Connected => {
+                    next.state = State::Idle;
+                    next.advertising_remaining = None;
+                }
+                State::Idle | State::Provisioned => {}
+                State::Provisioning => return Err(invalid),
+            },
+            Input::Tick => match (self.state, self.advertising_remaining) {
+                (State::Provisioning, _) | (_, None) => {}
+                (_, Some(0)) => {
+                    next.state = State::Idle;
+                    next.advertising_remaining = None;
+                }
+                (_, Some(remaining)) => {
+                    next.advertising_remaining = Some(remaining - 1);
+                    events.push(Event::Countdown {
+                        remaining: remaining - 1,
+                    });
+                }
+            },
+            Input::ClientConnected => {
+                match self.state {
+                    State::Advertising => next.state = State::Connected,
+                    State::Connected => {}
+                    State::Provisioning if self.resume == State::Advertising => {
+                        next.resume = State::Connected;
+                    }
+                    State::Provisioning => {}
+                    State::Idle | State::Provisioned => return Err(invalid),
+                }
+                events.push(Event::ClientConnected);
+            }
+            Input::ClientDisconnected => {
+                match self.state {
+                    State::Connected => next.state = State::Advertising,
+                    State::Provisioning if self.resume == State::Connected => {
+                        next.resume = State::Advertising;
+                    }
+                    _ => {}
+                }
+                events.push(Event::ClientDisconnected);
+            }
+            Input::ProvisioningStarted(ssid) => {
+                if self.state == State::Provisioning {
+                    return Err(invalid);
+                }
+                next.state = State::Provisioning;
+                next.resume = self.state;
+                next.improv_error = ImprovError::None;
+                events.push(Event::provisioning_started(ssid));
+            }
+            Input::ProvisioningSucceeded { redirect_url } => {
+                if self.state != State::Provisioning {
+                    return Err(invalid);
+                }
+                next.state = State::Provisioned;
+                next.advertising_remaining = None;
+                next.wifi_connected = true;
+                next.last_failure = None;
+                events.push(Event::ProvisioningComplete {
+                    redirect_url: redirect_url.clone(),
+                });
+            }
+            Input::ProvisioningFailed(failure) => {
+                if self.state != State::Provisioning {
+                    return Err(invalid);
+                }
+                next.state = self.resume;
+                next.last_failure = Some(failure.clone());
+                next.improv_error = improv_error(failure.code);
+                events.push(Event::ProvisioningFailed(failure.clone()));
+            }
+            Input::ImprovError(error) => next.improv_error = *error,
+        }
+
+        if next.state != State::Provisioning {
+            next.resume = next.state;
+        }
+        Ok((next, events))
+    }
+}
+
+/// Closest Improv error for a failed connection.
+///
+/// Problems with the network itself are "unable to connect"; problems with
+/// the device are not something the client can fix, so they are "unknown".
+fn improv_error(code: ErrorCode) -> ImprovError {
+    match code {
+        ErrorCode::WrongPassword
+        | ErrorCode::SsidNotFound
+        | ErrorCode::DhcpTimeout
+        | ErrorCode::Cancelled
+        | ErrorCode::Unknown => ImprovError::UnableToConnect,
+        ErrorCode::RadioDisabled
+        | ErrorCode::NoDevice
+        | ErrorCode::ServiceNotRunning
+        | ErrorCode::PermissionDenied => ImprovError::Unknown,
+    }
+}
+
+/// A snapshot the state machine moved to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    /// Counts changes, so a late observer can tell a stale one.
+    pub version: u64,
+    pub snapshot: Snapshot,
+}
+
+/// The daemon state, shared by every client and transport.
+///
+/// Derefs to a read-only `Snapshot`; changes go through `handle`.
+#[derive(Debug)]
+pub struct StateMachine {
+    current: Snapshot,
+    version: u64,
+    events: broadcast::Sender<Event>,
+    changes: broadcast::Sender<Change>,
+}
+
+impl Default for StateMachine {
+    fn default() -> Self {
+        Self::new(false)
+    }
+}
+
+impl Deref for StateMachine {
+    type Target = Snapshot;
+
+    fn deref(&self) -> &Snapshot {
+        &self.current
+    }
+}
+
+impl StateMachine {
+    /// Start idle, with WiFi connected or not.
+    pub fn new(wifi_connected: bool) -> Self {
+        Self {
+            current: Snapshot::new(wifi_connected),
+            version: 0,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            changes: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    /// Whether `input` would be accepted now.
+    pub fn check(&self, input: &Input) -> Result<(), InvalidTransition> {
+        self.current.next(input).map(|_| ())
+    }
+
+    /// Apply `input` and publish what changed.
+    pub fn handle(&mut self, input: Input) -> Result<(), InvalidTransition> {
+        let (next, events) = self.current.next(&input)?;
+        let previous = std::mem::replace(&mut self.current, next);
+
+        if previous.state != self.current.state {
+            debug!(
+                "Daemon state: {:?} -> {:?}",
+                previous.state, self.current.state
+            );
+            self.publish(Event::State {
+                state: self.current.state,
+            });
+        }
+        for event in events {
+            self.publish(event);
+        }
+        if previous != self.current {
+            self.version += 1;
+            // Sending only fails when nobody is watching.
+            let _ = self.changes.send(Change {
+                version: self.version,
+                snapshot: self.current.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Send an event that changes no state (e.g., identify) to every
+    /// subscribed connection.
+    pub fn publish(&self, event: Event) {
+        // Sending only fails when nobody is subscribed.
+        let _ = self.events.send(event);
+    }
+
+    /// Receive events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Number of changes so far; `Change::version` of the latest one.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Receive every change from now on, including those that publish no
+    /// event (e.g., the Improv error state).
+    pub fn changes(&self) -> broadcast::Receiver<Change> {
+        self.changes.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connectivity::ConnectivityStep;
+
+    const STATES: [State; 5] = [
+        State::Idle,
+        State::Advertising,
+        State::Connected,
+        State::Provisioning,
+        State::Provisioned,
+    ];
+
+    fn failure() -> ConnectivityFailure {
+        ConnectivityFailure::new(ConnectivityStep::Dns, "timed out")
+    }
+
+    fn inputs() -> Vec<Input> {
+        vec![
+            Input::StartAdvertising { timeout: 30 },
+            Input::StopAdvertising,
+            Input::Tick,
+            Input::ClientConnected,
+            Input::ClientDisconnected,
+            Input::ProvisioningStarted("home".into()),
+            Input::ProvisioningSucceeded { redirect_url: None },
+            Input::ProvisioningFailed(failure()),
+            Input::ImprovError(ImprovError::InvalidRpc),
+        ]
+    }
+
+    /// A consistent snapshot in `state`, reached the way the daemon would.
+    fn snapshot(state: State) -> Snapshot {
+        let mut snapshot = Snapshot::new(false);
+        let path: &[Input] = match state {
+            State::Idle => &[],
+            State::Advertising => &[Input::StartAdvertising { timeout: 30 }],
+            State::Connected => &[
+                Input::StartAdvertising { timeout: 30 },
+                Input::ClientConnected,
+            ],
+            State::Provisioning => &[
+                Input::StartAdvertising { timeout: 30 },
+                Input::ClientConnected,
+                Input::ProvisioningStarted("home".into()),
+            ],
+            State::Provisioned => &[
+                Input::ProvisioningStarted("home".into()),
+                Input::ProvisioningSucceeded { redirect_url: None },
+            ],
+        };
+        for input in path {
+            snapshot = snapshot.next(input).unwrap().0;
+        }
+        assert_eq!(snapshot.state, state);
+        snapshot
+    }
+
+    /// Expected state after `input` in `state`, or `None` if rejected.
+    fn expected(state: State, input: &Input) -> Option<State> {
+        use State::*;
+        let next = match (state, input) {
+            (Provisioning, Input::StartAdvertising { .. }) => return None,
+            (Connected, Input::StartAdvertising { .. }) => Connected,
+            (_, Input::StartAdvertising { .. }) => Advertising,
+
+            (Provisioning, Input::StopAdvertising) => return None,
+            (Advertising | Connected, Input::StopAdvertising) => Idle,
+            (_, Input::StopAdvertising) => state,
+
+            (_, Input::Tick) => state,
+
+            (Idle | Provisioned, Input::ClientConnected) => return None,
+            (Advertising, Input::ClientConnected) => Connected,
+            (_, Input::ClientConnected) => state,
+
+            (Connected, Input::ClientDisconnected) => Advertising,
+            (_, Input::ClientDisconnected) => state,
+
+            (Provisioning, Input::ProvisioningStarted(_)) => return None,
+            (_, Input::ProvisioningStarted(_)) => Provisioning,
+
+            (Provisioning, Input::ProvisioningSucceeded { .. }) => Provisioned,
+            (_, Input::ProvisioningSucceeded { .. }) => return None,
+
+            (Provisioning, Input::ProvisioningFailed(_)) => Connected,
+            (_, Input::ProvisioningFailed(_)) => return None,
+
+            (_, Input::ImprovError(_)) => state,
+        };
+        Some(next)
+    }
+
+    #[test]
+    fn transition_table() {
+        for state in STATES {
+            for input in inputs() {
+                let before = snapshot(state);
+                let result = before.next(&input);
+                match expected(state, &input) {
+                    Some(next) => {
+                        let (after, _) = result.unwrap_or_else(|e| {
+                            panic!("{:?} in {:?} was rejected: {}", input, state, e)
+                        });
+                        assert_eq!(after.state, next, "{:?} in {:?}", input, state);
+                    }
+                    None => assert_eq!(
+                        result.unwrap_err(),
+                        InvalidTransition {
+                            state,
+                            action: input.action()
+                        }
+                    ),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn advertising_stays_in_step_with_the_state() {
+        for state in STATES {
+            for input in inputs() {
+                let Ok((after, _)) = snapshot(state).next(&input) else {
+                    continue;
+                };
+                let advertising = after.advertising_remaining.is_some();
+                match after.state {
+                    State::Idle | State::Provisioned => assert!(!advertising),
+                    State::Advertising | State::Connected => assert!(advertising),
+                    State::Provisioning => {}
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn countdown_expires_into_idle() {
+        let mut machine = StateMachine::new(false);
+        let mut events = machine.subscribe();
+        machine
+            .handle(Input::StartAdvertising { timeout: 1 })
+            .unwrap();
+
+        machine.handle(Input::Tick).unwrap();
+        assert_eq!(machine.advertising_remaining, Some(0));
+        machine.handle(Input::Tick).unwrap();
+        assert_eq!(machine.state, State::Idle);
+        assert_eq!(machine.advertising_remaining, None);
+
+        let received: Vec<_> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        assert_eq!(
+            received,
+            [
+                Event::State {
+                    state: State::Advertising
+                },
+                Event::Countdown { remaining: 0 },
+                Event::State { state: State::Idle },
+            ]
+        );
+    }
+
+    #[test]
+    fn countdown_pauses_while_provisioning() {
+        let before = snapshot(State::Provisioning);
+        let (after, events) = before.next(&Input::Tick).unwrap();
+        assert_eq!(after, before);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn failed_provisioning_returns_to_the_previous_state() {
+        for state in [
+            State::Idle,
+            State::Advertising,
+            State::Connected,
+            State::Provisioned,
+        ] {
+            let provisioning = snapshot(state)
+                .next(&Input::ProvisioningStarted("home".into()))
+                .unwrap()
+                .0;
+            let (after, events) = provisioning
+                .next(&Input::ProvisioningFailed(failure()))
+                .unwrap();
+
+            assert_eq!(after.state, state);
+            assert_eq!(after.last_failure, Some(failure()));
+            assert_eq!(after.improv_error, ImprovError::UnableToConnect);
+            assert_eq!(events, [Event::ProvisioningFailed(failure())]);
+        }
+    }
+
+    #[test]
+    fn client_leaving_during_provisioning_is_remembered() {
+        let provisioning = snapshot(State::Provisioning);
+        let (provisioning, _) = provisioning.next(&Input::ClientDisconnected).unwrap();
+        assert_eq!(provisioning.state, State::Provisioning);
+
+        let (after, _) = provisioning
+            .next(&Input::ProvisioningFailed(failure()))
+            .unwrap();
+        assert_eq!(after.state, State::Advertising);
+        assert_eq!(after.advertising_remaining, Some(30));
+    }
+
+    #[test]
+    fn success_clears_the_failure_and_stops_advertising() {
+        let (provisioning, _) = snapshot(State::Provisioning)
+            .next(&Input::ProvisioningFailed(failure()))
+            .unwrap()
+            .0
+            .next(&Input::ProvisioningStarted("home".into()))
+            .unwrap();
+        let (after, events) = provisioning
+            .next(&Input::ProvisioningSucceeded {
+                redirect_url: Some("http://dirtsim.local:8081".into()),
+            })
+            .unwrap();
+
+        assert_eq!(after.state, State::Provisioned);
+        assert!(after.wifi_connected);
+        assert_eq!(after.last_failure, None);
+        assert_eq!(after.advertising_remaining, None);
+        assert_eq!(
+            events,
+            [Event::ProvisioningComplete {
+                redirect_url: Some("http://dirtsim.local:8081".into())
+            }]
+        );
+    }
+
+    #[test]
+    fn changes_carry_increasing_versions() {
+        let mut machine = StateMachine::new(false);
+        let mut changes = machine.changes();
+
+        machine.handle(Input::StopAdvertising).unwrap();
+        machine
+            .handle(Input::ImprovError(ImprovError::InvalidRpc))
+            .unwrap();
+        machine.handle(Input::ProvisioningStarted("home".into())).unwrap();
+
+        let received: Vec<_> = std::iter::from_fn(|| changes.try_recv().ok())
+            .map(|change| (change.version, change.snapshot.state, change.snapshot.improv_error))
+            .collect();
+        assert_eq!(
+            received,
+            [
+                (1, State::Idle, ImprovError::InvalidRpc),
+                (2, State::Provisioning, ImprovError::None),
+            ]
+        );
+        assert_eq!(machine.version(), 2);
+    }
+
+    #[test]
+    fn rejected_inputs_publish_nothing() {
+        let mut machine = StateMachine::new(false);
+        let mut events = machine.subscribe();
+
+        let err = machine
+            .handle(Input::ProvisioningSucceeded { redirect_url: None })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Cannot finish provisioning while idle");
+        assert_eq!(machine.state, State::Idle);
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -2,67 +2,77 @@
 //!
 //! Listens on 127.0.0.1:8888 and handles commands from local applications.
 
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 
 use futures_util::{SinkExt, StreamExt};
+use serde::Serialize;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, watch, RwLock};
+use tokio::task::JoinHandle;
 use tokio_tungstenite::tungstenite::Message;
 use tracing::{debug, error, info, warn};
 
-use crate::protocol::{Command, ErrorResponse, OkResponse, Response, State};
-use crate::wifi::WifiManager;
-
-/// Shared daemon state accessible from WebSocket handlers.
-#[derive(Debug)]
-pub struct DaemonState {
-    pub state: State,
-    pub advertising_remaining: Option<u32>,
-    pub wifi_connected: bool,
-}
-
-impl Default for DaemonState {
-    fn default() -> Self {
-        Self {
-            state: State::Idle,
-            advertising_remaining: None,
-            wifi_connected: false,
-        }
-    }
-}
+use crate::ble::BleControl;
+use crate::connectivity::VERIFY_TIMEOUT;
+use crate::protocol::{
+    Command, ErrorResponse, Event, Hello, OkResponse, Request, RequestHeader, RequestId, Response,
+    Transport,
+};
+use crate::regdomain::CountryCode;
+use crate::secret;
+use crate::ssid::Ssid;
+use crate::state_machine::{Input, StateMachine};
+use crate::wifi::{self, ConnectRequest, Credentials, ErrorCode, WifiManager, WifiResult};
 
 /// WebSocket server configuration.
 pub struct ServerConfig {
     pub addr: SocketAddr,
+    /// Enabled transports, reported by `hello`.
+    pub transports: Vec<Transport>,
+    /// Name of the WiFi backend in use, reported by `hello`.
+    pub wifi_backend: String,
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
             addr: "127.0.0.1:8888".parse().unwrap(),
+            transports: vec![Transport::Websocket],
+            wifi_backend: "nmcli".to_string(),
         }
     }
 }
 
 /// Shared context for request handlers.
-struct HandlerContext<W: WifiManager> {
-    state: Arc<RwLock<DaemonState>>,
+struct HandlerContext<W: WifiManager, B: BleControl> {
+    state: Arc<RwLock<StateMachine>>,
     wifi: Arc<W>,
+    ble: Arc<B>,
+    hello: Hello,
 }
 
 /// Run the WebSocket server.
 ///
 /// This function runs indefinitely, accepting connections and handling commands.
-pub async fn run_server<W: WifiManager + 'static>(
+pub async fn run_server<W: WifiManager + 'static, B: BleControl + 'static>(
     config: ServerConfig,
-    state: Arc<RwLock<DaemonState>>,
+    state: Arc<RwLock<StateMachine>>,
     wifi: Arc<W>,
+    ble: Arc<B>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let listener = TcpListener::bind(&config.addr).await?;
     info!("WebSocket server listening on {}", config.addr);
 
-    let ctx = Arc::new(HandlerContext { state, wifi });
+    let hello = Hello::new(config.transports, config.wifi_backend);
+    let ctx = Arc::new(HandlerContext {
+        state,
+        wifi,
+        ble,
+        hello,
+    });
 
     loop {
         match listener.accept().await {
@@ -81,16 +91,133 @@ pub async fn run_server<W: WifiManager + 'static>(
     }
 }
 
+/// A client connection.
+///
+/// Replies, progress and events are queued here and written in order by the
+/// connection's writer task, so handlers can send while a command runs.
+struct Connection {
+    out: mpsc::UnboundedSender<Message>,
+    /// Task forwarding events, once the client has subscribed.
+    subscription: std::sync::Mutex<Option<JoinHandle<()>>>,
+    /// Cancel signals of the requests with an id that are still running.
+    in_flight: std::sync::Mutex<HashMap<RequestId, watch::Sender<bool>>>,
+}
+
+impl Connection {
+    fn new(out: mpsc::UnboundedSender<Message>) -> Self {
+        Self {
+            out,
+            subscription: std::sync::Mutex::new(None),
+            in_flight: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Track request `id` until `finish`, returning its cancel signal.
+    ///
+    /// Returns `None` if a request with the same id is still running.
+    fn start(&self, id: &RequestId) -> Option<watch::Receiver<bool>> {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        if in_flight.contains_key(id) {
+            return None;
+        }
+        let (cancel, cancelled) = watch::channel(false);
+        in_flight.insert(id.clone(), cancel);
+        Some(cancelled)
+    }
+
+    /// Stop tracking request `id`.
+    fn finish(&self, id: &RequestId) {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        in_flight.remove(id);
+    }
+
+    /// Signal request `id` to stop. Returns false if it isn't running.
+    fn cancel(&self, id: &RequestId) -> bool {
+        let in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        match in_flight.get(id) {
+            Some(cancel) => {
+                cancel.send_replace(true);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Queue a JSON message for the client.
+    fn send(&self, message: &impl Serialize) {
+        match serde_json::to_string(message) {
+            // Fails only once the connection is closing.
+            Ok(json) => drop(self.out.send(Message::Text(json))),
+            Err(e) => error!("Failed to serialize message: {}", e),
+        }
+    }
+
+    /// Forward `events` to the client until the connection closes.
+    ///
+    /// Returns false if the client was already subscribed.
+    fn subscribe(&self, mut events: broadcast::Receiver<Event>) -> bool {
+        let mut subscription = self.subscription.lock().unwrap_or_else(|e| e.into_inner());
+        if subscription.is_some() {
+            return false;
+        }
+
+        let out = self.out.clone();
+        *subscription = Some(tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Subscriber missed {} events", missed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                let Ok(json) = serde_json::to_string(&event) else {
+                    continue;
+                };
+                if out.send(Message::Text(json)).is_err() {
+                    return;
+                }
+            }
+        }));
+        true
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let subscription = self.subscription.get_mut().unwrap_or_else(|e| e.into_inner());
+        if let Some(task) = subscription.take() {
+            task.abort();
+        }
+    }
+}
+
 /// Handle a single WebSocket connection.
-async fn handle_connection<W: WifiManager>(
+async fn handle_connection<W: WifiManager + 'static, B: BleControl + 'static>(
     stream: TcpStream,
     addr: SocketAddr,
-    ctx: Arc<HandlerContext<W>>,
+    ctx: Arc<HandlerContext<W, B>>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let ws_stream = tokio_tungstenite::accept_async(stream).await?;
     info!("New WebSocket connection from {}", addr);
 
     let (mut write, mut read) = ws_stream.split();
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel();
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = out_rx.recv().await {
+            if let Message::Text(text) = &msg {
+                debug!("Sending to {}: {}", addr, text);
+            }
+            if let Err(e) = write.send(msg).await {
+                warn!("WebSocket write error to {}: {}", addr, e);
+                break;
+            }
+        }
+    });
+    let conn = Arc::new(Connection::new(out_tx));
+    // Requests without an id are answered one at a time, in order.
+    let mut ordered = None;
 
     while let Some(msg) = read.next().await {
         let msg = match msg {
@@ -102,21 +229,21 @@ async fn handle_connection<W: WifiManager>(
         };
 
         match msg {
-            Message::Text(text) => {
-                debug!("Received from {}: {}", addr, text);
-                let response = handle_command(&text, &ctx).await;
-                let response_json = serde_json::to_string(&response)?;
-                debug!("Sending to {}: {}", addr, response_json);
-                write.send(Message::Text(response_json.into())).await?;
+            Message::Text(mut text) => {
+                // The text may hold a password, so only the parsed command
+                // (whose `Debug` redacts it) is logged.
+                let request = parse_request(&text);
+                secret::zeroize_string(&mut text);
+                dispatch(request, &ctx, &conn, &mut ordered).await;
             }
             Message::Binary(_) => {
                 // Binary messages not supported.
-                let resp = Response::Error(ErrorResponse::new("Binary messages not supported"));
-                let json = serde_json::to_string(&resp)?;
-                write.send(Message::Text(json.into())).await?;
+                conn.send(&Response::Error(ErrorResponse::new(
+                    "Binary messages not supported",
+                )));
             }
             Message::Ping(data) => {
-                write.send(Message::Pong(data)).await?;
+                let _ = conn.out.send(Message::Pong(data));
             }
             Message::Pong(_) => {
                 // Ignore pong.
@@ -131,59 +258,268 @@ async fn handle_connection<W: WifiManager>(
         }
     }
 
+    // Requests still running finish and reply into the void. Dropping the
+    // last reference to the connection ends the event forwarding, and then
+    // the writer once everything queued has been sent.
+    drop(conn);
+    let _ = writer.await;
+
     info!("Connection closed: {}", addr);
     Ok(())
 }
 
-/// Parse and handle a command, returning the appropriate response.
-async fn handle_command<W: WifiManager>(text: &str, ctx: &HandlerContext<W>) -> Response {
-    let cmd = match serde_json::from_str::<Command>(text) {
-        Ok(cmd) => cmd,
-        Err(e) => {
-            return Response::Error(ErrorResponse::new(format!("Invalid command: {}", e)));
+/// A parsed request, or the id (if any) and error of one that didn't parse.
+type ParsedRequest = Result<Request, (Option<RequestId>, String)>;
+
+/// Parse a request. Commands this daemon doesn't know get their own error,
+/// so newer clients can tell them from malformed ones.
+fn parse_request(text: &str) -> ParsedRequest {
+    serde_json::from_str(text).map_err(|e| {
+        let header = RequestHeader::parse(text);
+        let error = match header.cmd {
+            Some(cmd) if !Command::NAMES.contains(&cmd.as_str()) => {
+                format!("Unknown command {:?}", cmd)
+            }
+            _ => format!("Invalid command: {}", e),
+        };
+        (header.id, error)
+    })
+}
+
+/// Run a request and send its reply.
+///
+/// `cancel` is answered at once. Requests with an id run concurrently;
+/// those without one run one after another, so their replies stay in order
+/// for clients that match replies to commands by position.
+async fn dispatch<W: WifiManager + 'static, B: BleControl + 'static>(
+    request: ParsedRequest,
+    ctx: &Arc<HandlerContext<W, B>>,
+    conn: &Arc<Connection>,
+    ordered: &mut Option<JoinHandle<()>>,
+) {
+    let (id, command) = match request {
+        Ok(Request { id, command }) => {
+            debug!("Received command: {:?}", command);
+            (id, Ok(command))
+        }
+        Err((id, error)) => (id, Err(error)),
+    };
+    let immediate = matches!(command, Ok(Command::Cancel { .. }));
+
+    let cancel = match &id {
+        Some(id) => match conn.start(id) {
+            Some(cancel) => cancel,
+            None => {
+                let message = format!("Request {} is already in progress", id);
+                conn.send(&Response::Error(ErrorResponse::new(message)).with_id(Some(id.clone())));
+                return;
+            }
+        },
+        // Never signalled: there is no id to cancel it by.
+        None => watch::channel(false).1,
+    };
+
+    let concurrent = id.is_some();
+    let reply = {
+        let ctx = Arc::clone(ctx);
+        let conn = Arc::clone(conn);
+        async move {
+            let response = match command {
+                Ok(command) => handle_command(command, &ctx, &conn, id.as_ref(), cancel).await,
+                Err(error) => Response::Error(ErrorResponse::new(error)),
+            };
+            if let Some(id) = &id {
+                conn.finish(id);
+            }
+            conn.send(&response.with_id(id));
         }
     };
 
+    if immediate {
+        reply.await;
+    } else if concurrent {
+        tokio::spawn(reply);
+    } else {
+        let previous = ordered.take();
+        *ordered = Some(tokio::spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+            reply.await;
+        }));
+    }
+}
+
+/// Wait until `cancel` is signalled; never, if its sender is gone.
+async fn cancelled(mut cancel: watch::Receiver<bool>) {
+    if cancel.wait_for(|&cancelled| cancelled).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Handle a command, returning the appropriate response.
+///
+/// Progress and events for the client are sent on `conn`. `scan` and
+/// `connect` stop early once `cancel` is signalled.
+async fn handle_command<W: WifiManager, B: BleControl>(
+    cmd: Command,
+    ctx: &HandlerContext<W, B>,
+    conn: &Connection,
+    id: Option<&RequestId>,
+    cancel: watch::Receiver<bool>,
+) -> Response {
     match cmd {
         Command::Start { timeout } => handle_start(timeout, ctx).await,
         Command::Stop => handle_stop(ctx).await,
         Command::Status => handle_status(ctx).await,
-        Command::Scan => handle_scan(ctx).await,
+        Command::Scan => tokio::select! {
+            biased;
+            () = cancelled(cancel) => {
+                info!("Scan cancelled");
+                Response::Error(ErrorResponse::new("Cancelled").with_code(ErrorCode::Cancelled))
+            }
+            response = handle_scan(ctx) => response,
+        },
+        Command::Authorize => handle_authorize(ctx).await,
+        Command::ListSaved => handle_list_saved(ctx).await,
+        Command::Forget { profile } => {
+            info!("Forgetting saved network {}", profile);
+            let result = ctx.wifi.forget(&profile).await;
+            handle_saved_change("Forget", result, ctx).await
+        }
+        Command::SetPriority { profile, priority } => {
+            info!("Setting priority of saved network {} to {}", profile, priority);
+            let result = ctx.wifi.set_priority(&profile, priority).await;
+            handle_saved_change("Set priority", result, ctx).await
+        }
+        Command::SetAutoconnect {
+            profile,
+            autoconnect,
+        } => {
+            info!("Setting autoconnect of saved network {} to {}", profile, autoconnect);
+            let result = ctx.wifi.set_autoconnect(&profile, autoconnect).await;
+            handle_saved_change("Set autoconnect", result, ctx).await
+        }
+        Command::Connect {
+            ssid,
+            ssid_hex,
+            password,
+            enterprise,
+            ip,
+            hidden,
+            security,
+        } => {
+            let Some(ssid) = Ssid::from_json(&ssid, ssid_hex.as_deref()) else {
+                return Response::Error(ErrorResponse::new("Invalid ssid_hex"));
+            };
+            let credentials = match enterprise {
+                Some(enterprise) => Credentials::Enterprise(enterprise),
+                None => Credentials::Psk(password),
+            };
+            let request = ConnectRequest {
+                hidden,
+                ip: *ip,
+                ..ConnectRequest::new(ssid, credentials)
+            };
+            let checked = request
+                .validate()
+                .and_then(|()| security.map_or(Ok(()), |s| request.check_security(s)));
+            if let Err(e) = checked {
+                return Response::Error(ErrorResponse::new(e.to_string()));
+            }
+            handle_connect(request, ctx, conn, id, cancel).await
+        }
+        Command::SetCountry { country } => handle_set_country(&country, ctx).await,
+        Command::Subscribe => handle_subscribe(ctx, conn).await,
+        Command::Cancel { target } => handle_cancel(&target, ctx, conn).await,
+        Command::Hello => {
+            let state = ctx.state.read().await;
+            Response::Ok(OkResponse::new(state.state).with_hello(ctx.hello.clone()))
+        }
     }
 }
 
 /// Handle the "start" command - begin BLE advertising.
-async fn handle_start<W: WifiManager>(timeout: u32, ctx: &HandlerContext<W>) -> Response {
-    let mut state = ctx.state.write().await;
+async fn handle_start<W: WifiManager, B: BleControl>(
+    timeout: u32,
+    ctx: &HandlerContext<W, B>,
+) -> Response {
+    let input = Input::StartAdvertising { timeout };
+    let _advertising = ctx.ble.advertising_lock().lock().await;
+    if let Err(e) = ctx.state.read().await.check(&input) {
+        return Response::Error(ErrorResponse::new(e.to_string()));
+    }
+
+    let was_advertising = ctx.ble.is_advertising().await;
+    if let Err(e) = ctx.ble.start_advertising().await {
+        error!("Failed to start advertising: {}", e);
+        return Response::Error(ErrorResponse::new(format!(
+            "Failed to start advertising: {}",
+            e
+        )));
+    }
 
-    // TODO: Actually start BLE advertising in Phase 3.
-    state.state = State::Advertising;
-    state.advertising_remaining = Some(timeout);
+    // The state may have moved on (e.g. to provisioning) while BlueZ was
+    // busy; if so, put advertising back.
+    let mut state = ctx.state.write().await;
+    if let Err(e) = state.handle(input) {
+        drop(state);
+        if !was_advertising {
+            if let Err(e) = ctx.ble.stop_advertising().await {
+                warn!("Failed to stop advertising: {}", e);
+            }
+        }
+        return Response::Error(ErrorResponse::new(e.to_string()));
+    }
 
     info!("Started advertising with timeout {}s", timeout);
 
     Response::Ok(
-        OkResponse::new(State::Advertising)
+        OkResponse::new(state.state)
             .with_remaining(timeout)
             .with_wifi_connected(state.wifi_connected),
     )
 }
 
 /// Handle the "stop" command - stop BLE advertising.
-async fn handle_stop<W: WifiManager>(ctx: &HandlerContext<W>) -> Response {
-    let mut state = ctx.state.write().await;
+async fn handle_stop<W: WifiManager, B: BleControl>(
+    ctx: &HandlerContext<W, B>,
+) -> Response {
+    let _advertising = ctx.ble.advertising_lock().lock().await;
+    if let Err(e) = ctx.state.read().await.check(&Input::StopAdvertising) {
+        return Response::Error(ErrorResponse::new(e.to_string()));
+    }
+
+    let was_advertising = ctx.ble.is_advertising().await;
+    if let Err(e) = ctx.ble.stop_advertising().await {
+        error!("Failed to stop advertising: {}", e);
+        return Response::Error(ErrorResponse::new(format!(
+            "Failed to stop advertising: {}",
+            e
+        )));
+    }
 
-    // TODO: Actually stop BLE advertising in Phase 3.
-    state.state = State::Idle;
-    state.advertising_remaining = None;
+    // As in `handle_start`, the state may have moved on meanwhile.
+    let mut state = ctx.state.write().await;
+    if let Err(e) = state.handle(Input::StopAdvertising) {
+        drop(state);
+        if was_advertising {
+            if let Err(e) = ctx.ble.start_advertising().await {
+                warn!("Failed to restart advertising: {}", e);
+            }
+        }
+        return Response::Error(ErrorResponse::new(e.to_string()));
+    }
 
     info!("Stopped advertising");
 
-    Response::Ok(OkResponse::new(State::Idle).with_wifi_connected(state.wifi_connected))
+    Response::Ok(OkResponse::new(state.state).with_wifi_connected(state.wifi_connected))
 }
 
 /// Handle the "status" command - return current daemon state.
-async fn handle_status<W: WifiManager>(ctx: &HandlerContext<W>) -> Response {
+async fn handle_status<W: WifiManager, B: BleControl>(
+    ctx: &HandlerContext<W, B>,
+) -> Response {
     // Check real WiFi status.
     let wifi_connected = match ctx.wifi.status().await {
         Ok(status) => status.connected,
@@ -192,20 +528,41 @@ async fn handle_status<W: WifiManager>(ctx: &HandlerContext<W>) -> Response {
             false
         }
     };
+    let addresses = if wifi_connected {
+        Some(ctx.wifi.addresses().await)
+    } else {
+        None
+    };
+    let country = ctx.wifi.country().await.unwrap_or_else(|e| {
+        warn!("Failed to get WiFi country: {}", e);
+        None
+    });
 
     let state = ctx.state.read().await;
 
     let mut resp = OkResponse::new(state.state).with_wifi_connected(wifi_connected);
 
+    if let Some(addresses) = addresses {
+        resp = resp.with_addresses(addresses);
+    }
+    if let Some(country) = country {
+        resp = resp.with_country(country);
+    }
+
     if let Some(remaining) = state.advertising_remaining {
         resp = resp.with_remaining(remaining);
     }
+    if let Some(failure) = &state.last_failure {
+        resp = resp.with_connectivity_error(failure.clone());
+    }
 
     Response::Ok(resp)
 }
 
 /// Handle the "scan" command - scan for WiFi networks.
-async fn handle_scan<W: WifiManager>(ctx: &HandlerContext<W>) -> Response {
+async fn handle_scan<W: WifiManager, B: BleControl>(
+    ctx: &HandlerContext<W, B>,
+) -> Response {
     info!("Scanning for WiFi networks");
 
     match ctx.wifi.scan().await {
@@ -215,21 +572,234 @@ async fn handle_scan<W: WifiManager>(ctx: &HandlerContext<W>) -> Response {
         }
         Err(e) => {
             error!("WiFi scan failed: {}", e);
-            Response::Error(ErrorResponse::new(format!("Scan failed: {}", e)))
+            Response::Error(ErrorResponse::new(format!("Scan failed: {}", e)).with_code(e.code()))
+        }
+    }
+}
+
+/// Handle the "list_saved" command - list saved network profiles.
+async fn handle_list_saved<W: WifiManager, B: BleControl>(
+    ctx: &HandlerContext<W, B>,
+) -> Response {
+    match ctx.wifi.list_saved().await {
+        Ok(saved) => {
+            let state = ctx.state.read().await;
+            Response::Ok(OkResponse::new(state.state).with_saved(saved))
+        }
+        Err(e) => {
+            error!("Failed to list saved networks: {}", e);
+            let message = format!("List saved failed: {}", e);
+            Response::Error(ErrorResponse::new(message).with_code(e.code()))
         }
     }
 }
 
+/// Finish a saved-profile change, replying with the updated profile list.
+async fn handle_saved_change<W: WifiManager, B: BleControl>(
+    action: &str,
+    result: WifiResult<()>,
+    ctx: &HandlerContext<W, B>,
+) -> Response {
+    match result {
+        Ok(()) => handle_list_saved(ctx).await,
+        Err(e) => {
+            error!("{} failed: {}", action, e);
+            let message = format!("{} failed: {}", action, e);
+            Response::Error(ErrorResponse::new(message).with_code(e.code()))
+        }
+    }
+}
+
+/// Handle the "connect" command - join a network with verification.
+///
+/// Streams each `ProvisioningStage` to the client, tagged with the request
+/// `id`, and replies with the final WiFi status. A cancelled connect fails
+/// and rolls back like any other failure. Improv clients follow it through
+/// the state machine, as they do provisioning over BLE.
+async fn handle_connect<W: WifiManager, B: BleControl>(
+    request: ConnectRequest,
+    ctx: &HandlerContext<W, B>,
+    conn: &Connection,
+    id: Option<&RequestId>,
+    cancel: watch::Receiver<bool>,
+) -> Response {
+    info!("Connecting to {} on local request", request.ssid);
+
+    let started = Input::ProvisioningStarted(request.ssid.clone());
+    if let Err(e) = ctx.state.write().await.handle(started) {
+        return Response::Error(ErrorResponse::new(e.to_string()));
+    }
+
+    let result = wifi::connect_verified_cancellable(
+        &*ctx.wifi,
+        &request,
+        VERIFY_TIMEOUT,
+        |stage| {
+            conn.send(&Event::Progress {
+                id: id.cloned(),
+                stage,
+            })
+        },
+        cancelled(cancel),
+    )
+    .await;
+
+    if let Err(failure) = result {
+        error!("Connect failed: {}", failure);
+        let message = format!("Connect failed: {}", failure);
+        let code = failure.code;
+        let _ = ctx.state.write().await.handle(Input::ProvisioningFailed(failure));
+        return Response::Error(ErrorResponse::new(message).with_code(code));
+    }
+
+    // The reply reports the status as read back; it is left out rather than
+    // guessed if it can't be read.
+    let status = match ctx.wifi.status().await {
+        Ok(status) => Some(status),
+        Err(e) => {
+            warn!("Failed to get WiFi status: {}", e);
+            None
+        }
+    };
+    let addresses = ctx.wifi.addresses().await;
+
+    // Stop advertising under the advertising lock only, so status readers
+    // don't wait on BlueZ and a concurrent start can't slip in between.
+    let _advertising = ctx.ble.advertising_lock().lock().await;
+    let (state, advertising) = {
+        let mut state = ctx.state.write().await;
+        let advertising = state.advertising_remaining.is_some();
+        let _ = state.handle(Input::ProvisioningSucceeded { redirect_url: None });
+        (state.state, advertising)
+    };
+    if advertising {
+        if let Err(e) = ctx.ble.stop_advertising().await {
+            warn!("Failed to stop advertising: {}", e);
+        }
+    }
+
+    let mut resp = OkResponse::new(state).with_addresses(addresses);
+    if let Some(status) = &status {
+        resp = resp.with_wifi_status(status);
+    }
+    Response::Ok(resp)
+}
+
+/// Handle the "set_country" command - set and persist the regulatory country.
+async fn handle_set_country<W: WifiManager, B: BleControl>(
+    country: &str,
+    ctx: &HandlerContext<W, B>,
+) -> Response {
+    let country: CountryCode = match country.parse() {
+        Ok(country) => country,
+        Err(e) => return Response::Error(ErrorResponse::new(e.to_string())),
+    };
+
+    info!("Setting WiFi country to {}", country);
+    if let Err(e) = ctx.wifi.set_country(country).await {
+        error!("Failed to set WiFi country: {}", e);
+        let message = format!("Set country failed: {}", e);
+        return Response::Error(ErrorResponse::new(message).with_code(e.code()));
+    }
+
+    let state = ctx.state.read().await;
+    Response::Ok(OkResponse::new(state.state).with_country(country))
+}
+
+/// Handle the "subscribe" command - push events to this connection.
+///
+/// Replies with the current state, so the client can follow it from the
+/// events alone.
+async fn handle_subscribe<W: WifiManager, B: BleControl>(
+    ctx: &HandlerContext<W, B>,
+    conn: &Connection,
+) -> Response {
+    let state = ctx.state.read().await;
+    if conn.subscribe(state.subscribe()) {
+        info!("Client subscribed to events");
+    }
+
+    let mut resp = OkResponse::new(state.state).with_wifi_connected(state.wifi_connected);
+    if let Some(remaining) = state.advertising_remaining {
+        resp = resp.with_remaining(remaining);
+    }
+    Response::Ok(resp)
+}
+
+/// Handle the "cancel" command - stop a request still running on `conn`.
+///
+/// The cancelled request replies for itself. Only `scan` and `connect` stop
+/// early; other requests finish as usual.
+async fn handle_cancel<W: WifiManager, B: BleControl>(
+    target: &RequestId,
+    ctx: &HandlerContext<W, B>,
+    conn: &Connection,
+) -> Response {
+    if !conn.cancel(target) {
+        return Response::Error(ErrorResponse::new(format!(
+            "No request {} in progress",
+            target
+        )));
+    }
+    info!("Cancelling request {}", target);
+
+    let state = ctx.state.read().await;
+    Response::Ok(OkResponse::new(state.state))
+}
+
+/// Handle the "authorize" command - confirm the BLE client locally.
+async fn handle_authorize<W: WifiManager, B: BleControl>(
+    ctx: &HandlerContext<W, B>,
+) -> Response {
+    let improv_state = ctx.ble.authorize().await;
+    info!("Local authorization confirmed ({:?})", improv_state);
+
+    let state = ctx.state.read().await;
+    Response::Ok(OkResponse::new(state.state).with_wifi_connected(state.wifi_connected))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::protocol::Network;
+    use crate::ble::{BleConfig, MockBleControl};
+    use crate::connectivity::ConnectivityFailure;
+    use crate::improv::ImprovState;
+    use crate::protocol::{Network, SavedNetwork, State};
+    use crate::session::{SessionHarness, SessionOutput};
     use crate::wifi::{MockWifiManager, WifiStatus};
 
-    fn make_ctx(wifi: MockWifiManager) -> HandlerContext<MockWifiManager> {
+    /// Handle a command on a connection that is not subscribed.
+    async fn handle_command(
+        text: &str,
+        ctx: &HandlerContext<MockWifiManager, MockBleControl>,
+    ) -> Response {
+        let conn = Connection::new(mpsc::unbounded_channel().0);
+        handle_command_on(text, ctx, &conn).await
+    }
+
+    /// Handle a command on `conn`, without running it as a task.
+    async fn handle_command_on(
+        text: &str,
+        ctx: &HandlerContext<MockWifiManager, MockBleControl>,
+        conn: &Connection,
+    ) -> Response {
+        match parse_request(text) {
+            Ok(Request { id, command }) => {
+                let cancel = watch::channel(false).1;
+                super::handle_command(command, ctx, conn, id.as_ref(), cancel)
+                    .await
+                    .with_id(id)
+            }
+            Err((id, error)) => Response::Error(ErrorResponse::new(error)).with_id(id),
+        }
+    }
+
+    fn make_ctx(wifi: MockWifiManager) -> HandlerContext<MockWifiManager, MockBleControl> {
         HandlerContext {
-            state: Arc::new(RwLock::new(DaemonState::default())),
+            state: Arc::new(RwLock::new(StateMachine::default())),
             wifi: Arc::new(wifi),
+            ble: Arc::new(MockBleControl::default()),
+            hello: Hello::new(vec![Transport::Websocket], "mock"),
         }
     }
 
@@ -248,6 +818,94 @@ mod tests {
     }
 
     #[tokio::test]
+    async fn handle_status_reports_last_connectivity_failure() {
+        let ctx = make_ctx(MockWifiManager::default());
+        let failure = ConnectivityFailure::new(
+            crate::connectivity::ConnectivityStep::Gateway,
+            "no default route",
+        );
+        {
+            let mut state = ctx.state.write().await;
+            state.handle(Input::ProvisioningStarted("home".into())).unwrap();
+            state.handle(Input::ProvisioningFailed(failure.clone())).unwrap();
+        }
+
+        match handle_command(r#"{"cmd":"status"}"#, &ctx).await {
+            Response::Ok(ok) => assert_eq!(ok.connectivity_error, Some(failure)),
+            Response::Error(_) => panic!("Expected Ok response"),
+        }
+    }
+
+    fn saved(id: &str, ssid: &str) -> SavedNetwork {
+        SavedNetwork {
+            id: id.into(),
+            ssid: ssid.into(),
+            ssid_hex: None,
+            priority: 0,
+            autoconnect: true,
+            persistent: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_list_saved_returns_profiles() {
+        let wifi = MockWifiManager::default();
+        *wifi.saved.lock().unwrap() = vec![saved("1", "home"), saved("2", "cafe")];
+        let ctx = make_ctx(wifi);
+
+        match handle_command(r#"{"cmd":"list_saved"}"#, &ctx).await {
+            Response::Ok(ok) => assert_eq!(ok.saved.unwrap().len(), 2),
+            Response::Error(e) => panic!("Expected Ok response, got {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_forget_returns_remaining_profiles() {
+        let wifi = MockWifiManager::default();
+        *wifi.saved.lock().unwrap() = vec![saved("1", "home"), saved("2", "cafe")];
+        let ctx = make_ctx(wifi);
+
+        match handle_command(r#"{"cmd":"forget","profile":"2"}"#, &ctx).await {
+            Response::Ok(ok) => assert_eq!(ok.saved, Some(vec![saved("1", "home")])),
+            Response::Error(e) => panic!("Expected Ok response, got {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_forget_unknown_profile_is_an_error() {
+        let ctx = make_ctx(MockWifiManager::default());
+
+        match handle_command(r#"{"cmd":"forget","profile":"9"}"#, &ctx).await {
+            Response::Error(e) => assert!(e.error.contains("No saved network with id 9")),
+            Response::Ok(_) => panic!("Expected Error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_set_priority_and_autoconnect() {
+        let wifi = MockWifiManager::default();
+        *wifi.saved.lock().unwrap() = vec![saved("1", "home")];
+        let ctx = make_ctx(wifi);
+
+        handle_command(r#"{"cmd":"set_priority","profile":"1","priority":7}"#, &ctx).await;
+        let resp = handle_command(
+            r#"{"cmd":"set_autoconnect","profile":"1","autoconnect":false}"#,
+            &ctx,
+        )
+        .await;
+
+        match resp {
+            Response::Ok(ok) => {
+                let profile = &ok.saved.unwrap()[0];
+                assert_eq!(profile.priority, 7);
+                assert!(!profile.autoconnect);
+            }
+            Response::Error(e) => panic!("Expected Ok response, got {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    #[allow(clippy::field_reassign_with_default)]
     async fn handle_status_shows_wifi_connected() {
         let mut wifi = MockWifiManager::default();
         wifi.status = WifiStatus {
@@ -282,6 +940,30 @@ mod tests {
         // Verify state was actually updated.
         let state = ctx.state.read().await;
         assert_eq!(state.state, State::Advertising);
+        assert!(ctx.ble.is_advertising().await);
+    }
+
+    #[tokio::test]
+    async fn handle_start_reports_advertising_failure() {
+        let mut ctx = make_ctx(MockWifiManager::default());
+        ctx.ble = Arc::new(MockBleControl {
+            start_error: Some("adapter powered off".into()),
+            ..Default::default()
+        });
+
+        let resp = handle_command(r#"{"cmd":"start"}"#, &ctx).await;
+
+        match resp {
+            Response::Error(err) => {
+                assert!(err.error.contains("adapter powered off"));
+            }
+            Response::Ok(_) => panic!("Expected Error response"),
+        }
+
+        // State must not claim we are advertising.
+        let state = ctx.state.read().await;
+        assert_eq!(state.state, State::Idle);
+        assert!(state.advertising_remaining.is_none());
     }
 
     #[tokio::test]
@@ -289,11 +971,10 @@ mod tests {
         let ctx = make_ctx(MockWifiManager::default());
 
         // Set initial state to advertising.
-        {
-            let mut state = ctx.state.write().await;
-            state.state = State::Advertising;
-            state.advertising_remaining = Some(100);
-        }
+        let start = Input::StartAdvertising { timeout: 100 };
+        ctx.state.write().await.handle(start).unwrap();
+
+        ctx.ble.start_advertising().await.unwrap();
 
         let resp = handle_command(r#"{"cmd":"stop"}"#, &ctx).await;
 
@@ -304,21 +985,69 @@ mod tests {
             }
             Response::Error(_) => panic!("Expected Ok response"),
         }
+
+        assert!(!ctx.ble.is_advertising().await);
     }
 
     #[tokio::test]
+    async fn handle_start_and_connect_run_concurrently() {
+        let gate = Arc::new(tokio::sync::Notify::new());
+        let mut ctx = make_ctx(MockWifiManager::default());
+        ctx.ble = Arc::new(MockBleControl {
+            start_gate: Some(Arc::clone(&gate)),
+            ..Default::default()
+        });
+
+        let start = handle_command(r#"{"cmd":"start","timeout":120}"#, &ctx);
+        let connect = async {
+            // Wait until start is waiting on BlueZ.
+            while ctx.ble.advertising_lock().try_lock().is_ok() {
+                tokio::task::yield_now().await;
+            }
+            handle_command(r#"{"cmd":"connect","ssid":"home","password":"x"}"#, &ctx).await
+        };
+        let release = async {
+            // Connecting proceeds, and status stays readable, while BlueZ
+            // is busy.
+            let provisioning = async {
+                while ctx.state.read().await.state != State::Provisioning {
+                    tokio::task::yield_now().await;
+                }
+            };
+            tokio::time::timeout(std::time::Duration::from_secs(5), provisioning)
+                .await
+                .expect("state lock held across BlueZ");
+            gate.notify_one();
+        };
+        let (start, connect, ()) = tokio::join!(start, connect, release);
+
+        // Start finished mid-provisioning, so it was refused and undone.
+        assert!(matches!(start, Response::Error(_)));
+        match connect {
+            Response::Ok(ok) => assert_eq!(ok.state, State::Provisioned),
+            Response::Error(e) => panic!("Expected Ok response, got {:?}", e),
+        }
+        let state = ctx.state.read().await;
+        assert!(state.advertising_remaining.is_none());
+        assert!(!ctx.ble.is_advertising().await);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::field_reassign_with_default)]
     async fn handle_scan_returns_networks_from_wifi_manager() {
         let mut wifi = MockWifiManager::default();
         wifi.networks = vec![
             Network {
                 ssid: "Network1".into(),
-                signal: -45,
+                signal: Some(-45),
                 security: "wpa2".into(),
+                ..Default::default()
             },
             Network {
                 ssid: "Network2".into(),
-                signal: -60,
+                signal: Some(-60),
                 security: "open".into(),
+                ..Default::default()
             },
         ];
         let ctx = make_ctx(wifi);
@@ -337,6 +1066,18 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn handle_authorize_authorizes_ble_client() {
+        let ctx = make_ctx(MockWifiManager::default());
+        let resp = handle_command(r#"{"cmd":"authorize"}"#, &ctx).await;
+
+        assert!(matches!(resp, Response::Ok(_)));
+        assert!(ctx
+            .ble
+            .authorized
+            .load(std::sync::atomic::Ordering::SeqCst));
+    }
+
     #[tokio::test]
     async fn handle_invalid_command_returns_error() {
         let ctx = make_ctx(MockWifiManager::default());
@@ -344,10 +1085,41 @@ mod tests {
 
         match resp {
             Response::Error(err) => {
-                assert!(err.error.contains("Invalid command"));
+                assert_eq!(err.error, r#"Unknown command "invalid""#);
             }
             Response::Ok(_) => panic!("Expected Error response"),
         }
+
+        match handle_command(r#"{"cmd":"start","timeout":"soon"}"#, &ctx).await {
+            Response::Error(err) => assert!(err.error.starts_with("Invalid command")),
+            Response::Ok(_) => panic!("Expected Error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_fields_are_ignored() {
+        let ctx = make_ctx(MockWifiManager::default());
+        let resp = handle_command(r#"{"cmd":"status","id":1,"verbose":true}"#, &ctx).await;
+
+        match resp {
+            Response::Ok(ok) => assert_eq!(ok.id, Some(RequestId::Number(1))),
+            Response::Error(e) => panic!("Expected Ok response, got {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn hello_reports_capabilities() {
+        let ctx = make_ctx(MockWifiManager::default());
+        let json = serde_json::to_value(handle_command(r#"{"cmd":"hello"}"#, &ctx).await).unwrap();
+
+        let hello = &json["hello"];
+        assert_eq!(hello["protocol_version"], crate::protocol::PROTOCOL_VERSION);
+        assert_eq!(hello["daemon_version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(hello["transports"], serde_json::json!(["websocket"]));
+        assert_eq!(hello["wifi_backend"], "mock");
+        let commands = hello["commands"].as_array().unwrap();
+        assert!(commands.contains(&serde_json::json!("connect")));
+        assert!(commands.contains(&serde_json::json!("hello")));
     }
 
     #[tokio::test]
@@ -362,4 +1134,392 @@ mod tests {
             Response::Ok(_) => panic!("Expected Error response"),
         }
     }
+
+    #[tokio::test]
+    async fn handle_connect_enterprise_network() {
+        let ctx = make_ctx(MockWifiManager::default());
+        let resp = handle_command(
+            r#"{"cmd":"connect","ssid":"eduroam","enterprise":
+                {"eap":"peap","identity":"alice","password":"hunter22"}}"#,
+            &ctx,
+        )
+        .await;
+
+        match resp {
+            Response::Ok(ok) => assert_eq!(ok.wifi_connected, Some(true)),
+            Response::Error(e) => panic!("Expected Ok response, got {:?}", e),
+        }
+        let request = ctx.wifi.last_request.lock().unwrap().clone().unwrap();
+        assert_eq!(request.ssid, "eduroam");
+        assert!(matches!(request.credentials, Credentials::Enterprise(_)));
+        assert!(ctx.state.read().await.wifi_connected);
+    }
+
+    #[tokio::test]
+    async fn handle_connect_notifies_improv_clients() {
+        let mut improv = SessionHarness::new(MockWifiManager::default(), BleConfig::default());
+        improv.settle().await;
+        let mut ctx = make_ctx(MockWifiManager::default());
+        ctx.state = Arc::clone(&improv.machine);
+
+        match handle_command(r#"{"cmd":"connect","ssid":"home","password":"x"}"#, &ctx).await {
+            Response::Ok(ok) => assert_eq!(ok.state, State::Provisioned),
+            Response::Error(e) => panic!("Expected Ok response, got {:?}", e),
+        }
+
+        assert_eq!(
+            improv.settle().await,
+            vec![
+                SessionOutput::State(ImprovState::Provisioning),
+                SessionOutput::State(ImprovState::Provisioned),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn handle_connect_failure_is_recorded() {
+        let ctx = make_ctx(MockWifiManager {
+            connect_result: Err("auth failed".into()),
+            ..Default::default()
+        });
+
+        match handle_command(r#"{"cmd":"connect","ssid":"home","password":"x"}"#, &ctx).await {
+            Response::Error(e) => assert!(e.error.starts_with("Connect failed")),
+            Response::Ok(_) => panic!("Expected Error response"),
+        }
+        let state = ctx.state.read().await;
+        assert_eq!(state.state, State::Idle);
+        assert!(state.last_failure.is_some());
+    }
+
+    #[tokio::test]
+    async fn handle_connect_failure_carries_error_code() {
+        let ctx = make_ctx(MockWifiManager {
+            connect_result: Err("No network with SSID 'home' found".into()),
+            ..Default::default()
+        });
+
+        let resp = handle_command(r#"{"cmd":"connect","ssid":"home","password":"x"}"#, &ctx).await;
+        let json = serde_json::to_value(&resp).unwrap();
+        assert_eq!(json["code"], 3);
+        assert_eq!(json["ok"], false);
+    }
+
+    #[tokio::test]
+    async fn handle_connect_applies_ip_settings() {
+        let ctx = make_ctx(MockWifiManager::default());
+        let json = r#"{"cmd":"connect","ssid":"home","password":"x",
+            "ip":{"ipv4":{"method":"manual","addresses":["10.0.0.2/8"]},"dns":["10.0.0.1"]}}"#;
+
+        match handle_command(json, &ctx).await {
+            Response::Ok(ok) => assert_eq!(ok.wifi_connected, Some(true)),
+            Response::Error(e) => panic!("Expected Ok response, got {:?}", e),
+        }
+        let request = ctx.wifi.last_request.lock().unwrap().clone().unwrap();
+        assert_eq!(request.ip.dns, ["10.0.0.1".parse::<std::net::IpAddr>().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn handle_connect_rejects_invalid_ip_settings() {
+        let ctx = make_ctx(MockWifiManager::default());
+        let json = r#"{"cmd":"connect","ssid":"home","ip":{"ipv4":{"method":"manual"}}}"#;
+
+        match handle_command(json, &ctx).await {
+            Response::Error(e) => assert!(e.error.starts_with("Invalid IP settings")),
+            Response::Ok(_) => panic!("Expected Error response"),
+        }
+        assert!(ctx.wifi.last_request.lock().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn handle_status_reports_addresses_when_connected() {
+        let ctx = make_ctx(MockWifiManager {
+            status: crate::wifi::WifiStatus {
+                connected: true,
+                ssid: Some("home".into()),
+            },
+            addresses: vec!["192.168.1.50/24".parse().unwrap()],
+            ..Default::default()
+        });
+
+        let json = serde_json::to_value(handle_status(&ctx).await).unwrap();
+        assert_eq!(json["addresses"], serde_json::json!(["192.168.1.50/24"]));
+
+        let ctx = make_ctx(MockWifiManager::default());
+        let json = serde_json::to_value(handle_status(&ctx).await).unwrap();
+        assert!(json.get("addresses").is_none());
+    }
+
+    #[tokio::test]
+    async fn handle_set_country_updates_status() {
+        let ctx = make_ctx(MockWifiManager::default());
+
+        let json = serde_json::to_value(handle_status(&ctx).await).unwrap();
+        assert!(json.get("country").is_none());
+
+        let resp = handle_command(r#"{"cmd":"set_country","country":"gb"}"#, &ctx).await;
+        let json = serde_json::to_value(resp).unwrap();
+        assert_eq!(json["ok"], true);
+        assert_eq!(json["country"], "GB");
+
+        let json = serde_json::to_value(handle_status(&ctx).await).unwrap();
+        assert_eq!(json["country"], "GB");
+    }
+
+    #[tokio::test]
+    async fn handle_set_country_rejects_bad_codes() {
+        let ctx = make_ctx(MockWifiManager::default());
+
+        match handle_command(r#"{"cmd":"set_country","country":"GBR"}"#, &ctx).await {
+            Response::Error(e) => assert_eq!(e.error, r#"Invalid country code "GBR""#),
+            Response::Ok(_) => panic!("Expected Error response"),
+        }
+        assert_eq!(*ctx.wifi.country.lock().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn handle_connect_streams_progress_and_reports_status() {
+        let ctx = make_ctx(MockWifiManager {
+            addresses: vec!["192.168.1.50/24".parse().unwrap()],
+            ..Default::default()
+        });
+        let (out, mut sent) = mpsc::unbounded_channel();
+        let conn = Connection::new(out);
+
+        let json = r#"{"cmd":"connect","ssid":"home","password":"hunter22","hidden":true}"#;
+        let resp = handle_command_on(json, &ctx, &conn).await;
+
+        let mut stages = Vec::new();
+        while let Ok(Message::Text(text)) = sent.try_recv() {
+            let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+            assert_eq!(event["event"], "progress");
+            stages.push(event["stage"].as_str().unwrap().to_string());
+        }
+        assert_eq!(stages, ["provisioning", "verifying", "connected"]);
+
+        let json = serde_json::to_value(resp).unwrap();
+        assert_eq!(json["ok"], true);
+        assert_eq!(json["wifi_connected"], true);
+        assert_eq!(json["ssid"], "home");
+        assert_eq!(json["addresses"], serde_json::json!(["192.168.1.50/24"]));
+        assert!(ctx.wifi.last_request.lock().unwrap().as_ref().unwrap().hidden);
+    }
+
+    #[tokio::test]
+    async fn handle_connect_checks_security_hint() {
+        let ctx = make_ctx(MockWifiManager::default());
+        let json = r#"{"cmd":"connect","ssid":"office","password":"x",
+            "security":"wpa2-enterprise"}"#;
+
+        match handle_command(json, &ctx).await {
+            Response::Error(e) => assert_eq!(
+                e.error,
+                "Network is wpa2-enterprise: enterprise credentials are required"
+            ),
+            Response::Ok(_) => panic!("Expected Error response"),
+        }
+        assert!(ctx.wifi.last_request.lock().unwrap().is_none());
+
+        match handle_command(r#"{"cmd":"connect","ssid":"","password":"x"}"#, &ctx).await {
+            Response::Error(e) => assert_eq!(e.error, "SSID must be 1 to 32 bytes"),
+            Response::Ok(_) => panic!("Expected Error response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_connect_publishes_progress() {
+        let ctx = make_ctx(MockWifiManager::default());
+        let mut events = ctx.state.read().await.subscribe();
+
+        handle_command(r#"{"cmd":"connect","ssid":"home","password":"x"}"#, &ctx).await;
+
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        assert_eq!(
+            received,
+            vec![
+                Event::State {
+                    state: State::Provisioning
+                },
+                Event::provisioning_started(&Ssid::from("home")),
+                Event::State {
+                    state: State::Provisioned
+                },
+                Event::ProvisioningComplete { redirect_url: None },
+            ]
+        );
+    }
+
+    /// Read the next message from a client connection as JSON.
+    async fn next_json(
+        ws: &mut tokio_tungstenite::WebSocketStream<
+            tokio_tungstenite::MaybeTlsStream<TcpStream>,
+        >,
+    ) -> serde_json::Value {
+        match ws.next().await {
+            Some(Ok(Message::Text(text))) => serde_json::from_str(&text).unwrap(),
+            other => panic!("Expected text message, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribed_connections_receive_events() {
+        let ctx = Arc::new(make_ctx(MockWifiManager::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_ctx = Arc::clone(&ctx);
+        tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            handle_connection(stream, peer, server_ctx).await.unwrap();
+        });
+
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+
+        // Not subscribed yet: nothing is pushed.
+        {
+            let mut state = ctx.state.write().await;
+            state.handle(Input::StartAdvertising { timeout: 60 }).unwrap();
+            state.handle(Input::ClientConnected).unwrap();
+        }
+
+        ws.send(Message::Text(r#"{"cmd":"subscribe"}"#.into())).await.unwrap();
+        let resp = next_json(&mut ws).await;
+        assert_eq!(resp["ok"], true);
+        assert_eq!(resp["state"], "connected");
+
+        ctx.state.write().await.handle(Input::ClientDisconnected).unwrap();
+        ctx.state.read().await.publish(Event::Identify);
+
+        let event = next_json(&mut ws).await;
+        assert_eq!(event, serde_json::json!({"event":"state","state":"advertising"}));
+        let event = next_json(&mut ws).await;
+        assert_eq!(event, serde_json::json!({"event":"client_disconnected"}));
+        let event = next_json(&mut ws).await;
+        assert_eq!(event, serde_json::json!({"event":"identify"}));
+    }
+
+    #[tokio::test]
+    async fn commands_are_rejected_while_provisioning() {
+        let ctx = make_ctx(MockWifiManager::default());
+        let started = Input::ProvisioningStarted("other".into());
+        ctx.state.write().await.handle(started).unwrap();
+
+        match handle_command(r#"{"cmd":"start"}"#, &ctx).await {
+            Response::Error(e) => {
+                assert_eq!(e.error, "Cannot start advertising while provisioning")
+            }
+            Response::Ok(_) => panic!("Expected Error response"),
+        }
+        assert!(!ctx.ble.is_advertising().await);
+
+        match handle_command(r#"{"cmd":"connect","ssid":"home","password":"x"}"#, &ctx).await {
+            Response::Error(e) => {
+                assert_eq!(e.error, "Cannot start provisioning while provisioning")
+            }
+            Response::Ok(_) => panic!("Expected Error response"),
+        }
+        assert!(ctx.wifi.last_request.lock().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn handle_connect_stops_advertising() {
+        let ctx = make_ctx(MockWifiManager::default());
+        handle_command(r#"{"cmd":"start"}"#, &ctx).await;
+
+        match handle_command(r#"{"cmd":"connect","ssid":"home","password":"x"}"#, &ctx).await {
+            Response::Ok(ok) => {
+                assert_eq!(ok.state, State::Provisioned);
+                assert!(ok.remaining.is_none());
+            }
+            Response::Error(e) => panic!("Expected Ok response, got {:?}", e),
+        }
+        assert!(!ctx.ble.is_advertising().await);
+    }
+
+    #[tokio::test]
+    async fn requests_with_ids_run_concurrently_and_can_be_cancelled() {
+        // Verification keeps failing, so the connect runs until cancelled.
+        let ctx = Arc::new(make_ctx(MockWifiManager {
+            network_check: Err(ConnectivityFailure::new(
+                crate::connectivity::ConnectivityStep::Dns,
+                "timed out",
+            )),
+            ..Default::default()
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_ctx = Arc::clone(&ctx);
+        tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            handle_connection(stream, peer, server_ctx).await.unwrap();
+        });
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr))
+            .await
+            .unwrap();
+
+        let connect = r#"{"cmd":"connect","ssid":"home","password":"x","hidden":true,"id":"c"}"#;
+        ws.send(Message::Text(connect.into())).await.unwrap();
+        assert_eq!(
+            next_json(&mut ws).await,
+            serde_json::json!({"event":"progress","id":"c","stage":"provisioning"})
+        );
+        assert_eq!(next_json(&mut ws).await["stage"], "verifying");
+
+        // Answered while the connect is still running.
+        ws.send(Message::Text(r#"{"cmd":"status","id":2}"#.into())).await.unwrap();
+        let resp = next_json(&mut ws).await;
+        assert_eq!(resp["id"], 2);
+        assert_eq!(resp["state"], "provisioning");
+
+        ws.send(Message::Text(connect.into())).await.unwrap();
+        let resp = next_json(&mut ws).await;
+        assert_eq!(resp["id"], "c");
+        assert_eq!(resp["error"], r#"Request "c" is already in progress"#);
+
+        ws.send(Message::Text(r#"{"cmd":"cancel","target":"c","id":3}"#.into()))
+            .await
+            .unwrap();
+        let mut replies = Vec::new();
+        while replies.len() < 3 {
+            replies.push(next_json(&mut ws).await);
+        }
+        let cancel = replies.iter().find(|r| r["id"] == 3).unwrap();
+        assert_eq!(cancel["ok"], true);
+        let failed = replies.iter().find(|r| r["event"] == "progress").unwrap();
+        assert_eq!(failed["stage"], "failed");
+        let resp = replies.iter().find(|r| r["id"] == "c" && r["ok"] == false).unwrap();
+        assert_eq!(resp["ok"], false);
+        assert_eq!(resp["code"], ErrorCode::Cancelled.number());
+
+        ws.send(Message::Text(r#"{"cmd":"cancel","target":"c"}"#.into()))
+            .await
+            .unwrap();
+        let resp = next_json(&mut ws).await;
+        assert_eq!(resp["error"], r#"No request "c" in progress"#);
+        assert_eq!(ctx.state.read().await.state, State::Idle);
+    }
+
+    #[tokio::test]
+    async fn invalid_requests_echo_their_id() {
+        let ctx = make_ctx(MockWifiManager::default());
+
+        let resp = handle_command(r#"{"cmd":"bogus","id":9}"#, &ctx).await;
+        let json = serde_json::to_value(resp).unwrap();
+        assert_eq!(json["id"], 9);
+        assert_eq!(json["ok"], false);
+    }
+
+    #[tokio::test]
+    async fn handle_connect_rejects_bad_ssid_hex() {
+        let ctx = make_ctx(MockWifiManager::default());
+
+        match handle_command(r#"{"cmd":"connect","ssid":"","ssid_hex":"zz"}"#, &ctx).await {
+            Response::Error(e) => assert_eq!(e.error, "Invalid ssid_hex"),
+            Response::Ok(_) => panic!("Expected Error response"),
+        }
+    }
 }
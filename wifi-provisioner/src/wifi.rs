@@ -1,13 +1,32 @@
 //! WiFi management via NetworkManager (nmcli).
 //!
 //! Provides a trait-based abstraction for WiFi operations, with a real
-//! implementation using nmcli and a mock for testing.
+//! implementation using nmcli and a mock for testing. The D-Bus backend lives
+//! in `networkmanager` and the wpa_supplicant one in `wpa_supplicant`;
+//! `WifiBackend` picks one at runtime.
 
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
 use std::process::Stdio;
+use std::sync::atomic::{AtomicU32, Ordering};
 use tokio::process::Command;
 use tracing::{debug, error, info, warn};
 
-use crate::protocol::Network;
+use crate::connectivity::{self, ConnectivityFailure, ConnectivityResult, ConnectivityStep};
+use crate::ipconfig::{IpConfig, IpMethod, IpPrefix, IpSettings};
+use crate::networkmanager::NmDbusWifiManager;
+use crate::protocol::{
+    AccessPointInfo, EnterpriseCredentials, Network, ProvisioningStage, SavedNetwork, Security,
+};
+use crate::regdomain::{self, CountryCode};
+use crate::secret::Secret;
+use crate::ssid::Ssid;
+use crate::wpa_supplicant::WpaSupplicantWifiManager;
 
 /// Result type for WiFi operations.
 pub type WifiResult<T> = Result<T, WifiError>;
@@ -23,6 +42,14 @@ pub enum WifiError {
     NoWifiDevice,
     /// Connection attempt failed.
     ConnectionFailed(String),
+    /// NetworkManager D-Bus call failed.
+    DbusError(String),
+    /// wpa_supplicant control socket request failed.
+    ControlSocket(String),
+    /// No saved profile has this id.
+    UnknownProfile(String),
+    /// A request was rejected before reaching the backend.
+    Invalid(String),
 }
 
 impl std::fmt::Display for WifiError {
@@ -32,19 +59,312 @@ impl std::fmt::Display for WifiError {
             WifiError::ParseError(msg) => write!(f, "Failed to parse nmcli output: {}", msg),
             WifiError::NoWifiDevice => write!(f, "No WiFi device available"),
             WifiError::ConnectionFailed(msg) => write!(f, "Connection failed: {}", msg),
+            WifiError::DbusError(msg) => write!(f, "NetworkManager D-Bus call failed: {}", msg),
+            WifiError::ControlSocket(msg) => write!(f, "wpa_supplicant request failed: {}", msg),
+            WifiError::UnknownProfile(id) => write!(f, "No saved network with id {}", id),
+            WifiError::Invalid(msg) => write!(f, "{}", msg),
         }
     }
 }
 
 impl std::error::Error for WifiError {}
 
+impl WifiError {
+    /// Client-visible class of this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            WifiError::NoWifiDevice => ErrorCode::NoDevice,
+            WifiError::CommandFailed(msg)
+            | WifiError::ConnectionFailed(msg)
+            | WifiError::DbusError(msg)
+            | WifiError::ControlSocket(msg) => ErrorCode::classify(msg),
+            WifiError::ParseError(_) | WifiError::UnknownProfile(_) | WifiError::Invalid(_) => {
+                ErrorCode::Unknown
+            }
+        }
+    }
+}
+
+/// Client-visible class of a failure, sent to WebSocket clients as a number.
+///
+/// The numbers are part of the protocol; never reuse or renumber them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u16)]
+pub enum ErrorCode {
+    /// None of the classes below.
+    #[default]
+    Unknown = 1,
+    /// Authentication was rejected (PSK or 802.1X credentials).
+    WrongPassword = 2,
+    /// No access point with the SSID could be found.
+    SsidNotFound = 3,
+    /// Associated, but no address was assigned in time.
+    DhcpTimeout = 4,
+    /// WiFi is switched off (rfkill or NetworkManager's radio switch).
+    RadioDisabled = 5,
+    /// There is no WiFi device.
+    NoDevice = 6,
+    /// NetworkManager (or wpa_supplicant) is not running.
+    ServiceNotRunning = 7,
+    /// The daemon is not allowed to manage the network.
+    PermissionDenied = 8,
+    /// The client cancelled the request.
+    Cancelled = 9,
+}
+
+impl ErrorCode {
+    const ALL: [ErrorCode; 9] = [
+        ErrorCode::Unknown,
+        ErrorCode::WrongPassword,
+        ErrorCode::SsidNotFound,
+        ErrorCode::DhcpTimeout,
+        ErrorCode::RadioDisabled,
+        ErrorCode::NoDevice,
+        ErrorCode::ServiceNotRunning,
+        ErrorCode::PermissionDenied,
+        ErrorCode::Cancelled,
+    ];
+
+    pub fn number(self) -> u16 {
+        self as u16
+    }
+
+    pub fn from_number(number: u16) -> Option<Self> {
+        Self::ALL.into_iter().find(|code| code.number() == number)
+    }
+
+    /// Classify a backend error message.
+    ///
+    /// Matches nmcli's stderr, NetworkManager's D-Bus errors and activation
+    /// reasons, and wpa_supplicant's events. Environment problems are
+    /// checked first, since they can mention a connection attempt too.
+    pub fn classify(message: &str) -> Self {
+        const PATTERNS: &[(ErrorCode, &[&str])] = &[
+            (
+                ErrorCode::PermissionDenied,
+                &["not authorized", "permission denied", "insufficient privileges", "accessdenied"],
+            ),
+            (
+                ErrorCode::ServiceNotRunning,
+                &[
+                    "networkmanager is not running",
+                    "networkmanager stopped",
+                    "could not create nmclient",
+                    "serviceunknown",
+                    "namehasnoowner",
+                    "connection refused",
+                ],
+            ),
+            (
+                ErrorCode::NoDevice,
+                &["no wi-fi device", "no wifi device", "no suitable device"],
+            ),
+            (
+                ErrorCode::RadioDisabled,
+                &["rfkill", "radio is disabled", "wi-fi is disabled", "wireless is disabled"],
+            ),
+            (
+                ErrorCode::WrongPassword,
+                &[
+                    "secrets were required",
+                    "wrong password",
+                    "password must be",
+                    "authentication failed",
+                    "login failed",
+                    "psk: property is invalid",
+                ],
+            ),
+            (
+                ErrorCode::SsidNotFound,
+                &["no network with ssid", "network not found"],
+            ),
+            (
+                ErrorCode::DhcpTimeout,
+                &["ip configuration", "dhcp"],
+            ),
+        ];
+
+        let message = message.to_lowercase();
+        PATTERNS
+            .iter()
+            .find(|(_, patterns)| patterns.iter().any(|p| message.contains(p)))
+            .map_or(ErrorCode::Unknown, |(code, _)| *code)
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(self.number())
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let number = u16::deserialize(deserializer)?;
+        Self::from_number(number)
+            .ok_or_else(|| serde::de::Error::custom(format!("unknown error code {}", number)))
+    }
+}
+
+#[cfg(test)]
+impl schemars::JsonSchema for ErrorCode {
+    fn schema_name() -> String {
+        "ErrorCode".into()
+    }
+
+    fn json_schema(_: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        let numbers: Vec<u16> = Self::ALL.iter().map(|code| code.number()).collect();
+        serde_json::from_value(serde_json::json!({
+            "description": "Client-visible class of a failure (see the README's error codes).",
+            "type": "integer",
+            "enum": numbers,
+        }))
+        .unwrap()
+    }
+}
+
 /// WiFi connection status.
 #[derive(Debug, Clone, PartialEq)]
 pub struct WifiStatus {
     /// Whether connected to a WiFi network.
     pub connected: bool,
     /// SSID of current network (if connected).
-    pub ssid: Option<String>,
+    pub ssid: Option<Ssid>,
+}
+
+/// How to authenticate to a network.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credentials {
+    /// WPA/WPA2/WPA3-Personal passphrase; empty for an open network.
+    Psk(Secret),
+    /// WPA2/WPA3-Enterprise (802.1X).
+    Enterprise(EnterpriseCredentials),
+}
+
+/// A network to join.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectRequest {
+    pub ssid: Ssid,
+    pub credentials: Credentials,
+    /// The network does not broadcast its SSID, so it must be probed for by
+    /// name rather than picked from scan results.
+    pub hidden: bool,
+    /// Addressing, DNS and proxy settings; DHCP by default.
+    pub ip: IpConfig,
+}
+
+impl ConnectRequest {
+    pub fn new(ssid: impl Into<Ssid>, credentials: Credentials) -> Self {
+        Self {
+            ssid: ssid.into(),
+            credentials,
+            hidden: false,
+            ip: IpConfig::default(),
+        }
+    }
+
+    /// A WPA-Personal (or, with an empty password, open) network.
+    pub fn psk(ssid: impl Into<Ssid>, password: impl Into<Secret>) -> Self {
+        Self::new(ssid, Credentials::Psk(password.into()))
+    }
+
+    /// Check the request before touching the network.
+    pub fn validate(&self) -> WifiResult<()> {
+        if self.ssid.is_empty() || self.ssid.as_bytes().len() > Ssid::MAX_LEN {
+            return Err(WifiError::Invalid(format!(
+                "SSID must be 1 to {} bytes",
+                Ssid::MAX_LEN
+            )));
+        }
+        self.ip
+            .validate()
+            .map_err(|e| WifiError::Invalid(e.to_string()))
+    }
+
+    /// Check that the credentials suit a network with this security.
+    pub fn check_security(&self, security: Security) -> WifiResult<()> {
+        let problem = match (&self.credentials, security) {
+            (Credentials::Enterprise(_), security) if !security.is_enterprise() => {
+                "enterprise credentials are not used"
+            }
+            (Credentials::Psk(_), security) if security.is_enterprise() => {
+                "enterprise credentials are required"
+            }
+            (Credentials::Psk(password), Security::Open) if !password.is_empty() => {
+                "a password is not used"
+            }
+            (Credentials::Psk(password), security)
+                if password.is_empty() && security != Security::Open =>
+            {
+                "a password is required"
+            }
+            _ => return Ok(()),
+        };
+        Err(WifiError::Invalid(format!(
+            "Network is {}: {}",
+            security.as_str(),
+            problem
+        )))
+    }
+}
+
+/// Directory CA certificates for 802.1X profiles are written to.
+pub const CA_CERT_DIR: &str = "/data/certs";
+
+/// Write a PEM CA certificate for `ssid` under `CA_CERT_DIR`.
+///
+/// Backends reference certificates by path, and profiles outlive the
+/// provisioning request, so the file is kept. Returns the path.
+pub fn store_ca_cert(ssid: &Ssid, pem: &str) -> WifiResult<String> {
+    if !pem.contains("-----BEGIN CERTIFICATE-----") {
+        return Err(WifiError::ConnectionFailed(
+            "CA certificate is not PEM encoded".to_string(),
+        ));
+    }
+
+    let path = ca_cert_path(CA_CERT_DIR, ssid);
+    std::fs::create_dir_all(CA_CERT_DIR)
+        .and_then(|()| std::fs::write(&path, pem))
+        .map_err(|e| {
+            WifiError::ConnectionFailed(format!("Failed to store CA certificate {}: {}", path, e))
+        })?;
+    Ok(path)
+}
+
+/// Path of the CA certificate file for `ssid`.
+fn ca_cert_path(dir: &str, ssid: &Ssid) -> String {
+    format!("{}/{}.pem", dir.trim_end_matches('/'), ssid.to_hex())
+}
+
+/// NetworkManager's profile store on disk.
+///
+/// The image bind-mounts /data/NetworkManager/system-connections here, and
+/// NetworkManager reports the /etc path.
+pub const PERSISTENT_STORE_DIR: &str = "/etc/NetworkManager/system-connections/";
+
+/// Whether a profile file lives in the persistent store, rather than in
+/// NetworkManager's in-memory store under /run.
+pub fn is_persistent(filename: &str) -> bool {
+    filename.starts_with(PERSISTENT_STORE_DIR)
+}
+
+/// A connection profile that can be reactivated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedConnection {
+    /// Backend-specific profile id (nmcli UUID, D-Bus settings path or
+    /// wpa_supplicant network id).
+    pub id: String,
+    /// SSID the profile connects to.
+    pub ssid: Ssid,
+}
+
+/// WiFi profiles captured before trying new credentials.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ConnectionSnapshot {
+    /// Connection that was active, if any.
+    pub active: Option<SavedConnection>,
+    /// Ids of every profile that existed.
+    pub profiles: Vec<String>,
 }
 
 /// Trait for WiFi operations.
@@ -60,9 +380,87 @@ pub trait WifiManager: Send + Sync {
     /// Connect to a WiFi network.
     fn connect(
         &self,
-        ssid: &str,
-        password: &str,
+        request: &ConnectRequest,
+    ) -> impl std::future::Future<Output = WifiResult<()>> + Send;
+
+    /// Capture the active connection and existing profiles.
+    fn snapshot(&self) -> impl std::future::Future<Output = WifiResult<ConnectionSnapshot>> + Send;
+
+    /// Delete profiles created since `snapshot` and reactivate its active
+    /// connection.
+    fn restore(
+        &self,
+        snapshot: &ConnectionSnapshot,
+    ) -> impl std::future::Future<Output = WifiResult<()>> + Send;
+
+    /// Delete profiles in `previous` that a verified connection to `ssid`
+    /// replaces, so re-provisioning a network doesn't leave stale copies.
+    /// Profiles are matched on their raw SSID bytes.
+    fn remove_superseded(
+        &self,
+        _ssid: &Ssid,
+        _previous: &ConnectionSnapshot,
+    ) -> impl std::future::Future<Output = WifiResult<()>> + Send {
+        std::future::ready(Ok(()))
+    }
+
+    /// List saved WiFi profiles.
+    fn list_saved(&self) -> impl std::future::Future<Output = WifiResult<Vec<SavedNetwork>>> + Send;
+
+    /// Delete a saved profile.
+    fn forget(&self, id: &str) -> impl std::future::Future<Output = WifiResult<()>> + Send;
+
+    /// Set a saved profile's autoconnect priority.
+    fn set_priority(
+        &self,
+        id: &str,
+        priority: i32,
     ) -> impl std::future::Future<Output = WifiResult<()>> + Send;
+
+    /// Enable or disable autoconnect for a saved profile.
+    fn set_autoconnect(
+        &self,
+        id: &str,
+        autoconnect: bool,
+    ) -> impl std::future::Future<Output = WifiResult<()>> + Send;
+
+    /// Name of the WiFi interface (e.g., "wlan0"), if known.
+    fn interface(&self) -> impl std::future::Future<Output = Option<String>> + Send {
+        std::future::ready(None)
+    }
+
+    /// Check that the connected network is usable: address, default gateway
+    /// and DNS, on the WiFi interface if it is known. Association is checked
+    /// separately via `status`.
+    fn check_network(&self) -> impl std::future::Future<Output = ConnectivityResult> + Send {
+        async move {
+            let interface = self.interface().await;
+            connectivity::check_system(interface.as_deref()).await
+        }
+    }
+
+    /// Addresses in use on the WiFi interface, with prefix lengths.
+    fn addresses(&self) -> impl std::future::Future<Output = Vec<IpPrefix>> + Send {
+        async move {
+            let interface = self.interface().await;
+            connectivity::local_addresses(interface.as_deref())
+        }
+    }
+
+    /// Current regulatory country, or `None` while unset.
+    fn country(
+        &self,
+    ) -> impl std::future::Future<Output = WifiResult<Option<CountryCode>>> + Send {
+        regdomain::get_with_iw()
+    }
+
+    /// Set the regulatory country and persist it for the next boot.
+    fn set_country(
+        &self,
+        country: CountryCode,
+    ) -> impl std::future::Future<Output = WifiResult<()>> + Send {
+        regdomain::set_with_iw(country)
+    }
 }
 
 /// Real WiFi manager using nmcli.
@@ -75,7 +473,13 @@ impl NmcliWifiManager {
 
     /// Run an nmcli command and return stdout.
     async fn run_nmcli(&self, args: &[&str]) -> WifiResult<String> {
-        debug!("Running: nmcli {}", args.join(" "));
+        self.run_nmcli_os(args).await
+    }
+
+    /// Run an nmcli command whose arguments need not be UTF-8.
+    async fn run_nmcli_os<S: AsRef<OsStr> + Sync>(&self, args: &[S]) -> WifiResult<String> {
+        let line: Vec<_> = args.iter().map(|arg| arg.as_ref().to_string_lossy()).collect();
+        debug!("Running: nmcli {}", line.join(" "));
 
         let output = Command::new("nmcli")
             .args(args)
@@ -93,6 +497,74 @@ impl NmcliWifiManager {
 
         Ok(String::from_utf8_lossy(&output.stdout).into_owned())
     }
+
+    /// Run a profile command, reporting an unknown UUID as `UnknownProfile`.
+    async fn modify_profile(&self, id: &str, args: &[&str]) -> WifiResult<()> {
+        let output = self
+            .run_nmcli(&["-t", "-f", "UUID,TYPE,DEVICE", "connection", "show"])
+            .await?;
+        let (profiles, _) = parse_connection_list(&output);
+        if !profiles.iter().any(|uuid| uuid == id) {
+            return Err(WifiError::UnknownProfile(id.to_string()));
+        }
+
+        self.run_nmcli(args).await?;
+        Ok(())
+    }
+
+    /// Add a profile for `request` and bring it up.
+    ///
+    /// The profile is created without secrets, which are handed to
+    /// `connection up` in a passwd-file so they never appear on a command
+    /// line. A profile that fails to come up is deleted.
+    async fn add_and_activate(&self, request: &ConnectRequest) -> WifiResult<String> {
+        let ca_cert = match &request.credentials {
+            Credentials::Enterprise(EnterpriseCredentials {
+                ca_cert: Some(pem), ..
+            }) => Some(store_ca_cert(&request.ssid, pem)?),
+            _ => None,
+        };
+        let passwd_file = match passwd_file_contents(&request.credentials) {
+            Some(contents) => Some(PasswdFile::create(&contents)?),
+            None => None,
+        };
+
+        let output = self
+            .run_nmcli_os(&profile_args(request, ca_cert.as_deref())?)
+            .await?;
+        let uuid = parse_added_uuid(&output).ok_or_else(|| {
+            WifiError::ParseError(format!("no profile UUID in: {}", output.trim()))
+        })?;
+
+        let mut up: Vec<OsString> = ["connection", "up", "uuid", &uuid]
+            .into_iter()
+            .map(OsString::from)
+            .collect();
+        if let Some(file) = &passwd_file {
+            up.extend(["passwd-file".into(), file.path.clone().into_os_string()]);
+        }
+
+        match self.run_nmcli_os(&up).await {
+            Ok(output) => Ok(output),
+            Err(e) => {
+                if let Err(e) = self.run_nmcli(&["connection", "delete", "uuid", &uuid]).await {
+                    warn!("Failed to delete failed connection profile: {}", e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// SSID stored in a connection profile.
+    ///
+    /// nmcli only prints the SSID as text, so one that isn't valid UTF-8
+    /// comes back altered and matches no raw SSID.
+    async fn profile_ssid(&self, uuid: &str) -> WifiResult<Ssid> {
+        let output = self
+            .run_nmcli(&["-g", "802-11-wireless.ssid", "connection", "show", "uuid", uuid])
+            .await?;
+        Ok(Ssid::from(unescape_terse(output.trim_end_matches('\n'))))
+    }
 }
 
 impl Default for NmcliWifiManager {
@@ -103,145 +575,916 @@ impl Default for NmcliWifiManager {
 
 impl WifiManager for NmcliWifiManager {
     async fn status(&self) -> WifiResult<WifiStatus> {
-        // Check for active WiFi connections.
+        // The access point in use gives the exact SSID bytes; a profile name
+        // need not match the SSID.
         let output = self
-            .run_nmcli(&["-t", "-f", "NAME,TYPE,DEVICE", "connection", "show", "--active"])
+            .run_nmcli(&[
+                "-t", "-f", "IN-USE,SSID-HEX", "device", "wifi", "list", "--rescan", "no",
+            ])
             .await?;
 
-        // Look for a wifi connection.
-        for line in output.lines() {
-            let parts: Vec<&str> = line.split(':').collect();
-            if parts.len() >= 2 && parts[1] == "802-11-wireless" {
-                let ssid = parts[0].to_string();
+        match parse_active_ssid(&output) {
+            Some(ssid) => {
                 info!("WiFi connected to: {}", ssid);
-                return Ok(WifiStatus {
+                Ok(WifiStatus {
                     connected: true,
                     ssid: Some(ssid),
-                });
+                })
+            }
+            None => {
+                info!("WiFi not connected");
+                Ok(WifiStatus {
+                    connected: false,
+                    ssid: None,
+                })
             }
         }
+    }
 
-        info!("WiFi not connected");
-        Ok(WifiStatus {
-            connected: false,
-            ssid: None,
-        })
+    async fn scan(&self) -> WifiResult<Vec<Network>> {
+        // Trigger a fresh scan first.
+        let _ = self.run_nmcli(&["device", "wifi", "rescan"]).await;
+
+        // Get the list of networks.
+        let output = self
+            .run_nmcli(&["-t", "-f", SCAN_FIELDS, "device", "wifi", "list"])
+            .await?;
+
+        let signals = measured_signals(self.interface().await).await;
+        let networks = parse_scan_output(&output, &signals);
+        info!("Found {} WiFi networks", networks.len());
+        Ok(networks)
+    }
+
+    async fn connect(&self, request: &ConnectRequest) -> WifiResult<()> {
+        let ssid = &request.ssid;
+        info!("Connecting to WiFi network: {}", ssid);
+
+        match self.add_and_activate(request).await {
+            Ok(output) => {
+                if output.contains("successfully activated") {
+                    info!("Successfully connected to {}", ssid);
+                    Ok(())
+                } else {
+                    warn!("Unexpected nmcli output: {}", output);
+                    // Still might be OK; connectivity verification decides.
+                    Ok(())
+                }
+            }
+            Err(e) => {
+                error!("Failed to connect to {}: {}", ssid, e);
+                Err(WifiError::ConnectionFailed(format!(
+                    "Failed to connect to {}: {}",
+                    ssid, e
+                )))
+            }
+        }
+    }
+
+    async fn snapshot(&self) -> WifiResult<ConnectionSnapshot> {
+        let output = self
+            .run_nmcli(&["-t", "-f", "UUID,TYPE,DEVICE", "connection", "show"])
+            .await?;
+        let (profiles, active) = parse_connection_list(&output);
+
+        let active = match active {
+            Some(uuid) => {
+                let ssid = self.profile_ssid(&uuid).await?;
+                Some(SavedConnection { id: uuid, ssid })
+            }
+            None => None,
+        };
+
+        Ok(ConnectionSnapshot { active, profiles })
+    }
+
+    async fn restore(&self, snapshot: &ConnectionSnapshot) -> WifiResult<()> {
+        let output = self
+            .run_nmcli(&["-t", "-f", "UUID,TYPE,DEVICE", "connection", "show"])
+            .await?;
+        let (profiles, _) = parse_connection_list(&output);
+
+        for uuid in profiles.iter().filter(|uuid| !snapshot.profiles.contains(uuid)) {
+            info!("Deleting connection profile {}", uuid);
+            if let Err(e) = self.run_nmcli(&["connection", "delete", "uuid", uuid]).await {
+                warn!("Failed to delete connection profile {}: {}", uuid, e);
+            }
+        }
+
+        if let Some(active) = &snapshot.active {
+            info!("Reactivating previous connection to {}", active.ssid);
+            self.run_nmcli(&["connection", "up", "uuid", &active.id]).await?;
+        }
+        Ok(())
+    }
+
+    async fn remove_superseded(
+        &self,
+        ssid: &Ssid,
+        previous: &ConnectionSnapshot,
+    ) -> WifiResult<()> {
+        // Profiles are added with the SSID as their name; the SSID itself is
+        // checked too, since a profile's name can be changed.
+        let output = self
+            .run_nmcli(&["-t", "-f", "UUID,TYPE,NAME", "connection", "show"])
+            .await?;
+        let named = parse_profiles_named(&output, &ssid.to_string());
+        for uuid in named.iter().filter(|uuid| previous.profiles.contains(uuid)) {
+            if self.profile_ssid(uuid).await? != *ssid {
+                continue;
+            }
+            info!("Deleting superseded connection profile {}", uuid);
+            if let Err(e) = self.run_nmcli(&["connection", "delete", "uuid", uuid]).await {
+                warn!("Failed to delete connection profile {}: {}", uuid, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn list_saved(&self) -> WifiResult<Vec<SavedNetwork>> {
+        let output = self
+            .run_nmcli(&[
+                "-t",
+                "-f",
+                "UUID,TYPE,AUTOCONNECT,AUTOCONNECT-PRIORITY,FILENAME",
+                "connection",
+                "show",
+            ])
+            .await?;
+
+        let mut saved = Vec::new();
+        for mut profile in parse_saved_list(&output) {
+            profile.ssid = self.profile_ssid(&profile.id).await?.to_string();
+            saved.push(profile);
+        }
+        Ok(saved)
+    }
+
+    async fn forget(&self, id: &str) -> WifiResult<()> {
+        info!("Deleting connection profile {}", id);
+        self.modify_profile(id, &["connection", "delete", "uuid", id])
+            .await
+    }
+
+    async fn set_priority(&self, id: &str, priority: i32) -> WifiResult<()> {
+        let priority = priority.to_string();
+        self.modify_profile(
+            id,
+            &["connection", "modify", "uuid", id, "connection.autoconnect-priority", &priority],
+        )
+        .await
+    }
+
+    async fn set_autoconnect(&self, id: &str, autoconnect: bool) -> WifiResult<()> {
+        let value = if autoconnect { "yes" } else { "no" };
+        self.modify_profile(
+            id,
+            &["connection", "modify", "uuid", id, "connection.autoconnect", value],
+        )
+        .await
+    }
+
+    async fn interface(&self) -> Option<String> {
+        match self.run_nmcli(&["-t", "-f", "DEVICE,TYPE", "device"]).await {
+            Ok(output) => parse_wifi_device(&output),
+            Err(e) => {
+                warn!("Failed to find the WiFi device: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Parse `nmcli -t -f DEVICE,TYPE device` output into the first WiFi
+/// device's interface name.
+pub fn parse_wifi_device(output: &str) -> Option<String> {
+    output
+        .lines()
+        .map(split_terse)
+        .find(|parts| parts.len() >= 2 && parts[1] == "wifi")
+        .map(|parts| parts[0].clone())
+}
+
+/// Parse `nmcli -t -f UUID,TYPE,AUTOCONNECT,AUTOCONNECT-PRIORITY,FILENAME
+/// connection show` output into WiFi profiles.
+///
+/// The SSID is left empty; nmcli only reports the profile name here.
+pub fn parse_saved_list(output: &str) -> Vec<SavedNetwork> {
+    let mut saved = Vec::new();
+
+    for line in output.lines() {
+        let parts = split_terse(line);
+        if parts.len() < 5 || parts[1] != "802-11-wireless" {
+            continue;
+        }
+        saved.push(SavedNetwork {
+            id: parts[0].clone(),
+            ssid: String::new(),
+            ssid_hex: None,
+            priority: parts[3].parse().unwrap_or(0),
+            autoconnect: parts[2] == "yes",
+            persistent: is_persistent(&parts[4]),
+        });
+    }
+
+    saved
+}
+
+/// Parse `nmcli -t -f UUID,TYPE,DEVICE connection show` output.
+///
+/// Returns the UUIDs of all WiFi profiles and the one active on a device.
+pub fn parse_connection_list(output: &str) -> (Vec<String>, Option<String>) {
+    let mut profiles = Vec::new();
+    let mut active = None;
+
+    for line in output.lines() {
+        let parts = split_terse(line);
+        if parts.len() < 3 || parts[1] != "802-11-wireless" {
+            continue;
+        }
+        if !parts[2].is_empty() && active.is_none() {
+            active = Some(parts[0].clone());
+        }
+        profiles.push(parts[0].clone());
+    }
+
+    (profiles, active)
+}
+
+/// Parse `nmcli -t -f UUID,TYPE,NAME connection show` output.
+///
+/// Returns the UUIDs of WiFi profiles named `name`.
+pub fn parse_profiles_named(output: &str, name: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(split_terse)
+        .filter(|parts| parts.len() >= 3 && parts[1] == "802-11-wireless" && parts[2] == name)
+        .map(|parts| parts[0].clone())
+        .collect()
+}
+
+/// Arguments for `nmcli connection add` creating a profile for `request`.
+///
+/// Secrets are left out; see `passwd_file_contents`. nmcli takes the SSID
+/// argument as raw bytes, so any SSID without a NUL byte can be given.
+pub fn profile_args(request: &ConnectRequest, ca_cert: Option<&str>) -> WifiResult<Vec<OsString>> {
+    let ssid = request.ssid.as_bytes();
+    if ssid.contains(&0) {
+        return Err(WifiError::ConnectionFailed(format!(
+            "Cannot pass SSID {} to nmcli: it contains a NUL byte",
+            request.ssid
+        )));
+    }
+
+    let mut args: Vec<OsString> = ["connection", "add", "type", "wifi", "con-name"]
+        .into_iter()
+        .map(OsString::from)
+        .collect();
+    args.push(request.ssid.to_string().into());
+    args.push("ssid".into());
+    args.push(OsStr::from_bytes(ssid).to_owned());
+
+    let mut push = |name: &str, value: &str| args.extend([name.into(), value.into()]);
+    if request.hidden {
+        push("802-11-wireless.hidden", "yes");
+    }
+    match &request.credentials {
+        Credentials::Psk(psk) if psk.is_empty() => {}
+        Credentials::Psk(_) => push("wifi-sec.key-mgmt", "wpa-psk"),
+        Credentials::Enterprise(enterprise) => {
+            push("wifi-sec.key-mgmt", "wpa-eap");
+            push("802-1x.eap", enterprise.eap.as_str());
+            push("802-1x.phase2-auth", enterprise.phase2.as_str());
+            push("802-1x.identity", &enterprise.identity);
+            if let Some(anonymous) = &enterprise.anonymous_identity {
+                push("802-1x.anonymous-identity", anonymous);
+            }
+            if let Some(path) = ca_cert {
+                push("802-1x.ca-cert", path);
+            }
+        }
+    }
+    for (name, value) in ip_properties(&request.ip) {
+        push(&name, &value);
+    }
+    Ok(args)
+}
+
+/// NetworkManager properties for non-default IP settings, as nmcli takes
+/// them: lists are comma-separated.
+pub fn ip_properties(ip: &IpConfig) -> Vec<(String, String)> {
+    let mut properties = Vec::new();
+    for (family, settings, ipv6) in [("ipv4", &ip.ipv4, false), ("ipv6", &ip.ipv6, true)] {
+        let mut push = |name: &str, value: String| {
+            properties.push((format!("{}.{}", family, name), value));
+        };
+        let IpSettings {
+            method,
+            addresses,
+            gateway,
+        } = settings;
+
+        if *method != IpMethod::Auto {
+            push("method", method.as_str().to_string());
+        }
+        if !addresses.is_empty() {
+            push("addresses", join(addresses));
+        }
+        if let Some(gateway) = gateway {
+            push("gateway", gateway.to_string());
+        }
+        let dns: Vec<_> = ip.dns_for(ipv6).collect();
+        if !dns.is_empty() {
+            push("dns", join(dns));
+        }
+        if !ip.dns_search.is_empty() && *method != IpMethod::Disabled {
+            push("dns-search", ip.dns_search.join(","));
+        }
+    }
+    if let Some(url) = &ip.proxy_pac_url {
+        properties.push(("proxy.method".to_string(), "auto".to_string()));
+        properties.push(("proxy.pac-url".to_string(), url.clone()));
+    }
+    properties
+}
+
+fn join<T: std::fmt::Display>(items: impl IntoIterator<Item = T>) -> String {
+    items
+        .into_iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Contents of the passwd-file for `nmcli connection up`, one
+/// `setting.property:value` line per secret. `None` for an open network.
+pub fn passwd_file_contents(credentials: &Credentials) -> Option<Secret> {
+    let line = match credentials {
+        Credentials::Psk(psk) if psk.is_empty() => return None,
+        Credentials::Psk(psk) => format!("802-11-wireless-security.psk:{}\n", psk.expose()),
+        Credentials::Enterprise(enterprise) => {
+            format!("802-1x.password:{}\n", enterprise.password.expose())
+        }
+    };
+    Some(Secret::new(line))
+}
+
+/// A passwd-file for nmcli, readable only by us and removed on drop.
+struct PasswdFile {
+    path: PathBuf,
+}
+
+impl PasswdFile {
+    fn create(contents: &Secret) -> WifiResult<Self> {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "wifi-provisioner-{}-{}.passwd",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let error =
+            |e| WifiError::CommandFailed(format!("Failed to write nmcli passwd-file: {}", e));
+        let mut f = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .map_err(error)?;
+        let file = PasswdFile { path };
+        f.write_all(contents.expose().as_bytes()).map_err(error)?;
+        Ok(file)
+    }
+}
+
+impl Drop for PasswdFile {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+/// UUID from `nmcli connection add` output, e.g.
+/// `Connection 'eduroam' (5b2e...) successfully added.`
+pub fn parse_added_uuid(output: &str) -> Option<String> {
+    let start = output.rfind('(')? + 1;
+    let end = start + output[start..].find(')')?;
+    Some(output[start..end].to_string()).filter(|uuid| !uuid.is_empty())
+}
+
+/// Parse `nmcli -t -f IN-USE,SSID-HEX device wifi list` output.
+///
+/// Returns the SSID of the access point marked in use.
+pub fn parse_active_ssid(output: &str) -> Option<Ssid> {
+    output
+        .lines()
+        .map(split_terse)
+        .find(|fields| fields.len() >= 2 && fields[0] == "*")
+        .and_then(|fields| Ssid::from_hex(&fields[1]))
+        .filter(|ssid| !ssid.is_empty())
+}
+
+/// Whether `ssid` is missing from a fresh scan, i.e. should be joined as a
+/// hidden network.
+///
+/// Improv credentials carry no hidden flag, so this is how hidden networks
+/// are recognized. If the scan fails, the network is assumed visible.
+pub async fn is_hidden<W: WifiManager>(wifi: &W, ssid: &Ssid) -> bool {
+    match wifi.scan().await {
+        Ok(networks) => !networks.iter().any(|network| network.raw_ssid() == *ssid),
+        Err(e) => {
+            warn!("Scan before connecting failed, assuming {} is visible: {}", ssid, e);
+            false
+        }
+    }
+}
+
+/// Connect and verify, restoring the previous connection on failure.
+///
+/// Networks missing from a fresh scan are joined as hidden networks.
+///
+/// Connecting may tear down a working connection before the new credentials
+/// are known to be good. If they fail, profiles created by the attempt are
+/// deleted and the previously active connection is reactivated; the
+/// returned failure then names the restored SSID.
+pub async fn connect_verified<W: WifiManager>(
+    wifi: &W,
+    request: &ConnectRequest,
+    verify_timeout: std::time::Duration,
+) -> ConnectivityResult {
+    connect_verified_with_progress(wifi, request, verify_timeout, |_| {}).await
+}
+
+/// `connect_verified`, calling `progress` as the attempt moves through each
+/// stage.
+pub async fn connect_verified_with_progress<W: WifiManager>(
+    wifi: &W,
+    request: &ConnectRequest,
+    verify_timeout: std::time::Duration,
+    progress: impl FnMut(ProvisioningStage) + Send,
+) -> ConnectivityResult {
+    connect_verified_cancellable(wifi, request, verify_timeout, progress, std::future::pending())
+        .await
+}
+
+/// `connect_verified_with_progress` that gives up once `cancel` completes.
+///
+/// Cancelling fails the attempt with `ErrorCode::Cancelled` and rolls back
+/// like any other failure. A connection the backend is already activating
+/// is finished first, so the rollback can't race it.
+pub async fn connect_verified_cancellable<W: WifiManager>(
+    wifi: &W,
+    request: &ConnectRequest,
+    verify_timeout: std::time::Duration,
+    mut progress: impl FnMut(ProvisioningStage) + Send,
+    cancel: impl std::future::Future<Output = ()> + Send,
+) -> ConnectivityResult {
+    progress(ProvisioningStage::Provisioning);
+    let result = connect_and_verify(wifi, request, verify_timeout, &mut progress, cancel).await;
+    progress(match result {
+        Ok(()) => ProvisioningStage::Connected,
+        Err(_) => ProvisioningStage::Failed,
+    });
+    result
+}
+
+async fn connect_and_verify<W: WifiManager>(
+    wifi: &W,
+    request: &ConnectRequest,
+    verify_timeout: std::time::Duration,
+    progress: &mut (impl FnMut(ProvisioningStage) + Send),
+    cancel: impl std::future::Future<Output = ()> + Send,
+) -> ConnectivityResult {
+    let ssid = &request.ssid;
+    let cancelled = || {
+        info!("Connecting to {} was cancelled", ssid);
+        ConnectivityFailure::new(ConnectivityStep::Association, "cancelled")
+            .with_code(ErrorCode::Cancelled)
+    };
+    tokio::pin!(cancel);
+
+    let snapshot = match wifi.snapshot().await {
+        Ok(snapshot) => Some(snapshot),
+        Err(e) => {
+            warn!("Failed to capture current connection, rollback disabled: {}", e);
+            None
+        }
+    };
+
+    let mut request = request.clone();
+    if !request.hidden {
+        // Nothing has changed yet, so there is nothing to roll back.
+        let hidden = tokio::select! {
+            biased;
+            () = &mut cancel => return Err(cancelled()),
+            hidden = is_hidden(wifi, ssid) => hidden,
+        };
+        if hidden {
+            info!("{} is not in the scan, connecting as a hidden network", ssid);
+            request.hidden = true;
+        }
+    }
+
+    let failure = match wifi.connect(&request).await {
+        Ok(()) => {
+            progress(ProvisioningStage::Verifying);
+            tokio::select! {
+                biased;
+                () = &mut cancel => cancelled(),
+                result = connectivity::verify(wifi, ssid, verify_timeout) => match result {
+                    Ok(()) => {
+                        if let Some(snapshot) = &snapshot {
+                            if let Err(e) = wifi.remove_superseded(ssid, snapshot).await {
+                                warn!("Failed to remove superseded profiles: {}", e);
+                            }
+                        }
+                        return Ok(());
+                    }
+                    Err(failure) => failure,
+                },
+            }
+        }
+        Err(e) => ConnectivityFailure::new(ConnectivityStep::Association, e.to_string())
+            .with_code(e.code()),
+    };
+
+    let Some(snapshot) = snapshot else {
+        return Err(failure);
+    };
+    match wifi.restore(&snapshot).await {
+        Ok(()) => match snapshot.active {
+            Some(previous) => {
+                info!("Restored previous connection to {}", previous.ssid);
+                Err(failure.with_restored(previous.ssid.to_string()))
+            }
+            None => Err(failure),
+        },
+        Err(e) => {
+            error!("Failed to restore previous connection: {}", e);
+            Err(failure)
+        }
+    }
+}
+
+/// WiFi backend selected at startup.
+pub enum WifiBackend {
+    /// nmcli subprocesses.
+    Nmcli(NmcliWifiManager),
+    /// NetworkManager D-Bus API.
+    NetworkManager(NmDbusWifiManager),
+    /// wpa_supplicant control socket, for images without NetworkManager.
+    WpaSupplicant(WpaSupplicantWifiManager),
+}
+
+impl WifiBackend {
+    /// Name as used in `WIFI_PROVISIONER_WIFI_BACKEND` (e.g., "dbus").
+    pub fn name(&self) -> &'static str {
+        match self {
+            WifiBackend::Nmcli(_) => "nmcli",
+            WifiBackend::NetworkManager(_) => "dbus",
+            WifiBackend::WpaSupplicant(_) => "wpa_supplicant",
+        }
+    }
+}
+
+impl WifiManager for WifiBackend {
+    async fn status(&self) -> WifiResult<WifiStatus> {
+        match self {
+            WifiBackend::Nmcli(wifi) => wifi.status().await,
+            WifiBackend::NetworkManager(wifi) => wifi.status().await,
+            WifiBackend::WpaSupplicant(wifi) => wifi.status().await,
+        }
+    }
+
+    async fn scan(&self) -> WifiResult<Vec<Network>> {
+        match self {
+            WifiBackend::Nmcli(wifi) => wifi.scan().await,
+            WifiBackend::NetworkManager(wifi) => wifi.scan().await,
+            WifiBackend::WpaSupplicant(wifi) => wifi.scan().await,
+        }
+    }
+
+    async fn connect(&self, request: &ConnectRequest) -> WifiResult<()> {
+        match self {
+            WifiBackend::Nmcli(wifi) => wifi.connect(request).await,
+            WifiBackend::NetworkManager(wifi) => wifi.connect(request).await,
+            WifiBackend::WpaSupplicant(wifi) => wifi.connect(request).await,
+        }
+    }
+
+    async fn snapshot(&self) -> WifiResult<ConnectionSnapshot> {
+        match self {
+            WifiBackend::Nmcli(wifi) => wifi.snapshot().await,
+            WifiBackend::NetworkManager(wifi) => wifi.snapshot().await,
+            WifiBackend::WpaSupplicant(wifi) => wifi.snapshot().await,
+        }
+    }
+
+    async fn restore(&self, snapshot: &ConnectionSnapshot) -> WifiResult<()> {
+        match self {
+            WifiBackend::Nmcli(wifi) => wifi.restore(snapshot).await,
+            WifiBackend::NetworkManager(wifi) => wifi.restore(snapshot).await,
+            WifiBackend::WpaSupplicant(wifi) => wifi.restore(snapshot).await,
+        }
+    }
+
+    async fn remove_superseded(
+        &self,
+        ssid: &Ssid,
+        previous: &ConnectionSnapshot,
+    ) -> WifiResult<()> {
+        match self {
+            WifiBackend::Nmcli(wifi) => wifi.remove_superseded(ssid, previous).await,
+            WifiBackend::NetworkManager(wifi) => wifi.remove_superseded(ssid, previous).await,
+            WifiBackend::WpaSupplicant(wifi) => wifi.remove_superseded(ssid, previous).await,
+        }
+    }
+
+    async fn list_saved(&self) -> WifiResult<Vec<SavedNetwork>> {
+        match self {
+            WifiBackend::Nmcli(wifi) => wifi.list_saved().await,
+            WifiBackend::NetworkManager(wifi) => wifi.list_saved().await,
+            WifiBackend::WpaSupplicant(wifi) => wifi.list_saved().await,
+        }
+    }
+
+    async fn forget(&self, id: &str) -> WifiResult<()> {
+        match self {
+            WifiBackend::Nmcli(wifi) => wifi.forget(id).await,
+            WifiBackend::NetworkManager(wifi) => wifi.forget(id).await,
+            WifiBackend::WpaSupplicant(wifi) => wifi.forget(id).await,
+        }
+    }
+
+    async fn set_priority(&self, id: &str, priority: i32) -> WifiResult<()> {
+        match self {
+            WifiBackend::Nmcli(wifi) => wifi.set_priority(id, priority).await,
+            WifiBackend::NetworkManager(wifi) => wifi.set_priority(id, priority).await,
+            WifiBackend::WpaSupplicant(wifi) => wifi.set_priority(id, priority).await,
+        }
+    }
+
+    async fn set_autoconnect(&self, id: &str, autoconnect: bool) -> WifiResult<()> {
+        match self {
+            WifiBackend::Nmcli(wifi) => wifi.set_autoconnect(id, autoconnect).await,
+            WifiBackend::NetworkManager(wifi) => wifi.set_autoconnect(id, autoconnect).await,
+            WifiBackend::WpaSupplicant(wifi) => wifi.set_autoconnect(id, autoconnect).await,
+        }
     }
 
-    async fn scan(&self) -> WifiResult<Vec<Network>> {
-        // Trigger a fresh scan first.
-        let _ = self.run_nmcli(&["device", "wifi", "rescan"]).await;
+    async fn interface(&self) -> Option<String> {
+        match self {
+            WifiBackend::Nmcli(wifi) => wifi.interface().await,
+            WifiBackend::NetworkManager(wifi) => wifi.interface().await,
+            WifiBackend::WpaSupplicant(wifi) => wifi.interface().await,
+        }
+    }
 
-        // Get the list of networks.
-        let output = self
-            .run_nmcli(&["-t", "-f", "SSID,SIGNAL,SECURITY", "device", "wifi", "list"])
-            .await?;
+    async fn country(&self) -> WifiResult<Option<CountryCode>> {
+        match self {
+            WifiBackend::Nmcli(wifi) => wifi.country().await,
+            WifiBackend::NetworkManager(wifi) => wifi.country().await,
+            WifiBackend::WpaSupplicant(wifi) => wifi.country().await,
+        }
+    }
 
-        let networks = parse_scan_output(&output);
-        info!("Found {} WiFi networks", networks.len());
-        Ok(networks)
+    async fn set_country(&self, country: CountryCode) -> WifiResult<()> {
+        match self {
+            WifiBackend::Nmcli(wifi) => wifi.set_country(country).await,
+            WifiBackend::NetworkManager(wifi) => wifi.set_country(country).await,
+            WifiBackend::WpaSupplicant(wifi) => wifi.set_country(country).await,
+        }
     }
+}
 
-    async fn connect(&self, ssid: &str, password: &str) -> WifiResult<()> {
-        info!("Connecting to WiFi network: {}", ssid);
+/// Fields requested from `nmcli device wifi list`.
+///
+/// SSID-HEX gives the exact SSID bytes; SSID is lossy for non-UTF-8 names.
+const SCAN_FIELDS: &str = "SSID-HEX,BSSID,FREQ,CHAN,SIGNAL,SECURITY";
 
-        // Try to connect. nmcli will create a connection profile if needed.
-        let result = self
-            .run_nmcli(&[
-                "device", "wifi", "connect", ssid, "password", password,
-            ])
-            .await;
+/// Split a line of nmcli terse output into fields.
+///
+/// nmcli escapes `:` and `\` inside values with a backslash. Every nmcli
+/// parser goes through here rather than splitting on `:` directly.
+pub fn split_terse(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.chars();
 
-        match result {
-            Ok(output) => {
-                if output.contains("successfully activated") {
-                    info!("Successfully connected to {}", ssid);
-                    Ok(())
-                } else {
-                    warn!("Unexpected nmcli output: {}", output);
-                    // Still might be OK, check status.
-                    Ok(())
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    fields.last_mut().unwrap().push(escaped);
                 }
             }
-            Err(e) => {
-                error!("Failed to connect to {}: {}", ssid, e);
-                Err(WifiError::ConnectionFailed(format!(
-                    "Failed to connect to {}: {}",
-                    ssid, e
-                )))
-            }
+            ':' => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
         }
     }
+
+    fields
+}
+
+/// Unescape a single nmcli terse value, such as `nmcli -g` output.
+pub fn unescape_terse(value: &str) -> String {
+    split_terse(value).join(":")
 }
 
 /// Parse nmcli wifi list output into Network structs.
 ///
-/// Input format (terse mode): `SSID:SIGNAL:SECURITY`
-/// Example: `onionchan:65:WPA1 WPA2`
-pub fn parse_scan_output(output: &str) -> Vec<Network> {
-    let mut networks = Vec::new();
-    let mut seen_ssids = std::collections::HashSet::new();
+/// Input format (terse mode): `SSID-HEX:BSSID:FREQ:CHAN:SIGNAL:SECURITY`
+/// Example: `6F6E696F6E6368616E:AA\:BB\:CC\:DD\:EE\:FF:5180 MHz:36:65:WPA1 WPA2`
+///
+/// nmcli only reports signal quality (0-100); dBm is taken from `signals`,
+/// the measured strength per BSSID, and left out for access points missing
+/// from it.
+pub fn parse_scan_output(output: &str, signals: &HashMap<String, i32>) -> Vec<Network> {
+    let mut access_points = Vec::new();
 
     for line in output.lines() {
         if line.is_empty() {
             continue;
         }
 
-        // Split on colon, but SSID might be empty or contain special chars.
-        // Format: SSID:SIGNAL:SECURITY
-        // We need at least 3 parts.
-        let parts: Vec<&str> = line.splitn(3, ':').collect();
-        if parts.len() < 3 {
+        let fields = split_terse(line);
+        if fields.len() < 6 {
             debug!("Skipping malformed line: {}", line);
             continue;
         }
 
-        let ssid = parts[0].to_string();
-
-        // Skip empty SSIDs (hidden networks).
-        if ssid.is_empty() {
-            continue;
-        }
-
-        // Skip duplicates (same SSID from multiple APs).
-        if seen_ssids.contains(&ssid) {
+        let Some(ssid) = Ssid::from_hex(&fields[0]) else {
+            debug!("Skipping line with invalid SSID: {}", line);
             continue;
-        }
+        };
 
-        let signal = match parts[1].parse::<i32>() {
-            Ok(s) => {
-                // nmcli reports signal as 0-100 percentage.
-                // Convert to approximate dBm: -100 + percentage.
-                -100 + s
-            }
+        let quality = match fields[4].parse::<u8>() {
+            Ok(q) => q.min(100),
             Err(_) => {
                 debug!("Skipping line with invalid signal: {}", line);
                 continue;
             }
         };
 
-        // Normalize security string.
-        let security = normalize_security(parts[2]);
+        // FREQ looks like "5180 MHz".
+        let frequency = fields[2]
+            .split_whitespace()
+            .next()
+            .and_then(|f| f.parse::<u32>().ok())
+            .unwrap_or(0);
 
-        seen_ssids.insert(ssid.clone());
-        networks.push(Network {
-            ssid,
-            signal,
-            security,
-        });
+        let bssid = fields[1].to_lowercase();
+        let ap = AccessPointInfo {
+            signal: signals.get(&bssid).copied(),
+            bssid,
+            frequency,
+            band: band_for_frequency(frequency).to_string(),
+            channel: fields[3]
+                .parse()
+                .unwrap_or_else(|_| channel_for_frequency(frequency)),
+            quality,
+        };
+        access_points.push((ssid, normalize_security(&fields[5]), ap));
     }
 
-    // Sort by signal strength (strongest first).
-    networks.sort_by(|a, b| b.signal.cmp(&a.signal));
+    group_access_points(access_points)
+}
+
+/// Group access points into one network per SSID.
+///
+/// Each network takes its signal and security from its strongest access
+/// point, going by quality, which every backend reports. Access points
+/// hiding their SSID (empty or all zero bytes) are grouped into a single
+/// entry with an empty SSID and `hidden` set. Networks are sorted by signal
+/// strength (strongest first).
+///
+/// Networks are keyed by raw SSID bytes, so distinct non-UTF-8 SSIDs stay
+/// apart even if their text forms match.
+pub fn group_access_points(access_points: Vec<(Ssid, String, AccessPointInfo)>) -> Vec<Network> {
+    let mut by_ssid: HashMap<Ssid, Network> = HashMap::new();
+
+    for (ssid, security, ap) in access_points {
+        let hidden = ssid.as_bytes().iter().all(|&b| b == 0);
+        let ssid = if hidden { Ssid::default() } else { ssid };
+
+        let network = by_ssid.entry(ssid.clone()).or_insert_with(|| Network {
+            ssid: ssid.to_string(),
+            ssid_hex: ssid.hex_if_not_utf8(),
+            hidden,
+            ..Default::default()
+        });
+        let stronger = (ap.quality, ap.signal) > (network.quality, network.signal);
+        if network.access_points.is_empty() || stronger {
+            network.signal = ap.signal;
+            network.quality = ap.quality;
+            network.security = security;
+        }
+        network.access_points.push(ap);
+    }
 
+    let mut networks: Vec<Network> = by_ssid.into_values().collect();
+    for network in &mut networks {
+        network.access_points.sort_by(|a, b| {
+            (b.quality, b.signal)
+                .cmp(&(a.quality, a.signal))
+                .then_with(|| a.bssid.cmp(&b.bssid))
+        });
+    }
+    networks.sort_by(|a, b| {
+        (b.quality, b.signal)
+            .cmp(&(a.quality, a.signal))
+            .then_with(|| a.ssid.cmp(&b.ssid))
+    });
     networks
 }
 
+/// WiFi band for a center frequency in MHz.
+pub fn band_for_frequency(frequency: u32) -> &'static str {
+    match frequency {
+        2400..=2500 => "2.4GHz",
+        4900..=5899 => "5GHz",
+        5925..=7125 => "6GHz",
+        _ => "unknown",
+    }
+}
+
+/// Channel number for a center frequency in MHz, or 0 if unknown.
+pub fn channel_for_frequency(frequency: u32) -> u32 {
+    match frequency {
+        2484 => 14,
+        2412..=2472 => (frequency - 2407) / 5,
+        4900..=5899 => (frequency - 5000) / 5,
+        5955..=7115 => (frequency - 5950) / 5,
+        _ => 0,
+    }
+}
+
+/// Signal strength per BSSID in `iw dev <interface> scan dump` output.
+///
+/// Each BSS starts with a `BSS aa:bb:cc:dd:ee:ff(on wlan0)` line, followed
+/// by tab-indented details including `signal: -47.00 dBm`.
+pub fn parse_iw_scan_dump(output: &str) -> HashMap<String, i32> {
+    let mut signals = HashMap::new();
+    let mut bssid = None;
+
+    for line in output.lines() {
+        if let Some(rest) = line.strip_prefix("BSS ") {
+            bssid = rest.get(..17).map(str::to_lowercase);
+        } else if let Some(signal) = line.strip_prefix("\tsignal: ") {
+            let dbm = signal
+                .split_whitespace()
+                .next()
+                .and_then(|dbm| dbm.parse::<f32>().ok());
+            if let (Some(bssid), Some(dbm)) = (&bssid, dbm) {
+                signals.insert(bssid.clone(), dbm.round() as i32);
+            }
+        }
+    }
+
+    signals
+}
+
+/// Measured signal strength per BSSID on `interface`, from the kernel's
+/// latest scan results.
+///
+/// NetworkManager only reports signal quality, so its backends read dBm
+/// with `iw`. Empty if the interface is unknown or `iw` fails.
+pub async fn measured_signals(interface: Option<String>) -> HashMap<String, i32> {
+    let Some(interface) = interface else {
+        return HashMap::new();
+    };
+    match regdomain::run_iw(&["dev", &interface, "scan", "dump"]).await {
+        Ok(output) => parse_iw_scan_dump(&output),
+        Err(e) => {
+            debug!("No measured signal strengths: {}", e);
+            HashMap::new()
+        }
+    }
+}
+
+/// Signal quality for a dBm value, using NetworkManager's mapping.
+pub fn dbm_to_quality(dbm: i32) -> u8 {
+    ((dbm.clamp(-90, -20) + 90) * 100 / 70) as u8
+}
+
 /// Normalize security type to a simpler format.
+///
+/// 802.1X networks get an "-enterprise" suffix, e.g. "wpa2-enterprise".
 fn normalize_security(raw: &str) -> String {
     let raw_upper = raw.to_uppercase();
 
-    if raw_upper.contains("WPA3") {
+    if raw_upper.contains("802.1X") {
+        let version = if raw_upper.contains("WPA3") {
+            "wpa3"
+        } else if raw_upper.contains("WPA2") {
+            "wpa2"
+        } else {
+            "wpa"
+        };
+        format!("{}-enterprise", version)
+    } else if raw_upper.contains("WPA3") {
         "wpa3".to_string()
     } else if raw_upper.contains("WPA2") {
         "wpa2".to_string()
@@ -262,6 +1505,22 @@ pub struct MockWifiManager {
     pub status: WifiStatus,
     pub networks: Vec<Network>,
     pub connect_result: Result<(), String>,
+    /// Result of `check_network` once connected.
+    pub network_check: ConnectivityResult,
+    /// Addresses reported by `addresses`.
+    pub addresses: Vec<IpPrefix>,
+    /// Regulatory country, changed by `set_country`.
+    pub country: std::sync::Mutex<Option<CountryCode>>,
+    /// SSID of the last successful connect, reported by `status`.
+    pub connected_to: std::sync::Mutex<Option<Ssid>>,
+    /// Last connect request.
+    pub last_request: std::sync::Mutex<Option<ConnectRequest>>,
+    /// Snapshots passed to `restore`.
+    pub restored: std::sync::Mutex<Vec<ConnectionSnapshot>>,
+    /// SSIDs passed to `remove_superseded`.
+    pub superseded: std::sync::Mutex<Vec<Ssid>>,
+    /// Saved profiles.
+    pub saved: std::sync::Mutex<Vec<SavedNetwork>>,
 }
 
 #[cfg(test)]
@@ -274,6 +1533,14 @@ impl Default for MockWifiManager {
             },
             networks: vec![],
             connect_result: Ok(()),
+            network_check: Ok(()),
+            addresses: vec![],
+            country: std::sync::Mutex::new(None),
+            connected_to: std::sync::Mutex::new(None),
+            last_request: std::sync::Mutex::new(None),
+            restored: std::sync::Mutex::new(Vec::new()),
+            superseded: std::sync::Mutex::new(Vec::new()),
+            saved: std::sync::Mutex::new(Vec::new()),
         }
     }
 }
@@ -281,90 +1548,255 @@ impl Default for MockWifiManager {
 #[cfg(test)]
 impl WifiManager for MockWifiManager {
     async fn status(&self) -> WifiResult<WifiStatus> {
-        Ok(self.status.clone())
+        match self.connected_to.lock().unwrap().clone() {
+            Some(ssid) => Ok(WifiStatus {
+                connected: true,
+                ssid: Some(ssid),
+            }),
+            None => Ok(self.status.clone()),
+        }
     }
 
     async fn scan(&self) -> WifiResult<Vec<Network>> {
         Ok(self.networks.clone())
     }
 
-    async fn connect(&self, ssid: &str, _password: &str) -> WifiResult<()> {
+    async fn connect(&self, request: &ConnectRequest) -> WifiResult<()> {
+        let ssid = &request.ssid;
+        *self.last_request.lock().unwrap() = Some(request.clone());
         match &self.connect_result {
-            Ok(()) => Ok(()),
+            Ok(()) => {
+                *self.connected_to.lock().unwrap() = Some(ssid.clone());
+                Ok(())
+            }
             Err(msg) => Err(WifiError::ConnectionFailed(format!(
                 "Mock connect to {} failed: {}",
                 ssid, msg
             ))),
         }
     }
+
+    async fn snapshot(&self) -> WifiResult<ConnectionSnapshot> {
+        let status = self.status().await?;
+        let active = match status.ssid {
+            Some(ssid) if status.connected => Some(SavedConnection {
+                id: ssid.to_string(),
+                ssid,
+            }),
+            _ => None,
+        };
+        Ok(ConnectionSnapshot {
+            active,
+            profiles: Vec::new(),
+        })
+    }
+
+    async fn restore(&self, snapshot: &ConnectionSnapshot) -> WifiResult<()> {
+        *self.connected_to.lock().unwrap() = snapshot.active.as_ref().map(|a| a.ssid.clone());
+        self.restored.lock().unwrap().push(snapshot.clone());
+        Ok(())
+    }
+
+    async fn remove_superseded(
+        &self,
+        ssid: &Ssid,
+        _previous: &ConnectionSnapshot,
+    ) -> WifiResult<()> {
+        self.superseded.lock().unwrap().push(ssid.clone());
+        Ok(())
+    }
+
+    async fn list_saved(&self) -> WifiResult<Vec<SavedNetwork>> {
+        Ok(self.saved.lock().unwrap().clone())
+    }
+
+    async fn forget(&self, id: &str) -> WifiResult<()> {
+        let mut saved = self.saved.lock().unwrap();
+        let before = saved.len();
+        saved.retain(|profile| profile.id != id);
+        if saved.len() == before {
+            return Err(WifiError::UnknownProfile(id.to_string()));
+        }
+        Ok(())
+    }
+
+    async fn set_priority(&self, id: &str, priority: i32) -> WifiResult<()> {
+        let mut saved = self.saved.lock().unwrap();
+        let profile = saved
+            .iter_mut()
+            .find(|profile| profile.id == id)
+            .ok_or_else(|| WifiError::UnknownProfile(id.to_string()))?;
+        profile.priority = priority;
+        Ok(())
+    }
+
+    async fn set_autoconnect(&self, id: &str, autoconnect: bool) -> WifiResult<()> {
+        let mut saved = self.saved.lock().unwrap();
+        let profile = saved
+            .iter_mut()
+            .find(|profile| profile.id == id)
+            .ok_or_else(|| WifiError::UnknownProfile(id.to_string()))?;
+        profile.autoconnect = autoconnect;
+        Ok(())
+    }
+
+    async fn check_network(&self) -> ConnectivityResult {
+        self.network_check.clone()
+    }
+
+    async fn addresses(&self) -> Vec<IpPrefix> {
+        self.addresses.clone()
+    }
+
+    async fn country(&self) -> WifiResult<Option<CountryCode>> {
+        Ok(*self.country.lock().unwrap())
+    }
+
+    async fn set_country(&self, country: CountryCode) -> WifiResult<()> {
+        *self.country.lock().unwrap() = Some(country);
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::{EapMethod, Phase2Auth};
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+
+    /// Build `SSID-HEX:rest` scan lines.
+    fn scan_lines(lines: &[(&str, &str)]) -> String {
+        lines
+            .iter()
+            .map(|(ssid, rest)| format!("{}:{}\n", Ssid::from(*ssid).to_hex().to_uppercase(), rest))
+            .collect()
+    }
 
     #[test]
     fn parse_basic_scan_output() {
-        let output = "turtleback:72:WPA1\nonionchan:65:WPA1 WPA2\n";
-        let networks = parse_scan_output(output);
+        let output = scan_lines(&[
+            ("turtleback", "AA\\:BB\\:CC\\:DD\\:EE\\:01:2412 MHz:1:72:WPA1"),
+            ("onionchan", "AA\\:BB\\:CC\\:DD\\:EE\\:02:5180 MHz:36:65:WPA1 WPA2"),
+        ]);
+        let signals = HashMap::from([("aa:bb:cc:dd:ee:02".to_string(), -52)]);
+        let networks = parse_scan_output(&output, &signals);
 
         assert_eq!(networks.len(), 2);
 
         // Sorted by signal strength.
         assert_eq!(networks[0].ssid, "turtleback");
-        assert_eq!(networks[0].signal, -28); // -100 + 72
+        assert_eq!(networks[0].signal, None);
+        assert_eq!(networks[0].quality, 72);
         assert_eq!(networks[0].security, "wpa");
 
+        // dBm is only reported where it was measured.
         assert_eq!(networks[1].ssid, "onionchan");
-        assert_eq!(networks[1].signal, -35); // -100 + 65
+        assert_eq!(networks[1].signal, Some(-52));
         assert_eq!(networks[1].security, "wpa2");
+
+        let ap = &networks[1].access_points[0];
+        assert_eq!(ap.bssid, "aa:bb:cc:dd:ee:02");
+        assert_eq!(ap.frequency, 5180);
+        assert_eq!(ap.band, "5GHz");
+        assert_eq!(ap.channel, 36);
     }
 
     #[test]
     fn parse_empty_output() {
-        let networks = parse_scan_output("");
+        let networks = parse_scan_output("", &HashMap::new());
         assert!(networks.is_empty());
     }
 
     #[test]
-    fn parse_skips_empty_ssids() {
-        let output = ":50:WPA2\nvisible:60:WPA2\n";
-        let networks = parse_scan_output(output);
+    fn parse_groups_hidden_access_points() {
+        let output = scan_lines(&[
+            ("", "AA\\:BB\\:CC\\:DD\\:EE\\:01:2412 MHz:1:50:WPA2"),
+            ("visible", "AA\\:BB\\:CC\\:DD\\:EE\\:02:2412 MHz:1:60:WPA2"),
+            ("\0\0\0", "AA\\:BB\\:CC\\:DD\\:EE\\:03:5180 MHz:36:40:WPA2"),
+        ]);
+        let networks = parse_scan_output(&output, &HashMap::new());
 
-        assert_eq!(networks.len(), 1);
+        assert_eq!(networks.len(), 2);
         assert_eq!(networks[0].ssid, "visible");
+        assert!(!networks[0].hidden);
+        assert_eq!(networks[1].ssid, "");
+        assert!(networks[1].hidden);
+        assert_eq!(networks[1].access_points.len(), 2);
     }
 
     #[test]
-    fn parse_deduplicates_ssids() {
-        // Same SSID from multiple APs.
-        let output = "mynet:80:WPA2\nmynet:60:WPA2\nmynet:40:WPA2\n";
-        let networks = parse_scan_output(output);
+    fn parse_groups_access_points_by_ssid() {
+        // Same SSID from multiple APs on both bands.
+        let output = scan_lines(&[
+            ("mynet", "AA\\:BB\\:CC\\:DD\\:EE\\:01:2437 MHz:6:60:WPA2"),
+            ("mynet", "AA\\:BB\\:CC\\:DD\\:EE\\:02:5745 MHz:149:80:WPA2"),
+            ("mynet", "AA\\:BB\\:CC\\:DD\\:EE\\:03:2462 MHz:11:40:WPA2"),
+        ]);
+        let networks = parse_scan_output(&output, &HashMap::new());
 
         assert_eq!(networks.len(), 1);
         assert_eq!(networks[0].ssid, "mynet");
-        // Should keep the first one seen.
-        assert_eq!(networks[0].signal, -20);
+        // Takes the strongest AP, not the first one seen.
+        assert_eq!(networks[0].quality, 80);
+        assert_eq!(networks[0].access_points[0].bssid, "aa:bb:cc:dd:ee:02");
+
+        let aps = &networks[0].access_points;
+        assert_eq!(aps.len(), 3);
+        assert_eq!(aps[0].band, "5GHz");
+        assert_eq!(aps[0].channel, 149);
+        assert_eq!(aps[1].bssid, "aa:bb:cc:dd:ee:01");
+        assert_eq!(aps[2].band, "2.4GHz");
     }
 
     #[test]
     fn parse_skips_malformed_lines() {
-        let output = "good:50:WPA2\nbad line\nalso:bad\ngood2:30:open\n";
-        let networks = parse_scan_output(output);
+        let output = scan_lines(&[
+            ("good", "AA\\:BB\\:CC\\:DD\\:EE\\:01:2412 MHz:1:50:WPA2"),
+            ("bad", "line"),
+            ("also", "bad"),
+            ("good2", "AA\\:BB\\:CC\\:DD\\:EE\\:02:2412 MHz:1:30:open"),
+        ]) + "not-hex:AA\\:BB\\:CC\\:DD\\:EE\\:03:2412 MHz:1:30:open\n";
+        let networks = parse_scan_output(&output, &HashMap::new());
 
         assert_eq!(networks.len(), 2);
     }
 
     #[test]
     fn parse_handles_open_networks() {
-        let output = "opennet:45:\ncafewifi:50:--\n";
-        let networks = parse_scan_output(output);
+        let output = scan_lines(&[
+            ("opennet", "AA\\:BB\\:CC\\:DD\\:EE\\:01:2412 MHz:1:45:"),
+            ("cafewifi", "AA\\:BB\\:CC\\:DD\\:EE\\:02:2412 MHz:1:50:--"),
+        ]);
+        let networks = parse_scan_output(&output, &HashMap::new());
 
         assert_eq!(networks.len(), 2);
         assert_eq!(networks[0].security, "open");
         assert_eq!(networks[1].security, "open");
     }
 
+    #[test]
+    fn parse_keeps_ssid_bytes_exact() {
+        let output = scan_lines(&[
+            ("my:net\\", "AA\\:BB\\:CC\\:DD\\:EE\\:01:2412 MHz:1:50:WPA2"),
+        ]) + "636166E9:AA\\:BB\\:CC\\:DD\\:EE\\:02:2412 MHz:1:40:WPA2\n";
+        let networks = parse_scan_output(&output, &HashMap::new());
+
+        assert_eq!(networks[0].ssid, "my:net\\");
+        assert_eq!(networks[0].ssid_hex, None);
+        assert_eq!(networks[1].ssid, "caf\u{fffd}");
+        assert_eq!(networks[1].ssid_hex.as_deref(), Some("636166e9"));
+        assert_eq!(networks[1].raw_ssid().as_bytes(), b"caf\xe9");
+    }
+
+    #[test]
+    fn parse_active_ssid_from_hex() {
+        let output = " :686F6D65\n*:636166E9\n";
+        assert_eq!(parse_active_ssid(output), Some(Ssid::new(b"caf\xe9".to_vec())));
+        assert_eq!(parse_active_ssid(" :686F6D65\n"), None);
+        assert_eq!(parse_active_ssid(""), None);
+    }
+
     #[test]
     fn normalize_security_types() {
         assert_eq!(normalize_security("WPA3"), "wpa3");
@@ -376,16 +1808,529 @@ mod tests {
         assert_eq!(normalize_security("WEP"), "wep");
         assert_eq!(normalize_security(""), "open");
         assert_eq!(normalize_security("--"), "open");
+        assert_eq!(normalize_security("WPA2 802.1X"), "wpa2-enterprise");
+        assert_eq!(normalize_security("WPA3 802.1X"), "wpa3-enterprise");
+        assert_eq!(normalize_security("WPA1 802.1X"), "wpa-enterprise");
+    }
+
+    #[test]
+    fn parses_iw_scan_dump() {
+        let output = "BSS aa:bb:cc:dd:ee:01(on wlan0) -- associated\n\
+            \tfreq: 2412\n\
+            \tsignal: -47.00 dBm\n\
+            \tSSID: home\n\
+            BSS AA:BB:CC:DD:EE:02(on wlan0)\n\
+            \tsignal: -71.50 dBm\n\
+            BSS aa:bb:cc:dd:ee:03(on wlan0)\n\
+            \tSSID: no-signal\n";
+        let signals = parse_iw_scan_dump(output);
+
+        assert_eq!(signals.len(), 2);
+        assert_eq!(signals["aa:bb:cc:dd:ee:01"], -47);
+        assert_eq!(signals["aa:bb:cc:dd:ee:02"], -72);
+        assert!(parse_iw_scan_dump("").is_empty());
+    }
+
+    #[test]
+    fn signal_conversion_to_quality() {
+        // NetworkManager maps -90..-20 dBm onto 0..100%.
+        assert_eq!(dbm_to_quality(-20), 100);
+        assert_eq!(dbm_to_quality(-55), 50);
+        assert_eq!(dbm_to_quality(-95), 0);
+        assert_eq!(dbm_to_quality(-10), 100);
+    }
+
+    #[test]
+    fn bands_and_channels() {
+        assert_eq!(band_for_frequency(2412), "2.4GHz");
+        assert_eq!(channel_for_frequency(2412), 1);
+        assert_eq!(channel_for_frequency(2484), 14);
+        assert_eq!(band_for_frequency(5500), "5GHz");
+        assert_eq!(channel_for_frequency(5500), 100);
+        assert_eq!(band_for_frequency(5975), "6GHz");
+        assert_eq!(channel_for_frequency(5975), 5);
+        assert_eq!(band_for_frequency(0), "unknown");
+        assert_eq!(channel_for_frequency(0), 0);
+    }
+
+    #[test]
+    fn split_terse_unescapes() {
+        assert_eq!(split_terse("a\\:b:c\\\\d:"), vec!["a:b", "c\\d", ""]);
+        assert_eq!(split_terse("\\\\\\::x"), vec!["\\:", "x"]);
+        assert_eq!(unescape_terse("my\\:net\\\\"), "my:net\\");
+    }
+
+    #[test]
+    fn parse_connection_list_finds_wifi_profiles() {
+        let output = "0b1c-home:802-11-wireless:wlan0\n\
+            77aa-eth:802-3-ethernet:eth0\n\
+            5d2e-bridge:bridge:br\\:0\n\
+            9f00-cafe:802-11-wireless:\n";
+        let (profiles, active) = parse_connection_list(output);
+
+        assert_eq!(profiles, vec!["0b1c-home", "9f00-cafe"]);
+        assert_eq!(active.as_deref(), Some("0b1c-home"));
+    }
+
+    #[test]
+    fn parse_wifi_device_skips_other_types() {
+        let output = "eth0:ethernet\nusb0:ethernet\np2p-dev-wlan0:wifi-p2p\nwlan0:wifi\nlo:loopback\n";
+        assert_eq!(parse_wifi_device(output).as_deref(), Some("wlan0"));
+        assert_eq!(parse_wifi_device("eth0:ethernet\n"), None);
+    }
+
+    #[test]
+    fn parse_profiles_named_finds_same_ssid_profiles() {
+        let output = "0b1c-old:802-11-wireless:home\n\
+            9f00-new:802-11-wireless:home\n\
+            77aa-eth:802-3-ethernet:home\n\
+            5d2e-cafe:802-11-wireless:home 2\n\
+            c3d4-colon:802-11-wireless:a\\:b\n";
+
+        assert_eq!(
+            parse_profiles_named(output, "home"),
+            vec!["0b1c-old", "9f00-new"]
+        );
+        assert_eq!(parse_profiles_named(output, "a:b"), vec!["c3d4-colon"]);
+        assert!(parse_profiles_named(output, "office").is_empty());
+    }
+
+    #[tokio::test]
+    async fn connect_verified_restores_previous_connection() {
+        let wifi = MockWifiManager {
+            status: WifiStatus {
+                connected: true,
+                ssid: Some("old".into()),
+            },
+            connect_result: Err("auth failed".into()),
+            ..Default::default()
+        };
+
+        let failure = connect_verified(&wifi, &ConnectRequest::psk("new", "wrong"), Duration::ZERO)
+            .await
+            .unwrap_err();
+
+        assert_eq!(failure.step, ConnectivityStep::Association);
+        assert_eq!(failure.restored.as_deref(), Some("old"));
+        assert!(failure.to_string().ends_with("restored old"));
+        assert_eq!(wifi.restored.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn connect_verified_rolls_back_unverified_connection() {
+        let wifi = MockWifiManager {
+            status: WifiStatus {
+                connected: true,
+                ssid: Some("old".into()),
+            },
+            network_check: Err(ConnectivityFailure::new(ConnectivityStep::Address, "no address")),
+            ..Default::default()
+        };
+
+        let request = ConnectRequest::psk("new", "hunter22");
+        let failure = connect_verified(&wifi, &request, Duration::ZERO)
+            .await
+            .unwrap_err();
+
+        assert_eq!(failure.step, ConnectivityStep::Address);
+        assert_eq!(failure.restored.as_deref(), Some("old"));
+        assert_eq!(wifi.status().await.unwrap().ssid, Some("old".into()));
+        assert!(wifi.superseded.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn connect_verified_joins_unlisted_ssid_as_hidden() {
+        let wifi = MockWifiManager {
+            networks: vec![Network {
+                ssid: "visible".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        connect_verified(&wifi, &ConnectRequest::psk("secret", "hunter22"), Duration::ZERO)
+            .await
+            .unwrap();
+        assert!(wifi.last_request.lock().unwrap().as_ref().unwrap().hidden);
+
+        connect_verified(&wifi, &ConnectRequest::psk("visible", "hunter22"), Duration::ZERO)
+            .await
+            .unwrap();
+        assert!(!wifi.last_request.lock().unwrap().as_ref().unwrap().hidden);
+    }
+
+    #[tokio::test]
+    async fn connect_verified_leaves_good_connection_alone() {
+        let wifi = MockWifiManager::default();
+
+        connect_verified(&wifi, &ConnectRequest::psk("new", "hunter22"), Duration::ZERO)
+            .await
+            .unwrap();
+
+        assert!(wifi.restored.lock().unwrap().is_empty());
+        assert_eq!(*wifi.superseded.lock().unwrap(), vec![Ssid::from("new")]);
+    }
+
+    #[tokio::test]
+    async fn cancelled_connect_rolls_back() {
+        let wifi = MockWifiManager {
+            status: WifiStatus {
+                connected: true,
+                ssid: Some("old".into()),
+            },
+            network_check: Err(ConnectivityFailure::new(ConnectivityStep::Dns, "timed out")),
+            ..Default::default()
+        };
+        let request = ConnectRequest::psk("new", "hunter22");
+
+        // Before connecting: nothing to roll back.
+        let failure = connect_verified_cancellable(
+            &wifi,
+            &request,
+            Duration::ZERO,
+            |_| {},
+            std::future::ready(()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(failure.code, ErrorCode::Cancelled);
+        assert!(wifi.last_request.lock().unwrap().is_none());
+        assert!(wifi.restored.lock().unwrap().is_empty());
+
+        // While verifying, which would otherwise keep retrying for a minute.
+        let mut stages = Vec::new();
+        let failure = connect_verified_cancellable(
+            &wifi,
+            &request,
+            Duration::from_secs(60),
+            |stage| stages.push(stage),
+            tokio::time::sleep(Duration::from_millis(10)),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(failure.code, ErrorCode::Cancelled);
+        assert_eq!(failure.restored.as_deref(), Some("old"));
+        assert_eq!(wifi.restored.lock().unwrap().len(), 1);
+        assert_eq!(stages.last(), Some(&ProvisioningStage::Failed));
+    }
+
+    #[tokio::test]
+    async fn connect_verified_reports_each_stage() {
+        let wifi = MockWifiManager::default();
+        let mut stages = Vec::new();
+        let request = ConnectRequest::psk("new", "hunter22");
+        connect_verified_with_progress(&wifi, &request, Duration::ZERO, |stage| {
+            stages.push(stage)
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            stages,
+            [
+                ProvisioningStage::Provisioning,
+                ProvisioningStage::Verifying,
+                ProvisioningStage::Connected
+            ]
+        );
+
+        let wifi = MockWifiManager {
+            connect_result: Err("auth failed".into()),
+            ..Default::default()
+        };
+        let mut stages = Vec::new();
+        connect_verified_with_progress(&wifi, &request, Duration::ZERO, |stage| {
+            stages.push(stage)
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(
+            stages,
+            [ProvisioningStage::Provisioning, ProvisioningStage::Failed]
+        );
+    }
+
+    #[test]
+    fn validates_connect_requests() {
+        assert!(ConnectRequest::psk("home", "hunter22").validate().is_ok());
+        assert!(ConnectRequest::psk("", "hunter22").validate().is_err());
+        assert!(ConnectRequest::psk([b'x'; 32].as_slice(), "").validate().is_ok());
+        assert!(ConnectRequest::psk([b'x'; 33].as_slice(), "").validate().is_err());
+
+        let mut request = ConnectRequest::psk("home", "hunter22");
+        request.ip.ipv4.method = IpMethod::Manual;
+        let err = request.validate().unwrap_err();
+        assert!(err.to_string().starts_with("Invalid IP settings"));
+    }
+
+    #[test]
+    fn checks_credentials_against_security() {
+        let psk = ConnectRequest::psk("home", "hunter22");
+        let open = ConnectRequest::psk("cafe", "");
+        let enterprise = ConnectRequest::new("eduroam", Credentials::Enterprise(eduroam()));
+
+        assert!(psk.check_security(Security::Wpa2).is_ok());
+        assert!(psk.check_security(Security::Wep).is_ok());
+        assert!(open.check_security(Security::Open).is_ok());
+        assert!(enterprise.check_security(Security::Wpa3Enterprise).is_ok());
+
+        let err = open.check_security(Security::Wpa3).unwrap_err();
+        assert_eq!(err.to_string(), "Network is wpa3: a password is required");
+        assert!(psk.check_security(Security::Open).is_err());
+        assert!(psk.check_security(Security::Wpa2Enterprise).is_err());
+        assert!(enterprise.check_security(Security::Wpa2).is_err());
+    }
+
+    fn eduroam() -> EnterpriseCredentials {
+        EnterpriseCredentials {
+            eap: EapMethod::Peap,
+            phase2: Phase2Auth::Mschapv2,
+            identity: "alice@example.edu".to_string(),
+            anonymous_identity: Some("anonymous@example.edu".to_string()),
+            password: "hunter22".into(),
+            ca_cert: None,
+        }
+    }
+
+    fn args_to_strings(args: &[OsString]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn enterprise_profile_arguments() {
+        let request = ConnectRequest {
+            hidden: true,
+            ..ConnectRequest::new("eduroam", Credentials::Enterprise(eduroam()))
+        };
+        let args = profile_args(&request, Some("/data/certs/ca.pem")).unwrap();
+
+        assert_eq!(
+            args_to_strings(&args),
+            [
+                "connection",
+                "add",
+                "type",
+                "wifi",
+                "con-name",
+                "eduroam",
+                "ssid",
+                "eduroam",
+                "802-11-wireless.hidden",
+                "yes",
+                "wifi-sec.key-mgmt",
+                "wpa-eap",
+                "802-1x.eap",
+                "peap",
+                "802-1x.phase2-auth",
+                "mschapv2",
+                "802-1x.identity",
+                "alice@example.edu",
+                "802-1x.anonymous-identity",
+                "anonymous@example.edu",
+                "802-1x.ca-cert",
+                "/data/certs/ca.pem",
+            ]
+        );
+    }
+
+    #[test]
+    fn profile_arguments_never_carry_secrets() {
+        for request in [
+            ConnectRequest::psk("home", "hunter22"),
+            ConnectRequest::new("eduroam", Credentials::Enterprise(eduroam())),
+        ] {
+            let args = args_to_strings(&profile_args(&request, None).unwrap());
+            assert!(!args.iter().any(|arg| arg.contains("hunter22")), "{:?}", args);
+        }
+
+        let args = profile_args(&ConnectRequest::psk("home", "hunter22"), None).unwrap();
+        assert_eq!(args_to_strings(&args[8..]), ["wifi-sec.key-mgmt", "wpa-psk"]);
+        let args = profile_args(&ConnectRequest::psk("cafe", ""), None).unwrap();
+        assert_eq!(args.len(), 8);
+    }
+
+    /// Static IPv4 with DNS servers of both families and a PAC URL.
+    fn static_ip() -> IpConfig {
+        serde_json::from_str(
+            r#"{"ipv4":{"method":"manual","addresses":["192.168.1.50/24"],"gateway":"192.168.1.1"},
+                "ipv6":{"method":"disabled"},
+                "dns":["192.168.1.1","1.1.1.1"],"dns_search":["lan"],
+                "proxy_pac_url":"http://wpad/wpad.dat"}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn profile_arguments_apply_ip_settings() {
+        let request = ConnectRequest {
+            ip: static_ip(),
+            ..ConnectRequest::psk("cafe", "")
+        };
+        let args = profile_args(&request, None).unwrap();
+
+        assert_eq!(
+            args_to_strings(&args[8..]),
+            [
+                "ipv4.method",
+                "manual",
+                "ipv4.addresses",
+                "192.168.1.50/24",
+                "ipv4.gateway",
+                "192.168.1.1",
+                "ipv4.dns",
+                "192.168.1.1,1.1.1.1",
+                "ipv4.dns-search",
+                "lan",
+                "ipv6.method",
+                "disabled",
+                "proxy.method",
+                "auto",
+                "proxy.pac-url",
+                "http://wpad/wpad.dat",
+            ]
+        );
+        assert!(ip_properties(&IpConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn profile_arguments_keep_non_utf8_ssid_bytes() {
+        let args = profile_args(&ConnectRequest::psk(vec![b'c', 0xe9], "hunter22"), None).unwrap();
+        assert_eq!(args[7].as_bytes(), [b'c', 0xe9]);
+
+        assert!(profile_args(&ConnectRequest::psk(vec![b'a', 0], "hunter22"), None).is_err());
+    }
+
+    #[test]
+    fn passwd_file_holds_the_secret() {
+        let psk = Credentials::Psk("hunter22".into());
+        assert_eq!(
+            passwd_file_contents(&psk).unwrap().expose(),
+            "802-11-wireless-security.psk:hunter22\n"
+        );
+        assert_eq!(
+            passwd_file_contents(&Credentials::Enterprise(eduroam())).unwrap().expose(),
+            "802-1x.password:hunter22\n"
+        );
+        assert!(passwd_file_contents(&Credentials::Psk(Secret::default())).is_none());
+
+        let contents = passwd_file_contents(&psk).unwrap();
+        let file = PasswdFile::create(&contents).unwrap();
+        let path = file.path.clone();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), contents.expose());
+        drop(file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn parse_added_uuid_from_output() {
+        assert_eq!(
+            parse_added_uuid(
+                "Connection 'lab (2)' (5b2e1c4a-0000-4000-8000-000000000001) successfully added.\n"
+            )
+            .as_deref(),
+            Some("5b2e1c4a-0000-4000-8000-000000000001")
+        );
+        assert_eq!(parse_added_uuid("Error: failed"), None);
+    }
+
+    #[test]
+    fn ca_cert_paths_are_keyed_by_ssid_hex() {
+        assert_eq!(
+            ca_cert_path("/data/certs/", &Ssid::new(vec![0x65, 0xff])),
+            "/data/certs/65ff.pem"
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_verified_passes_enterprise_credentials() {
+        let wifi = MockWifiManager {
+            networks: vec![Network {
+                ssid: "eduroam".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let request = ConnectRequest::new("eduroam", Credentials::Enterprise(eduroam()));
+
+        connect_verified(&wifi, &request, Duration::ZERO).await.unwrap();
+
+        assert_eq!(wifi.last_request.lock().unwrap().as_ref(), Some(&request));
     }
 
     #[test]
-    fn signal_conversion_to_dbm() {
-        // nmcli reports 0-100, we convert to dBm.
-        let output = "net100:100:WPA2\nnet50:50:WPA2\nnet0:0:WPA2\n";
-        let networks = parse_scan_output(output);
-
-        assert_eq!(networks[0].signal, 0);    // -100 + 100
-        assert_eq!(networks[1].signal, -50);  // -100 + 50
-        assert_eq!(networks[2].signal, -100); // -100 + 0
+    fn parse_saved_list_marks_persistent_profiles() {
+        let output = "0b1c:802-11-wireless:yes:10:\
+            /etc/NetworkManager/system-connections/home\\:2.nmconnection\n\
+            77aa:802-3-ethernet:yes:0:/etc/NetworkManager/system-connections/eth.nmconnection\n\
+            9f00:802-11-wireless:no:0:/run/NetworkManager/system-connections/cafe.nmconnection\n";
+        let saved = parse_saved_list(output);
+
+        assert_eq!(saved.len(), 2);
+        assert_eq!(saved[0].id, "0b1c");
+        assert_eq!(saved[0].priority, 10);
+        assert!(saved[0].autoconnect);
+        assert!(saved[0].persistent);
+        assert_eq!(saved[1].id, "9f00");
+        assert!(!saved[1].autoconnect);
+        assert!(!saved[1].persistent);
+    }
+
+    #[test]
+    fn classifies_backend_errors() {
+        let cases = [
+            (
+                "Error: Connection activation failed: Secrets were required, but not provided.",
+                ErrorCode::WrongPassword,
+            ),
+            ("wrong password", ErrorCode::WrongPassword),
+            ("Error: No network with SSID 'home' found.", ErrorCode::SsidNotFound),
+            ("Activation failed: IP configuration could not be reserved", ErrorCode::DhcpTimeout),
+            ("Error: Wi-Fi is disabled", ErrorCode::RadioDisabled),
+            ("Error: NetworkManager is not running.", ErrorCode::ServiceNotRunning),
+            (
+                "org.freedesktop.DBus.Error.ServiceUnknown: The name is not activatable",
+                ErrorCode::ServiceNotRunning,
+            ),
+            (
+                "Error: Failed to add 'home' connection: Insufficient privileges",
+                ErrorCode::PermissionDenied,
+            ),
+            ("connect /run/wpa_supplicant/wlan0: Permission denied", ErrorCode::PermissionDenied),
+            ("something odd", ErrorCode::Unknown),
+        ];
+        for (message, code) in cases {
+            assert_eq!(ErrorCode::classify(message), code, "{}", message);
+        }
+
+        assert_eq!(WifiError::NoWifiDevice.code(), ErrorCode::NoDevice);
+        assert_eq!(WifiError::ParseError("Secrets".into()).code(), ErrorCode::Unknown);
+    }
+
+    #[test]
+    fn error_codes_serialize_as_numbers() {
+        assert_eq!(serde_json::to_string(&ErrorCode::DhcpTimeout).unwrap(), "4");
+        assert_eq!(
+            serde_json::from_str::<ErrorCode>("8").unwrap(),
+            ErrorCode::PermissionDenied
+        );
+        assert!(serde_json::from_str::<ErrorCode>("0").is_err());
+        for code in ErrorCode::ALL {
+            assert_eq!(ErrorCode::from_number(code.number()), Some(code));
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_verified_classifies_connect_errors() {
+        let wifi = MockWifiManager {
+            connect_result: Err("Secrets were required, but not provided".into()),
+            ..Default::default()
+        };
+
+        let failure = connect_verified(&wifi, &ConnectRequest::psk("home", "wrong"), Duration::ZERO)
+            .await
+            .unwrap_err();
+
+        assert_eq!(failure.code, ErrorCode::WrongPassword);
     }
 }
@@ -0,0 +1,1440 @@
+//! WiFi management via the wpa_supplicant control socket.
+//!
+//! For images without NetworkManager. Commands go over the same Unix
+//! datagram protocol `wpa_cli` uses; a second, attached socket receives
+//! events so scans and connection attempts can be awaited.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use tokio::net::UnixDatagram;
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
+
+use crate::protocol::{AccessPointInfo, EnterpriseCredentials, Network, SavedNetwork};
+use crate::regdomain::{self, CountryCode, COUNTRY_FILE};
+use crate::secret::Secret;
+use crate::ssid::Ssid;
+use crate::wifi::{
+    band_for_frequency, channel_for_frequency, dbm_to_quality, group_access_points,
+    store_ca_cert, ConnectRequest, ConnectionSnapshot, Credentials, SavedConnection, WifiError,
+    WifiManager, WifiResult, WifiStatus,
+};
+
+/// Default control socket for the WiFi interface.
+pub const DEFAULT_CTRL_PATH: &str = "/var/run/wpa_supplicant/wlan0";
+
+/// Directory for our end of the control socket.
+///
+/// wpa_supplicant sends its replies to this socket, so it can't be in the
+/// service's private /tmp. systemd creates it from `RuntimeDirectory=`.
+pub const DEFAULT_LOCAL_DIR: &str = "/run/wifi-provisioner";
+
+/// Maximum size of a control socket reply.
+const REPLY_BUF_SIZE: usize = 8192;
+
+/// How long to wait for a command reply.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for a requested scan to finish.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for a connection attempt to succeed or fail.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Unique suffix for local socket names within this process.
+static SOCKET_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+/// One client socket connected to wpa_supplicant.
+///
+/// The local socket file is removed on drop.
+struct CtrlSocket {
+    socket: UnixDatagram,
+    local_path: PathBuf,
+}
+
+impl CtrlSocket {
+    async fn open(ctrl_path: &Path, local_dir: &Path) -> WifiResult<Self> {
+        let local_path = local_dir.join(format!(
+            "wifi-provisioner-{}-{}",
+            std::process::id(),
+            SOCKET_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_file(&local_path);
+
+        let socket = UnixDatagram::bind(&local_path).map_err(|e| {
+            WifiError::ControlSocket(format!("Failed to bind {}: {}", local_path.display(), e))
+        })?;
+        let ctrl = Self { socket, local_path };
+
+        ctrl.socket.connect(ctrl_path).map_err(|e| {
+            WifiError::ControlSocket(format!(
+                "Failed to connect to {}: {}",
+                ctrl_path.display(),
+                e
+            ))
+        })?;
+
+        Ok(ctrl)
+    }
+
+    /// Send a command and return its reply, skipping unsolicited events.
+    async fn request(&self, command: &str) -> WifiResult<String> {
+        let verb = command.split(' ').next().unwrap_or(command);
+        debug!("wpa_supplicant request: {}", verb);
+
+        self.socket
+            .send(command.as_bytes())
+            .await
+            .map_err(|e| WifiError::ControlSocket(format!("Failed to send {}: {}", verb, e)))?;
+
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        loop {
+            let message = tokio::time::timeout_at(deadline, self.recv())
+                .await
+                .map_err(|_| {
+                    WifiError::ControlSocket(format!("No reply from wpa_supplicant to {}", verb))
+                })??;
+
+            // Events start with a priority like "<3>".
+            if !message.starts_with('<') {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Send a command that replies "OK".
+    async fn request_ok(&self, command: &str) -> WifiResult<()> {
+        let reply = self.request(command).await?;
+        if reply.trim() == "OK" {
+            Ok(())
+        } else {
+            let verb = command.split(' ').next().unwrap_or(command);
+            Err(WifiError::ControlSocket(format!(
+                "{} failed: {}",
+                verb,
+                reply.trim()
+            )))
+        }
+    }
+
+    /// Start receiving events on this socket.
+    async fn attach(&self) -> WifiResult<()> {
+        self.request_ok("ATTACH").await
+    }
+
+    /// Wait for the next event, without its priority prefix.
+    async fn next_event(&self) -> WifiResult<String> {
+        loop {
+            let message = self.recv().await?;
+            if let Some(event) = strip_priority(&message) {
+                return Ok(event.to_string());
+            }
+        }
+    }
+
+    async fn recv(&self) -> WifiResult<String> {
+        let mut buf = vec![0u8; REPLY_BUF_SIZE];
+        let n = self.socket.recv(&mut buf).await.map_err(|e| {
+            WifiError::ControlSocket(format!("Failed to read from wpa_supplicant: {}", e))
+        })?;
+        Ok(String::from_utf8_lossy(&buf[..n]).into_owned())
+    }
+}
+
+impl Drop for CtrlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.local_path);
+    }
+}
+
+/// Strip the "<N>" priority from an event message.
+fn strip_priority(message: &str) -> Option<&str> {
+    let rest = message.strip_prefix('<')?;
+    let end = rest.find('>')?;
+    Some(&rest[end + 1..])
+}
+
+/// Outcome of a connection attempt signalled by an event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectEvent {
+    /// Associated and authenticated.
+    Connected,
+    /// The attempt failed for this reason.
+    Failed(String),
+}
+
+/// Classify an event received while connecting.
+///
+/// Returns `None` for events that don't decide the outcome.
+pub fn classify_connect_event(event: &str) -> Option<ConnectEvent> {
+    if event.starts_with("CTRL-EVENT-CONNECTED") {
+        Some(ConnectEvent::Connected)
+    } else if event.starts_with("CTRL-EVENT-SSID-TEMP-DISABLED") {
+        let reason = event
+            .split(' ')
+            .find_map(|field| field.strip_prefix("reason="))
+            .unwrap_or("unknown");
+        let reason = match reason {
+            "WRONG_KEY" => "wrong password".to_string(),
+            "AUTH_FAILED" => "authentication failed".to_string(),
+            "CONN_FAILED" => "connection failed".to_string(),
+            other => other.to_lowercase(),
+        };
+        Some(ConnectEvent::Failed(reason))
+    } else if event.starts_with("CTRL-EVENT-NETWORK-NOT-FOUND") {
+        Some(ConnectEvent::Failed("network not found".to_string()))
+    } else {
+        None
+    }
+}
+
+/// Decode an SSID as printed by wpa_supplicant.
+///
+/// Non-printable bytes are escaped as `\xNN`, and `\\`, `\"`, `\e`, `\n`,
+/// `\r` and `\t` are escaped too. Returns the raw bytes, which need not be
+/// UTF-8.
+pub fn decode_ssid(escaped: &str) -> Ssid {
+    let bytes = escaped.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != b'\\' || i + 1 >= bytes.len() {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        match bytes[i + 1] {
+            b'x' if i + 3 < bytes.len() => {
+                match u8::from_str_radix(&escaped[i + 2..i + 4], 16) {
+                    Ok(b) => out.push(b),
+                    Err(_) => out.extend_from_slice(&bytes[i..i + 4]),
+                }
+                i += 4;
+                continue;
+            }
+            b'e' => out.push(0x1b),
+            b'n' => out.push(b'\n'),
+            b'r' => out.push(b'\r'),
+            b't' => out.push(b'\t'),
+            other => out.push(other),
+        }
+        i += 2;
+    }
+
+    Ssid::new(out)
+}
+
+/// Parse STATUS output into a WiFi status.
+pub fn parse_status(output: &str) -> WifiStatus {
+    let fields: HashMap<&str, &str> = output
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .collect();
+
+    let connected = fields.get("wpa_state") == Some(&"COMPLETED");
+    WifiStatus {
+        connected,
+        ssid: if connected {
+            fields.get("ssid").map(|ssid| decode_ssid(ssid))
+        } else {
+            None
+        },
+    }
+}
+
+/// Parse LIST_NETWORKS output.
+///
+/// Format (after a header line): `id\tssid\tbssid\tflags`. Returns every
+/// network id and the network flagged `[CURRENT]`.
+pub fn parse_network_list(output: &str) -> ConnectionSnapshot {
+    let mut snapshot = ConnectionSnapshot::default();
+
+    for line in output.lines().skip(1) {
+        let parts: Vec<&str> = line.split('\t').collect();
+        if parts.len() < 4 || parts[0].parse::<u32>().is_err() {
+            continue;
+        }
+        snapshot.profiles.push(parts[0].to_string());
+        if parts[3].contains("[CURRENT]") {
+            snapshot.active = Some(SavedConnection {
+                id: parts[0].to_string(),
+                ssid: decode_ssid(parts[1]),
+            });
+        }
+    }
+
+    snapshot
+}
+
+/// Parse LIST_NETWORKS output into saved profiles.
+///
+/// Priorities aren't listed and are left at 0. wpa_supplicant.conf is not
+/// part of the NetworkManager store, so no profile is marked persistent.
+pub fn parse_saved_networks(output: &str) -> Vec<SavedNetwork> {
+    output
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.split('\t').collect();
+            if parts.len() < 4 || parts[0].parse::<u32>().is_err() {
+                return None;
+            }
+            let ssid = decode_ssid(parts[1]);
+            Some(SavedNetwork {
+                id: parts[0].to_string(),
+                ssid: ssid.to_string(),
+                ssid_hex: ssid.hex_if_not_utf8(),
+                priority: 0,
+                autoconnect: !parts[3].contains("[DISABLED]"),
+                persistent: false,
+            })
+        })
+        .collect()
+}
+
+/// Ids of the networks in LIST_NETWORKS output that aren't `[DISABLED]`.
+pub fn parse_enabled_networks(output: &str) -> Vec<String> {
+    parse_saved_networks(output)
+        .into_iter()
+        .filter(|network| network.autoconnect)
+        .map(|network| network.id)
+        .collect()
+}
+
+/// Ids of the networks in LIST_NETWORKS output for this SSID.
+pub fn parse_networks_for_ssid(output: &str, ssid: &Ssid) -> Vec<String> {
+    output
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.split('\t').collect();
+            if parts.len() < 4 || parts[0].parse::<u32>().is_err() {
+                return None;
+            }
+            (decode_ssid(parts[1]) == *ssid).then(|| parts[0].to_string())
+        })
+        .collect()
+}
+
+/// Security type from SCAN_RESULTS flags, e.g. `[WPA2-PSK-CCMP][ESS]`.
+fn security_from_flags(flags: &str) -> String {
+    let security = if flags.contains("EAP-SUITE-B") {
+        "wpa3-enterprise"
+    } else if flags.contains("[WPA2-EAP") || flags.contains("[RSN-EAP") {
+        "wpa2-enterprise"
+    } else if flags.contains("[WPA-EAP") {
+        "wpa-enterprise"
+    } else if flags.contains("SAE") {
+        "wpa3"
+    } else if flags.contains("[WPA2-") || flags.contains("[RSN-") {
+        "wpa2"
+    } else if flags.contains("[WPA-") {
+        "wpa"
+    } else if flags.contains("[WEP]") {
+        "wep"
+    } else {
+        "open"
+    };
+    security.to_string()
+}
+
+/// A `SET_NETWORK` setting (name and value) for an 802.1X network.
+#[derive(Debug, PartialEq)]
+enum EapSetting {
+    Plain(String),
+    /// The identity or password, redacted from logs.
+    Credential(Secret),
+}
+
+/// `SET_NETWORK` settings for an 802.1X network.
+///
+/// Free-form strings are sent as hex, like the SSID, so quotes and
+/// backslashes in identities and passwords need no escaping.
+fn eap_network_settings(
+    enterprise: &EnterpriseCredentials,
+    ca_cert: Option<&str>,
+) -> Vec<EapSetting> {
+    let hex = |value: &str| Ssid::from(value).to_hex();
+    let credential = |setting: String| EapSetting::Credential(Secret::new(setting));
+    let mut settings = vec![
+        EapSetting::Plain("key_mgmt WPA-EAP".to_string()),
+        EapSetting::Plain(format!("eap {}", enterprise.eap.as_str().to_uppercase())),
+        credential(format!("identity {}", hex(&enterprise.identity))),
+        credential(format!("password {}", hex(enterprise.password.expose()))),
+        EapSetting::Plain(format!(
+            "phase2 \"auth={}\"",
+            enterprise.phase2.as_str().to_uppercase()
+        )),
+    ];
+    if let Some(anonymous) = &enterprise.anonymous_identity {
+        settings.push(EapSetting::Plain(format!(
+            "anonymous_identity {}",
+            hex(anonymous)
+        )));
+    }
+    if let Some(path) = ca_cert {
+        settings.push(EapSetting::Plain(format!("ca_cert \"{}\"", path)));
+    }
+    settings
+}
+
+/// Parse SCAN_RESULTS output into networks.
+///
+/// Format (after a header line): `bssid\tfrequency\tsignal\tflags\tssid`,
+/// with the signal already in dBm. Groups BSSes by SSID, with hidden ones in
+/// a single entry, and sorts by signal strength (strongest first).
+pub fn parse_scan_results(output: &str) -> Vec<Network> {
+    let mut access_points = Vec::new();
+
+    for line in output.lines().skip(1) {
+        let parts: Vec<&str> = line.splitn(5, '\t').collect();
+        if parts.len() < 5 {
+            debug!("Skipping malformed scan result: {}", line);
+            continue;
+        }
+
+        let signal = match parts[2].parse::<i32>() {
+            Ok(s) => s,
+            Err(_) => {
+                debug!("Skipping scan result with invalid signal: {}", line);
+                continue;
+            }
+        };
+        let frequency = parts[1].parse::<u32>().unwrap_or(0);
+
+        let ap = AccessPointInfo {
+            bssid: parts[0].to_lowercase(),
+            frequency,
+            band: band_for_frequency(frequency).to_string(),
+            channel: channel_for_frequency(frequency),
+            signal: Some(signal),
+            quality: dbm_to_quality(signal),
+        };
+        access_points.push((decode_ssid(parts[4]), security_from_flags(parts[3]), ap));
+    }
+
+    group_access_points(access_points)
+}
+
+/// WiFi manager using the wpa_supplicant control socket.
+pub struct WpaSupplicantWifiManager {
+    ctrl_path: PathBuf,
+    local_dir: PathBuf,
+    country_file: PathBuf,
+}
+
+impl WpaSupplicantWifiManager {
+    /// Create a manager for the control socket at `ctrl_path`.
+    pub fn new(ctrl_path: impl Into<PathBuf>) -> Self {
+        Self {
+            ctrl_path: ctrl_path.into(),
+            local_dir: PathBuf::from(DEFAULT_LOCAL_DIR),
+            country_file: PathBuf::from(COUNTRY_FILE),
+        }
+    }
+
+    /// Create our end of the socket in `local_dir` instead of
+    /// `DEFAULT_LOCAL_DIR`.
+    pub fn with_local_dir(mut self, local_dir: impl Into<PathBuf>) -> Self {
+        self.local_dir = local_dir.into();
+        self
+    }
+
+    /// Persist the country code in `country_file` instead of `COUNTRY_FILE`.
+    pub fn with_country_file(mut self, country_file: impl Into<PathBuf>) -> Self {
+        self.country_file = country_file.into();
+        self
+    }
+
+    async fn open(&self) -> WifiResult<CtrlSocket> {
+        CtrlSocket::open(&self.ctrl_path, &self.local_dir).await
+    }
+
+    /// Open a socket that receives events.
+    async fn events(&self) -> WifiResult<CtrlSocket> {
+        let events = self.open().await?;
+        events.attach().await?;
+        Ok(events)
+    }
+
+    /// Run a command on a saved network and persist the change.
+    async fn modify_network(&self, id: &str, command: &str) -> WifiResult<()> {
+        let ctrl = self.open().await?;
+        let networks = parse_network_list(&ctrl.request("LIST_NETWORKS").await?);
+        if !networks.profiles.iter().any(|profile| profile == id) {
+            return Err(WifiError::UnknownProfile(id.to_string()));
+        }
+
+        ctrl.request_ok(command).await?;
+        if let Err(e) = ctrl.request_ok("SAVE_CONFIG").await {
+            warn!("Changed network {}, but failed to save config: {}", id, e);
+        }
+        Ok(())
+    }
+
+    /// Request a scan and wait for its results.
+    ///
+    /// A refused scan (e.g. one already running) falls back to the cached
+    /// results.
+    async fn rescan(&self, ctrl: &CtrlSocket) -> WifiResult<()> {
+        let events = self.events().await?;
+
+        if let Err(e) = ctrl.request_ok("SCAN").await {
+            debug!("Scan request refused, using cached results: {}", e);
+            return Ok(());
+        }
+
+        let wait = async {
+            loop {
+                if events
+                    .next_event()
+                    .await?
+                    .starts_with("CTRL-EVENT-SCAN-RESULTS")
+                {
+                    return Ok::<(), WifiError>(());
+                }
+            }
+        };
+        match tokio::time::timeout(SCAN_TIMEOUT, wait).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!("Scan did not finish within {}s", SCAN_TIMEOUT.as_secs());
+                Ok(())
+            }
+        }
+    }
+
+    /// Re-enable `ids` after SELECT_NETWORK disabled every other network.
+    ///
+    /// Only networks that were enabled beforehand are passed in, so the ones
+    /// the user turned autoconnect off for stay off.
+    async fn reenable(&self, ctrl: &CtrlSocket, ids: &[String]) {
+        for id in ids {
+            if let Err(e) = ctrl.request_ok(&format!("ENABLE_NETWORK {}", id)).await {
+                warn!("Failed to re-enable network {}: {}", id, e);
+            }
+        }
+    }
+
+    /// Add and select a network, then wait for the outcome.
+    async fn select(
+        &self,
+        ctrl: &CtrlSocket,
+        events: &CtrlSocket,
+        id: &str,
+        request: &ConnectRequest,
+        ca_cert: Option<&str>,
+    ) -> WifiResult<()> {
+        let ssid = &request.ssid;
+        // Hex avoids any quoting rules and carries non-UTF-8 bytes as is.
+        ctrl.request_ok(&format!("SET_NETWORK {} ssid {}", id, ssid.to_hex()))
+            .await?;
+
+        if request.hidden {
+            // Probe for the SSID instead of waiting for it in a broadcast.
+            ctrl.request_ok(&format!("SET_NETWORK {} scan_ssid 1", id))
+                .await?;
+        }
+
+        match &request.credentials {
+            Credentials::Psk(password) if password.is_empty() => {
+                ctrl.request_ok(&format!("SET_NETWORK {} key_mgmt NONE", id))
+                    .await?;
+            }
+            Credentials::Psk(password) => {
+                let command =
+                    Secret::new(format!("SET_NETWORK {} psk \"{}\"", id, password.expose()));
+                ctrl.request_ok(command.expose())
+                    .await
+                    .map_err(|_| {
+                        WifiError::ConnectionFailed("Password must be 8-63 characters".to_string())
+                    })?;
+            }
+            Credentials::Enterprise(enterprise) => {
+                for setting in eap_network_settings(enterprise, ca_cert) {
+                    match setting {
+                        EapSetting::Plain(setting) => {
+                            ctrl.request_ok(&format!("SET_NETWORK {} {}", id, setting))
+                                .await?;
+                        }
+                        EapSetting::Credential(setting) => {
+                            let command =
+                                Secret::new(format!("SET_NETWORK {} {}", id, setting.expose()));
+                            ctrl.request_ok(command.expose()).await?;
+                        }
+                    }
+                }
+            }
+        }
+
+        ctrl.request_ok(&format!("SELECT_NETWORK {}", id)).await?;
+
+        let wait = async {
+            loop {
+                let event = events.next_event().await?;
+                debug!("wpa_supplicant event: {}", event);
+                if let Some(outcome) = classify_connect_event(&event) {
+                    return Ok::<ConnectEvent, WifiError>(outcome);
+                }
+            }
+        };
+
+        match tokio::time::timeout(CONNECT_TIMEOUT, wait).await {
+            Ok(Ok(ConnectEvent::Connected)) => Ok(()),
+            Ok(Ok(ConnectEvent::Failed(reason))) => Err(WifiError::ConnectionFailed(format!(
+                "Failed to connect to {}: {}",
+                ssid, reason
+            ))),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(WifiError::ConnectionFailed(format!(
+                "Failed to connect to {}: timed out",
+                ssid
+            ))),
+        }
+    }
+}
+
+impl WifiManager for WpaSupplicantWifiManager {
+    async fn status(&self) -> WifiResult<WifiStatus> {
+        let output = self.open().await?.request("STATUS").await?;
+        let status = parse_status(&output);
+
+        match &status.ssid {
+            Some(ssid) => info!("WiFi connected to: {}", ssid),
+            None => info!("WiFi not connected"),
+        }
+        Ok(status)
+    }
+
+    async fn scan(&self) -> WifiResult<Vec<Network>> {
+        let ctrl = self.open().await?;
+        self.rescan(&ctrl).await?;
+
+        let output = ctrl.request("SCAN_RESULTS").await?;
+        let networks = parse_scan_results(&output);
+        info!("Found {} WiFi networks", networks.len());
+        Ok(networks)
+    }
+
+    async fn connect(&self, request: &ConnectRequest) -> WifiResult<()> {
+        let ssid = &request.ssid;
+        info!("Connecting to WiFi network: {}", ssid);
+
+        // wpa_supplicant only associates; addressing is up to whatever runs
+        // DHCP on the interface.
+        if !request.ip.is_default() {
+            return Err(WifiError::ConnectionFailed(
+                "IP, DNS and proxy settings need the NetworkManager backend".to_string(),
+            ));
+        }
+
+        let ca_cert = match &request.credentials {
+            Credentials::Enterprise(EnterpriseCredentials {
+                ca_cert: Some(pem), ..
+            }) => Some(store_ca_cert(ssid, pem)?),
+            _ => None,
+        };
+
+        let ctrl = self.open().await?;
+        let events = self.events().await?;
+
+        // SELECT_NETWORK disables everything else; note what to turn back on.
+        let enabled = parse_enabled_networks(&ctrl.request("LIST_NETWORKS").await?);
+
+        let id = ctrl.request("ADD_NETWORK").await?.trim().to_string();
+        if id.parse::<u32>().is_err() {
+            return Err(WifiError::ControlSocket(format!(
+                "ADD_NETWORK failed: {}",
+                id
+            )));
+        }
+
+        match self
+            .select(&ctrl, &events, &id, request, ca_cert.as_deref())
+            .await
+        {
+            Ok(()) => {
+                info!("Successfully connected to {}", ssid);
+                // Before saving, or the others are written as disabled=1.
+                self.reenable(&ctrl, &enabled).await;
+                if let Err(e) = ctrl.request_ok("SAVE_CONFIG").await {
+                    // Needs update_config=1 in wpa_supplicant.conf.
+                    warn!("Connected, but failed to save config: {}", e);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                error!("{}", e);
+                // Drop the new network and re-enable the ones SELECT_NETWORK disabled.
+                let _ = ctrl.request_ok(&format!("REMOVE_NETWORK {}", id)).await;
+                self.reenable(&ctrl, &enabled).await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn snapshot(&self) -> WifiResult<ConnectionSnapshot> {
+        let output = self.open().await?.request("LIST_NETWORKS").await?;
+        Ok(parse_network_list(&output))
+    }
+
+    async fn restore(&self, snapshot: &ConnectionSnapshot) -> WifiResult<()> {
+        let ctrl = self.open().await?;
+        let list = ctrl.request("LIST_NETWORKS").await?;
+        let current = parse_network_list(&list);
+        let enabled: Vec<String> = parse_enabled_networks(&list)
+            .into_iter()
+            .filter(|id| snapshot.profiles.contains(id))
+            .collect();
+
+        for id in current
+            .profiles
+            .iter()
+            .filter(|id| !snapshot.profiles.contains(id))
+        {
+            info!("Removing network {}", id);
+            if let Err(e) = ctrl.request_ok(&format!("REMOVE_NETWORK {}", id)).await {
+                warn!("Failed to remove network {}: {}", id, e);
+            }
+        }
+
+        if let Some(active) = &snapshot.active {
+            info!("Reselecting previous network {}", active.ssid);
+            ctrl.request_ok(&format!("SELECT_NETWORK {}", active.id))
+                .await?;
+            self.reenable(&ctrl, &enabled).await;
+        }
+
+        if let Err(e) = ctrl.request_ok("SAVE_CONFIG").await {
+            warn!("Restored, but failed to save config: {}", e);
+        }
+        Ok(())
+    }
+
+    async fn remove_superseded(
+        &self,
+        ssid: &Ssid,
+        previous: &ConnectionSnapshot,
+    ) -> WifiResult<()> {
+        // Every connect adds a network, so older ones for the same SSID are
+        // removed once the new one is verified.
+        let ctrl = self.open().await?;
+        let list = ctrl.request("LIST_NETWORKS").await?;
+        let superseded: Vec<String> = parse_networks_for_ssid(&list, ssid)
+            .into_iter()
+            .filter(|id| previous.profiles.contains(id))
+            .collect();
+        if superseded.is_empty() {
+            return Ok(());
+        }
+
+        for id in &superseded {
+            info!("Removing superseded network {}", id);
+            if let Err(e) = ctrl.request_ok(&format!("REMOVE_NETWORK {}", id)).await {
+                warn!("Failed to remove network {}: {}", id, e);
+            }
+        }
+        if let Err(e) = ctrl.request_ok("SAVE_CONFIG").await {
+            warn!(
+                "Removed superseded networks, but failed to save config: {}",
+                e
+            );
+        }
+        Ok(())
+    }
+
+    async fn list_saved(&self) -> WifiResult<Vec<SavedNetwork>> {
+        let ctrl = self.open().await?;
+        let mut saved = parse_saved_networks(&ctrl.request("LIST_NETWORKS").await?);
+
+        for network in &mut saved {
+            let reply = ctrl
+                .request(&format!("GET_NETWORK {} priority", network.id))
+                .await?;
+            network.priority = reply.trim().parse().unwrap_or(0);
+        }
+        Ok(saved)
+    }
+
+    async fn forget(&self, id: &str) -> WifiResult<()> {
+        info!("Removing network {}", id);
+        self.modify_network(id, &format!("REMOVE_NETWORK {}", id))
+            .await
+    }
+
+    async fn set_priority(&self, id: &str, priority: i32) -> WifiResult<()> {
+        self.modify_network(id, &format!("SET_NETWORK {} priority {}", id, priority))
+            .await
+    }
+
+    async fn set_autoconnect(&self, id: &str, autoconnect: bool) -> WifiResult<()> {
+        let verb = if autoconnect {
+            "ENABLE_NETWORK"
+        } else {
+            "DISABLE_NETWORK"
+        };
+        self.modify_network(id, &format!("{} {}", verb, id)).await
+    }
+
+    async fn country(&self) -> WifiResult<Option<CountryCode>> {
+        let reply = self.open().await?.request("GET country").await?;
+        Ok(reply.parse().ok())
+    }
+
+    /// wpa_supplicant passes the country on to the driver.
+    async fn set_country(&self, country: CountryCode) -> WifiResult<()> {
+        let ctrl = self.open().await?;
+        ctrl.request_ok(&format!("SET country {}", country)).await?;
+        regdomain::store(&self.country_file, country)
+    }
+
+    /// wpa_supplicant names each control socket after its interface.
+    async fn interface(&self) -> Option<String> {
+        self.ctrl_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+    }
+}
+
+/// Scripted stand-in for wpa_supplicant's control socket.
+///
+/// Replies to commands by prefix and, after selected commands, sends events
+/// to every attached client. Records every command it receives.
+#[cfg(test)]
+pub struct FakeWpaSupplicant {
+    pub ctrl_path: PathBuf,
+    pub dir: PathBuf,
+    commands: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeScript {
+    /// (command prefix, reply); the first match wins, otherwise "OK".
+    pub replies: Vec<(String, String)>,
+    /// (command prefix, events sent to attached clients after replying).
+    pub events: Vec<(String, Vec<String>)>,
+}
+
+#[cfg(test)]
+impl FakeScript {
+    pub fn reply(mut self, prefix: &str, reply: &str) -> Self {
+        self.replies.push((prefix.to_string(), reply.to_string()));
+        self
+    }
+
+    pub fn event_after(mut self, prefix: &str, event: &str) -> Self {
+        self.events
+            .push((prefix.to_string(), vec![format!("<3>{}", event)]));
+        self
+    }
+}
+
+#[cfg(test)]
+impl FakeWpaSupplicant {
+    /// Start a fake in a fresh directory.
+    pub fn spawn(name: &str, script: FakeScript) -> Self {
+        let dir = std::env::temp_dir().join(format!("wpa-fake-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let ctrl_path = dir.join("wlan0");
+        let socket = UnixDatagram::bind(&ctrl_path).unwrap();
+        let commands = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = std::sync::Arc::clone(&commands);
+
+        tokio::spawn(async move {
+            let mut attached: Vec<PathBuf> = Vec::new();
+            let mut buf = vec![0u8; REPLY_BUF_SIZE];
+            loop {
+                let (n, addr) = match socket.recv_from(&mut buf).await {
+                    Ok(r) => r,
+                    Err(_) => return,
+                };
+                let Some(client) = addr.as_pathname().map(Path::to_path_buf) else {
+                    continue;
+                };
+                let command = String::from_utf8_lossy(&buf[..n]).into_owned();
+                log.lock().unwrap().push(command.clone());
+
+                match command.as_str() {
+                    "ATTACH" => attached.push(client.clone()),
+                    "DETACH" => attached.retain(|c| c != &client),
+                    _ => {}
+                }
+
+                let reply = script
+                    .replies
+                    .iter()
+                    .find(|(prefix, _)| command.starts_with(prefix.as_str()))
+                    .map_or("OK\n", |(_, reply)| reply.as_str());
+                let _ = socket.send_to(reply.as_bytes(), &client).await;
+
+                for (prefix, events) in &script.events {
+                    if command.starts_with(prefix.as_str()) {
+                        for event in events {
+                            for target in &attached {
+                                let _ = socket.send_to(event.as_bytes(), target).await;
+                            }
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            ctrl_path,
+            dir,
+            commands,
+        }
+    }
+
+    /// A manager pointed at this fake.
+    pub fn manager(&self) -> WpaSupplicantWifiManager {
+        WpaSupplicantWifiManager::new(&self.ctrl_path)
+            .with_local_dir(&self.dir)
+            .with_country_file(self.dir.join("wifi-country"))
+    }
+
+    /// Commands received so far.
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Drop for FakeWpaSupplicant {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{EapMethod, Phase2Auth};
+
+    const SCAN_RESULTS: &str = "bssid / frequency / signal level / flags / ssid\n\
+        aa:bb:cc:dd:ee:01\t2412\t-60\t[WPA2-PSK-CCMP][ESS]\thome\n\
+        aa:bb:cc:dd:ee:02\t5180\t-45\t[WPA2-PSK-CCMP][ESS]\thome\n\
+        aa:bb:cc:dd:ee:03\t2437\t-70\t[ESS]\tcafe\n\
+        aa:bb:cc:dd:ee:04\t2462\t-50\t[WPA2-SAE-CCMP][ESS]\tnew\\xc3\\xa9\n\
+        aa:bb:cc:dd:ee:05\t2462\t-30\t[WPA2-PSK-CCMP][ESS]\t\n";
+
+    #[test]
+    fn parses_connected_status() {
+        let status = parse_status(
+            "bssid=aa:bb:cc:dd:ee:01\nssid=my \\\"wifi\\\"\nid=0\nwpa_state=COMPLETED\n",
+        );
+        assert!(status.connected);
+        assert_eq!(status.ssid, Some("my \"wifi\"".into()));
+    }
+
+    #[test]
+    fn parses_disconnected_status() {
+        let status = parse_status("wpa_state=SCANNING\nssid=home\n");
+        assert!(!status.connected);
+        assert_eq!(status.ssid, None);
+    }
+
+    #[test]
+    fn parses_scan_results() {
+        let networks = parse_scan_results(SCAN_RESULTS);
+
+        assert_eq!(networks.len(), 4);
+        assert!(networks[0].hidden);
+        assert_eq!(networks[0].signal, Some(-30));
+        assert_eq!(networks[1].ssid, "home");
+        assert_eq!(networks[1].signal, Some(-45));
+        assert_eq!(networks[1].security, "wpa2");
+        assert_eq!(networks[2].ssid, "newé");
+        assert_eq!(networks[2].security, "wpa3");
+        assert_eq!(networks[3].ssid, "cafe");
+        assert_eq!(networks[3].security, "open");
+
+        let home = &networks[1].access_points;
+        assert_eq!(home.len(), 2);
+        assert_eq!(home[0].bssid, "aa:bb:cc:dd:ee:02");
+        assert_eq!((home[0].band.as_str(), home[0].channel), ("5GHz", 36));
+        assert_eq!(home[0].quality, 64);
+        assert_eq!((home[1].band.as_str(), home[1].signal), ("2.4GHz", Some(-60)));
+    }
+
+    #[test]
+    fn enterprise_security_from_flags() {
+        assert_eq!(security_from_flags("[WPA2-EAP-CCMP][ESS]"), "wpa2-enterprise");
+        assert_eq!(security_from_flags("[RSN-EAP-CCMP][ESS]"), "wpa2-enterprise");
+        assert_eq!(security_from_flags("[WPA-EAP-TKIP][ESS]"), "wpa-enterprise");
+        assert_eq!(
+            security_from_flags("[RSN-EAP-SUITE-B-192-GCMP-256][ESS]"),
+            "wpa3-enterprise"
+        );
+    }
+
+    #[test]
+    fn decodes_escaped_ssids() {
+        assert_eq!(decode_ssid("plain"), "plain");
+        assert_eq!(decode_ssid("a\\\\b"), "a\\b");
+        assert_eq!(decode_ssid("tab\\there"), "tab\there");
+        assert_eq!(decode_ssid("caf\\xc3\\xa9"), "café");
+        assert_eq!(decode_ssid("caf\\xe9").as_bytes(), b"caf\xe9");
+    }
+
+    #[test]
+    fn classifies_connect_events() {
+        assert_eq!(
+            classify_connect_event("CTRL-EVENT-CONNECTED - Connection to aa:bb completed [id=1]"),
+            Some(ConnectEvent::Connected)
+        );
+        assert_eq!(
+            classify_connect_event(
+                "CTRL-EVENT-SSID-TEMP-DISABLED id=1 ssid=\"home\" auth_failures=1 duration=10 reason=WRONG_KEY"
+            ),
+            Some(ConnectEvent::Failed("wrong password".into()))
+        );
+        assert_eq!(
+            classify_connect_event("CTRL-EVENT-NETWORK-NOT-FOUND"),
+            Some(ConnectEvent::Failed("network not found".into()))
+        );
+        assert_eq!(classify_connect_event("CTRL-EVENT-SCAN-STARTED "), None);
+    }
+
+    #[tokio::test]
+    async fn status_over_fake_socket() {
+        let fake = FakeWpaSupplicant::spawn(
+            "status",
+            FakeScript::default().reply("STATUS", "wpa_state=COMPLETED\nssid=home\n"),
+        );
+
+        let status = fake.manager().status().await.unwrap();
+
+        assert_eq!(status.ssid, Some("home".into()));
+        assert_eq!(fake.commands(), vec!["STATUS"]);
+    }
+
+    #[tokio::test]
+    async fn scan_waits_for_results() {
+        let fake = FakeWpaSupplicant::spawn(
+            "scan",
+            FakeScript::default()
+                .reply("SCAN_RESULTS", SCAN_RESULTS)
+                .event_after("SCAN", "CTRL-EVENT-SCAN-RESULTS "),
+        );
+
+        let networks = fake.manager().scan().await.unwrap();
+
+        assert_eq!(networks.len(), 4);
+        assert_eq!(fake.commands(), vec!["ATTACH", "SCAN", "SCAN_RESULTS"]);
+    }
+
+    #[tokio::test]
+    async fn connect_saves_config_on_success() {
+        let fake = FakeWpaSupplicant::spawn(
+            "connect",
+            FakeScript::default()
+                .reply("ADD_NETWORK", "1\n")
+                .event_after(
+                    "SELECT_NETWORK",
+                    "CTRL-EVENT-CONNECTED - Connection completed [id=1]",
+                ),
+        );
+
+        fake.manager().connect(&ConnectRequest::psk("home", "hunter22")).await.unwrap();
+
+        assert_eq!(
+            fake.commands(),
+            vec![
+                "ATTACH",
+                "LIST_NETWORKS",
+                "ADD_NETWORK",
+                "SET_NETWORK 1 ssid 686f6d65",
+                "SET_NETWORK 1 psk \"hunter22\"",
+                "SELECT_NETWORK 1",
+                "SAVE_CONFIG",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_open_network() {
+        let fake = FakeWpaSupplicant::spawn(
+            "open",
+            FakeScript::default()
+                .reply("ADD_NETWORK", "0\n")
+                .event_after(
+                    "SELECT_NETWORK",
+                    "CTRL-EVENT-CONNECTED - Connection completed [id=0]",
+                ),
+        );
+
+        fake.manager().connect(&ConnectRequest::psk("cafe", "")).await.unwrap();
+
+        assert!(fake
+            .commands()
+            .contains(&"SET_NETWORK 0 key_mgmt NONE".to_string()));
+    }
+
+    #[tokio::test]
+    async fn connect_hidden_network_probes_for_ssid() {
+        let fake = FakeWpaSupplicant::spawn(
+            "hidden",
+            FakeScript::default()
+                .reply("ADD_NETWORK", "4\n")
+                .event_after(
+                    "SELECT_NETWORK",
+                    "CTRL-EVENT-CONNECTED - Connection completed [id=4]",
+                ),
+        );
+
+        let request = ConnectRequest {
+            hidden: true,
+            ..ConnectRequest::psk("secret", "hunter22")
+        };
+        fake.manager().connect(&request).await.unwrap();
+
+        assert!(fake
+            .commands()
+            .contains(&"SET_NETWORK 4 scan_ssid 1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn connect_enterprise_network() {
+        let fake = FakeWpaSupplicant::spawn(
+            "enterprise",
+            FakeScript::default()
+                .reply("ADD_NETWORK", "5\n")
+                .event_after(
+                    "SELECT_NETWORK",
+                    "CTRL-EVENT-CONNECTED - Connection completed [id=5]",
+                ),
+        );
+        let enterprise = EnterpriseCredentials {
+            eap: EapMethod::Peap,
+            phase2: Phase2Auth::Mschapv2,
+            identity: "bob".to_string(),
+            anonymous_identity: Some("anon".to_string()),
+            password: "pa\"ss".into(),
+            ca_cert: None,
+        };
+
+        fake.manager()
+            .connect(&ConnectRequest::new("eduroam", Credentials::Enterprise(enterprise)))
+            .await
+            .unwrap();
+
+        let commands = fake.commands();
+        for expected in [
+            "SET_NETWORK 5 key_mgmt WPA-EAP",
+            "SET_NETWORK 5 eap PEAP",
+            "SET_NETWORK 5 identity 626f62",
+            "SET_NETWORK 5 password 7061227373",
+            "SET_NETWORK 5 phase2 \"auth=MSCHAPV2\"",
+            "SET_NETWORK 5 anonymous_identity 616e6f6e",
+        ] {
+            assert!(commands.contains(&expected.to_string()), "missing {}", expected);
+        }
+        assert!(!commands.iter().any(|c| c.contains(" psk ")));
+    }
+
+    #[tokio::test]
+    async fn connect_keeps_disabled_networks_disabled() {
+        let fake = FakeWpaSupplicant::spawn(
+            "keep-disabled",
+            FakeScript::default()
+                .reply(
+                    "LIST_NETWORKS",
+                    "network id / ssid / bssid / flags\n0\thome\tany\t[CURRENT]\n1\tcafe\tany\t[DISABLED]\n",
+                )
+                .reply("ADD_NETWORK", "2\n")
+                .event_after(
+                    "SELECT_NETWORK",
+                    "CTRL-EVENT-CONNECTED - Connection completed [id=2]",
+                ),
+        );
+
+        fake.manager().connect(&ConnectRequest::psk("new", "hunter22")).await.unwrap();
+
+        let commands = fake.commands();
+        let tail: Vec<&str> = commands
+            .iter()
+            .skip_while(|c| !c.starts_with("SELECT_NETWORK"))
+            .map(String::as_str)
+            .collect();
+        assert_eq!(tail, vec!["SELECT_NETWORK 2", "ENABLE_NETWORK 0", "SAVE_CONFIG"]);
+    }
+
+    #[test]
+    fn eap_settings_wrap_only_credentials() {
+        let enterprise = EnterpriseCredentials {
+            eap: EapMethod::Ttls,
+            phase2: Phase2Auth::Pap,
+            identity: "bob".to_string(),
+            anonymous_identity: Some("anon".to_string()),
+            password: "pw".into(),
+            ca_cert: None,
+        };
+
+        let settings = eap_network_settings(&enterprise, Some("/ca.pem"));
+        let credentials: Vec<&str> = settings
+            .iter()
+            .filter_map(|setting| match setting {
+                EapSetting::Credential(secret) => secret.expose().split(' ').next(),
+                EapSetting::Plain(_) => None,
+            })
+            .collect();
+        assert_eq!(credentials, ["identity", "password"]);
+    }
+
+    #[tokio::test]
+    async fn connect_reports_wrong_password_and_cleans_up() {
+        let fake = FakeWpaSupplicant::spawn(
+            "wrong-key",
+            FakeScript::default()
+                .reply(
+                    "LIST_NETWORKS",
+                    "network id / ssid / bssid / flags\n0\thome\tany\t\n1\tcafe\tany\t[DISABLED]\n",
+                )
+                .reply("ADD_NETWORK", "2\n")
+                .event_after(
+                    "SELECT_NETWORK",
+                    "CTRL-EVENT-SSID-TEMP-DISABLED id=2 ssid=\"home\" auth_failures=1 duration=10 reason=WRONG_KEY",
+                ),
+        );
+
+        let err = fake
+            .manager()
+            .connect(&ConnectRequest::psk("home", "wrongpass"))
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("wrong password"));
+        let commands = fake.commands();
+        assert!(commands.contains(&"REMOVE_NETWORK 2".to_string()));
+        assert!(commands.contains(&"ENABLE_NETWORK 0".to_string()));
+        assert!(!commands.contains(&"ENABLE_NETWORK 1".to_string()));
+        assert!(!commands.contains(&"ENABLE_NETWORK all".to_string()));
+        assert!(!commands.contains(&"SAVE_CONFIG".to_string()));
+    }
+
+    #[tokio::test]
+    async fn connect_rejects_short_password() {
+        let fake = FakeWpaSupplicant::spawn(
+            "short-psk",
+            FakeScript::default()
+                .reply("ADD_NETWORK", "3\n")
+                .reply("SET_NETWORK 3 psk", "FAIL\n"),
+        );
+
+        let err = fake.manager().connect(&ConnectRequest::psk("home", "short")).await.unwrap_err();
+
+        assert!(matches!(err, WifiError::ConnectionFailed(_)));
+        assert!(fake.commands().contains(&"REMOVE_NETWORK 3".to_string()));
+    }
+
+    #[test]
+    fn parses_network_list() {
+        let snapshot = parse_network_list(
+            "network id / ssid / bssid / flags\n\
+             0\thome\tany\t[CURRENT]\n\
+             1\tcafe\tany\t[DISABLED]\n",
+        );
+
+        assert_eq!(snapshot.profiles, vec!["0", "1"]);
+        assert_eq!(
+            snapshot.active,
+            Some(SavedConnection {
+                id: "0".into(),
+                ssid: "home".into(),
+            })
+        );
+
+        // The active SSID keeps its raw bytes.
+        let snapshot =
+            parse_network_list("network id / ssid / bssid / flags\n2\tcaf\\xe9\tany\t[CURRENT]\n");
+        assert_eq!(
+            snapshot.active.unwrap().ssid,
+            Ssid::new(b"caf\xe9".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn restore_removes_new_networks_and_reselects_previous() {
+        let fake = FakeWpaSupplicant::spawn(
+            "restore",
+            FakeScript::default().reply(
+                "LIST_NETWORKS",
+                "network id / ssid / bssid / flags\n0\thome\tany\t\n1\tcafe\tany\t[DISABLED]\n\
+                 2\tnew\tany\t[CURRENT]\n3\twork\tany\t\n",
+            ),
+        );
+        let snapshot = ConnectionSnapshot {
+            active: Some(SavedConnection {
+                id: "0".into(),
+                ssid: "home".into(),
+            }),
+            profiles: vec!["0".into(), "1".into(), "3".into()],
+        };
+
+        fake.manager().restore(&snapshot).await.unwrap();
+
+        assert_eq!(
+            fake.commands(),
+            vec![
+                "LIST_NETWORKS",
+                "REMOVE_NETWORK 2",
+                "SELECT_NETWORK 0",
+                "ENABLE_NETWORK 0",
+                "ENABLE_NETWORK 3",
+                "SAVE_CONFIG",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn remove_superseded_removes_older_networks_for_the_ssid() {
+        let fake = FakeWpaSupplicant::spawn(
+            "superseded",
+            FakeScript::default().reply(
+                "LIST_NETWORKS",
+                "network id / ssid / bssid / flags\n0\thome\tany\t\n1\tcafe\tany\t\n\
+                 2\thome\tany\t\n3\thome\tany\t[CURRENT]\n",
+            ),
+        );
+        let previous = ConnectionSnapshot {
+            active: None,
+            profiles: vec!["0".into(), "1".into(), "2".into()],
+        };
+
+        fake.manager()
+            .remove_superseded(&Ssid::from("home"), &previous)
+            .await
+            .unwrap();
+        fake.manager()
+            .remove_superseded(&Ssid::from("work"), &previous)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            fake.commands(),
+            vec![
+                "LIST_NETWORKS",
+                "REMOVE_NETWORK 0",
+                "REMOVE_NETWORK 2",
+                "SAVE_CONFIG",
+                "LIST_NETWORKS",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_saved_networks_with_priorities() {
+        let fake = FakeWpaSupplicant::spawn(
+            "list-saved",
+            FakeScript::default()
+                .reply(
+                    "LIST_NETWORKS",
+                    "network id / ssid / bssid / flags\n0\thome\tany\t[CURRENT]\n1\tcafe\tany\t[DISABLED]\n",
+                )
+                .reply("GET_NETWORK 0 priority", "5")
+                .reply("GET_NETWORK 1 priority", "FAIL\n"),
+        );
+
+        let saved = fake.manager().list_saved().await.unwrap();
+
+        assert_eq!(saved.len(), 2);
+        assert_eq!((saved[0].ssid.as_str(), saved[0].priority), ("home", 5));
+        assert!(saved[0].autoconnect);
+        assert_eq!((saved[1].ssid.as_str(), saved[1].priority), ("cafe", 0));
+        assert!(!saved[1].autoconnect);
+    }
+
+    #[tokio::test]
+    async fn forget_removes_and_saves() {
+        let fake = FakeWpaSupplicant::spawn(
+            "forget",
+            FakeScript::default().reply(
+                "LIST_NETWORKS",
+                "network id / ssid / bssid / flags\n1\tcafe\tany\t\n",
+            ),
+        );
+
+        fake.manager().forget("1").await.unwrap();
+        assert!(matches!(
+            fake.manager().forget("7").await,
+            Err(WifiError::UnknownProfile(_))
+        ));
+
+        assert_eq!(
+            fake.commands(),
+            vec![
+                "LIST_NETWORKS",
+                "REMOVE_NETWORK 1",
+                "SAVE_CONFIG",
+                "LIST_NETWORKS"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_socket_is_an_error() {
+        let manager = WpaSupplicantWifiManager::new("/nonexistent/wpa_supplicant/wlan0")
+            .with_local_dir(std::env::temp_dir());
+
+        assert!(matches!(
+            manager.status().await,
+            Err(WifiError::ControlSocket(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn sets_and_persists_the_country() {
+        let fake = FakeWpaSupplicant::spawn("country", FakeScript::default());
+
+        fake.manager().set_country("de".parse().unwrap()).await.unwrap();
+
+        assert_eq!(fake.commands(), vec!["SET country DE"]);
+        assert_eq!(
+            std::fs::read_to_string(fake.dir.join("wifi-country")).unwrap(),
+            "DE\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_the_country() {
+        let fake = FakeWpaSupplicant::spawn(
+            "get-country",
+            FakeScript::default().reply("GET country", "US"),
+        );
+        assert_eq!(fake.manager().country().await.unwrap(), Some("US".parse().unwrap()));
+
+        let fake = FakeWpaSupplicant::spawn(
+            "no-country",
+            FakeScript::default().reply("GET country", "FAIL\n"),
+        );
+        assert_eq!(fake.manager().country().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn interface_is_named_after_the_socket() {
+        let wifi = WpaSupplicantWifiManager::new("/run/wpa_supplicant/wlan1");
+        assert_eq!(wifi.interface().await.as_deref(), Some("wlan1"));
+    }
+
+    #[tokio::test]
+    async fn connect_rejects_ip_settings() {
+        let manager = WpaSupplicantWifiManager::new("/nonexistent/wpa_supplicant/wlan0")
+            .with_local_dir(std::env::temp_dir());
+        let mut request = ConnectRequest::psk("home", "hunter22");
+        request.ip.proxy_pac_url = Some("http://wpad/wpad.dat".to_string());
+
+        assert!(matches!(
+            manager.connect(&request).await,
+            Err(WifiError::ConnectionFailed(_))
+        ));
+    }
+}
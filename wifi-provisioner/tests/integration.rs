@@ -1,5 +1,9 @@
 //! Integration tests for wifi-provisioner WebSocket API.
 
+// `Message::Text` takes a `String` in tungstenite 0.24; the `.into()` calls
+// keep these tests source-compatible with later versions.
+#![allow(clippy::useless_conversion)]
+
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
@@ -13,7 +17,7 @@ use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
 use tokio_tungstenite::tungstenite::Message;
 
 // Import from the crate.
-use wifi_provisioner::websocket::DaemonState;
+use wifi_provisioner::state_machine::{Input, StateMachine};
 
 /// Helper to start test server on a random port.
 async fn start_test_server() -> SocketAddr {
@@ -21,7 +25,7 @@ async fn start_test_server() -> SocketAddr {
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     let actual_addr = listener.local_addr().unwrap();
 
-    let state = Arc::new(RwLock::new(DaemonState::default()));
+    let state = Arc::new(RwLock::new(StateMachine::default()));
 
     // Spawn server task.
     tokio::spawn(async move {
@@ -42,9 +46,12 @@ async fn start_test_server() -> SocketAddr {
 /// Simplified connection handler for tests.
 async fn handle_test_connection(
     ws: WebSocketStream<TcpStream>,
-    state: Arc<RwLock<DaemonState>>,
+    state: Arc<RwLock<StateMachine>>,
 ) {
-    use wifi_provisioner::protocol::{Command, ErrorResponse, OkResponse, Response, State, Network};
+    use wifi_provisioner::protocol::{
+        Command, ErrorResponse, Hello, OkResponse, Response, SavedNetwork, State, Network,
+        Transport,
+    };
 
     let (mut write, mut read) = ws.split();
 
@@ -54,19 +61,21 @@ async fn handle_test_connection(
                 Ok(cmd) => match cmd {
                     Command::Start { timeout } => {
                         let mut s = state.write().await;
-                        s.state = State::Advertising;
-                        s.advertising_remaining = Some(timeout);
-                        Response::Ok(
-                            OkResponse::new(State::Advertising)
-                                .with_remaining(timeout)
-                                .with_wifi_connected(s.wifi_connected),
-                        )
+                        match s.handle(Input::StartAdvertising { timeout }) {
+                            Ok(()) => Response::Ok(
+                                OkResponse::new(s.state)
+                                    .with_remaining(timeout)
+                                    .with_wifi_connected(s.wifi_connected),
+                            ),
+                            Err(e) => Response::Error(ErrorResponse::new(e.to_string())),
+                        }
                     }
                     Command::Stop => {
                         let mut s = state.write().await;
-                        s.state = State::Idle;
-                        s.advertising_remaining = None;
-                        Response::Ok(OkResponse::new(State::Idle))
+                        match s.handle(Input::StopAdvertising) {
+                            Ok(()) => Response::Ok(OkResponse::new(s.state)),
+                            Err(e) => Response::Error(ErrorResponse::new(e.to_string())),
+                        }
                     }
                     Command::Status => {
                         let s = state.read().await;
@@ -81,12 +90,66 @@ async fn handle_test_connection(
                         let networks = vec![
                             Network {
                                 ssid: "TestNetwork".into(),
-                                signal: -50,
+                                signal: Some(-50),
                                 security: "wpa2".into(),
+                                ..Default::default()
                             },
                         ];
                         Response::Ok(OkResponse::new(State::Idle).with_networks(networks))
                     }
+                    Command::Authorize => {
+                        let s = state.read().await;
+                        Response::Ok(OkResponse::new(s.state))
+                    }
+                    Command::ListSaved
+                    | Command::Forget { .. }
+                    | Command::SetPriority { .. }
+                    | Command::SetAutoconnect { .. } => {
+                        let saved = vec![SavedNetwork {
+                            id: "0b1c".into(),
+                            ssid: "TestNetwork".into(),
+                            ssid_hex: None,
+                            priority: 0,
+                            autoconnect: true,
+                            persistent: true,
+                        }];
+                        Response::Ok(OkResponse::new(State::Idle).with_saved(saved))
+                    }
+                    Command::Connect { ssid, .. } => {
+                        let mut s = state.write().await;
+                        let result = s
+                            .handle(Input::ProvisioningStarted(ssid.into()))
+                            .and_then(|()| {
+                                s.handle(Input::ProvisioningSucceeded { redirect_url: None })
+                            });
+                        match result {
+                            Ok(()) => Response::Ok(
+                                OkResponse::new(s.state).with_wifi_connected(s.wifi_connected),
+                            ),
+                            Err(e) => Response::Error(ErrorResponse::new(e.to_string())),
+                        }
+                    }
+                    Command::SetCountry { country } => match country.parse() {
+                        Ok(country) => {
+                            let s = state.read().await;
+                            Response::Ok(OkResponse::new(s.state).with_country(country))
+                        }
+                        Err(e) => Response::Error(ErrorResponse::new(e.to_string())),
+                    },
+                    Command::Cancel { target } => Response::Error(ErrorResponse::new(
+                        format!("No request {} in progress", target),
+                    )),
+                    Command::Hello => {
+                        let s = state.read().await;
+                        let hello = Hello::new(vec![Transport::Websocket], "nmcli");
+                        Response::Ok(OkResponse::new(s.state).with_hello(hello))
+                    }
+                    Command::Subscribe => {
+                        let s = state.read().await;
+                        Response::Ok(
+                            OkResponse::new(s.state).with_wifi_connected(s.wifi_connected),
+                        )
+                    }
                 },
                 Err(e) => Response::Error(ErrorResponse::new(format!("Invalid command: {}", e))),
             };
@@ -243,3 +306,31 @@ async fn test_multiple_commands_same_connection() {
     assert_eq!(resp4["state"], "idle");
     assert_eq!(resp5["state"], "idle");
 }
+
+#[tokio::test]
+async fn test_list_saved_command() {
+    let addr = start_test_server().await;
+    let mut ws = connect(addr).await;
+
+    let resp = send_command(&mut ws, json!({"cmd": "list_saved"})).await;
+
+    assert_eq!(resp["ok"], true);
+    assert_eq!(resp["saved"][0]["ssid"], "TestNetwork");
+    assert_eq!(resp["saved"][0]["persistent"], true);
+}
+
+#[tokio::test]
+async fn test_hello_command() {
+    let addr = start_test_server().await;
+    let mut ws = connect(addr).await;
+
+    let resp = send_command(&mut ws, json!({"cmd": "hello"})).await;
+
+    assert_eq!(resp["ok"], true);
+    assert_eq!(resp["hello"]["protocol_version"], 1);
+    assert_eq!(resp["hello"]["transports"], json!(["websocket"]));
+    assert!(resp["hello"]["commands"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("scan")));
+}